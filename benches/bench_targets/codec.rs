@@ -0,0 +1,61 @@
+// Parse and serialize throughput for representative messages.
+//
+// Run with: cargo bench --manifest-path benches/Cargo.toml
+use codecrafters_dns_server::dns::dns_id::FixedIds;
+use codecrafters_dns_server::dns::dns_record::DnsRecord;
+use codecrafters_dns_server::dns::DnsMessage;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+// Captured packets shared with the fuzz corpus
+const A_QUERY: &[u8] = include_bytes!("../../fuzz/corpus/parse/a_query.bin");
+const A_RESPONSE_COMPRESSED: &[u8] =
+    include_bytes!("../../fuzz/corpus/parse/a_response_compressed.bin");
+const CNAME_CHAIN: &[u8] = include_bytes!("../../fuzz/corpus/parse/cname_chain.bin");
+
+// Records in the large response, close to what fits a 1232-byte EDNS payload
+const LARGE_RESPONSE_ANSWERS: u8 = 64;
+
+// A response carrying many A records for one name, as a round-robin pool would
+fn large_response() -> Vec<u8> {
+    let request = DnsMessage::query(&FixedIds::new(vec![0x1234]), "pool.example.com", 1);
+    let answers = (0..LARGE_RESPONSE_ANSWERS)
+        .map(|host| DnsRecord::new(request.questions[0].name.clone(), [10, 0, 0, host].into()))
+        .collect();
+
+    DnsMessage::new_answer_from_request(&request, answers)
+        .to_bytes()
+        .to_vec()
+}
+
+fn fixtures() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("a_query", A_QUERY.to_vec()),
+        ("a_response_compressed", A_RESPONSE_COMPRESSED.to_vec()),
+        ("cname_chain", CNAME_CHAIN.to_vec()),
+        ("large_response", large_response()),
+    ]
+}
+
+fn bench_from_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("from_bytes");
+    for (name, packet) in fixtures() {
+        group.throughput(Throughput::Bytes(packet.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| DnsMessage::from_bytes(black_box(&packet)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_to_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("to_bytes");
+    for (name, packet) in fixtures() {
+        let message = DnsMessage::from_bytes(&packet).unwrap();
+        group.throughput(Throughput::Bytes(packet.len() as u64));
+        group.bench_function(name, |b| b.iter(|| black_box(&message).to_bytes()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_from_bytes, bench_to_bytes);
+criterion_main!(benches);
@@ -0,0 +1,152 @@
+// A tiny dig-style lookup tool built on the server's DNS library.
+//
+// Usage: cargo run --example dig -- <name> [type] [@server]
+//   e.g. cargo run --example dig -- codecrafters.io A @8.8.8.8:53
+
+use codecrafters_dns_server::dns::dns_error::DnsError;
+use codecrafters_dns_server::dns::dns_id::RandomIds;
+use codecrafters_dns_server::dns::dns_question::DnsQuestion;
+use codecrafters_dns_server::dns::dns_record::DnsRecord;
+use codecrafters_dns_server::dns::DnsMessage;
+use codecrafters_dns_server::transport::{Transport, UdpTransport};
+use std::env;
+use std::process;
+
+// Map a record type mnemonic (or a plain number) to its numeric value
+fn parse_record_type(value: &str) -> Option<u16> {
+    match value.to_ascii_uppercase().as_str() {
+        "A" => Some(1),
+        "NS" => Some(2),
+        "CNAME" => Some(5),
+        "SOA" => Some(6),
+        "PTR" => Some(12),
+        "MX" => Some(15),
+        "TXT" => Some(16),
+        "AAAA" => Some(28),
+        "SPF" => Some(99),
+        "ANY" => Some(255),
+        other => other.parse().ok(),
+    }
+}
+
+// Resolve a name through the given transport and return the answer records
+fn resolve(
+    transport: &dyn Transport,
+    name: &str,
+    record_type: u16,
+) -> Result<Vec<DnsRecord>, DnsError> {
+    let mut query = DnsMessage::query(&RandomIds, name, record_type);
+    query.questions[0] = DnsQuestion::new_for(name, record_type, 1)?;
+    let response_bytes = transport.exchange(&query.to_bytes())?;
+    let response = DnsMessage::from_bytes(&response_bytes)?;
+
+    Ok(response.answers)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let mut name = None;
+    let mut record_type = 1; // A record by default
+    let mut server = String::from("8.8.8.8:53");
+
+    for arg in &args {
+        if let Some(address) = arg.strip_prefix('@') {
+            server = address.to_string();
+        } else if name.is_none() {
+            name = Some(arg.clone());
+        } else {
+            record_type = match parse_record_type(arg) {
+                Some(record_type) => record_type,
+                None => {
+                    eprintln!("Unknown record type: {}", arg);
+                    process::exit(2);
+                }
+            };
+        }
+    }
+
+    let name = match name {
+        Some(name) => name,
+        None => {
+            eprintln!("Usage: dig <name> [type] [@server]");
+            process::exit(2);
+        }
+    };
+
+    let transport = UdpTransport::new(&server);
+    match resolve(&transport, &name, record_type) {
+        Ok(answers) => {
+            if answers.is_empty() {
+                println!(";; no answers for {} from {}", name, transport.describe());
+            }
+
+            for answer in answers {
+                let owner = answer
+                    .decode_name()
+                    .unwrap_or_else(|_| String::from("<invalid>"));
+                println!(
+                    "{}\t{}\tTYPE{}\t{}",
+                    owner,
+                    answer.ttl,
+                    answer.record_type,
+                    answer.rdata_to_string()
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!(";; lookup failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    // Answers every query with two A records for the queried name
+    struct MockResolver;
+
+    impl Transport for MockResolver {
+        fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, DnsError> {
+            let query = DnsMessage::from_bytes(query)?;
+            let name = query.questions[0].name.clone();
+            let answers = vec![
+                DnsRecord::new(name.clone(), Ipv4Addr::new(192, 0, 2, 1)),
+                DnsRecord::new(name, Ipv4Addr::new(192, 0, 2, 2)),
+            ];
+            Ok(DnsMessage::new_answer_from_request(&query, answers)
+                .to_bytes()
+                .to_vec())
+        }
+
+        fn describe(&self) -> String {
+            String::from("mock")
+        }
+    }
+
+    #[test]
+    fn resolve_returns_the_answer_records() {
+        let answers = resolve(&MockResolver, "example.com", 1).unwrap();
+
+        let addresses: Vec<_> = answers
+            .iter()
+            .filter_map(DnsRecord::rdata_as_ipv4)
+            .collect();
+        assert_eq!(
+            addresses,
+            vec![Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)]
+        );
+        assert_eq!(answers[0].decode_name().unwrap(), "example.com");
+    }
+
+    #[test]
+    fn record_types_parse_by_name_or_number() {
+        assert_eq!(parse_record_type("aaaa"), Some(28));
+        assert_eq!(parse_record_type("MX"), Some(15));
+        assert_eq!(parse_record_type("65"), Some(65));
+        assert_eq!(parse_record_type("BOGUS"), None);
+    }
+}
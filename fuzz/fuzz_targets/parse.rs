@@ -0,0 +1,20 @@
+// Feed arbitrary bytes to the message parser: it must return Ok or Err, never panic.
+//
+// Run with: cargo +nightly fuzz run parse
+#![no_main]
+
+use codecrafters_dns_server::dns::DnsMessage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = DnsMessage::from_bytes(data) {
+        // Anything we managed to parse must also be safe to serialize and decode
+        let _ = message.to_bytes();
+        for question in &message.questions {
+            let _ = question.decode_name();
+        }
+        for answer in &message.answers {
+            let _ = answer.decode_name();
+        }
+    }
+});
@@ -0,0 +1,106 @@
+use crate::dns::dns_error::DnsError;
+use crate::dns::dns_question::DnsQuestion;
+use std::collections::HashSet;
+use std::fs;
+
+// Domains we refuse to resolve, e.g. ad and tracker hosts.
+// A plain entry blocks exactly that name; "*.example.com" blocks every name
+// below example.com.
+#[derive(Default)]
+pub struct Blocklist {
+    exact: HashSet<String>,
+    suffixes: HashSet<String>,
+}
+
+impl Blocklist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: &str) -> Result<Self, DnsError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| DnsError::Zone(format!("cannot read {}: {}", path, e)))?;
+        Ok(Self::parse(&text))
+    }
+
+    // One domain per line; '#' starts a comment
+    pub fn parse(text: &str) -> Self {
+        let mut blocklist = Blocklist::new();
+
+        for line in text.lines() {
+            let entry = line.split('#').next().unwrap_or("").trim();
+            let entry = entry.trim_end_matches('.').to_ascii_lowercase();
+            if entry.is_empty() {
+                continue;
+            }
+
+            match entry.strip_prefix("*.") {
+                Some(suffix) => blocklist.suffixes.insert(suffix.to_string()),
+                None => blocklist.exact.insert(entry),
+            };
+        }
+
+        blocklist
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.suffixes.is_empty()
+    }
+
+    // Check an encoded domain name against the list
+    pub fn contains(&self, name: &[u8]) -> bool {
+        let name = match DnsQuestion::decode_domain_name(name) {
+            Ok(name) => name.to_ascii_lowercase(),
+            Err(_) => return false,
+        };
+
+        if self.exact.contains(&name) {
+            return true;
+        }
+
+        // Walk up the parents: a.b.example.com -> b.example.com -> example.com -> com
+        let mut rest = name.as_str();
+        while let Some((_, parent)) = rest.split_once('.') {
+            if self.suffixes.contains(parent) {
+                return true;
+            }
+            rest = parent;
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks(blocklist: &Blocklist, domain: &str) -> bool {
+        blocklist.contains(&DnsQuestion::encode_domain_name(domain))
+    }
+
+    #[test]
+    fn exact_entries_block_only_that_name() {
+        let blocklist = Blocklist::parse("ads.example.test\n");
+        assert!(blocks(&blocklist, "ads.example.test"));
+        assert!(blocks(&blocklist, "ADS.Example.Test"));
+        assert!(!blocks(&blocklist, "x.ads.example.test"));
+        assert!(!blocks(&blocklist, "example.test"));
+    }
+
+    #[test]
+    fn wildcard_entries_block_every_name_below() {
+        let blocklist = Blocklist::parse("*.doubleclick.test\n");
+        assert!(blocks(&blocklist, "ad.doubleclick.test"));
+        assert!(blocks(&blocklist, "a.b.doubleclick.test"));
+        assert!(!blocks(&blocklist, "doubleclick.test"));
+        assert!(!blocks(&blocklist, "notdoubleclick.test"));
+    }
+
+    #[test]
+    fn comments_blank_lines_and_trailing_dots() {
+        let blocklist = Blocklist::parse("# trackers\n\ntracker.test. # inline\n");
+        assert!(blocks(&blocklist, "tracker.test"));
+        assert!(Blocklist::parse("# nothing\n\n").is_empty());
+    }
+}
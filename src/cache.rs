@@ -0,0 +1,641 @@
+use crate::dns::dns_error::DnsError;
+use crate::dns::dns_record::DnsRecord;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Default number of entries kept before evicting
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+// Version of the on-disk cache format, bumped whenever the layout changes
+const CACHE_FILE_VERSION: u32 = 2;
+
+// TTL given to stale answers (RFC 8767 recommends 30 seconds)
+pub const STALE_ANSWER_TTL: u32 = 30;
+
+// QTYPE value for ANY queries
+pub const QTYPE_ANY: u16 = 255;
+
+// The record type part of a cache key. ANY answers are only whatever the upstream
+// chose to return, so they live under their own key: they never satisfy a
+// specific-type query, and specific-type entries are never merged to answer ANY.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum QueryType {
+    Any,
+    Specific(u16),
+}
+
+impl QueryType {
+    pub fn from_u16(record_type: u16) -> Self {
+        if record_type == QTYPE_ANY {
+            QueryType::Any
+        } else {
+            QueryType::Specific(record_type)
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            QueryType::Any => QTYPE_ANY,
+            QueryType::Specific(record_type) => record_type,
+        }
+    }
+}
+
+// Cached answers are keyed by the question they answer
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: Vec<u8>, // Encoded domain name, lowercased
+    pub query_type: QueryType,
+    pub class: u16,
+}
+
+impl CacheKey {
+    pub fn new(name: &[u8], record_type: u16, class: u16) -> Self {
+        CacheKey {
+            name: name.to_ascii_lowercase(),
+            query_type: QueryType::from_u16(record_type),
+            class,
+        }
+    }
+}
+
+struct CacheEntry {
+    answers: Vec<DnsRecord>,
+    expires_at: Instant,
+    last_used: u64, // Position in the recency order
+}
+
+// The cache as saved to disk, as JSON
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    entries: Vec<SavedEntry>,
+}
+
+// A saved entry: its key, its absolute expiry in UNIX seconds and its answers in
+// wire format (uncompressed, with TTLs as they were when saved)
+#[derive(Serialize, Deserialize)]
+struct SavedEntry {
+    name: Vec<u8>,
+    record_type: u16,
+    class: u16,
+    expires: u64,
+    answers: Vec<Vec<u8>>,
+}
+
+// Answer cache with TTL expiry and least-recently-used eviction
+pub struct DnsCache {
+    capacity: usize,
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Access order: the smallest tick is the least recently used entry
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    // How long expired entries are kept around for serving stale (zero disables it)
+    max_stale: Duration,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize) -> Self {
+        DnsCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            max_stale: Duration::ZERO,
+        }
+    }
+
+    // Keep expired entries for up to `max_stale` so `get_stale` can fall back on them
+    pub fn with_max_stale(mut self, max_stale: Duration) -> Self {
+        self.max_stale = max_stale;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Look up live answers for a question, refreshing the entry's recency.
+    // TTLs are reduced by the time the answers spent in the cache.
+    pub fn get(&mut self, key: &CacheKey, now: Instant) -> Option<Vec<DnsRecord>> {
+        let entry = self.entries.get(key)?;
+
+        // Drop expired entries on access, unless they may still be served stale
+        if now >= entry.expires_at {
+            if self.is_past_stale(entry, now) {
+                self.remove(key);
+            }
+            return None;
+        }
+
+        let answers = entry
+            .answers
+            .iter()
+            .map(|record| Self::aged_copy(record, now))
+            .collect();
+
+        self.touch(key);
+        Some(answers)
+    }
+
+    // Look up answers regardless of expiry, as long as they expired less than
+    // `max_stale` ago (RFC 8767). Meant for when the upstream can't be reached;
+    // the answers get a short TTL so clients come back soon.
+    pub fn get_stale(&mut self, key: &CacheKey, now: Instant) -> Option<Vec<DnsRecord>> {
+        let entry = self.entries.get(key)?;
+        if self.is_past_stale(entry, now) {
+            return None;
+        }
+
+        let answers = entry
+            .answers
+            .iter()
+            .map(|record| {
+                let mut record = Self::aged_copy(record, now);
+                record.ttl = STALE_ANSWER_TTL;
+                record
+            })
+            .collect();
+
+        self.touch(key);
+        Some(answers)
+    }
+
+    // Whether an entry is beyond even the stale-serving window
+    fn is_past_stale(&self, entry: &CacheEntry, now: Instant) -> bool {
+        now >= entry.expires_at + self.max_stale
+    }
+
+    // Store answers for a question; they expire after `ttl` seconds, normally the
+    // response's `DnsMessage::min_ttl`. A TTL of 0 means "use once, never cache",
+    // so such answers are not stored (and never evict a live entry).
+    pub fn insert(&mut self, key: CacheKey, answers: Vec<DnsRecord>, ttl: u32, now: Instant) {
+        if self.capacity == 0 || answers.is_empty() || ttl == 0 {
+            return;
+        }
+
+        let expires_at = now + Duration::from_secs(ttl as u64);
+        self.insert_entry(key, answers, now, expires_at);
+    }
+
+    // Store an entry with an explicit expiry, evicting if the cache is full
+    fn insert_entry(
+        &mut self,
+        key: CacheKey,
+        answers: Vec<DnsRecord>,
+        now: Instant,
+        expires_at: Instant,
+    ) {
+        // Replace any previous entry for the same question
+        self.remove(&key);
+
+        // TTLs count down from the moment the answers entered the cache
+        let answers = answers
+            .into_iter()
+            .map(|mut record| {
+                record.received_at = Some(now);
+                record
+            })
+            .collect();
+
+        // Make room by evicting the least recently used entry
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                answers,
+                expires_at,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    // Write all live entries to disk, least recently used first so that loading
+    // them back in order restores the recency order
+    pub fn save(&self, path: &str, now: Instant) -> Result<usize, DnsError> {
+        let now_unix = unix_now();
+        let entries: Vec<SavedEntry> = self
+            .recency
+            .values()
+            .map(|key| (key, &self.entries[key]))
+            .filter(|(_, entry)| now < entry.expires_at)
+            .map(|(key, entry)| SavedEntry {
+                name: key.name.clone(),
+                record_type: key.query_type.to_u16(),
+                class: key.class,
+                expires: now_unix + entry.expires_at.duration_since(now).as_secs(),
+                answers: entry
+                    .answers
+                    .iter()
+                    .map(|answer| Self::aged_copy(answer, now).to_bytes().to_vec())
+                    .collect(),
+            })
+            .collect();
+
+        let saved = entries.len();
+        let file = CacheFile {
+            version: CACHE_FILE_VERSION,
+            entries,
+        };
+        let json = serde_json::to_vec(&file).map_err(|e| DnsError::CacheFile(e.to_string()))?;
+
+        fs::write(path, json)?;
+        Ok(saved)
+    }
+
+    // Load entries saved by `save`, skipping ones that expired while we were down.
+    // Returns the number of entries restored.
+    pub fn load(&mut self, path: &str, now: Instant) -> Result<usize, DnsError> {
+        let bytes = fs::read(path)?;
+        let now_unix = unix_now();
+
+        let file: CacheFile =
+            serde_json::from_slice(&bytes).map_err(|e| DnsError::CacheFile(e.to_string()))?;
+        if file.version != CACHE_FILE_VERSION {
+            return Err(DnsError::CacheFile(format!(
+                "unsupported version {}",
+                file.version
+            )));
+        }
+
+        // Parse everything first so a corrupt file leaves the cache untouched
+        let mut loaded = Vec::new();
+        for entry in file.entries {
+            let answers = entry
+                .answers
+                .iter()
+                .map(|answer| DnsRecord::from_bytes(answer, 0).map(|(record, _)| record))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if entry.expires > now_unix {
+                let key = CacheKey::new(&entry.name, entry.record_type, entry.class);
+                let expires_at = now + Duration::from_secs(entry.expires - now_unix);
+                loaded.push((key, answers, expires_at));
+            }
+        }
+
+        let restored = loaded.len();
+        for (key, answers, expires_at) in loaded {
+            self.insert_entry(key, answers, now, expires_at);
+        }
+
+        Ok(restored)
+    }
+
+    // Copy of a cached record with its TTL reduced to what remains at `now`
+    fn aged_copy(record: &DnsRecord, now: Instant) -> DnsRecord {
+        let mut record = record.clone();
+        record.ttl = record.remaining_ttl(now);
+        record.received_at = Some(now);
+        record
+    }
+
+    // Drop all expired entries (past the stale window, if any), returning how many
+    // were removed
+    pub fn purge_expired(&mut self, now: Instant) -> usize {
+        let expired: Vec<CacheKey> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| self.is_past_stale(entry, now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            self.remove(key);
+        }
+
+        expired.len()
+    }
+
+    // Drop every entry
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    // Mark an entry as the most recently used
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.last_used);
+            self.tick += 1;
+            entry.last_used = self.tick;
+            self.recency.insert(self.tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+}
+
+// Seconds since the UNIX epoch, for expiries that must survive a restart
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::dns_question::DnsQuestion;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::path::PathBuf;
+
+    // A cache file in the temp directory, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            TempFile(std::env::temp_dir().join(format!(
+                "dns-cache-{}-{}.json",
+                std::process::id(),
+                name
+            )))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn name(domain: &str) -> Vec<u8> {
+        DnsQuestion::encode_domain_name(domain)
+    }
+
+    fn a_record(domain: &str, ttl: u32) -> DnsRecord {
+        let mut record = DnsRecord::new(name(domain), Ipv4Addr::new(192, 0, 2, 1));
+        record.ttl = ttl;
+        record
+    }
+
+    fn key(domain: &str, record_type: u16) -> CacheKey {
+        CacheKey::new(&name(domain), record_type, 1)
+    }
+
+    #[test]
+    fn full_cache_evicts_the_least_recently_inserted() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(3);
+        for domain in ["a.test", "b.test", "c.test", "d.test"] {
+            cache.insert(key(domain, 1), vec![a_record(domain, 60)], 60, now);
+        }
+
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.capacity(), 3);
+        assert!(cache.get(&key("a.test", 1), now).is_none());
+        for domain in ["b.test", "c.test", "d.test"] {
+            assert!(cache.get(&key(domain, 1), now).is_some(), "{}", domain);
+        }
+    }
+
+    #[test]
+    fn access_refreshes_recency() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(2);
+        cache.insert(key("a.test", 1), vec![a_record("a.test", 60)], 60, now);
+        cache.insert(key("b.test", 1), vec![a_record("b.test", 60)], 60, now);
+
+        // a.test becomes the most recently used, leaving b.test to be evicted
+        assert!(cache.get(&key("a.test", 1), now).is_some());
+        cache.insert(key("c.test", 1), vec![a_record("c.test", 60)], 60, now);
+
+        assert!(cache.get(&key("a.test", 1), now).is_some());
+        assert!(cache.get(&key("b.test", 1), now).is_none());
+        assert!(cache.get(&key("c.test", 1), now).is_some());
+    }
+
+    #[test]
+    fn replacing_an_entry_does_not_evict_another() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(2);
+        cache.insert(key("a.test", 1), vec![a_record("a.test", 60)], 60, now);
+        cache.insert(key("b.test", 1), vec![a_record("b.test", 60)], 60, now);
+        cache.insert(key("b.test", 1), vec![a_record("b.test", 30)], 30, now);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&key("a.test", 1), now).is_some());
+    }
+
+    #[test]
+    fn keys_ignore_name_case() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(2);
+        cache.insert(
+            key("Example.TEST", 1),
+            vec![a_record("example.test", 60)],
+            60,
+            now,
+        );
+        assert!(cache.get(&key("example.test", 1), now).is_some());
+    }
+
+    #[test]
+    fn zero_capacity_caches_nothing() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(0);
+        cache.insert(key("a.test", 1), vec![a_record("a.test", 60)], 60, now);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn save_then_load_keeps_live_entries_only() {
+        let file = TempFile::new("live-only");
+        let start = Instant::now();
+        let mut cache = DnsCache::new(10);
+        cache.insert(
+            key("live.test", 1),
+            vec![a_record("live.test", 300)],
+            300,
+            start,
+        );
+        let mut aaaa = DnsRecord::new_aaaa(name("gone.test"), Ipv6Addr::LOCALHOST);
+        aaaa.ttl = 5;
+        cache.insert(key("gone.test", 28), vec![aaaa], 5, start);
+
+        // The AAAA entry has expired by the time the cache is saved
+        let saved_at = start + Duration::from_secs(10);
+        assert_eq!(cache.save(file.path(), saved_at).unwrap(), 1);
+
+        let now = Instant::now();
+        let mut restored = DnsCache::new(10);
+        assert_eq!(restored.load(file.path(), now).unwrap(), 1);
+        assert!(restored.get(&key("gone.test", 28), now).is_none());
+
+        let answers = restored.get(&key("live.test", 1), now).unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(
+            answers[0].rdata_as_ipv4(),
+            Some(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        // The TTL kept counting down while the entry was cached
+        assert!(answers[0].ttl <= 290, "ttl {}", answers[0].ttl);
+    }
+
+    #[test]
+    fn load_skips_entries_that_expired_while_down() {
+        let file = TempFile::new("expired-while-down");
+        let record = a_record("example.test", 60).to_bytes().to_vec();
+        let saved = CacheFile {
+            version: CACHE_FILE_VERSION,
+            entries: vec![
+                SavedEntry {
+                    name: name("old.test"),
+                    record_type: 1,
+                    class: 1,
+                    expires: unix_now() - 1,
+                    answers: vec![record.clone()],
+                },
+                SavedEntry {
+                    name: name("new.test"),
+                    record_type: 1,
+                    class: 1,
+                    expires: unix_now() + 60,
+                    answers: vec![record],
+                },
+            ],
+        };
+        fs::write(file.path(), serde_json::to_vec(&saved).unwrap()).unwrap();
+
+        let now = Instant::now();
+        let mut cache = DnsCache::new(10);
+        assert_eq!(cache.load(file.path(), now).unwrap(), 1);
+        assert!(cache.get(&key("old.test", 1), now).is_none());
+        assert!(cache.get(&key("new.test", 1), now).is_some());
+    }
+
+    #[test]
+    fn load_restores_recency_order() {
+        let file = TempFile::new("recency");
+        let now = Instant::now();
+        let mut cache = DnsCache::new(3);
+        for domain in ["a.test", "b.test", "c.test"] {
+            cache.insert(key(domain, 1), vec![a_record(domain, 60)], 60, now);
+        }
+        cache.get(&key("a.test", 1), now);
+        cache.save(file.path(), now).unwrap();
+
+        let mut restored = DnsCache::new(3);
+        restored.load(file.path(), now).unwrap();
+        restored.insert(key("d.test", 1), vec![a_record("d.test", 60)], 60, now);
+
+        // b.test was the least recently used before saving
+        assert!(restored.get(&key("b.test", 1), now).is_none());
+        assert!(restored.get(&key("a.test", 1), now).is_some());
+        assert!(restored.get(&key("c.test", 1), now).is_some());
+    }
+
+    #[test]
+    fn unreadable_files_leave_the_cache_untouched() {
+        let file = TempFile::new("unreadable");
+        let now = Instant::now();
+        let mut cache = DnsCache::new(10);
+        cache.insert(
+            key("kept.test", 1),
+            vec![a_record("kept.test", 60)],
+            60,
+            now,
+        );
+
+        fs::write(file.path(), b"DNSC\x01\x00\x00\x00\x00").unwrap();
+        assert!(cache.load(file.path(), now).is_err());
+
+        let other_version = CacheFile {
+            version: CACHE_FILE_VERSION + 1,
+            entries: Vec::new(),
+        };
+        fs::write(file.path(), serde_json::to_vec(&other_version).unwrap()).unwrap();
+        assert!(cache.load(file.path(), now).is_err());
+
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn any_answers_do_not_satisfy_specific_types() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(10);
+        cache.insert(
+            key("a.test", QTYPE_ANY),
+            vec![a_record("a.test", 60)],
+            60,
+            now,
+        );
+
+        assert!(cache.get(&key("a.test", 1), now).is_none());
+        assert!(cache.get(&key("a.test", QTYPE_ANY), now).is_some());
+    }
+
+    #[test]
+    fn specific_types_are_not_merged_for_any() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(10);
+        cache.insert(key("a.test", 1), vec![a_record("a.test", 60)], 60, now);
+        let aaaa = DnsRecord::new_aaaa(name("a.test"), Ipv6Addr::LOCALHOST);
+        cache.insert(key("a.test", 28), vec![aaaa], 60, now);
+
+        assert!(cache.get(&key("a.test", QTYPE_ANY), now).is_none());
+        assert!(cache.get(&key("a.test", 1), now).is_some());
+        assert!(cache.get(&key("a.test", 28), now).is_some());
+    }
+
+    #[test]
+    fn ttl_zero_answers_are_not_stored() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(10);
+        cache.insert(key("a.test", 1), vec![a_record("a.test", 0)], 0, now);
+
+        assert!(cache.is_empty());
+        assert!(cache.get(&key("a.test", 1), now).is_none());
+    }
+
+    #[test]
+    fn stale_entries_are_served_within_the_window() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(10).with_max_stale(Duration::from_secs(60));
+        cache.insert(key("a.test", 1), vec![a_record("a.test", 10)], 10, now);
+
+        let later = now + Duration::from_secs(20);
+        assert!(cache.get(&key("a.test", 1), later).is_none());
+        let stale = cache.get_stale(&key("a.test", 1), later).unwrap();
+        assert_eq!(stale[0].ttl, STALE_ANSWER_TTL);
+
+        let too_late = now + Duration::from_secs(70);
+        assert!(cache.get_stale(&key("a.test", 1), too_late).is_none());
+    }
+
+    #[test]
+    fn stale_entries_are_dropped_without_a_window() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(10);
+        cache.insert(key("a.test", 1), vec![a_record("a.test", 10)], 10, now);
+
+        let later = now + Duration::from_secs(20);
+        assert!(cache.get_stale(&key("a.test", 1), later).is_none());
+    }
+}
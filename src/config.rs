@@ -0,0 +1,728 @@
+use crate::cache::DEFAULT_CACHE_SIZE;
+use crate::config_file;
+use crate::dns::dns_edns::ClientSubnet;
+use crate::dns::dns_record::DnsRecord;
+use crate::dns::dns_tsig::TsigKey;
+use crate::dns::DEFAULT_MAX_ANSWERS;
+use crate::dns64::{self, Dns64Prefix};
+use crate::rules::{Rule, RuleEngine};
+use crate::transport::UPSTREAM_TIMEOUT;
+use crate::upstream::UpstreamStrategy;
+use crate::views::{self, ClientNetwork};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+// UDP payload size we advertise to EDNS clients (the DNS Flag Day 2020 value)
+const DEFAULT_EDNS_UDP_SIZE: u16 = 1232;
+
+// How long expired cache entries stay servable with --serve-stale (one day)
+const DEFAULT_MAX_STALE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Queries waiting for a worker before new ones are shed
+const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+// Where the server listens for queries unless told otherwise
+const DEFAULT_LISTEN: &str = "127.0.0.1:2053";
+
+// Default upstream: Google's public DNS server
+const DEFAULT_RESOLVER: &str = "8.8.8.8:53";
+
+// Protocol used to talk to the upstream resolver
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ResolverProto {
+    Udp,
+    Tcp,
+    Doh,
+}
+
+// What to do with queries we decide not to answer
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    Refuse, // Reply with RCODE=5 so the client doesn't sit waiting for a timeout
+    Silent, // Send nothing at all
+}
+
+// How requests with more than one question are handled
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MultiQuestion {
+    Reject,      // Answer FORMERR, as most real resolvers do
+    Split,       // Forward each question separately and combine the answers
+    Passthrough, // Forward the request unchanged and relay whatever comes back
+}
+
+// Runtime settings collected from the command line
+pub struct Config {
+    pub listen: SocketAddr,
+    pub resolvers: Vec<String>,
+    pub upstream_strategy: UpstreamStrategy,
+    pub resolver_proto: ResolverProto,
+    pub hide_version: bool,
+    pub cache_size: usize,
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+    pub drop_policy: DropPolicy,
+    pub multi_question: MultiQuestion,
+    pub blocked_types: Vec<u16>,
+    pub control_path: Option<String>,
+    pub zone_path: Option<String>,
+    pub zone_ttls: Vec<(u16, u32)>, // Default TTL by record type for zone records
+    pub cache_file: Option<String>,
+    pub ecs: Option<ClientSubnet>,
+    pub hosts_path: Option<String>,
+    pub local_only: bool,
+    pub allow_update: bool,
+    pub tsig_key: Option<TsigKey>,
+    pub allow_transfer: Vec<ClientNetwork>,
+    pub force_tcp_for: Vec<ClientNetwork>,
+    pub serve_stale: bool,
+    pub max_stale: Duration,
+    pub minimal_responses: bool,
+    pub single_answer: bool,
+    pub relay_upstream_errors: bool,
+    pub canonicalize_names: bool,
+    pub blocklist_path: Option<String>,
+    pub sinkhole: Vec<IpAddr>,
+    pub rules: RuleEngine,
+    pub dns64: Option<Dns64Prefix>,
+    pub max_answers: usize,
+    pub dump_packets: bool,
+    pub debug_delay: Option<Duration>,
+    pub workers: usize,
+    pub queue_size: usize,
+    pub overload_refuse: bool,
+    pub max_concurrent_forwards: Option<usize>,
+    pub forward_wait: Duration,
+    pub upstream_timeout: Duration,
+    pub request_timeout: Option<Duration>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub edns_udp_size: u16,
+    pub parse_file: Option<String>,
+    pub zone_url: Option<String>,
+    pub zone_refresh: Option<Duration>,
+    pub views: Vec<(ClientNetwork, String)>,
+    pub reverse_zones: Vec<(ClientNetwork, String)>,
+}
+
+impl Config {
+    // Parse settings from the process arguments (args[0] is the program name),
+    // on top of those from the `--config` file when one is given
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut config = Self::defaults();
+        let args = args.get(1..).unwrap_or_default();
+
+        if let Some(path) = Self::config_path(args)? {
+            for setting in config_file::load(path)? {
+                config
+                    .apply(&setting.args, false)
+                    .map_err(|e| format!("{}: `{}`: {}", path, setting.key, e))?;
+            }
+        }
+
+        // Lists given on the command line replace the file's instead of growing them
+        config.apply(args, true)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn defaults() -> Self {
+        Config {
+            listen: DEFAULT_LISTEN.parse().unwrap(),
+            resolvers: Vec::new(),
+            upstream_strategy: UpstreamStrategy::First,
+            resolver_proto: ResolverProto::Udp,
+            hide_version: false,
+            cache_size: DEFAULT_CACHE_SIZE,
+            min_ttl: None,
+            max_ttl: None,
+            drop_policy: DropPolicy::Refuse,
+            multi_question: MultiQuestion::Split,
+            blocked_types: Vec::new(),
+            control_path: None,
+            zone_path: None,
+            zone_ttls: Vec::new(),
+            cache_file: None,
+            ecs: None,
+            hosts_path: None,
+            local_only: false,
+            allow_update: false,
+            tsig_key: None,
+            allow_transfer: Vec::new(),
+            force_tcp_for: Vec::new(),
+            serve_stale: false,
+            max_stale: DEFAULT_MAX_STALE,
+            minimal_responses: false,
+            single_answer: false,
+            relay_upstream_errors: false,
+            canonicalize_names: false,
+            blocklist_path: None,
+            sinkhole: Vec::new(),
+            rules: RuleEngine::new(),
+            dns64: None,
+            max_answers: DEFAULT_MAX_ANSWERS,
+            dump_packets: false,
+            debug_delay: None,
+            workers: 0,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            overload_refuse: false,
+            max_concurrent_forwards: None,
+            forward_wait: Duration::ZERO,
+            upstream_timeout: UPSTREAM_TIMEOUT,
+            request_timeout: None,
+            user: None,
+            group: None,
+            edns_udp_size: DEFAULT_EDNS_UDP_SIZE,
+            parse_file: None,
+            zone_url: None,
+            zone_refresh: None,
+            views: Vec::new(),
+            reverse_zones: Vec::new(),
+        }
+    }
+
+    // The `--config` file named among the arguments, if any
+    fn config_path(args: &[String]) -> Result<Option<&str>, String> {
+        match args.iter().position(|arg| arg == "--config") {
+            Some(index) => Self::value_for(args, index, "--config").map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Apply command-line style flags. With `replace_lists`, the first value for a
+    // list setting (resolvers, views, ...) drops the values it already had.
+    fn apply(&mut self, args: &[String], replace_lists: bool) -> Result<(), String> {
+        let mut replaced = Vec::new();
+        let mut first_value = |list: &'static str| {
+            let first = replace_lists && !replaced.contains(&list);
+            replaced.push(list);
+            first
+        };
+
+        let mut i = 0;
+        while i < args.len() {
+            match args[i].as_str() {
+                "--listen" => {
+                    self.listen = Self::parse_value(args, i, "--listen")?;
+                    i += 1;
+                }
+                "--resolver" => {
+                    // A bare "--resolver" keeps the default resolver. The flag may be
+                    // repeated or given a comma-separated list.
+                    if let Some(value) = args.get(i + 1) {
+                        if first_value("resolvers") {
+                            self.resolvers.clear();
+                        }
+                        self.add_resolvers(value);
+                        i += 1;
+                    }
+                }
+                "--resolver-proto" => {
+                    self.resolver_proto = match Self::value_for(args, i, "--resolver-proto")? {
+                        "udp" => ResolverProto::Udp,
+                        "tcp" => ResolverProto::Tcp,
+                        "doh" => ResolverProto::Doh,
+                        other => return Err(format!("Unknown resolver protocol: {}", other)),
+                    };
+                    i += 1;
+                }
+                "--upstream-strategy" => {
+                    self.upstream_strategy = match Self::value_for(args, i, "--upstream-strategy")?
+                    {
+                        "first" => UpstreamStrategy::First,
+                        "round-robin" => UpstreamStrategy::RoundRobin,
+                        "random" => UpstreamStrategy::Random,
+                        "fastest" => UpstreamStrategy::Fastest,
+                        other => return Err(format!("Unknown upstream strategy: {}", other)),
+                    };
+                    i += 1;
+                }
+                "--upstream-timeout" => {
+                    let millis = Self::parse_value(args, i, "--upstream-timeout")?;
+                    self.upstream_timeout = Duration::from_millis(millis);
+                    i += 1;
+                }
+                "--request-timeout" => {
+                    let millis = Self::parse_value(args, i, "--request-timeout")?;
+                    self.request_timeout = Some(Duration::from_millis(millis));
+                    i += 1;
+                }
+                // Already loaded by from_args
+                "--config" => {
+                    Self::value_for(args, i, "--config")?;
+                    i += 1;
+                }
+                "--hide-version" => self.hide_version = true,
+                "--cache-size" => {
+                    self.cache_size = Self::parse_value(args, i, "--cache-size")?;
+                    i += 1;
+                }
+                "--min-ttl" => {
+                    self.min_ttl = Some(Self::parse_value(args, i, "--min-ttl")?);
+                    i += 1;
+                }
+                "--max-ttl" => {
+                    self.max_ttl = Some(Self::parse_value(args, i, "--max-ttl")?);
+                    i += 1;
+                }
+                "--drop-policy" => {
+                    self.drop_policy = match Self::value_for(args, i, "--drop-policy")? {
+                        "refuse" => DropPolicy::Refuse,
+                        "silent" => DropPolicy::Silent,
+                        other => return Err(format!("Unknown drop policy: {}", other)),
+                    };
+                    i += 1;
+                }
+                "--multi-question" => {
+                    self.multi_question = match Self::value_for(args, i, "--multi-question")? {
+                        "reject" => MultiQuestion::Reject,
+                        "split" => MultiQuestion::Split,
+                        "passthrough" => MultiQuestion::Passthrough,
+                        other => return Err(format!("Unknown multi-question mode: {}", other)),
+                    };
+                    i += 1;
+                }
+                "--block-type" => {
+                    let record_type = Self::parse_value(args, i, "--block-type")?;
+                    if first_value("block_types") {
+                        self.blocked_types.clear();
+                    }
+                    self.blocked_types.push(record_type);
+                    i += 1;
+                }
+                "--control" => {
+                    self.control_path = Some(Self::value_for(args, i, "--control")?.to_string());
+                    i += 1;
+                }
+                "--zone" => {
+                    self.zone_path = Some(Self::value_for(args, i, "--zone")?.to_string());
+                    i += 1;
+                }
+                "--view" => {
+                    let view =
+                        views::parse_view_arg(Self::value_for(args, i, "--view")?, "--view")?;
+                    if first_value("views") {
+                        self.views.clear();
+                    }
+                    self.views.push(view);
+                    i += 1;
+                }
+                "--reverse-zone" => {
+                    let reverse_zone = views::parse_view_arg(
+                        Self::value_for(args, i, "--reverse-zone")?,
+                        "--reverse-zone",
+                    )?;
+                    if first_value("reverse_zones") {
+                        self.reverse_zones.clear();
+                    }
+                    self.reverse_zones.push(reverse_zone);
+                    i += 1;
+                }
+                "--zone-url" => {
+                    self.zone_url = Some(Self::value_for(args, i, "--zone-url")?.to_string());
+                    i += 1;
+                }
+                "--zone-refresh" => {
+                    let seconds: u64 = Self::parse_value(args, i, "--zone-refresh")?;
+                    if seconds == 0 {
+                        return Err(String::from("--zone-refresh must be at least 1 second"));
+                    }
+                    self.zone_refresh = Some(Duration::from_secs(seconds));
+                    i += 1;
+                }
+                "--zone-ttl" => {
+                    // TYPE=SECONDS, e.g. NS=86400; a later value for a type replaces
+                    // an earlier one
+                    let value = Self::value_for(args, i, "--zone-ttl")?;
+                    let (record_type, ttl) = value
+                        .split_once('=')
+                        .and_then(|(record_type, ttl)| {
+                            let record_type = record_type.to_ascii_uppercase();
+                            let record_type = DnsRecord::type_from_mnemonic(&record_type)
+                                .or_else(|| record_type.parse().ok())?;
+                            Some((record_type, ttl.parse::<u32>().ok()?))
+                        })
+                        .ok_or_else(|| {
+                            format!("Invalid --zone-ttl {}, expected TYPE=SECONDS", value)
+                        })?;
+                    if first_value("zone_ttls") {
+                        self.zone_ttls.clear();
+                    }
+                    self.zone_ttls
+                        .retain(|(configured, _)| *configured != record_type);
+                    self.zone_ttls.push((record_type, ttl));
+                    i += 1;
+                }
+                "--cache-file" => {
+                    self.cache_file = Some(Self::value_for(args, i, "--cache-file")?.to_string());
+                    i += 1;
+                }
+                "--ecs" => {
+                    self.ecs = Some(ClientSubnet::from_cidr(Self::value_for(args, i, "--ecs")?)?);
+                    i += 1;
+                }
+                "--hosts" => {
+                    self.hosts_path = Some(Self::value_for(args, i, "--hosts")?.to_string());
+                    i += 1;
+                }
+                "--blocklist" => {
+                    self.blocklist_path =
+                        Some(Self::value_for(args, i, "--blocklist")?.to_string());
+                    i += 1;
+                }
+                "--rule" => {
+                    let rule = Self::value_for(args, i, "--rule")?;
+                    if first_value("rules") {
+                        self.rules = RuleEngine::new();
+                    }
+                    self.rules.push(Rule::parse(rule)?);
+                    i += 1;
+                }
+                // May be given once for IPv4 and once for IPv6
+                "--sinkhole" => {
+                    self.sinkhole
+                        .push(Self::parse_value(args, i, "--sinkhole")?);
+                    i += 1;
+                }
+                "--dns64" => {
+                    // The prefix is optional and defaults to the well-known one
+                    let prefix = match args.get(i + 1) {
+                        Some(value) if !value.starts_with("--") => {
+                            i += 1;
+                            value.as_str()
+                        }
+                        _ => dns64::WELL_KNOWN_PREFIX,
+                    };
+                    self.dns64 = Some(Dns64Prefix::from_cidr(prefix)?);
+                }
+                "--max-answers" => {
+                    self.max_answers = Self::parse_value(args, i, "--max-answers")?;
+                    i += 1;
+                }
+                "--dump-packets" => self.dump_packets = true,
+                // Debugging aid, deliberately undocumented: hold every response
+                // back to exercise client timeouts. Never use in production.
+                "--debug-delay" => {
+                    let millis = Self::parse_value(args, i, "--debug-delay")?;
+                    self.debug_delay = Some(Duration::from_millis(millis));
+                    i += 1;
+                }
+                "--workers" => {
+                    self.workers = Self::parse_value(args, i, "--workers")?;
+                    i += 1;
+                }
+                "--queue-size" => {
+                    self.queue_size = Self::parse_value(args, i, "--queue-size")?;
+                    if self.queue_size == 0 {
+                        return Err(String::from("--queue-size must be at least 1"));
+                    }
+                    i += 1;
+                }
+                "--overload-refuse" => self.overload_refuse = true,
+                "--max-concurrent-forwards" => {
+                    let limit = Self::parse_value(args, i, "--max-concurrent-forwards")?;
+                    if limit == 0 {
+                        return Err(String::from("--max-concurrent-forwards must be at least 1"));
+                    }
+                    self.max_concurrent_forwards = Some(limit);
+                    i += 1;
+                }
+                "--forward-wait" => {
+                    let millis = Self::parse_value(args, i, "--forward-wait")?;
+                    self.forward_wait = Duration::from_millis(millis);
+                    i += 1;
+                }
+                "--user" => {
+                    self.user = Some(Self::value_for(args, i, "--user")?.to_string());
+                    i += 1;
+                }
+                "--group" => {
+                    self.group = Some(Self::value_for(args, i, "--group")?.to_string());
+                    i += 1;
+                }
+                "--edns-udp-size" => {
+                    self.edns_udp_size = Self::parse_value(args, i, "--edns-udp-size")?;
+                    if self.edns_udp_size < 512 {
+                        return Err(format!(
+                            "--edns-udp-size ({}) must be at least 512",
+                            self.edns_udp_size
+                        ));
+                    }
+                    i += 1;
+                }
+                "--parse-file" => {
+                    self.parse_file = Some(Self::value_for(args, i, "--parse-file")?.to_string());
+                    i += 1;
+                }
+                "--local-only" => self.local_only = true,
+                "--allow-update" => self.allow_update = true,
+                "--allow-transfer" => {
+                    let cidr = Self::value_for(args, i, "--allow-transfer")?;
+                    self.allow_transfer.push(ClientNetwork::from_cidr(cidr)?);
+                    i += 1;
+                }
+                "--force-tcp-for" => {
+                    let cidr = Self::value_for(args, i, "--force-tcp-for")?;
+                    self.force_tcp_for.push(ClientNetwork::from_cidr(cidr)?);
+                    i += 1;
+                }
+                "--tsig-key" => {
+                    let value = Self::value_for(args, i, "--tsig-key")?;
+                    self.tsig_key = Some(TsigKey::from_arg(value)?);
+                    i += 1;
+                }
+                "--serve-stale" => self.serve_stale = true,
+                "--max-stale" => {
+                    let seconds = Self::parse_value(args, i, "--max-stale")?;
+                    self.max_stale = Duration::from_secs(seconds);
+                    i += 1;
+                }
+                "--minimal-responses" => self.minimal_responses = true,
+                "--single-answer" => self.single_answer = true,
+                "--relay-upstream-errors" => self.relay_upstream_errors = true,
+                "--canonicalize-names" => self.canonicalize_names = true,
+                // A bare argument is the resolver address
+                other if !other.starts_with("--") => {
+                    if first_value("resolvers") {
+                        self.resolvers.clear();
+                    }
+                    self.add_resolvers(other)
+                }
+                other => return Err(format!("Unknown option: {}", other)),
+            }
+            i += 1;
+        }
+
+        Ok(())
+    }
+
+    // Check settings that depend on each other, and fill in the default resolver
+    fn validate(&mut self) -> Result<(), String> {
+        if self.resolvers.is_empty() {
+            self.resolvers.push(String::from(DEFAULT_RESOLVER));
+        }
+
+        if let (Some(min_ttl), Some(max_ttl)) = (self.min_ttl, self.max_ttl) {
+            if min_ttl > max_ttl {
+                return Err(format!(
+                    "--min-ttl ({}) must not exceed --max-ttl ({})",
+                    min_ttl, max_ttl
+                ));
+            }
+        }
+
+        if self.zone_path.is_some() && self.zone_url.is_some() {
+            return Err(String::from(
+                "--zone and --zone-url cannot be used together",
+            ));
+        }
+
+        if self.zone_refresh.is_some() && self.zone_url.is_none() {
+            return Err(String::from("--zone-refresh requires --zone-url"));
+        }
+
+        Ok(())
+    }
+
+    // The IPv6 address to serve alongside an IPv4 listen address: the same port
+    // on the IPv6 loopback or wildcard. None when listening on IPv6 already, or
+    // on a single IPv4 interface that has no IPv6 counterpart.
+    pub fn listen_v6(&self) -> Option<SocketAddr> {
+        let SocketAddr::V4(listen) = self.listen else {
+            return None;
+        };
+        let ip = if listen.ip().is_loopback() {
+            Ipv6Addr::LOCALHOST
+        } else if listen.ip().is_unspecified() {
+            Ipv6Addr::UNSPECIFIED
+        } else {
+            return None;
+        };
+        Some(SocketAddr::new(IpAddr::V6(ip), listen.port()))
+    }
+
+    // Append resolvers from a comma-separated list
+    fn add_resolvers(&mut self, list: &str) {
+        for resolver in list.split(',') {
+            if !resolver.is_empty() {
+                self.resolvers.push(resolver.to_string());
+            }
+        }
+    }
+
+    // Fetch the value following a flag, failing if it is missing
+    fn value_for<'a>(args: &'a [String], index: usize, flag: &str) -> Result<&'a str, String> {
+        match args.get(index + 1) {
+            Some(value) => Ok(value.as_str()),
+            None => Err(format!("Missing value for {}", flag)),
+        }
+    }
+
+    // Fetch and parse the value following a flag
+    fn parse_value<T: FromStr>(args: &[String], index: usize, flag: &str) -> Result<T, String> {
+        let value = Self::value_for(args, index, flag)?;
+        value
+            .parse()
+            .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A config file in the temp directory, removed when dropped
+    struct TempConfig(PathBuf);
+
+    impl TempConfig {
+        fn new(name: &str, text: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "dns-config-{}-{}.toml",
+                std::process::id(),
+                name
+            ));
+            fs::write(&path, text).unwrap();
+            TempConfig(path)
+        }
+
+        fn path(&self) -> String {
+            self.0.to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn from_args(args: &[&str]) -> Result<Config, String> {
+        let args: Vec<String> = std::iter::once("server")
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect();
+        Config::from_args(&args)
+    }
+
+    const FILE: &str = r#"
+        listen = "0.0.0.0:5353"
+        resolvers = ["1.1.1.1:53", "8.8.8.8:53"]
+        block_types = [255]
+
+        [cache]
+        size = 4096
+        min_ttl = 60
+
+        [timeouts]
+        upstream = 2000
+    "#;
+
+    #[test]
+    fn file_settings_apply_without_flags() {
+        let file = TempConfig::new("file-only", FILE);
+        let config = from_args(&["--config", &file.path()]).unwrap();
+
+        assert_eq!(config.listen, "0.0.0.0:5353".parse().unwrap());
+        assert_eq!(config.resolvers, vec!["1.1.1.1:53", "8.8.8.8:53"]);
+        assert_eq!(config.blocked_types, vec![255]);
+        assert_eq!(config.cache_size, 4096);
+        assert_eq!(config.min_ttl, Some(60));
+        assert_eq!(config.upstream_timeout, Duration::from_millis(2000));
+    }
+
+    #[test]
+    fn command_line_takes_precedence_over_file() {
+        let file = TempConfig::new("precedence", FILE);
+        let path = file.path();
+        let config = from_args(&[
+            "--cache-size",
+            "100",
+            "--config",
+            &path,
+            "--resolver",
+            "9.9.9.9:53",
+            "--listen",
+            "127.0.0.1:5300",
+        ])
+        .unwrap();
+
+        assert_eq!(config.cache_size, 100);
+        assert_eq!(config.listen, "127.0.0.1:5300".parse().unwrap());
+        // Lists on the command line replace the file's rather than extending them
+        assert_eq!(config.resolvers, vec!["9.9.9.9:53"]);
+        // Settings not given on the command line keep the file's values
+        assert_eq!(config.blocked_types, vec![255]);
+        assert_eq!(config.min_ttl, Some(60));
+    }
+
+    #[test]
+    fn ipv6_listener_follows_the_listen_address() {
+        let listen_v6 = |listen: &str| from_args(&["--listen", listen]).unwrap().listen_v6();
+
+        assert_eq!(from_args(&[]).unwrap().listen.to_string(), "127.0.0.1:2053");
+        assert_eq!(
+            listen_v6("127.0.0.1:2053"),
+            Some("[::1]:2053".parse().unwrap())
+        );
+        assert_eq!(listen_v6("0.0.0.0:53"), Some("[::]:53".parse().unwrap()));
+        assert_eq!(listen_v6("192.0.2.1:53"), None);
+        assert_eq!(listen_v6("[::1]:53"), None);
+        assert!(from_args(&["--listen", "localhost"]).is_err());
+    }
+
+    #[test]
+    fn file_settings_are_validated_together_with_flags() {
+        let file = TempConfig::new("validate", FILE);
+        let error = from_args(&["--config", &file.path(), "--max-ttl", "30"])
+            .err()
+            .unwrap();
+        assert!(error.contains("--min-ttl"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_file_settings_are_rejected() {
+        let file = TempConfig::new("unknown", "[cache]\nsise = 10\n");
+        let error = from_args(&["--config", &file.path()]).err().unwrap();
+        assert!(error.contains("unknown field `sise`"), "{}", error);
+    }
+
+    #[test]
+    fn bad_file_values_name_the_setting() {
+        let file = TempConfig::new("bad-value", "resolver_proto = \"quic\"\n");
+        let error = from_args(&["--config", &file.path()]).err().unwrap();
+        assert!(error.contains("`resolver_proto`"), "{}", error);
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        assert!(from_args(&["--config", "/nonexistent/server.toml"]).is_err());
+    }
+
+    #[test]
+    fn edns_udp_size_must_be_at_least_512() {
+        assert_eq!(from_args(&[]).unwrap().edns_udp_size, 1232);
+        assert_eq!(
+            from_args(&["--edns-udp-size", "512"])
+                .unwrap()
+                .edns_udp_size,
+            512
+        );
+        assert!(from_args(&["--edns-udp-size", "511"]).is_err());
+        assert!(from_args(&["--edns-udp-size", "70000"]).is_err());
+    }
+
+    #[test]
+    fn zone_ttls_take_mnemonics_or_numbers() {
+        let config = from_args(&["--zone-ttl", "ns=86400", "--zone-ttl", "16=60"]).unwrap();
+        assert_eq!(config.zone_ttls, vec![(2, 86400), (16, 60)]);
+
+        let config = from_args(&["--zone-ttl", "NS=100", "--zone-ttl", "NS=200"]).unwrap();
+        assert_eq!(config.zone_ttls, vec![(2, 200)]);
+
+        for value in ["NS", "NS=soon", "BOGUS=60"] {
+            assert!(from_args(&["--zone-ttl", value]).is_err(), "{}", value);
+        }
+    }
+}
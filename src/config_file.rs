@@ -0,0 +1,290 @@
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+
+// Settings for `--config <file>`, a TOML file. Each setting stands for a
+// command-line flag, so the file is applied by replaying those flags before
+// the command line's own. Unknown keys and sections are rejected.
+//
+//     listen = "127.0.0.1:2053"
+//     resolvers = ["1.1.1.1:53", "8.8.8.8:53"]
+//     zone = "example.zone"
+//
+//     [cache]
+//     size = 4096
+//
+//     [timeouts]
+//     upstream = 2000 # ms
+//
+//     [limits]
+//     max_concurrent_forwards = 100
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    listen: Option<String>,
+    resolvers: Option<Vec<Item>>,
+    resolver_proto: Option<String>,
+    upstream_strategy: Option<String>,
+    zone: Option<String>,
+    zone_url: Option<String>,
+    zone_refresh: Option<u64>,
+    zone_ttls: Option<Vec<Item>>,
+    views: Option<Vec<Item>>,
+    reverse_zones: Option<Vec<Item>>,
+    hosts: Option<String>,
+    blocklist: Option<String>,
+    rules: Option<Vec<Item>>,
+    block_types: Option<Vec<Item>>,
+    local_only: Option<bool>,
+    control: Option<String>,
+    user: Option<String>,
+    group: Option<String>,
+    cache: CacheSection,
+    timeouts: TimeoutsSection,
+    limits: LimitsSection,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct CacheSection {
+    size: Option<u64>,
+    file: Option<String>,
+    min_ttl: Option<u64>,
+    max_ttl: Option<u64>,
+    serve_stale: Option<bool>,
+    max_stale: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct TimeoutsSection {
+    upstream: Option<u64>,
+    request: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    workers: Option<u64>,
+    queue_size: Option<u64>,
+    overload_refuse: Option<bool>,
+    max_concurrent_forwards: Option<u64>,
+    forward_wait: Option<u64>,
+}
+
+// An array item. Bare numbers (like record types) are kept as their text.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Item {
+    Text(String),
+    Integer(u64),
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Item::Text(text) => write!(f, "{}", text),
+            Item::Integer(number) => write!(f, "{}", number),
+        }
+    }
+}
+
+// One setting from the file, translated to the flags it stands for
+pub struct Setting {
+    pub key: &'static str,
+    pub args: Vec<String>,
+}
+
+// Read a config file into its settings
+pub fn load(path: &str) -> Result<Vec<Setting>, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("Cannot read config {}: {}", path, e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn parse(text: &str) -> Result<Vec<Setting>, String> {
+    let file: ConfigFile = toml::from_str(text).map_err(|e| e.to_string())?;
+    let mut settings = Settings(Vec::new());
+
+    settings.text("listen", "--listen", file.listen);
+    settings.list("resolvers", "--resolver", file.resolvers);
+    settings.text("resolver_proto", "--resolver-proto", file.resolver_proto);
+    settings.text(
+        "upstream_strategy",
+        "--upstream-strategy",
+        file.upstream_strategy,
+    );
+    settings.text("zone", "--zone", file.zone);
+    settings.text("zone_url", "--zone-url", file.zone_url);
+    settings.integer("zone_refresh", "--zone-refresh", file.zone_refresh);
+    settings.list("zone_ttls", "--zone-ttl", file.zone_ttls);
+    settings.list("views", "--view", file.views);
+    settings.list("reverse_zones", "--reverse-zone", file.reverse_zones);
+    settings.text("hosts", "--hosts", file.hosts);
+    settings.text("blocklist", "--blocklist", file.blocklist);
+    settings.list("rules", "--rule", file.rules);
+    settings.list("block_types", "--block-type", file.block_types);
+    settings.switch("local_only", "--local-only", file.local_only);
+    settings.text("control", "--control", file.control);
+    settings.text("user", "--user", file.user);
+    settings.text("group", "--group", file.group);
+
+    let cache = file.cache;
+    settings.integer("cache.size", "--cache-size", cache.size);
+    settings.text("cache.file", "--cache-file", cache.file);
+    settings.integer("cache.min_ttl", "--min-ttl", cache.min_ttl);
+    settings.integer("cache.max_ttl", "--max-ttl", cache.max_ttl);
+    settings.switch("cache.serve_stale", "--serve-stale", cache.serve_stale);
+    settings.integer("cache.max_stale", "--max-stale", cache.max_stale);
+
+    let timeouts = file.timeouts;
+    settings.integer("timeouts.upstream", "--upstream-timeout", timeouts.upstream);
+    settings.integer("timeouts.request", "--request-timeout", timeouts.request);
+
+    let limits = file.limits;
+    settings.integer("limits.workers", "--workers", limits.workers);
+    settings.integer("limits.queue_size", "--queue-size", limits.queue_size);
+    settings.switch(
+        "limits.overload_refuse",
+        "--overload-refuse",
+        limits.overload_refuse,
+    );
+    settings.integer(
+        "limits.max_concurrent_forwards",
+        "--max-concurrent-forwards",
+        limits.max_concurrent_forwards,
+    );
+    settings.integer("limits.forward_wait", "--forward-wait", limits.forward_wait);
+
+    Ok(settings.0)
+}
+
+// The settings given in the file, in a fixed order
+struct Settings(Vec<Setting>);
+
+impl Settings {
+    fn push(&mut self, key: &'static str, args: Vec<String>) {
+        self.0.push(Setting { key, args });
+    }
+
+    fn text(&mut self, key: &'static str, flag: &str, value: Option<String>) {
+        if let Some(text) = value {
+            self.push(key, vec![flag.to_string(), text]);
+        }
+    }
+
+    fn integer(&mut self, key: &'static str, flag: &str, value: Option<u64>) {
+        if let Some(number) = value {
+            self.push(key, vec![flag.to_string(), number.to_string()]);
+        }
+    }
+
+    // A boolean; true gives the flag, false leaves it out
+    fn switch(&mut self, key: &'static str, flag: &str, value: Option<bool>) {
+        if let Some(on) = value {
+            let args = if on {
+                vec![flag.to_string()]
+            } else {
+                Vec::new()
+            };
+            self.push(key, args);
+        }
+    }
+
+    // An array; the flag is repeated for each item
+    fn list(&mut self, key: &'static str, flag: &str, value: Option<Vec<Item>>) {
+        if let Some(items) = value {
+            let args = items
+                .iter()
+                .flat_map(|item| [flag.to_string(), item.to_string()])
+                .collect();
+            self.push(key, args);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args_of(text: &str) -> Vec<(&'static str, Vec<String>)> {
+        parse(text)
+            .unwrap()
+            .into_iter()
+            .map(|setting| (setting.key, setting.args))
+            .collect()
+    }
+
+    #[test]
+    fn settings_become_flags() {
+        let settings = args_of(
+            r#"
+            resolvers = ["1.1.1.1:53", "8.8.8.8:53"]
+            block_types = [28, "255"]
+            local_only = true
+
+            [cache]
+            size = 4096
+            serve_stale = false
+            "#,
+        );
+
+        assert_eq!(
+            settings,
+            vec![
+                (
+                    "resolvers",
+                    vec![
+                        "--resolver".into(),
+                        "1.1.1.1:53".into(),
+                        "--resolver".into(),
+                        "8.8.8.8:53".into(),
+                    ]
+                ),
+                (
+                    "block_types",
+                    vec![
+                        "--block-type".into(),
+                        "28".into(),
+                        "--block-type".into(),
+                        "255".into(),
+                    ]
+                ),
+                ("local_only", vec!["--local-only".into()]),
+                ("cache.size", vec!["--cache-size".into(), "4096".into()]),
+                ("cache.serve_stale", Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_file_has_no_settings() {
+        assert!(parse("# nothing here\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let error = parse("resolver = \"1.1.1.1:53\"\n").err().unwrap();
+        assert!(error.contains("unknown field `resolver`"), "{}", error);
+
+        let error = parse("[cache]\nsize = 1\nttl = 5\n").err().unwrap();
+        assert!(error.contains("unknown field `ttl`"), "{}", error);
+
+        let error = parse("[caches]\nsize = 1\n").err().unwrap();
+        assert!(error.contains("unknown field `caches`"), "{}", error);
+    }
+
+    #[test]
+    fn wrong_types_are_rejected() {
+        assert!(parse("zone = 5\n").is_err());
+        assert!(parse("local_only = \"yes\"\n").is_err());
+        assert!(parse("[cache]\nsize = -1\n").is_err());
+        assert!(parse("resolvers = \"1.1.1.1:53\"\n").is_err());
+    }
+
+    #[test]
+    fn duplicate_keys_are_rejected() {
+        assert!(parse("zone = \"a\"\nzone = \"b\"\n").is_err());
+    }
+}
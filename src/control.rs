@@ -0,0 +1,194 @@
+use crate::server::Server;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+// Serve text commands on a Unix-domain control socket:
+//   stats  - print the current counters
+//   flush  - empty the answer cache
+//   dump-zone - list the local zone's records, sorted by name
+//   shutdown - stop serving (saving the cache if configured)
+pub fn spawn(path: &str, server: Arc<Server>) -> io::Result<JoinHandle<()>> {
+    // A leftover socket file from a previous run would make bind fail
+    if fs::metadata(path).is_ok() {
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    println!("Control socket listening on {}", path);
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = serve_client(stream, &server) {
+                        eprintln!("Control connection failed: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Failed to accept control connection: {}", e),
+            }
+        }
+    }))
+}
+
+// Answer commands from one client until it disconnects
+fn serve_client(stream: UnixStream, server: &Server) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let reply = match line?.trim() {
+            "stats" => server.metrics.render(),
+            "flush" => {
+                server.cache.lock().unwrap().clear();
+                String::from("ok\n")
+            }
+            "dump-zone" => server
+                .zone
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(_, record)| format!("{}\n", record))
+                .collect(),
+            "shutdown" => {
+                server.request_shutdown();
+                String::from("ok\n")
+            }
+            "" => continue,
+            other => format!("unknown command: {}\n", other),
+        };
+
+        writer.write_all(reply.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocklist::Blocklist;
+    use crate::config::Config;
+    use crate::dns::dns_error::DnsError;
+    use crate::dns::dns_id::FixedIds;
+    use crate::dns::DnsMessage;
+    use crate::hosts::Hosts;
+    use crate::transport::Transport;
+    use crate::zone::Zone;
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::net::{IpAddr, Ipv4Addr, Shutdown};
+    use std::process;
+
+    struct NoUpstream;
+
+    impl Transport for NoUpstream {
+        fn exchange(&self, _query: &[u8]) -> Result<Vec<u8>, DnsError> {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "no upstream").into())
+        }
+
+        fn describe(&self) -> String {
+            String::from("none")
+        }
+    }
+
+    fn server() -> Arc<Server> {
+        server_with_zone(Zone::new())
+    }
+
+    fn server_with_zone(zone: Zone) -> Arc<Server> {
+        let config = Config::from_args(&[String::from("server")]).unwrap();
+        Arc::new(Server::new(
+            config,
+            Box::new(NoUpstream),
+            zone,
+            Hosts::new(),
+            Blocklist::new(),
+        ))
+    }
+
+    // Send one command on a fresh connection and read back its whole reply
+    fn command(path: &str, command: &str) -> String {
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream
+            .write_all(format!("{}\n", command).as_bytes())
+            .unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn stats_reports_the_counters() {
+        let path = std::env::temp_dir().join(format!("dns-control-test-{}", process::id()));
+        let path = path.to_str().unwrap();
+        let server = server();
+
+        let query = DnsMessage::query(&FixedIds::new(vec![7]), "example.test", 1);
+        server.handle_query(&query.to_bytes(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        spawn(path, server.clone()).unwrap();
+        let reply = command(path, "stats");
+        let flushed = command(path, "flush");
+        let unknown = command(path, "bogus");
+        let _ = fs::remove_file(path);
+
+        let counters: HashMap<&str, u64> = reply
+            .lines()
+            .map(|line| {
+                let (name, value) = line.split_once(' ').unwrap();
+                (name, value.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(counters["queries"], 1);
+        assert_eq!(counters["cache_hits"], 0);
+        assert!(counters.contains_key("forwards"));
+        assert!(counters.contains_key("errors"));
+
+        assert_eq!(flushed, "ok\n");
+        assert_eq!(unknown, "unknown command: bogus\n");
+    }
+
+    #[test]
+    fn dump_zone_lists_records_in_canonical_order() {
+        let path = std::env::temp_dir().join(format!("dns-control-dump-{}", process::id()));
+        let path = path.to_str().unwrap();
+        let zone = Zone::parse(
+            "www.example.test A 192.0.2.80\n\
+             example.test MX 10 mail.example.test.\n\
+             example.test A 192.0.2.1\n\
+             a.other.test A 198.51.100.1\n",
+            &[],
+        )
+        .unwrap();
+
+        spawn(path, server_with_zone(zone)).unwrap();
+        let reply = command(path, "dump-zone");
+        let _ = fs::remove_file(path);
+
+        let owners: Vec<&str> = reply
+            .lines()
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(
+            owners,
+            [
+                "example.test.",
+                "example.test.",
+                "www.example.test.",
+                "a.other.test."
+            ],
+            "{}",
+            reply
+        );
+        // Types in numeric order within a name
+        assert!(
+            reply.lines().nth(1).unwrap().contains("TYPE15"),
+            "{}",
+            reply
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use crate::dns::dns_edns::Cookie;
+use crate::dns::dns_id::random_u64;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+// Our side of DNS Cookies (RFC 7873), both as a server handing cookies to
+// clients and as a client of the upstreams we forward to
+pub struct CookieJar {
+    // Keys the server cookies; regenerated on every start, which only costs
+    // clients one round of fresh cookies
+    secret: [u8; 16],
+    // The client cookie we present to upstreams
+    client_cookie: [u8; 8],
+    // The server cookie last returned by an upstream, sent back on later queries.
+    // One slot for all upstreams: a stale one is harmless, the upstream just
+    // hands out a new one.
+    upstream_cookie: Mutex<Vec<u8>>,
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        let mut secret = [0; 16];
+        secret[..8].copy_from_slice(&random_u64().to_be_bytes());
+        secret[8..].copy_from_slice(&random_u64().to_be_bytes());
+
+        CookieJar {
+            secret,
+            client_cookie: random_u64().to_be_bytes(),
+            upstream_cookie: Mutex::new(Vec::new()),
+        }
+    }
+
+    // The cookie to answer a client with: its own client cookie, and a server
+    // cookie laid out as in RFC 9018 (version, reserved, timestamp, hash) with
+    // the hash a truncated HMAC-SHA256 over the client cookie, those first
+    // fields and the client address
+    pub fn server_cookie(&self, client_cookie: [u8; 8], client: IpAddr, now: u32) -> Cookie {
+        let mut server = vec![1, 0, 0, 0];
+        server.extend_from_slice(&now.to_be_bytes());
+
+        let mut data = client_cookie.to_vec();
+        data.extend_from_slice(&server);
+        match client {
+            IpAddr::V4(address) => data.extend_from_slice(&address.octets()),
+            IpAddr::V6(address) => data.extend_from_slice(&address.octets()),
+        }
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(&data);
+        server.extend_from_slice(&mac.finalize().into_bytes()[..8]);
+
+        Cookie {
+            client: client_cookie,
+            server,
+        }
+    }
+
+    // The cookie to send upstream with a forwarded query
+    pub fn upstream_request_cookie(&self) -> Cookie {
+        Cookie {
+            client: self.client_cookie,
+            server: self.upstream_cookie.lock().unwrap().clone(),
+        }
+    }
+
+    // Remember the server cookie from an upstream response, if it echoes our
+    // client cookie (anything else wasn't meant for us)
+    pub fn learn_upstream_cookie(&self, cookie: &Cookie) {
+        if cookie.client == self.client_cookie && !cookie.server.is_empty() {
+            *self.upstream_cookie.lock().unwrap() = cookie.server.clone();
+        }
+    }
+}
+
+impl Default for CookieJar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_COOKIE: [u8; 8] = *b"client01";
+
+    #[test]
+    fn server_cookies_depend_on_client_and_address() {
+        let jar = CookieJar::new();
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+
+        let cookie = jar.server_cookie(CLIENT_COOKIE, client, 1_700_000_000);
+        assert_eq!(cookie.client, CLIENT_COOKIE);
+        assert_eq!(cookie.server.len(), 16);
+        assert_eq!(cookie.server[..4], [1, 0, 0, 0]);
+        assert_eq!(cookie.server[4..8], 1_700_000_000u32.to_be_bytes());
+
+        // The same inputs give the same cookie, so clients can present it again
+        let again = jar.server_cookie(CLIENT_COOKIE, client, 1_700_000_000);
+        assert_eq!(again.server, cookie.server);
+
+        let elsewhere =
+            jar.server_cookie(CLIENT_COOKIE, "192.0.2.8".parse().unwrap(), 1_700_000_000);
+        assert_ne!(elsewhere.server, cookie.server);
+        let other_client = jar.server_cookie(*b"client02", client, 1_700_000_000);
+        assert_ne!(other_client.server, cookie.server);
+
+        // Another jar has another secret
+        let other_jar = CookieJar::new().server_cookie(CLIENT_COOKIE, client, 1_700_000_000);
+        assert_ne!(other_jar.server, cookie.server);
+    }
+
+    #[test]
+    fn upstream_cookies_are_learned_only_when_ours() {
+        let jar = CookieJar::new();
+        let ours = jar.upstream_request_cookie();
+        assert!(ours.server.is_empty());
+
+        jar.learn_upstream_cookie(&Cookie {
+            client: CLIENT_COOKIE,
+            server: b"not-ours".to_vec(),
+        });
+        assert!(jar.upstream_request_cookie().server.is_empty());
+
+        jar.learn_upstream_cookie(&Cookie {
+            client: ours.client,
+            server: b"upstream".to_vec(),
+        });
+        assert_eq!(jar.upstream_request_cookie().server, b"upstream");
+        assert_eq!(jar.upstream_request_cookie().client, ours.client);
+    }
+}
@@ -1,41 +1,79 @@
+pub mod dns_compression;
+pub mod dns_edns;
+pub mod dns_error;
 pub mod dns_header;
-mod dns_question;
-mod dns_record;
+pub mod dns_id;
+pub mod dns_question;
+pub mod dns_record;
+pub mod dns_tsig;
 
+use crate::transport::Transport;
 use bytes::{BufMut, BytesMut};
+use dns_compression::NameCompressor;
+use dns_error::DnsError;
 use dns_header::DnsHeader;
+use dns_id::IdSource;
 use dns_question::DnsQuestion;
 use dns_record::DnsRecord;
-use std::net::UdpSocket;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Instant;
 
+// Upper bound on the total bytes of expanded names in one message. Compression
+// lets a few bytes expand to a full name, so without a cap a crafted packet with
+// many pointer-only names forces far more work than its size suggests.
+const MAX_EXPANDED_NAME_BYTES: usize = 64 * 1024;
+
+// Default cap on the answer records we accept in one message
+pub const DEFAULT_MAX_ANSWERS: usize = 1000;
+
+#[derive(Clone, Debug)]
 pub struct DnsMessage {
     pub header: DnsHeader,
     pub questions: Vec<DnsQuestion>,
     pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub additionals: Vec<DnsRecord>,
 }
 
 impl DnsMessage {
     // Parse a complete DNS message from bytes
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DnsError> {
+        Self::from_bytes_with_limit(bytes, DEFAULT_MAX_ANSWERS)
+    }
+
+    // Parse a message held in any byte buffer, e.g. the `BytesMut` from `to_bytes`
+    pub fn from_buf(buf: impl AsRef<[u8]>) -> Result<Self, DnsError> {
+        Self::from_bytes(buf.as_ref())
+    }
+
+    // Parse a message, keeping at most `max_answers` answer records. A message
+    // with more is cut short: the excess answers and the sections after them are
+    // dropped and TC is set.
+    pub fn from_bytes_with_limit(bytes: &[u8], max_answers: usize) -> Result<Self, DnsError> {
         // Parse the header first
-        let header = DnsHeader::from_bytes(bytes)?;
+        let mut header = DnsHeader::from_bytes(bytes)?;
 
         // Start parsing questions from byte 12 (end of header)
         let mut position = 12;
         let mut questions = Vec::new();
+        let mut name_budget = MAX_EXPANDED_NAME_BYTES;
 
         // Parse all questions
         for _ in 0..header.qdcount {
             let (question, bytes_consumed) = DnsQuestion::from_bytes(bytes, position)?;
+            Self::spend_name_budget(&mut name_budget, &question.name)?;
             questions.push(question);
             position += bytes_consumed;
         }
 
         // Parse answers if present
         let mut answers = Vec::new();
-        for _ in 0..header.ancount {
+        for _ in 0..(header.ancount as usize).min(max_answers) {
             match DnsRecord::from_bytes(bytes, position) {
                 Ok((record, bytes_consumed)) => {
+                    Self::spend_record_budget(&mut name_budget, &record)?;
                     answers.push(record);
                     position += bytes_consumed;
                 }
@@ -47,15 +85,177 @@ impl DnsMessage {
             }
         }
 
-        // For simplicity, we'll ignore parsing authority and additional sections for now
+        if header.ancount as usize > max_answers {
+            println!(
+                "Warning: message claims {} answers, keeping {}",
+                header.ancount,
+                answers.len()
+            );
+            header.set_truncated(true);
+            header.ancount = answers.len() as u16;
+            header.nscount = 0;
+            header.arcount = 0;
+        }
+
+        // Parse the authority section (e.g. the SOA of negative answers)
+        let mut authorities = Vec::new();
+        for _ in 0..header.nscount {
+            match DnsRecord::from_bytes(bytes, position) {
+                Ok((record, bytes_consumed)) => {
+                    Self::spend_record_budget(&mut name_budget, &record)?;
+                    authorities.push(record);
+                    position += bytes_consumed;
+                }
+                Err(e) => {
+                    println!("Warning: Failed to parse authority record: {}", e);
+                    break;
+                }
+            }
+        }
+
+        // Parse the additional section (glue records, the EDNS OPT record)
+        let mut additionals = Vec::new();
+        for _ in 0..header.arcount {
+            match DnsRecord::from_bytes(bytes, position) {
+                Ok((record, bytes_consumed)) => {
+                    Self::spend_record_budget(&mut name_budget, &record)?;
+                    additionals.push(record);
+                    position += bytes_consumed;
+                }
+                Err(e) => {
+                    println!("Warning: Failed to parse additional record: {}", e);
+                    break;
+                }
+            }
+        }
 
         Ok(DnsMessage {
             header,
             questions,
             answers,
+            authorities,
+            additionals,
         })
     }
 
+    // Parse as much of a message as possible, for diagnosing malformed captures:
+    // whatever parsed comes back alongside every error met. Parsing stops at the
+    // first bad question or record, since nothing after it can be located; each
+    // section left unparsed because of that gets an error too. The header keeps
+    // the counts the packet claims.
+    pub fn from_bytes_lenient(bytes: &[u8]) -> (Self, Vec<DnsError>) {
+        let mut errors = Vec::new();
+        let mut message = DnsMessage {
+            header: DnsHeader::new_query(0, false, 0),
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        };
+
+        match DnsHeader::from_bytes(bytes) {
+            Ok(header) => message.header = header,
+            Err(e) => {
+                errors.push(e);
+                return (message, errors);
+            }
+        }
+
+        let mut position = 12;
+        let mut name_budget = MAX_EXPANDED_NAME_BYTES;
+
+        for _ in 0..message.header.qdcount {
+            let parsed = DnsQuestion::from_bytes(bytes, position).and_then(|(question, size)| {
+                Self::spend_name_budget(&mut name_budget, &question.name)?;
+                Ok((question, size))
+            });
+            match parsed {
+                Ok((question, size)) => {
+                    message.questions.push(question);
+                    position += size;
+                }
+                Err(e) => {
+                    errors.push(e);
+                    break;
+                }
+            }
+        }
+
+        let header = message.header.clone();
+        let sections = [
+            ("answer", header.ancount, &mut message.answers),
+            ("authority", header.nscount, &mut message.authorities),
+            ("additional", header.arcount, &mut message.additionals),
+        ];
+        for (section, count, records) in sections {
+            if !errors.is_empty() {
+                if count > 0 {
+                    errors.push(DnsError::Inconsistent(format!(
+                        "{} section not parsed after an earlier error",
+                        section
+                    )));
+                }
+                continue;
+            }
+
+            for _ in 0..count {
+                let parsed = DnsRecord::from_bytes(bytes, position).and_then(|(record, size)| {
+                    Self::spend_record_budget(&mut name_budget, &record)?;
+                    Ok((record, size))
+                });
+                match parsed {
+                    Ok((record, size)) => {
+                        records.push(record);
+                        position += size;
+                    }
+                    Err(e) => {
+                        errors.push(e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        (message, errors)
+    }
+
+    // Charge an expanded name against the message's budget
+    fn spend_name_budget(budget: &mut usize, name: &[u8]) -> Result<(), DnsError> {
+        *budget = budget.checked_sub(name.len()).ok_or(DnsError::Truncated(
+            "Too many expanded name bytes in message",
+        ))?;
+        Ok(())
+    }
+
+    // Charge a record's expanded names against the message's budget: its owner
+    // and, for types with names in their data, that data. Counting the whole
+    // data slightly overcharges the fixed fields around the names.
+    fn spend_record_budget(budget: &mut usize, record: &DnsRecord) -> Result<(), DnsError> {
+        Self::spend_name_budget(budget, &record.name)?;
+        if record.rdata_has_names() {
+            Self::spend_name_budget(budget, &record.rdata)?;
+        }
+        Ok(())
+    }
+
+    // Build a recursive query (RD=1) for a single name and record type, with its
+    // ID taken from `ids`
+    pub fn query(ids: &dyn IdSource, domain: &str, record_type: u16) -> Self {
+        let question = DnsQuestion {
+            name: DnsQuestion::encode_domain_name(domain),
+            record_type,
+            class: 1, // IN (Internet)
+        };
+
+        DnsMessage {
+            header: DnsHeader::new_query(ids.next_id(), true, 1),
+            questions: vec![question],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
     // Create a response for specific questions
     pub fn new(request_header: &DnsHeader, questions: Vec<DnsQuestion>) -> Self {
         // Create answers for each question
@@ -79,20 +279,23 @@ impl DnsMessage {
                     println!("Creating answer for domain: {}", domain_name);
                 }
 
-                // Create a valid question with type 1
+                // Create a valid question with type 1, echoing the class asked about
                 let valid_question = DnsQuestion {
                     name: question.name.clone(),
                     record_type: 1, // Force to A record
-                    class: 1,       // Force to IN class
+                    class: question.class,
                 };
 
                 valid_questions.push(valid_question.clone());
 
-                // Create an answer with the expected IP for codecrafters.io (76.76.21.21)
-                answers.push(DnsRecord::new(
-                    question.name.clone(),
-                    [76, 76, 21, 21].into(),
-                ));
+                // Create an answer with the expected IP for codecrafters.io (76.76.21.21).
+                // That record only exists in the IN class.
+                if question.class == 1 {
+                    answers.push(DnsRecord::new(
+                        question.name.clone(),
+                        [76, 76, 21, 21].into(),
+                    ));
+                }
             }
         }
 
@@ -107,6 +310,8 @@ impl DnsMessage {
             header,
             questions: valid_questions,
             answers,
+            authorities: Vec::new(),
+            additionals: Vec::new(),
         }
     }
 
@@ -115,42 +320,445 @@ impl DnsMessage {
         Self::new(&request.header, request.questions.clone())
     }
 
-    // Create a response from just the header (fallback if question parsing fails)
-    pub fn new_response_from_request_header(request_header: &DnsHeader) -> Self {
-        // Create a default question
-        let dns_questions = vec![DnsQuestion::new()];
-
-        // Create an answer
-        let dns_answers = vec![DnsRecord::default_codecrafters_record()];
-
-        // Create response header
+    // Create a response carrying the given answers for the request's questions
+    pub fn new_answer_from_request(request: &DnsMessage, answers: Vec<DnsRecord>) -> Self {
         let header = DnsHeader::new(
-            request_header,
-            dns_questions.len() as u16,
-            dns_answers.len() as u16,
+            &request.header,
+            request.questions.len() as u16,
+            answers.len() as u16,
         );
 
         DnsMessage {
             header,
-            questions: dns_questions,
-            answers: dns_answers,
+            questions: request.questions.clone(),
+            answers,
+            authorities: Vec::new(),
+            additionals: Vec::new(),
         }
     }
 
+    // Create a NODATA response: the name exists but has no records of the requested type.
+    // The zone's SOA, when known, goes in the authority section so resolvers can cache the
+    // negative answer.
+    pub fn new_nodata_from_request(request: &DnsMessage, soa: Option<DnsRecord>) -> Self {
+        let mut response = Self::new_answer_from_request(request, Vec::new());
+        response.authorities.extend(soa);
+        response.header.nscount = response.authorities.len() as u16;
+        response
+    }
+
+    // Create an error response carrying only the request's questions and the given
+    // RCODE. Opcode and RD are copied from the request, and RA is set when the
+    // client asked for recursion since that is what we offer.
+    pub fn new_error_response(request: &DnsMessage, rcode: u8) -> Self {
+        let mut response = Self::new_answer_from_request(request, Vec::new());
+        response.header.set_rcode(rcode as u16);
+        response
+            .header
+            .set_recursion_available(request.header.recursion_desired());
+        response
+    }
+
+    // Create an NXDOMAIN response (RCODE=3): the name does not exist
+    pub fn new_nxdomain_from_request(request: &DnsMessage) -> Self {
+        Self::new_error_response(request, 3)
+    }
+
+    // Create a SERVFAIL response (RCODE=2): we failed to resolve the query
+    pub fn new_servfail_from_request(request: &DnsMessage) -> Self {
+        Self::new_error_response(request, 2)
+    }
+
+    // Create a NOTIMP response (RCODE=4): we don't support the request's opcode
+    pub fn new_notimp_from_request(request: &DnsMessage) -> Self {
+        Self::new_error_response(request, 4)
+    }
+
+    // Create a REFUSED response (RCODE=5) echoing the request's questions
+    pub fn new_refused_from_request(request: &DnsMessage) -> Self {
+        Self::new_error_response(request, 5)
+    }
+
+    // Create a FORMERR response (RCODE=1) for a request whose header is readable
+    // but whose body isn't: the ID, opcode and RD are echoed, every section is empty
+    pub fn new_formerr(request_header: &DnsHeader) -> Self {
+        let mut header = DnsHeader::new(request_header, 0, 0);
+        header.set_rcode(1);
+
+        DnsMessage {
+            header,
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    // How long the message may be cached: the smallest TTL among the answers or,
+    // for a negative response, the SOA's negative-caching TTL (the lesser of its
+    // own TTL and its MINIMUM field, RFC 2308). None when neither is present.
+    pub fn min_ttl(&self) -> Option<u32> {
+        if let Some(ttl) = self.answers.iter().map(|record| record.ttl).min() {
+            return Some(ttl);
+        }
+
+        let soa = self
+            .authorities
+            .iter()
+            .find(|record| record.record_type == 6)?;
+        let minimum = soa.rdata.len().checked_sub(4).map(|start| {
+            u32::from_be_bytes([
+                soa.rdata[start],
+                soa.rdata[start + 1],
+                soa.rdata[start + 2],
+                soa.rdata[start + 3],
+            ])
+        })?;
+        Some(soa.ttl.min(minimum))
+    }
+
+    // Clamp every record's TTL into the configured bounds. A TTL of 0 is only
+    // raised when a minimum is configured, so uncacheable answers stay that way.
+    // The OPT and TSIG pseudo-records are skipped: their TTL fields aren't TTLs.
+    pub fn clamp_ttls(&mut self, min_ttl: Option<u32>, max_ttl: Option<u32>) {
+        let records = self
+            .answers
+            .iter_mut()
+            .chain(&mut self.authorities)
+            .chain(&mut self.additionals)
+            .filter(|record| !matches!(record.record_type, 41 | dns_tsig::TSIG_TYPE));
+
+        for record in records {
+            if let Some(min_ttl) = min_ttl {
+                record.ttl = record.ttl.max(min_ttl);
+            }
+            if let Some(max_ttl) = max_ttl {
+                record.ttl = record.ttl.min(max_ttl);
+            }
+        }
+    }
+
+    // The EDNS OPT record (type 41) from the additional section, if any
+    pub fn opt_record(&self) -> Option<&DnsRecord> {
+        self.additionals
+            .iter()
+            .find(|record| record.record_type == 41)
+    }
+
+    // Whether the DO bit is set in the OPT record's flags (RFC 3225): the sender
+    // wants DNSSEC records
+    pub fn dnssec_ok(&self) -> bool {
+        self.opt_record().is_some_and(|opt| opt.ttl & 0x8000 != 0)
+    }
+
+    pub fn opt_record_mut(&mut self) -> Option<&mut DnsRecord> {
+        self.additionals
+            .iter_mut()
+            .find(|record| record.record_type == 41)
+    }
+
+    // Order the answer section so every CNAME comes before the records owned by
+    // its target: records are ranked by how many CNAME hops separate their owner
+    // from the question name. Records outside the chain keep their relative order
+    // at the end.
+    pub fn order_answers(&mut self) {
+        let mut depths: HashMap<Vec<u8>, usize> = HashMap::new();
+        for question in &self.questions {
+            depths.insert(question.name.to_ascii_lowercase(), 0);
+        }
+
+        // CNAME owners that aren't anyone's target start chains of their own
+        let targets: Vec<&[u8]> = self
+            .answers
+            .iter()
+            .filter(|record| record.record_type == 5)
+            .map(|record| record.rdata.as_slice())
+            .collect();
+        for record in &self.answers {
+            if record.record_type == 5 && !targets.iter().any(|target| record.name_matches(target))
+            {
+                depths.entry(record.name.to_ascii_lowercase()).or_insert(0);
+            }
+        }
+
+        // Each pass pushes depths one hop further down the chains; a chain can't be
+        // longer than the answer section
+        for _ in 0..self.answers.len() {
+            let mut changed = false;
+            for record in self.answers.iter().filter(|record| record.record_type == 5) {
+                if let Some(&depth) = depths.get(&record.name.to_ascii_lowercase()) {
+                    let target = record.rdata.to_ascii_lowercase();
+                    if let Entry::Vacant(entry) = depths.entry(target) {
+                        entry.insert(depth + 1);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        self.answers.sort_by_key(|record| {
+            let depth = depths
+                .get(&record.name.to_ascii_lowercase())
+                .copied()
+                .unwrap_or(usize::MAX);
+            (depth, record.record_type != 5)
+        });
+    }
+
+    // If the serialized message would exceed `limit` bytes, set TC so the client
+    // retries over TCP and cut the message down to fit. Whole records are dropped,
+    // never parts of one: first the authority and additional sections (keeping
+    // OPT), then answers from the end. The questions always stay, so a message
+    // whose questions alone are too big goes out with TC set and no answers.
+    pub fn truncate_to(&mut self, limit: usize) {
+        if self.to_bytes().len() <= limit {
+            return;
+        }
+
+        self.header.set_truncated(true);
+        self.authorities.clear();
+        self.additionals.retain(|record| record.record_type == 41);
+        self.sync_counts();
+
+        // Names only point backwards, so dropping the last answer never grows the message
+        while !self.answers.is_empty() && self.to_bytes().len() > limit {
+            self.answers.pop();
+            self.sync_counts();
+        }
+    }
+
+    // Lowercase the owner name of every record (RFC 4034 section 6.2 canonical
+    // form). The question is left alone: it must echo the query exactly.
+    pub fn canonicalize_owner_names(&mut self) {
+        let sections = [
+            &mut self.answers,
+            &mut self.authorities,
+            &mut self.additionals,
+        ];
+        for record in sections.into_iter().flatten() {
+            record.name = DnsQuestion::lowercase_domain_name(&record.name);
+        }
+    }
+
+    // Drop answers repeating an earlier one (same owner, type, class and data,
+    // whatever the TTL), keeping the first
+    pub fn dedup_answers(&mut self) {
+        let mut seen = HashSet::new();
+        self.answers.retain(|record| seen.insert(record.clone()));
+        self.sync_counts();
+    }
+
+    // Keep only the first answer of the queried type (any type for ANY queries).
+    // Answers with no record of that type, like a bare CNAME, are left as they are.
+    pub fn keep_single_answer(&mut self) {
+        let Some(question) = self.questions.first() else {
+            return;
+        };
+        let record_type = question.record_type;
+
+        if let Some(index) = self
+            .answers
+            .iter()
+            .position(|record| record_type == 255 || record.record_type == record_type)
+        {
+            let answer = self.answers.swap_remove(index);
+            self.answers = vec![answer];
+            self.sync_counts();
+        }
+    }
+
+    // Drop the authority section and every additional record except OPT, as long
+    // as the answer section already answers the query
+    pub fn strip_to_minimal(&mut self) {
+        if self.answers.is_empty() {
+            return;
+        }
+
+        self.authorities.clear();
+        self.additionals.retain(|record| record.record_type == 41);
+        self.sync_counts();
+    }
+
+    // Mutable access to each section for in-place edits (TTLs, case, rdata, ...).
+    // Editing records keeps the header valid, but adding or removing entries
+    // changes the section sizes: call `sync_counts` afterwards, otherwise the
+    // header counts no longer match what `to_bytes` writes.
+    pub fn questions_mut(&mut self) -> &mut Vec<DnsQuestion> {
+        &mut self.questions
+    }
+
+    pub fn answers_mut(&mut self) -> &mut Vec<DnsRecord> {
+        &mut self.answers
+    }
+
+    pub fn authorities_mut(&mut self) -> &mut Vec<DnsRecord> {
+        &mut self.authorities
+    }
+
+    pub fn additionals_mut(&mut self) -> &mut Vec<DnsRecord> {
+        &mut self.additionals
+    }
+
+    // Make the header counts match the section contents after editing them
+    pub fn sync_counts(&mut self) {
+        self.header.qdcount = self.questions.len() as u16;
+        self.header.ancount = self.answers.len() as u16;
+        self.header.nscount = self.authorities.len() as u16;
+        self.header.arcount = self.additionals.len() as u16;
+    }
+
+    // Compare two messages by meaning rather than by bytes: same ID and flags, and
+    // each section holding the same questions/records in any order. Names compare
+    // case-insensitively; records match on owner, type, class and data (TTLs are
+    // ignored since they count down). Compression never matters because parsing
+    // already expands every name, including those inside record data.
+    pub fn semantically_eq(&self, other: &DnsMessage) -> bool {
+        if self.header.id != other.header.id || self.header.flags != other.header.flags {
+            return false;
+        }
+
+        let question_keys = |message: &DnsMessage| {
+            let mut keys: Vec<(Vec<u8>, u16, u16)> = message
+                .questions
+                .iter()
+                .map(|question| {
+                    (
+                        question.name.to_ascii_lowercase(),
+                        question.record_type,
+                        question.class,
+                    )
+                })
+                .collect();
+            keys.sort();
+            keys
+        };
+
+        question_keys(self) == question_keys(other)
+            && Self::same_records(&self.answers, &other.answers)
+            && Self::same_records(&self.authorities, &other.authorities)
+            && Self::same_records(&self.additionals, &other.additionals)
+    }
+
+    // Whether two record sections hold the same records, ignoring order and TTLs
+    fn same_records(left: &[DnsRecord], right: &[DnsRecord]) -> bool {
+        let record_keys = |records: &[DnsRecord]| {
+            let mut keys: Vec<(Vec<u8>, u16, u16, Vec<u8>)> = records
+                .iter()
+                .map(|record| {
+                    (
+                        record.name.to_ascii_lowercase(),
+                        record.record_type,
+                        record.class,
+                        record.rdata.clone(),
+                    )
+                })
+                .collect();
+            keys.sort();
+            keys
+        };
+
+        left.len() == right.len() && record_keys(left) == record_keys(right)
+    }
+
+    // Check the message is internally consistent before it goes on the wire,
+    // returning the first problem found
+    pub fn validate(&self) -> Result<(), DnsError> {
+        let sections = [
+            ("question", self.header.qdcount, self.questions.len()),
+            ("answer", self.header.ancount, self.answers.len()),
+            ("authority", self.header.nscount, self.authorities.len()),
+            ("additional", self.header.arcount, self.additionals.len()),
+        ];
+        for (section, count, actual) in sections {
+            if count as usize != actual {
+                return Err(DnsError::Inconsistent(format!(
+                    "header claims {} {} entries but there are {}",
+                    count, section, actual
+                )));
+            }
+        }
+
+        // A query (QR=0) has no answers
+        if self.header.flags & 0x8000 == 0 && !self.answers.is_empty() {
+            return Err(DnsError::Inconsistent(String::from(
+                "query (QR=0) carries answers",
+            )));
+        }
+
+        for question in &self.questions {
+            Self::validate_name(&question.name)?;
+        }
+
+        let records = self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals);
+        for record in records {
+            Self::validate_name(&record.name)?;
+
+            // RDLENGTH is a 16-bit field
+            if record.rdata.len() > u16::MAX as usize {
+                return Err(DnsError::Inconsistent(format!(
+                    "rdata of {} bytes does not fit RDLENGTH",
+                    record.rdata.len()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // An encoded name must be a sequence of labels ending in exactly one null byte
+    fn validate_name(name: &[u8]) -> Result<(), DnsError> {
+        let mut position = 0;
+
+        while let Some(&length) = name.get(position) {
+            if length == 0 {
+                if position + 1 != name.len() {
+                    return Err(DnsError::Inconsistent(String::from(
+                        "bytes after the name's terminating null",
+                    )));
+                }
+                return Ok(());
+            }
+
+            if length > 63 {
+                return Err(DnsError::Inconsistent(format!(
+                    "label length {} exceeds 63",
+                    length
+                )));
+            }
+
+            position += 1 + length as usize;
+        }
+
+        Err(DnsError::Inconsistent(String::from(
+            "name is empty or not null-terminated",
+        )))
+    }
+
     // Serialize the message to bytes
     pub fn to_bytes(&self) -> BytesMut {
         let mut bytes = self.header.to_bytes();
 
-        // Add question section
+        // Names repeated anywhere in the message point back to their first copy
+        let mut compressor = NameCompressor::new();
+
         for question in &self.questions {
-            let question_bytes = question.to_bytes();
-            bytes.extend_from_slice(&question_bytes);
+            compressor.write_question(&mut bytes, question);
         }
 
-        // Add answer section
-        for answer in &self.answers {
-            let answer_bytes = answer.to_bytes();
-            bytes.extend_from_slice(&answer_bytes);
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            compressor.write_record(&mut bytes, record);
         }
 
         bytes
@@ -163,17 +771,22 @@ impl DnsMessage {
         // Copy the ID
         bytes.put_u16(self.header.id);
 
-        // Copy flags but make sure QR=0 (query)
-        let query_flags = self.header.flags & 0x7FFF; // Clear the QR bit (bit 15)
+        // Copy the flags a query carries: OPCODE, RD, and the DNSSEC bits AD and
+        // CD (so validating clients get unvalidated data with CD set). QR, AA, TC,
+        // RA, Z and RCODE are response fields and stay 0.
+        let query_flags = self.header.flags & 0x7930;
         bytes.put_u16(query_flags);
 
         // Copy the question count
         bytes.put_u16(self.header.qdcount);
 
+        // Only the EDNS OPT record is carried over from the additional section
+        let opt_record = self.opt_record();
+
         // Set other counts to 0
         bytes.put_u16(0); // ANCOUNT = 0
         bytes.put_u16(0); // NSCOUNT = 0
-        bytes.put_u16(0); // ARCOUNT = 0
+        bytes.put_u16(opt_record.is_some() as u16); // ARCOUNT
 
         // Add all questions
         for question in &self.questions {
@@ -181,121 +794,1222 @@ impl DnsMessage {
             bytes.extend_from_slice(&question_bytes);
         }
 
+        if let Some(opt_record) = opt_record {
+            bytes.extend_from_slice(&opt_record.to_bytes());
+        }
+
         bytes
     }
 
-    // Forward a DNS query to an external DNS server and return the response
-    pub fn forward_query(request: &DnsMessage, dns_server: &str) -> Result<Self, &'static str> {
-        // Connect to the external DNS server
-        let socket = match UdpSocket::bind("0.0.0.0:0") {
-            Ok(s) => s,
-            Err(_) => return Err("Failed to bind UDP socket for forwarding"),
-        };
-
-        // Set a timeout for receiving responses
-        #[allow(clippy::redundant_pattern_matching)]
-        if let Err(_) = socket.set_read_timeout(Some(std::time::Duration::from_secs(5))) {
-            return Err("Failed to set socket timeout");
+    // Pick one RCODE for a response combining several sub-queries, since a message
+    // carries only one. The policy:
+    // - when every sub-query got the same RCODE, that RCODE (all NOERROR, all NXDOMAIN, ...)
+    // - otherwise NOERROR if any sub-query produced answers, so they aren't hidden
+    //   behind an error the client would act on instead
+    // - otherwise the first error RCODE in question order
+    fn combined_rcode(rcodes: &[u16], has_answers: bool) -> u16 {
+        match rcodes.first() {
+            Some(&first) if rcodes.iter().all(|&rcode| rcode == first) => first,
+            _ if has_answers => 0,
+            _ => rcodes
+                .iter()
+                .copied()
+                .find(|&rcode| rcode != 0)
+                .unwrap_or(0),
         }
+    }
+
+    // Forward a DNS query to an upstream server over the given transport and return the response
+    // Responses keep at most `max_answers` answers. With `split_questions`, a request
+    // with several questions is sent as one sub-query per question; otherwise it is
+    // forwarded as a whole.
+    pub fn forward_query(
+        request: &DnsMessage,
+        transport: &dyn Transport,
+        max_answers: usize,
+        ids: &dyn IdSource,
+        deadline: Option<Instant>,
+        split_questions: bool,
+    ) -> Result<Self, DnsError> {
+        // Every upstream exchange stops at the request's deadline, if it has one
+        let send = |query: &[u8]| match deadline {
+            Some(deadline) => transport.exchange_until(query, deadline),
+            None => transport.exchange(query),
+        };
 
         // Check if we have multiple questions
-        if request.questions.len() > 1 {
+        if split_questions && request.questions.len() > 1 {
             println!(
                 "Multiple questions detected ({}), splitting requests",
                 request.questions.len()
             );
 
-            // Create a combined response with the original request header
+            // Create a combined response derived from the request header so that
+            // the opcode and RD bit are preserved
             let mut combined_response = DnsMessage {
-                header: DnsHeader {
-                    id: request.header.id,
-                    flags: 0x8000, // QR=1 (Response)
-                    qdcount: request.header.qdcount,
-                    ancount: 0,
-                    nscount: 0,
-                    arcount: 0,
-                },
+                header: DnsHeader::new(&request.header, request.header.qdcount, 0),
                 questions: request.questions.clone(),
                 answers: Vec::new(),
+                authorities: Vec::new(),
+                additionals: Vec::new(),
             };
 
+            // Answers for each question, indexed by the question's position so the
+            // combined answer section follows question order whatever order the
+            // sub-queries complete in
+            let mut answers_by_question: Vec<Vec<DnsRecord>> =
+                vec![Vec::new(); request.questions.len()];
+
+            // RCODE of each sub-query that got a response, in question order
+            let mut rcodes = Vec::new();
+
+            // The combined answer is only authenticated if every part of it is
+            let mut all_authentic = true;
+
             // For each question, create and send a separate request
-            for question in &request.questions {
-                // Create a single-question request
+            for (index, question) in request.questions.iter().enumerate() {
+                // Create a single-question request with its own upstream ID
+                let upstream_id = ids.next_id();
+                let mut header =
+                    DnsHeader::new_query(upstream_id, request.header.recursion_desired(), 1);
+                header.set_checking_disabled(request.header.checking_disabled());
+                header.set_authentic_data(request.header.authentic_data());
                 let single_question_request = DnsMessage {
-                    header: DnsHeader {
-                        id: request.header.id,
-                        flags: request.header.flags & 0x7FFF, // QR=0 (Query)
-                        qdcount: 1,
-                        ancount: 0,
-                        nscount: 0,
-                        arcount: 0,
-                    },
+                    header,
                     questions: vec![question.clone()],
                     answers: Vec::new(),
+                    authorities: Vec::new(),
+                    additionals: request.additionals.clone(),
                 };
 
                 // Convert to bytes
                 let query_bytes = single_question_request.to_forwarded_request_bytes();
 
-                // Send the query
-                println!("Forwarding single question to DNS server: {}", dns_server);
-                #[allow(clippy::redundant_pattern_matching)]
-                if let Err(_) = socket.send_to(&query_bytes, dns_server) {
-                    continue; // Try the next question if this one fails
-                }
-
-                // Receive the response
-                let mut buf = [0; 512];
-                let (size, _) = match socket.recv_from(&mut buf) {
-                    Ok(res) => res,
-                    Err(_) => continue, // Try the next question if receiving fails
+                // Send the query and wait for the answer
+                println!(
+                    "Forwarding single question to DNS server: {}",
+                    transport.describe()
+                );
+                let response_bytes = match send(&query_bytes) {
+                    Ok(bytes) => bytes,
+                    Err(_) => continue, // Try the next question if this one fails
                 };
 
                 // Parse the response
-                if let Ok(response) = DnsMessage::from_bytes(&buf[..size]) {
-                    // Add the answers to our combined response
-                    let answer_count = response.answers.len();
-                    combined_response.answers.extend(response.answers);
-                    println!("Added {} answers from sub-query", answer_count);
+                if let Ok(response) =
+                    DnsMessage::from_bytes_with_limit(&response_bytes, max_answers)
+                {
+                    if response.header.id != upstream_id {
+                        println!("Ignoring sub-query response with mismatched ID");
+                        continue;
+                    }
+
+                    // Recursion is available if the upstream offered it
+                    if response.header.recursion_available() {
+                        combined_response.header.set_recursion_available(true);
+                    }
+
+                    println!("Received {} answers from sub-query", response.answers.len());
+                    all_authentic &= response.header.authentic_data();
+                    rcodes.push(response.header.rcode());
+                    answers_by_question[index] = response.answers;
                 }
             }
 
+            // Assemble the answers in question order
+            combined_response.answers = answers_by_question.into_iter().flatten().collect();
+
+            if combined_response.answers.len() > max_answers {
+                combined_response.answers.truncate(max_answers);
+                combined_response.header.set_truncated(true);
+            }
+
             // Update the answer count
             combined_response.header.ancount = combined_response.answers.len() as u16;
 
-            if combined_response.answers.is_empty() {
-                return Err("Failed to get any answers for the split queries");
+            if rcodes.is_empty() {
+                return Err(DnsError::Parse(
+                    "Failed to get any answers for the split queries",
+                ));
             }
 
+            let rcode = Self::combined_rcode(&rcodes, !combined_response.answers.is_empty());
+            combined_response.header.set_rcode(rcode);
+            combined_response.header.set_authentic_data(all_authentic);
+
             return Ok(combined_response);
         }
 
-        // For single-question requests, use the original forwarding logic
-        let query_bytes = request.to_forwarded_request_bytes();
+        // For single-question requests, use the original forwarding logic, but
+        // with a fresh ID so upstream traffic doesn't reveal the client's
+        let upstream_id = ids.next_id();
+        let mut query_bytes = request.to_forwarded_request_bytes();
+        query_bytes[..2].copy_from_slice(&upstream_id.to_be_bytes());
+
+        // Send the query to the upstream server and wait for the answer
+        println!("Forwarding query to DNS server: {}", transport.describe());
+        let response_bytes = send(&query_bytes)?;
+
+        // Parse the response
+        let mut response = DnsMessage::from_bytes_with_limit(&response_bytes, max_answers)?;
+
+        // A reply to some other query (or a spoofed one) must not be relayed
+        if response.header.id != upstream_id {
+            return Err(DnsError::Inconsistent(format!(
+                "upstream response ID {} does not match query ID {}",
+                response.header.id, upstream_id
+            )));
+        }
+
+        // Hand the response back under the client's ID
+        response.header.id = request.header.id;
+        Ok(response)
+    }
+}
+
+// Multi-line, dig-style summary of a message
+impl fmt::Display for DnsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = &self.header;
+        let flag_names = [
+            (15, "qr"),
+            (10, "aa"),
+            (9, "tc"),
+            (8, "rd"),
+            (7, "ra"),
+            (5, "ad"),
+            (4, "cd"),
+        ];
+        let flags: Vec<&str> = flag_names
+            .iter()
+            .filter(|(bit, _)| (header.flags >> bit) & 0x1 == 1)
+            .map(|(_, name)| *name)
+            .collect();
+
+        writeln!(
+            f,
+            ";; id {}, opcode {}, rcode {}, flags: {}",
+            header.id,
+            header.opcode(),
+            header.rcode(),
+            flags.join(" ")
+        )?;
+
+        writeln!(f, ";; QUESTION ({})", self.questions.len())?;
+        for question in &self.questions {
+            let name = question
+                .decode_name()
+                .unwrap_or_else(|_| String::from("<invalid>"));
+            writeln!(
+                f,
+                "{}.\tCLASS{}\tTYPE{}",
+                name, question.class, question.record_type
+            )?;
+        }
+
+        let sections = [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authorities),
+            ("ADDITIONAL", &self.additionals),
+        ];
+        for (title, records) in sections {
+            writeln!(f, ";; {} ({})", title, records.len())?;
+            for record in records {
+                writeln!(f, "{}", record)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_id::FixedIds;
+    use std::io;
+    use std::net::Ipv4Addr;
+    use std::sync::Mutex;
+
+    // Answers queries with `reply` (None means no answer arrives), keeping what it was sent
+    struct ScriptedTransport<F> {
+        reply: F,
+        sent: Mutex<Vec<DnsMessage>>,
+    }
+
+    impl<F: Fn(&DnsMessage) -> Option<DnsMessage> + Send + Sync> ScriptedTransport<F> {
+        fn new(reply: F) -> Self {
+            ScriptedTransport {
+                reply,
+                sent: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    impl<F: Fn(&DnsMessage) -> Option<DnsMessage> + Send + Sync> Transport for ScriptedTransport<F> {
+        fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, DnsError> {
+            let query = DnsMessage::from_bytes(query)?;
+            self.sent.lock().unwrap().push(query.clone());
+            match (self.reply)(&query) {
+                Some(response) => Ok(response.to_bytes().to_vec()),
+                None => Err(io::Error::new(io::ErrorKind::TimedOut, "no answer").into()),
+            }
+        }
 
-        // Send the query to the external DNS server
-        println!("Forwarding query to DNS server: {}", dns_server);
-        #[allow(clippy::redundant_pattern_matching)]
-        if let Err(_) = socket.send_to(&query_bytes, dns_server) {
-            return Err("Failed to send request to external DNS server");
+        fn describe(&self) -> String {
+            String::from("scripted")
         }
+    }
 
-        // Receive the response
-        let mut buf = [0; 512];
-        let (size, _) = match socket.recv_from(&mut buf) {
-            Ok(res) => res,
-            Err(_) => return Err("Failed to receive response from external DNS server"),
+    fn name(domain: &str) -> Vec<u8> {
+        DnsQuestion::encode_domain_name(domain)
+    }
+
+    fn question(domain: &str, record_type: u16) -> DnsQuestion {
+        DnsQuestion {
+            name: name(domain),
+            record_type,
+            class: 1,
+        }
+    }
+
+    // A query with one question per (name, type), RD as given
+    fn multi_query(questions: &[(&str, u16)], recursion_desired: bool) -> DnsMessage {
+        DnsMessage {
+            header: DnsHeader::new_query(0x4242, recursion_desired, questions.len() as u16),
+            questions: questions
+                .iter()
+                .map(|(domain, record_type)| question(domain, *record_type))
+                .collect(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    // An upstream answering A queries with 192.0.2.1 and anything else with NODATA
+    fn a_answers(query: &DnsMessage) -> Option<DnsMessage> {
+        let question = &query.questions[0];
+        let answers = if question.record_type == 1 {
+            vec![DnsRecord::new(
+                question.name.clone(),
+                Ipv4Addr::new(192, 0, 2, 1),
+            )]
+        } else {
+            Vec::new()
         };
+        Some(DnsMessage::new_answer_from_request(query, answers))
+    }
 
-        // Parse the response
-        match DnsMessage::from_bytes(&buf[..size]) {
-            Ok(mut response) => {
-                // Ensure the response ID matches the request ID
-                response.header.id = request.header.id;
-                Ok(response)
+    fn forward_split(request: &DnsMessage, transport: &dyn Transport) -> DnsMessage {
+        let ids = FixedIds::new(vec![1, 2, 3, 4]);
+        DnsMessage::forward_query(request, transport, DEFAULT_MAX_ANSWERS, &ids, None, true)
+            .unwrap()
+    }
+
+    #[test]
+    fn split_questions_keep_their_types_and_rd() {
+        for recursion_desired in [true, false] {
+            let transport = ScriptedTransport::new(a_answers);
+            let request = multi_query(&[("a.test", 1), ("b.test", 28)], recursion_desired);
+            let response = forward_split(&request, &transport);
+
+            assert_eq!(response.header.recursion_desired(), recursion_desired);
+            assert_eq!(response.header.id, 0x4242);
+            assert_eq!(response.header.qdcount, 2);
+            assert_eq!(response.questions[1].record_type, 28);
+
+            let sent = transport.sent.lock().unwrap();
+            assert_eq!(sent.len(), 2);
+            assert_eq!(sent[0].questions[0].record_type, 1);
+            assert_eq!(sent[1].questions[0].record_type, 28);
+            assert!(sent
+                .iter()
+                .all(|query| query.header.recursion_desired() == recursion_desired));
+        }
+    }
+
+    fn a_record(domain: &str, ttl: u32) -> DnsRecord {
+        let mut record = DnsRecord::new(name(domain), Ipv4Addr::new(192, 0, 2, 1));
+        record.ttl = ttl;
+        record
+    }
+
+    fn response_with(answers: Vec<DnsRecord>) -> DnsMessage {
+        let request = multi_query(&[("example.test", 1)], true);
+        DnsMessage::new_answer_from_request(&request, answers)
+    }
+
+    #[test]
+    fn ttls_are_clamped_into_bounds() {
+        let mut response = response_with(vec![
+            a_record("low.test", 0),
+            a_record("mid.test", 300),
+            a_record("high.test", 999_999),
+        ]);
+        response.clamp_ttls(Some(60), Some(86_400));
+
+        let ttls: Vec<u32> = response.answers.iter().map(|record| record.ttl).collect();
+        assert_eq!(ttls, vec![60, 300, 86_400]);
+    }
+
+    #[test]
+    fn authority_and_additional_ttls_are_clamped_too() {
+        let request = multi_query(&[("www.example.test", 28)], true);
+        let mut soa =
+            DnsRecord::from_zone_line("example.test", "@ SOA ns1 hostmaster 1 3600 600 86400 300")
+                .unwrap();
+        soa.ttl = 999_999;
+
+        let mut response = DnsMessage::new_nodata_from_request(&request, Some(soa));
+        response.additionals.push(a_record("ns1.example.test", 0));
+        let opt = dns_edns::OptRecord::new(1232).dnssec_ok(true).build();
+        let opt_ttl = opt.ttl;
+        response.additionals.push(opt);
+        response.clamp_ttls(Some(60), Some(86_400));
+
+        assert_eq!(response.authorities[0].ttl, 86_400);
+        assert_eq!(response.additionals[0].ttl, 60);
+        // The OPT record's TTL holds its flags, DO here
+        assert_eq!(response.additionals[1].ttl, opt_ttl);
+        assert!(response.dnssec_ok());
+    }
+
+    #[test]
+    fn ttls_are_left_alone_without_bounds() {
+        let mut response = response_with(vec![
+            a_record("low.test", 0),
+            a_record("high.test", 999_999),
+        ]);
+        response.clamp_ttls(None, None);
+        assert_eq!(response.answers[0].ttl, 0);
+        assert_eq!(response.answers[1].ttl, 999_999);
+
+        // Only a minimum raises a TTL of 0
+        response.clamp_ttls(None, Some(3600));
+        assert_eq!(response.answers[0].ttl, 0);
+        assert_eq!(response.answers[1].ttl, 3600);
+    }
+
+    #[test]
+    fn nodata_is_noerror_with_the_soa_in_authority() {
+        let request = multi_query(&[("www.example.test", 28)], true);
+        let soa =
+            DnsRecord::from_zone_line("example.test", "@ SOA ns1 hostmaster 1 3600 600 86400 300")
+                .unwrap();
+
+        let response = DnsMessage::new_nodata_from_request(&request, Some(soa.clone()));
+        assert_eq!(response.header.rcode(), 0);
+        assert_eq!(response.header.ancount, 0);
+        assert_eq!(response.header.nscount, 1);
+        assert_eq!(response.authorities, vec![soa]);
+
+        let response = DnsMessage::new_nodata_from_request(&request, None);
+        assert_eq!(response.header.rcode(), 0);
+        assert_eq!(response.header.nscount, 0);
+        assert!(response.authorities.is_empty());
+    }
+
+    #[test]
+    fn clones_reserialize_identically() {
+        let mut original = response_with(vec![a_record("example.test", 300)]);
+        original.header.set_recursion_available(true);
+        original.additionals.push(a_record("extra.test", 60));
+        original.header.arcount = 1;
+
+        let mut copy = original.clone();
+        assert_eq!(copy.to_bytes(), original.to_bytes());
+
+        // The copy is independent of the original
+        copy.answers[0].ttl = 1;
+        assert_eq!(original.answers[0].ttl, 300);
+        assert_ne!(copy.to_bytes(), original.to_bytes());
+    }
+
+    #[test]
+    fn answer_names_point_at_the_question() {
+        let response = response_with(vec![a_record("example.test", 300)]);
+        let bytes = response.to_bytes();
+
+        // Header, then the question: name, type and class
+        let question_end = 12 + name("example.test").len() + 4;
+        assert_eq!(&bytes[question_end..question_end + 2], &[0xC0, 0x0C]);
+        // The rest of the answer follows the pointer directly: type A, class IN
+        assert_eq!(&bytes[question_end + 2..question_end + 6], &[0, 1, 0, 1]);
+        assert_eq!(bytes.len(), question_end + 2 + 10 + 4);
+    }
+
+    // The fuzz seed corpus: valid packets of a few shapes
+    const SEEDS: [&[u8]; 4] = [
+        include_bytes!("../fuzz/corpus/parse/a_query.bin"),
+        include_bytes!("../fuzz/corpus/parse/a_response_compressed.bin"),
+        include_bytes!("../fuzz/corpus/parse/cname_chain.bin"),
+        include_bytes!("../fuzz/corpus/parse/multi_question_compressed.bin"),
+    ];
+
+    #[test]
+    fn seed_packets_parse() {
+        for seed in SEEDS {
+            assert!(DnsMessage::from_bytes(seed).is_ok());
+        }
+    }
+
+    // The fuzz target's body: parse, and reserialize whatever parsed
+    fn parse_like_the_fuzzer(packet: &[u8]) {
+        if let Ok(message) = DnsMessage::from_bytes(packet) {
+            let _ = message.to_bytes();
+            for answer in &message.answers {
+                let _ = answer.decode_name();
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_packets_never_panic() {
+        // What the fuzz target checks, over every truncation and every single-byte
+        // change of the seeds: parsing returns Ok or Err without panicking
+        for seed in SEEDS {
+            for end in 0..seed.len() {
+                parse_like_the_fuzzer(&seed[..end]);
+            }
+            for position in 0..seed.len() {
+                for value in [0x00, 0x3F, 0x40, 0xC0, 0xFF] {
+                    let mut packet = seed.to_vec();
+                    packet[position] = value;
+                    parse_like_the_fuzzer(&packet);
+                }
             }
-            Err(e) => Err(e),
         }
     }
+
+    #[test]
+    fn combined_answers_follow_question_order() {
+        // Each name gets a different number of answers; c.test needs two
+        let transport = ScriptedTransport::new(|query: &DnsMessage| {
+            let question = &query.questions[0];
+            let count = match question.decode_name().unwrap().as_str() {
+                "c.test" => 2,
+                _ => 1,
+            };
+            let answers = (0..count)
+                .map(|host| DnsRecord::new(question.name.clone(), Ipv4Addr::new(192, 0, 2, host)))
+                .collect();
+            Some(DnsMessage::new_answer_from_request(query, answers))
+        });
+
+        let request = multi_query(&[("c.test", 1), ("a.test", 1), ("b.test", 1)], true);
+        let response = forward_split(&request, &transport);
+
+        let owners: Vec<String> = response
+            .answers
+            .iter()
+            .map(|answer| answer.decode_name().unwrap())
+            .collect();
+        assert_eq!(owners, ["c.test", "c.test", "a.test", "b.test"]);
+        assert_eq!(response.header.ancount, 4);
+    }
+
+    #[test]
+    fn failed_sub_queries_leave_the_others_in_order() {
+        let transport = ScriptedTransport::new(|query: &DnsMessage| {
+            match query.questions[0].decode_name().unwrap().as_str() {
+                "b.test" => None,
+                _ => a_answers(query),
+            }
+        });
+
+        let request = multi_query(&[("a.test", 1), ("b.test", 1), ("c.test", 1)], true);
+        let response = forward_split(&request, &transport);
+
+        let owners: Vec<String> = response
+            .answers
+            .iter()
+            .map(|answer| answer.decode_name().unwrap())
+            .collect();
+        assert_eq!(owners, ["a.test", "c.test"]);
+    }
+
+    #[test]
+    fn split_responses_are_authentic_only_if_every_part_is() {
+        // An upstream that validated everything but b.test
+        let transport = ScriptedTransport::new(|query: &DnsMessage| {
+            let mut response = a_answers(query)?;
+            let validated = query.questions[0].decode_name().unwrap() != "b.test";
+            response.header.set_authentic_data(validated);
+            Some(response)
+        });
+
+        let mut request = multi_query(&[("a.test", 1), ("c.test", 1)], true);
+        request.header.set_authentic_data(true);
+        let response = forward_split(&request, &transport);
+        assert!(response.header.authentic_data());
+        assert!(transport
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|query| query.header.authentic_data()));
+
+        let request = multi_query(&[("a.test", 1), ("b.test", 1)], true);
+        let response = forward_split(&request, &transport);
+        assert!(!response.header.authentic_data());
+    }
+
+    // A query whose first question has a 253-byte name and whose other questions
+    // are each a pointer back to it: six bytes on the wire, 253 once expanded
+    fn pointer_questions(count: u16) -> Vec<u8> {
+        let mut packet = DnsHeader::new_query(1, true, count).to_bytes().to_vec();
+        let long_name = vec!["a".repeat(62); 4].join(".");
+        packet.extend(name(&long_name));
+        packet.extend([0, 1, 0, 1]);
+        for _ in 1..count {
+            packet.extend([0xC0, 0x0C, 0, 1, 0, 1]);
+        }
+        packet
+    }
+
+    #[test]
+    fn expanded_name_budget_stops_pointer_floods() {
+        let packet = pointer_questions(300);
+        assert!(packet.len() * 30 < MAX_EXPANDED_NAME_BYTES);
+        assert!(matches!(
+            DnsMessage::from_bytes(&packet),
+            Err(DnsError::Truncated(_))
+        ));
+
+        // The same shape within budget parses
+        let message = DnsMessage::from_bytes(&pointer_questions(200)).unwrap();
+        assert_eq!(message.questions.len(), 200);
+    }
+
+    // A response to `pointer_questions(1)` with `count` CNAME answers owned by
+    // the root, each a two-byte pointer back to the question's 253-byte name
+    fn pointer_cnames(count: u16) -> Vec<u8> {
+        let mut packet = pointer_questions(1);
+        packet[2] |= 0x80;
+        packet[6..8].copy_from_slice(&count.to_be_bytes());
+        for _ in 0..count {
+            packet.extend([0, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xC0, 0x0C]);
+        }
+        packet
+    }
+
+    #[test]
+    fn expanded_rdata_names_count_against_the_budget() {
+        // Only one byte of owner name per record: the data alone is over budget
+        let packet = pointer_cnames(300);
+        assert!(matches!(
+            DnsMessage::from_bytes(&packet),
+            Err(DnsError::Truncated(_))
+        ));
+        assert!(DnsMessage::from_bytes_lenient(&packet)
+            .1
+            .iter()
+            .any(|e| matches!(e, DnsError::Truncated(_))));
+
+        let message = DnsMessage::from_bytes(&pointer_cnames(200)).unwrap();
+        assert_eq!(message.answers.len(), 200);
+        assert_eq!(message.answers[0].rdata.len(), 253);
+    }
+
+    #[test]
+    fn minimal_stripping_needs_an_answer() {
+        let mut response = response_with(Vec::new());
+        response.authorities.push(a_record("ns.test", 60));
+        response.sync_counts();
+
+        response.strip_to_minimal();
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.header.nscount, 1);
+    }
+
+    // The message of the Inconsistent error `validate` gives, if any
+    fn validation_error(message: &DnsMessage) -> Option<String> {
+        match message.validate() {
+            Ok(()) => None,
+            Err(DnsError::Inconsistent(problem)) => Some(problem),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn consistent_messages_validate() {
+        assert_eq!(validation_error(&multi_query(&[("a.test", 1)], true)), None);
+        assert_eq!(
+            validation_error(&response_with(vec![a_record("a.test", 60)])),
+            None
+        );
+    }
+
+    #[test]
+    fn mismatched_counts_are_reported() {
+        let mut response = response_with(vec![a_record("a.test", 60)]);
+        response.header.ancount = 2;
+        let problem = validation_error(&response).unwrap();
+        assert!(problem.contains("2 answer entries"), "{}", problem);
+    }
+
+    #[test]
+    fn queries_with_answers_are_reported() {
+        let mut request = multi_query(&[("a.test", 1)], true);
+        request.answers.push(a_record("a.test", 60));
+        request.sync_counts();
+        assert!(validation_error(&request).unwrap().contains("QR=0"));
+    }
+
+    #[test]
+    fn malformed_names_are_reported() {
+        for bad_name in [vec![], vec![1, b'a'], vec![1, b'a', 0, 0], vec![64, 0]] {
+            let mut response = response_with(vec![a_record("a.test", 60)]);
+            response.answers[0].name = bad_name.clone();
+            assert!(validation_error(&response).is_some(), "{:?}", bad_name);
+        }
+    }
+
+    #[test]
+    fn oversized_rdata_is_reported() {
+        let mut response = response_with(vec![a_record("a.test", 60)]);
+        response.answers[0].rdata = vec![0; 70_000];
+        assert!(validation_error(&response).unwrap().contains("RDLENGTH"));
+    }
+
+    #[test]
+    fn unknown_types_are_skipped_by_rdlength() {
+        let mut opaque = a_record("a.test", 60);
+        opaque.record_type = 0xFF00;
+        opaque.rdata = vec![0xC0, 0x0C, 0, 3, 0xFF];
+        let response = response_with(vec![opaque.clone(), a_record("a.test", 60)]);
+
+        let parsed = DnsMessage::from_bytes(&response.to_bytes()).unwrap();
+        assert_eq!(parsed.answers.len(), 2);
+        assert_eq!(parsed.answers[0].rdata, opaque.rdata);
+        assert_eq!(
+            parsed.answers[1].rdata_as_ipv4(),
+            Some(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    #[test]
+    fn compressed_names_in_rdata_are_expanded_in_place() {
+        // Question example.test/MX; an MX answer whose exchange is `mail` plus a
+        // pointer to the question name; then an A record
+        let mut packet = DnsHeader::new(&DnsHeader::new_query(1, true, 1), 1, 2)
+            .to_bytes()
+            .to_vec();
+        packet.extend(name("example.test"));
+        packet.extend([0, 15, 0, 1]);
+        packet.extend([0xC0, 0x0C, 0, 15, 0, 1, 0, 0, 0, 60, 0, 9, 0, 10]);
+        packet.extend(b"\x04mail\xC0\x0C");
+        packet.extend([0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+
+        let parsed = DnsMessage::from_bytes(&packet).unwrap();
+        assert_eq!(parsed.answers.len(), 2);
+
+        let mut exchange = vec![0, 10];
+        exchange.extend(name("mail.example.test"));
+        assert_eq!(parsed.answers[0].rdata, exchange);
+        assert_eq!(
+            parsed.answers[1].rdata_as_ipv4(),
+            Some(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    // A response with `count` A records for a.test
+    fn response_with_answers(count: u8) -> DnsMessage {
+        let answers = (0..count)
+            .map(|host| DnsRecord::new(name("a.test"), Ipv4Addr::new(192, 0, 2, host)))
+            .collect();
+        response_with(answers)
+    }
+
+    #[test]
+    fn answers_past_the_cap_are_dropped_with_tc() {
+        let mut response = response_with_answers(10);
+        response.additionals.push(a_record("extra.test", 60));
+        response.sync_counts();
+
+        let parsed = DnsMessage::from_bytes_with_limit(&response.to_bytes(), 4).unwrap();
+        assert_eq!(parsed.answers.len(), 4);
+        assert_eq!(parsed.header.ancount, 4);
+        assert!(parsed.header.truncated());
+        assert!(parsed.additionals.is_empty());
+        assert_eq!(parsed.header.arcount, 0);
+
+        // At the cap nothing is cut
+        let parsed = DnsMessage::from_bytes_with_limit(&response.to_bytes(), 10).unwrap();
+        assert_eq!(parsed.answers.len(), 10);
+        assert!(!parsed.header.truncated());
+    }
+
+    #[test]
+    fn combined_answers_are_capped() {
+        let transport = ScriptedTransport::new(|query: &DnsMessage| {
+            let mut response = response_with_answers(3);
+            response.header.id = query.header.id;
+            response.questions = query.questions.clone();
+            Some(response)
+        });
+        let request = multi_query(&[("a.test", 1), ("b.test", 1)], true);
+
+        let ids = FixedIds::new(vec![1, 2]);
+        let response =
+            DnsMessage::forward_query(&request, &transport, 4, &ids, None, true).unwrap();
+        assert_eq!(response.answers.len(), 4);
+        assert_eq!(response.header.ancount, 4);
+        assert!(response.header.truncated());
+    }
+
+    #[test]
+    fn error_responses_carry_the_rcode() {
+        let request = multi_query(&[("a.test", 1)], true);
+
+        for rcode in 1..=5u8 {
+            let response = DnsMessage::new_error_response(&request, rcode);
+            let bytes = response.to_bytes();
+
+            // QR set, opcode 0, RD copied; then RA set and the RCODE
+            assert_eq!(bytes[2], 0x81, "rcode {}", rcode);
+            assert_eq!(bytes[3], 0x80 | rcode, "rcode {}", rcode);
+            assert_eq!(response.header.ancount, 0);
+            assert_eq!(response.header.qdcount, 1);
+            assert_eq!(response.questions.len(), 1);
+        }
+    }
+
+    #[test]
+    fn error_responses_keep_opcode_and_rd() {
+        let mut request = multi_query(&[("a.test", 1)], false);
+        request.header.flags |= 2 << 11; // STATUS
+
+        let response = DnsMessage::new_error_response(&request, 4);
+        assert_eq!(response.header.opcode(), 2);
+        assert!(!response.header.recursion_desired());
+        assert!(!response.header.recursion_available());
+
+        assert_eq!(
+            DnsMessage::new_nxdomain_from_request(&request)
+                .header
+                .rcode(),
+            3
+        );
+        assert_eq!(
+            DnsMessage::new_servfail_from_request(&request)
+                .header
+                .rcode(),
+            2
+        );
+    }
+
+    #[test]
+    fn parses_straight_from_buffers() {
+        let response = response_with(vec![a_record("a.test", 60)]);
+        let buf = response.to_bytes();
+
+        let parsed = DnsMessage::from_buf(&buf).unwrap();
+        assert_eq!(parsed.to_bytes(), buf);
+
+        let frozen = buf.freeze();
+        assert_eq!(
+            DnsMessage::from_buf(frozen.clone()).unwrap().to_bytes(),
+            frozen
+        );
+    }
+
+    fn cname(owner: &str, target: &str) -> DnsRecord {
+        DnsRecord::new_cname(name(owner), name(target))
+    }
+
+    fn answer_owners(message: &DnsMessage) -> Vec<(String, u16)> {
+        message
+            .answers
+            .iter()
+            .map(|record| (record.decode_name().unwrap(), record.record_type))
+            .collect()
+    }
+
+    #[test]
+    fn cnames_are_ordered_before_their_targets() {
+        let mut response = response_with(vec![
+            a_record("target.test", 60),
+            cname("example.test", "target.test"),
+        ]);
+        response.order_answers();
+
+        assert_eq!(
+            answer_owners(&response),
+            [
+                (String::from("example.test"), 5),
+                (String::from("target.test"), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn cname_chains_are_ordered_hop_by_hop() {
+        let mut response = response_with(vec![
+            a_record("c.test", 60),
+            cname("b.test", "c.test"),
+            cname("example.test", "b.test"),
+        ]);
+        response.order_answers();
+
+        assert_eq!(
+            answer_owners(&response),
+            [
+                (String::from("example.test"), 5),
+                (String::from("b.test"), 5),
+                (String::from("c.test"), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn ordering_is_stable_without_cnames() {
+        let mut response = response_with(vec![
+            a_record("example.test", 60),
+            DnsRecord::new(name("example.test"), Ipv4Addr::new(192, 0, 2, 9)),
+        ]);
+        let before = response.answers.clone();
+        response.order_answers();
+        assert_eq!(response.answers[1].rdata, before[1].rdata);
+    }
+
+    #[test]
+    fn order_case_ttl_and_compression_do_not_matter() {
+        let built = response_with(vec![
+            cname("example.test", "target.test"),
+            a_record("target.test", 60),
+        ]);
+        // Parsed from the compressed wire form
+        let parsed = DnsMessage::from_bytes(&built.to_bytes()).unwrap();
+
+        let mut reordered = built.clone();
+        reordered.answers.reverse();
+        reordered.answers[0].name = name("TARGET.Test");
+        reordered.answers[0].ttl = 5;
+
+        assert!(parsed.semantically_eq(&built));
+        assert!(reordered.semantically_eq(&built));
+        assert!(built.semantically_eq(&reordered));
+    }
+
+    #[test]
+    fn differing_content_is_not_equal() {
+        let base = response_with(vec![a_record("a.test", 60)]);
+
+        let mut other_id = base.clone();
+        other_id.header.id += 1;
+        let mut other_flags = base.clone();
+        other_flags.header.set_truncated(true);
+        let mut other_data = base.clone();
+        other_data.answers[0].rdata = vec![192, 0, 2, 99];
+        let mut other_section = base.clone();
+        let answer = other_section.answers.remove(0);
+        other_section.additionals.push(answer);
+        let mut other_question = base.clone();
+        other_question.questions[0].record_type = 28;
+
+        for other in [
+            other_id,
+            other_flags,
+            other_data,
+            other_section,
+            other_question,
+        ] {
+            assert!(!base.semantically_eq(&other));
+        }
+    }
+
+    #[test]
+    fn truncation_drops_whole_answers_and_keeps_the_question() {
+        let mut response = response_with_answers(40);
+        response.authorities.push(a_record("ns.test", 60));
+        response
+            .additionals
+            .push(dns_edns::OptRecord::new(1232).build());
+        response.sync_counts();
+
+        response.truncate_to(512);
+        let bytes = response.to_bytes();
+        assert!(bytes.len() <= 512);
+        assert!(response.header.truncated());
+
+        // What is left parses cleanly, whole records only
+        let parsed = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.questions.len(), 1);
+        assert_eq!(parsed.answers.len(), response.answers.len());
+        assert!(!parsed.answers.is_empty() && parsed.answers.len() < 40);
+        assert!(parsed.authorities.is_empty());
+        assert!(parsed.opt_record().is_some());
+        assert!(parsed.semantically_eq(&response));
+    }
+
+    #[test]
+    fn messages_within_the_limit_are_untouched() {
+        let mut response = response_with_answers(3);
+        let before = response.to_bytes();
+        response.truncate_to(512);
+        assert_eq!(response.to_bytes(), before);
+        assert!(!response.header.truncated());
+    }
+
+    #[test]
+    fn oversized_questions_leave_no_answers() {
+        let long_name = vec!["q".repeat(63); 3].join(".");
+        let request = multi_query(&[(long_name.as_str(), 1), (long_name.as_str(), 28)], true);
+        let mut response =
+            DnsMessage::new_answer_from_request(&request, vec![a_record("a.test", 60)]);
+
+        response.truncate_to(100);
+        assert!(response.header.truncated());
+        assert!(response.answers.is_empty());
+        assert_eq!(response.questions.len(), 2);
+        assert_eq!(response.header.ancount, 0);
+    }
+
+    #[test]
+    fn formerr_echoes_the_id_without_sections() {
+        let request = DnsHeader::new_query(0x4242, true, 3);
+        let response = DnsMessage::new_formerr(&request);
+
+        assert_eq!(response.header.id, 0x4242);
+        assert_eq!(response.header.rcode(), 1);
+        assert_eq!(response.header.qdcount, 0);
+        assert_eq!(response.header.ancount, 0);
+        assert!(response.questions.is_empty());
+        assert_eq!(response.to_bytes().len(), 12);
+    }
+
+    #[test]
+    fn combined_rcode_follows_agreement_then_answers() {
+        // All agree
+        assert_eq!(DnsMessage::combined_rcode(&[3, 3], false), 3);
+        assert_eq!(DnsMessage::combined_rcode(&[0, 0], true), 0);
+        // Mixed, but something was answered
+        assert_eq!(DnsMessage::combined_rcode(&[3, 0], true), 0);
+        // Mixed errors and nothing to show: the first error wins
+        assert_eq!(DnsMessage::combined_rcode(&[0, 3, 2], false), 3);
+        assert_eq!(DnsMessage::combined_rcode(&[2, 3], false), 2);
+    }
+
+    // An upstream answering a.test, NXDOMAIN for nx*.test and SERVFAIL for anything else
+    fn mixed_rcodes(query: &DnsMessage) -> Option<DnsMessage> {
+        let name = &query.questions[0].name;
+        if *name == DnsQuestion::encode_domain_name("a.test") {
+            a_answers(query)
+        } else if name.starts_with(b"\x03nx") {
+            Some(DnsMessage::new_error_response(query, 3))
+        } else {
+            Some(DnsMessage::new_error_response(query, 2))
+        }
+    }
+
+    #[test]
+    fn split_responses_combine_their_rcodes() {
+        let cases = [
+            (&[("nx1.test", 1), ("nx2.test", 1)][..], 3),
+            (&[("nx1.test", 1), ("a.test", 1)][..], 0),
+            (&[("nx1.test", 1), ("fail.test", 1)][..], 3),
+            (&[("fail.test", 1), ("nx1.test", 1)][..], 2),
+        ];
+
+        for (questions, rcode) in cases {
+            let transport = ScriptedTransport::new(mixed_rcodes);
+            let response = forward_split(&multi_query(questions, true), &transport);
+            assert_eq!(response.header.rcode(), rcode, "{:?}", questions);
+        }
+    }
+
+    #[test]
+    fn edited_ttls_survive_reserializing() {
+        let mut response = response_with(vec![a_record("a.test", 300)]);
+        response.answers_mut()[0].ttl = 60;
+
+        let parsed = DnsMessage::from_bytes(&response.to_bytes()).unwrap();
+        assert_eq!(parsed.answers[0].ttl, 60);
+    }
+
+    #[test]
+    fn sync_counts_follows_added_and_removed_records() {
+        let mut response = response_with(vec![a_record("a.test", 300)]);
+        response.answers_mut().clear();
+        response.additionals_mut().push(a_record("glue.test", 300));
+        response.questions_mut().push(question("b.test", 28));
+        response.sync_counts();
+
+        let parsed = DnsMessage::from_bytes(&response.to_bytes()).unwrap();
+        assert_eq!(parsed.header.qdcount, 2);
+        assert!(parsed.answers.is_empty());
+        assert_eq!(parsed.additionals.len(), 1);
+        assert_eq!(parsed.questions[1].record_type, 28);
+    }
+
+    #[test]
+    fn fallback_responses_echo_the_question_class() {
+        let mut request = multi_query(&[("example.test", 1), ("version.bind", 1)], true);
+        request.questions[1].class = 3;
+        let response = DnsMessage::new_response_from_request(&request);
+
+        let classes: Vec<u16> = response.questions.iter().map(|q| q.class).collect();
+        assert_eq!(classes, [1, 3]);
+        // Only the IN question has a record to answer with
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, name("example.test"));
+        assert_eq!(response.answers[0].class, 1);
+
+        let parsed = DnsMessage::from_bytes(&response.to_bytes()).unwrap();
+        assert_eq!(parsed.questions[1].class, 3);
+    }
+
+    // The benchmark fixtures: the seed packets plus a 64-record pool response.
+    // Every one must parse and reserialize, or the benchmarks would panic.
+    #[test]
+    fn benchmark_fixtures_round_trip() {
+        let large = response_with_answers(64).to_bytes().to_vec();
+        assert!(large.len() <= 1232);
+
+        for packet in SEEDS.iter().copied().chain([&large[..]]) {
+            let message = DnsMessage::from_bytes(packet).unwrap();
+            let reparsed = DnsMessage::from_bytes(&message.to_bytes()).unwrap();
+            assert_eq!(reparsed.answers.len(), message.answers.len());
+        }
+    }
+
+    #[test]
+    fn min_ttl_is_the_smallest_answer_ttl() {
+        let response = response_with(vec![
+            a_record("a.test", 300),
+            a_record("b.test", 60),
+            a_record("c.test", 120),
+        ]);
+        assert_eq!(response.min_ttl(), Some(60));
+    }
+
+    #[test]
+    fn negative_min_ttl_comes_from_the_soa() {
+        let mut soa = DnsRecord::from_zone_line(
+            "",
+            "example.test. SOA ns.example.test. host.example.test. 1 3600 600 86400 300",
+        )
+        .unwrap();
+        soa.ttl = 3600;
+        let mut response = response_with(Vec::new());
+        response.authorities.push(soa);
+        assert_eq!(response.min_ttl(), Some(300));
+
+        // The SOA's own TTL caps its MINIMUM field
+        response.authorities[0].ttl = 100;
+        assert_eq!(response.min_ttl(), Some(100));
+
+        response.authorities.clear();
+        assert_eq!(response.min_ttl(), None);
+    }
+
+    // www.example.test CNAME cdn.example.test, cdn.example.test A 192.0.2.1, with
+    // the CNAME target compressed against the question name
+    const COMPRESSED_CNAME: &[u8] = b"\x12\x34\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00\
+        \x03www\x07example\x04test\x00\x00\x01\x00\x01\
+        \xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x06\x03cdn\xc0\x10\
+        \xc0\x2e\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\xc0\x00\x02\x01";
+
+    #[test]
+    fn compressed_cname_targets_survive_relaying() {
+        let response = DnsMessage::from_bytes(COMPRESSED_CNAME).unwrap();
+        assert_eq!(response.answers[0].rdata, name("cdn.example.test"));
+        assert_eq!(response.answers[1].name, name("cdn.example.test"));
+
+        // Relayed into a message with a different layout, the old pointers would
+        // land somewhere else entirely
+        let mut relayed = response_with(response.answers.clone());
+        relayed.questions[0] = question("other.test", 1);
+        let parsed = DnsMessage::from_bytes(&relayed.to_bytes()).unwrap();
+
+        let target = DnsQuestion::decode_domain_name(&parsed.answers[0].rdata).unwrap();
+        assert_eq!(target, "cdn.example.test");
+        assert_eq!(parsed.answers[1].name, name("cdn.example.test"));
+    }
+
+    #[test]
+    fn lenient_parsing_keeps_what_came_before_an_error() {
+        let mut packet = response_with(vec![a_record("a.test", 60), a_record("b.test", 60)])
+            .to_bytes()
+            .to_vec();
+        // Claim an authority record, and cut the second answer's data short
+        packet[9] = 1;
+        packet.truncate(packet.len() - 2);
+
+        let (message, errors) = DnsMessage::from_bytes_lenient(&packet);
+        assert_eq!(message.questions.len(), 1);
+        assert_eq!(message.questions[0].name, name("example.test"));
+        assert_eq!(message.answers.len(), 1);
+        assert_eq!(message.header.ancount, 2);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[1].to_string().contains("authority section"));
+    }
+
+    #[test]
+    fn lenient_parsing_agrees_with_strict_on_good_packets() {
+        for seed in SEEDS {
+            let strict = DnsMessage::from_bytes(seed).unwrap();
+            let (lenient, errors) = DnsMessage::from_bytes_lenient(seed);
+            assert!(errors.is_empty());
+            assert!(lenient.semantically_eq(&strict));
+        }
+
+        let (_, errors) = DnsMessage::from_bytes_lenient(&[0x12, 0x34]);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn single_answer_keeps_the_first_of_the_queried_type() {
+        let mut response = response_with(vec![
+            cname("example.test", "cdn.test"),
+            a_record("cdn.test", 60),
+            DnsRecord::new(name("cdn.test"), Ipv4Addr::new(192, 0, 2, 2)),
+        ]);
+        response.keep_single_answer();
+
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.header.ancount, 1);
+        assert_eq!(
+            response.answers[0].rdata_as_ipv4(),
+            Some(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    #[test]
+    fn single_answer_leaves_answers_without_the_type() {
+        let mut response = response_with(vec![cname("example.test", "cdn.test")]);
+        response.keep_single_answer();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].record_type, 5);
+    }
+
+    #[test]
+    fn root_queries_round_trip() {
+        let request = DnsMessage::query(&FixedIds::new(vec![0x4242]), ".", 2);
+        let bytes = request.to_bytes();
+        assert_eq!(bytes.len(), 12 + 5);
+
+        let parsed = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.questions[0].name, [0]);
+        assert_eq!(parsed.questions[0].record_type, 2);
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn duplicate_answers_are_dropped_keeping_the_first() {
+        let mut response = response_with(vec![
+            a_record("a.test", 300),
+            a_record("A.TEST", 60),
+            a_record("b.test", 60),
+            DnsRecord::new(name("a.test"), Ipv4Addr::new(192, 0, 2, 2)),
+        ]);
+        response.dedup_answers();
+
+        assert_eq!(response.answers.len(), 3);
+        assert_eq!(response.header.ancount, 3);
+        assert_eq!(response.answers[0].ttl, 300);
+        assert_eq!(response.answers[0].name, name("a.test"));
+    }
 }
@@ -0,0 +1,137 @@
+use super::dns_question::DnsQuestion;
+use super::dns_record::DnsRecord;
+use bytes::{BufMut, BytesMut};
+use std::collections::HashMap;
+
+// Pointers carry a 14-bit offset, so only names early in a message can be targets
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+// Writes a message's names, replacing every suffix already written earlier in
+// the same message with a pointer to it (RFC 1035 section 4.1.4). Suffixes match
+// byte for byte, so a name's case is always preserved as given.
+#[derive(Default)]
+pub struct NameCompressor {
+    offsets: HashMap<Vec<u8>, u16>,
+}
+
+impl NameCompressor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Append an encoded (uncompressed) name to the message
+    pub fn write_name(&mut self, bytes: &mut BytesMut, name: &[u8]) {
+        let start = bytes.len();
+
+        let mut position = 0;
+        while position < name.len() && name[position] != 0 {
+            if let Some(&offset) = self.offsets.get(&name[position..]) {
+                bytes.extend_from_slice(&name[..position]);
+                bytes.put_u16(0xC000 | offset);
+                self.remember(name, start, position);
+                return;
+            }
+            position += 1 + name[position] as usize;
+        }
+
+        bytes.extend_from_slice(name);
+        self.remember(name, start, position);
+    }
+
+    // Record where each suffix of the name's first `end` bytes now lives
+    fn remember(&mut self, name: &[u8], start: usize, end: usize) {
+        let mut position = 0;
+        while position < end {
+            let offset = start + position;
+            if offset > MAX_POINTER_OFFSET {
+                break;
+            }
+            self.offsets
+                .entry(name[position..].to_vec())
+                .or_insert(offset as u16);
+            position += 1 + name[position] as usize;
+        }
+    }
+
+    pub fn write_question(&mut self, bytes: &mut BytesMut, question: &DnsQuestion) {
+        self.write_name(bytes, &question.name);
+        bytes.put_u16(question.record_type);
+        bytes.put_u16(question.class);
+    }
+
+    // Append a record, compressing its owner name and, for the types defined in
+    // RFC 1035, the names inside its data. Newer types keep their data verbatim,
+    // since resolvers that don't know them can't follow pointers into it
+    // (RFC 3597 section 4).
+    pub fn write_record(&mut self, bytes: &mut BytesMut, record: &DnsRecord) {
+        self.write_name(bytes, &record.name);
+        bytes.put_u16(record.record_type);
+        bytes.put_u16(record.class);
+        bytes.put_u32(record.ttl);
+
+        let length_position = bytes.len();
+        bytes.put_u16(0);
+
+        // Fixed bytes before the names, and how many names follow
+        let (prefix, name_count) = match record.record_type {
+            2 | 5 | 12 => (0, 1), // NS, CNAME, PTR
+            15 => (2, 1),         // MX: preference, exchange
+            6 => (0, 2),          // SOA: mname, rname, then five numbers
+            _ => (0, 0),
+        };
+        if !self.write_rdata_names(bytes, &record.rdata, prefix, name_count) {
+            bytes.truncate(length_position + 2);
+            bytes.extend_from_slice(&record.rdata);
+        }
+
+        let length = (bytes.len() - length_position - 2) as u16;
+        bytes[length_position..length_position + 2].copy_from_slice(&length.to_be_bytes());
+    }
+
+    // Write record data with its names compressed. False, with nothing usable
+    // written, when the data doesn't hold the names its type promises.
+    fn write_rdata_names(
+        &mut self,
+        bytes: &mut BytesMut,
+        rdata: &[u8],
+        prefix: usize,
+        name_count: usize,
+    ) -> bool {
+        if name_count == 0 || rdata.len() < prefix {
+            return false;
+        }
+
+        // Check the names first so a bad record doesn't leave stale offsets behind
+        let mut names = Vec::new();
+        let mut position = prefix;
+        for _ in 0..name_count {
+            let Some(length) = Self::name_length(&rdata[position..]) else {
+                return false;
+            };
+            names.push(&rdata[position..position + length]);
+            position += length;
+        }
+
+        bytes.extend_from_slice(&rdata[..prefix]);
+        for name in names {
+            self.write_name(bytes, name);
+        }
+        bytes.extend_from_slice(&rdata[position..]);
+        true
+    }
+
+    // Length of the uncompressed encoded name at the start of `bytes`
+    fn name_length(bytes: &[u8]) -> Option<usize> {
+        let mut position = 0;
+        loop {
+            let label = *bytes.get(position)? as usize;
+            if label == 0 {
+                return Some(position + 1);
+            }
+            if label > 63 {
+                return None;
+            }
+            position += 1 + label;
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use super::dns_record::DnsRecord;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// EDNS option code for the Name Server Identifier (RFC 5001)
+pub const OPTION_NSID: u16 = 3;
+
+// EDNS option code for Client Subnet (RFC 7871)
+pub const OPTION_CLIENT_SUBNET: u16 = 8;
+
+// EDNS option code for DNS Cookies (RFC 7873)
+pub const OPTION_COOKIE: u16 = 10;
+
+// Split OPT record data into its (code, data) options, ignoring a truncated trailer
+pub fn parse_options(rdata: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let mut options = Vec::new();
+    let mut position = 0;
+
+    while position + 4 <= rdata.len() {
+        let code = u16::from_be_bytes([rdata[position], rdata[position + 1]]);
+        let length = u16::from_be_bytes([rdata[position + 2], rdata[position + 3]]) as usize;
+        position += 4;
+
+        if position + length > rdata.len() {
+            break;
+        }
+
+        options.push((code, rdata[position..position + length].to_vec()));
+        position += length;
+    }
+
+    options
+}
+
+// Serialize options back into OPT record data
+pub fn encode_options(options: &[(u16, Vec<u8>)]) -> Vec<u8> {
+    let mut rdata = Vec::new();
+
+    for (code, data) in options {
+        rdata.extend_from_slice(&code.to_be_bytes());
+        rdata.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(data);
+    }
+
+    rdata
+}
+
+// Find the data of the first option with the given code
+pub fn find_option(rdata: &[u8], code: u16) -> Option<Vec<u8>> {
+    parse_options(rdata)
+        .into_iter()
+        .find(|(option_code, _)| *option_code == code)
+        .map(|(_, data)| data)
+}
+
+// Replace (or add) an option, or remove it entirely when `data` is None
+pub fn set_option(rdata: &mut Vec<u8>, code: u16, data: Option<Vec<u8>>) {
+    let mut options: Vec<(u16, Vec<u8>)> = parse_options(rdata)
+        .into_iter()
+        .filter(|(option_code, _)| *option_code != code)
+        .collect();
+
+    if let Some(data) = data {
+        options.push((code, data));
+    }
+
+    *rdata = encode_options(&options);
+}
+
+// Builder for OPT pseudo-records (RFC 6891). The CLASS field carries the UDP
+// payload size and the TTL packs the extended RCODE, version and flags.
+pub struct OptRecord {
+    payload_size: u16,
+    extended_rcode: u8,
+    version: u8,
+    dnssec_ok: bool,
+    options: Vec<(u16, Vec<u8>)>,
+}
+
+impl OptRecord {
+    pub fn new(payload_size: u16) -> Self {
+        OptRecord {
+            payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    // Upper eight bits of the 12-bit RCODE
+    pub fn extended_rcode(mut self, extended_rcode: u8) -> Self {
+        self.extended_rcode = extended_rcode;
+        self
+    }
+
+    pub fn version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    // The DO bit: the sender wants DNSSEC records
+    pub fn dnssec_ok(mut self, dnssec_ok: bool) -> Self {
+        self.dnssec_ok = dnssec_ok;
+        self
+    }
+
+    // Add a raw option
+    pub fn option(mut self, code: u16, data: Vec<u8>) -> Self {
+        self.options.push((code, data));
+        self
+    }
+
+    // Add an NSID option; requests carry empty data, responses the server's identifier
+    pub fn nsid(self, data: Vec<u8>) -> Self {
+        self.option(OPTION_NSID, data)
+    }
+
+    pub fn client_subnet(self, subnet: &ClientSubnet) -> Self {
+        self.option(OPTION_CLIENT_SUBNET, subnet.to_option_data())
+    }
+
+    pub fn build(self) -> DnsRecord {
+        let flags: u32 = if self.dnssec_ok { 0x8000 } else { 0 };
+        let ttl = ((self.extended_rcode as u32) << 24) | ((self.version as u32) << 16) | flags;
+
+        DnsRecord {
+            name: vec![0],   // Root domain
+            record_type: 41, // OPT record
+            class: self.payload_size,
+            ttl,
+            rdata: encode_options(&self.options),
+            received_at: None,
+        }
+    }
+}
+
+// EDNS Client Subnet option: the client network an answer should be tailored for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientSubnet {
+    pub family: u16, // 1 = IPv4, 2 = IPv6
+    pub source_prefix: u8,
+    pub scope_prefix: u8,
+    pub address: IpAddr,
+}
+
+impl ClientSubnet {
+    // Parse a CIDR such as 192.0.2.0/24 or 2001:db8::/56
+    pub fn from_cidr(cidr: &str) -> Result<Self, String> {
+        let (address, prefix) = match cidr.split_once('/') {
+            Some(parts) => parts,
+            None => return Err(format!("Missing prefix length in {}", cidr)),
+        };
+
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("Invalid address in {}", cidr))?;
+        let source_prefix: u8 = prefix
+            .parse()
+            .map_err(|_| format!("Invalid prefix length in {}", cidr))?;
+
+        let (family, max_prefix) = match address {
+            IpAddr::V4(_) => (1, 32),
+            IpAddr::V6(_) => (2, 128),
+        };
+        if source_prefix > max_prefix {
+            return Err(format!("Prefix length too long in {}", cidr));
+        }
+
+        Ok(ClientSubnet {
+            family,
+            source_prefix,
+            scope_prefix: 0,
+            address,
+        })
+    }
+
+    // Parse the option data of an ECS option
+    pub fn from_option_data(data: &[u8]) -> Option<Self> {
+        if data.len() < 4 {
+            return None;
+        }
+
+        let family = u16::from_be_bytes([data[0], data[1]]);
+        let source_prefix = data[2];
+        let scope_prefix = data[3];
+        let address_bytes = &data[4..];
+
+        // The address is truncated to the bytes covered by the source prefix
+        let address = match family {
+            1 if address_bytes.len() <= 4 => {
+                let mut octets = [0; 4];
+                octets[..address_bytes.len()].copy_from_slice(address_bytes);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            2 if address_bytes.len() <= 16 => {
+                let mut octets = [0; 16];
+                octets[..address_bytes.len()].copy_from_slice(address_bytes);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+
+        Some(ClientSubnet {
+            family,
+            source_prefix,
+            scope_prefix,
+            address,
+        })
+    }
+
+    // Encode as ECS option data, sending only the address bytes the source prefix
+    // covers and zeroing any bits past the prefix
+    pub fn to_option_data(&self) -> Vec<u8> {
+        let octets = match self.address {
+            IpAddr::V4(address) => address.octets().to_vec(),
+            IpAddr::V6(address) => address.octets().to_vec(),
+        };
+
+        let byte_count = (self.source_prefix as usize).div_ceil(8);
+        let mut address = octets[..byte_count].to_vec();
+        let spare_bits = byte_count * 8 - self.source_prefix as usize;
+        if let Some(last) = address.last_mut() {
+            *last &= 0xFF << spare_bits;
+        }
+
+        let mut data = self.family.to_be_bytes().to_vec();
+        data.push(self.source_prefix);
+        data.push(self.scope_prefix);
+        data.extend(address);
+        data
+    }
+}
+
+// DNS Cookie option: the client's 8-byte cookie, then the server cookie (8 to
+// 32 bytes) once the client has been given one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cookie {
+    pub client: [u8; 8],
+    pub server: Vec<u8>,
+}
+
+impl Cookie {
+    // Parse the option data of a cookie option. None for any other length than
+    // 8 or 16 to 40 bytes, which servers must answer with FORMERR.
+    pub fn from_option_data(data: &[u8]) -> Option<Self> {
+        if data.len() != 8 && !(16..=40).contains(&data.len()) {
+            return None;
+        }
+
+        let mut client = [0; 8];
+        client.copy_from_slice(&data[..8]);
+
+        Some(Cookie {
+            client,
+            server: data[8..].to_vec(),
+        })
+    }
+
+    pub fn to_option_data(&self) -> Vec<u8> {
+        let mut data = self.client.to_vec();
+        data.extend_from_slice(&self.server);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_subnet_encoding() {
+        let subnet = ClientSubnet::from_cidr("192.0.2.0/24").unwrap();
+        assert_eq!(subnet.to_option_data(), vec![0, 1, 24, 0, 192, 0, 2]);
+        assert_eq!(
+            ClientSubnet::from_option_data(&subnet.to_option_data()),
+            Some(subnet)
+        );
+
+        // Bits past the prefix are zeroed and uncovered bytes left out
+        let subnet = ClientSubnet::from_cidr("2001:db8:ffff::/36").unwrap();
+        assert_eq!(
+            subnet.to_option_data(),
+            vec![0, 2, 36, 0, 0x20, 0x01, 0x0d, 0xb8, 0xf0]
+        );
+    }
+
+    #[test]
+    fn invalid_client_subnets() {
+        assert!(ClientSubnet::from_cidr("192.0.2.0").is_err());
+        assert!(ClientSubnet::from_cidr("192.0.2.0/33").is_err());
+        assert!(ClientSubnet::from_cidr("example/24").is_err());
+        assert_eq!(ClientSubnet::from_option_data(&[0, 1, 24]), None);
+        assert_eq!(ClientSubnet::from_option_data(&[0, 3, 8, 0, 10]), None);
+    }
+
+    #[test]
+    fn opt_record_fields() {
+        let opt = OptRecord::new(4096).dnssec_ok(true).build();
+        assert_eq!(opt.name, vec![0]);
+        assert_eq!(opt.record_type, 41);
+        assert_eq!(opt.class, 4096);
+        assert_eq!(opt.ttl, 0x0000_8000);
+        assert!(opt.rdata.is_empty());
+
+        let opt = OptRecord::new(1232).extended_rcode(1).version(0).build();
+        assert_eq!(opt.ttl, 0x0100_0000);
+    }
+
+    #[test]
+    fn opt_record_options() {
+        let opt = OptRecord::new(1232)
+            .nsid(Vec::new())
+            .option(OPTION_COOKIE, vec![1; 8])
+            .build();
+
+        assert_eq!(
+            parse_options(&opt.rdata),
+            vec![(OPTION_NSID, Vec::new()), (OPTION_COOKIE, vec![1; 8])]
+        );
+        assert_eq!(find_option(&opt.rdata, OPTION_COOKIE), Some(vec![1; 8]));
+        assert_eq!(find_option(&opt.rdata, OPTION_CLIENT_SUBNET), None);
+    }
+
+    #[test]
+    fn cookie_lengths_follow_rfc_7873() {
+        let client_only = Cookie::from_option_data(b"client01").unwrap();
+        assert!(client_only.server.is_empty());
+
+        let data = [&b"client01"[..], &[7; 16]].concat();
+        let full = Cookie::from_option_data(&data).unwrap();
+        assert_eq!(full.server, [7; 16]);
+        assert_eq!(full.to_option_data(), data);
+
+        for length in [0, 7, 9, 15, 41] {
+            assert!(
+                Cookie::from_option_data(&vec![0; length]).is_none(),
+                "{}",
+                length
+            );
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use thiserror::Error;
+
+// Errors that can occur while parsing, building or exchanging DNS messages
+#[derive(Debug, Error)]
+pub enum DnsError {
+    // Malformed or unexpected packet contents
+    #[error("{0}")]
+    Parse(&'static str),
+
+    // A label length byte using the reserved 01 or 10 prefix
+    #[error("invalid label type 0x{0:02X}")]
+    InvalidLabel(u8),
+
+    // A domain name that can't be encoded on the wire
+    #[error("invalid name: {0}")]
+    InvalidName(String),
+
+    // A compression pointer to the given offset that doesn't point backward
+    #[error("compression pointer to offset {0} does not point backward")]
+    InvalidCompressionPointer(usize),
+
+    // A name longer than 255 bytes once its compression pointers are expanded
+    #[error("name expands past 255 bytes")]
+    NameTooLong,
+
+    // Parsing stopped early because the message exceeded a size or work limit
+    #[error("truncated: {0}")]
+    Truncated(&'static str),
+
+    // A message we built contradicts itself (e.g. counts not matching sections)
+    #[error("inconsistent message: {0}")]
+    Inconsistent(String),
+
+    // Network failure while talking to an upstream server
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    // Invalid zone file contents
+    #[error("zone error: {0}")]
+    Zone(String),
+
+    // A saved cache file that can't be read back
+    #[error("cache file error: {0}")]
+    CacheFile(String),
+
+    // DNS-over-HTTPS endpoint returned something other than a DNS answer
+    #[error("HTTP error: {0}")]
+    Http(String),
+}
+
+impl From<&'static str> for DnsError {
+    fn from(message: &'static str) -> Self {
+        DnsError::Parse(message)
+    }
+}
@@ -1,5 +1,7 @@
+use super::dns_error::DnsError;
 use bytes::{BufMut, BytesMut};
 
+#[derive(Clone, Debug)]
 pub struct DnsHeader {
     pub id: u16,      // Query identifier
     pub flags: u16,   // Combined flags (QR, Opcode, AA, TC, RD, RA, Z, RCODE)
@@ -52,10 +54,117 @@ impl DnsHeader {
         }
     }
 
+    // Create a standard query header (QR=0, OPCODE=0) with the RD bit as requested
+    pub fn new_query(id: u16, recursion_desired: bool, question_count: u16) -> Self {
+        let flags = if recursion_desired { 1 << 8 } else { 0 };
+
+        DnsHeader {
+            id,
+            flags,
+            qdcount: question_count,
+            ancount: 0,
+            nscount: 0,
+            arcount: 0,
+        }
+    }
+
+    // Extract the OPCODE (bits 11-14 of the flags)
+    pub fn opcode(&self) -> u16 {
+        (self.flags >> 11) & 0xF
+    }
+
+    // Extract the RCODE (lowest four bits of the flags)
+    pub fn rcode(&self) -> u16 {
+        self.flags & 0x000F
+    }
+
+    // Overwrite the RCODE (lowest four bits of the flags)
+    pub fn set_rcode(&mut self, rcode: u16) {
+        self.flags = (self.flags & !0x000F) | (rcode & 0x000F);
+    }
+
+    // Set or clear the AA (authoritative answer) bit
+    pub fn set_authoritative(&mut self, authoritative: bool) {
+        if authoritative {
+            self.flags |= 1 << 10;
+        } else {
+            self.flags &= !(1 << 10);
+        }
+    }
+
+    // Check the TC (truncated) bit
+    pub fn truncated(&self) -> bool {
+        (self.flags >> 9) & 0x1 == 1
+    }
+
+    // Set or clear the TC (truncated) bit
+    pub fn set_truncated(&mut self, truncated: bool) {
+        if truncated {
+            self.flags |= 1 << 9;
+        } else {
+            self.flags &= !(1 << 9);
+        }
+    }
+
+    // Check the RD (recursion desired) bit
+    pub fn recursion_desired(&self) -> bool {
+        (self.flags >> 8) & 0x1 == 1
+    }
+
+    // Check the RA (recursion available) bit
+    pub fn recursion_available(&self) -> bool {
+        (self.flags >> 7) & 0x1 == 1
+    }
+
+    // Set or clear the RA (recursion available) bit
+    pub fn set_recursion_available(&mut self, available: bool) {
+        if available {
+            self.flags |= 1 << 7;
+        } else {
+            self.flags &= !(1 << 7);
+        }
+    }
+
+    // Check the AD (authenticated data) bit: the validating resolver that built
+    // the response vouches for all of its data (RFC 4035)
+    pub fn authentic_data(&self) -> bool {
+        (self.flags >> 5) & 0x1 == 1
+    }
+
+    // Set or clear the AD (authenticated data) bit
+    pub fn set_authentic_data(&mut self, authentic: bool) {
+        if authentic {
+            self.flags |= 1 << 5;
+        } else {
+            self.flags &= !(1 << 5);
+        }
+    }
+
+    // Check the CD (checking disabled) bit: the client does its own DNSSEC
+    // validation and wants the data even if it doesn't validate (RFC 4035)
+    pub fn checking_disabled(&self) -> bool {
+        (self.flags >> 4) & 0x1 == 1
+    }
+
+    // Set or clear the CD (checking disabled) bit
+    pub fn set_checking_disabled(&mut self, disabled: bool) {
+        if disabled {
+            self.flags |= 1 << 4;
+        } else {
+            self.flags &= !(1 << 4);
+        }
+    }
+
+    // Clear the reserved Z bit. RFC 1035 reserved three bits, but two of them are
+    // now the AD and CD bits (RFC 4035) and are left alone.
+    pub fn clear_reserved_bits(&mut self) {
+        self.flags &= !(1 << 6);
+    }
+
     // Parse header from bytes
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DnsError> {
         if bytes.len() < 12 {
-            return Err("Header buffer too small");
+            return Err(DnsError::Parse("Header buffer too small"));
         }
 
         // Extract fields from the buffer
@@ -89,3 +198,45 @@ impl DnsHeader {
         bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_query_flags() {
+        let header = DnsHeader::new_query(0xBEEF, true, 1);
+        assert_eq!(header.id, 0xBEEF);
+        assert_eq!(header.flags & 0x8000, 0); // QR: a query
+        assert_eq!(header.opcode(), 0);
+        assert!(header.recursion_desired());
+        assert!(!header.recursion_available());
+        assert_eq!(header.rcode(), 0);
+        assert_eq!(
+            (
+                header.qdcount,
+                header.ancount,
+                header.nscount,
+                header.arcount
+            ),
+            (1, 0, 0, 0)
+        );
+
+        let header = DnsHeader::new_query(1, false, 2);
+        assert_eq!(header.flags, 0);
+        assert!(!header.recursion_desired());
+        assert_eq!(header.qdcount, 2);
+    }
+
+    #[test]
+    fn clearing_reserved_bits_keeps_ad_and_cd() {
+        let mut header = DnsHeader::new_query(1, true, 1);
+        header.flags |= 0x0070; // Z, AD and CD
+        header.clear_reserved_bits();
+
+        assert_eq!(header.flags & 0x0040, 0);
+        assert!(header.authentic_data());
+        assert!(header.checking_disabled());
+        assert!(header.recursion_desired());
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Cheap randomness from the standard library's randomly keyed hasher
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Where transaction IDs for outgoing queries come from
+pub trait IdSource: Send + Sync {
+    fn next_id(&self) -> u16;
+}
+
+// Unpredictable IDs, so an off-path attacker can't guess which ID to spoof
+pub struct RandomIds;
+
+impl IdSource for RandomIds {
+    fn next_id(&self) -> u16 {
+        random_u64() as u16
+    }
+}
+
+// A fixed sequence of IDs, repeated once exhausted, for deterministic tests
+pub struct FixedIds {
+    ids: Vec<u16>,
+    next: AtomicUsize,
+}
+
+impl FixedIds {
+    pub fn new(ids: Vec<u16>) -> Self {
+        assert!(!ids.is_empty(), "FixedIds needs at least one ID");
+        FixedIds {
+            ids,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl IdSource for FixedIds {
+    fn next_id(&self) -> u16 {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        self.ids[index % self.ids.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::DnsMessage;
+
+    #[test]
+    fn fixed_ids_repeat_their_sequence() {
+        let ids = FixedIds::new(vec![7, 8, 9]);
+        let sequence: Vec<u16> = (0..7).map(|_| ids.next_id()).collect();
+        assert_eq!(sequence, [7, 8, 9, 7, 8, 9, 7]);
+    }
+
+    #[test]
+    fn queries_take_their_ids_from_the_source() {
+        let ids = FixedIds::new(vec![0xAAAA, 0xBBBB]);
+        assert_eq!(DnsMessage::query(&ids, "a.test", 1).header.id, 0xAAAA);
+        assert_eq!(DnsMessage::query(&ids, "b.test", 1).header.id, 0xBBBB);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one ID")]
+    fn fixed_ids_need_an_id() {
+        FixedIds::new(Vec::new());
+    }
+}
@@ -1,13 +1,26 @@
+use super::dns_error::DnsError;
 use bytes::{BufMut, BytesMut};
 use std::str;
 
-#[derive(Clone)]
+// Longest a name may be on the wire, length bytes and final null included (RFC 1035)
+const MAX_NAME_LENGTH: usize = 255;
+
+// Compression pointers followed within one name before it's taken for a loop
+const MAX_JUMPS: usize = 10;
+
+#[derive(Clone, Debug)]
 pub struct DnsQuestion {
     pub name: Vec<u8>,
     pub record_type: u16,
     pub class: u16,
 }
 
+impl Default for DnsQuestion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DnsQuestion {
     // Create a default question for codecrafters.io
     pub fn new() -> Self {
@@ -18,8 +31,52 @@ impl DnsQuestion {
         }
     }
 
-    // Encode a domain name according to DNS protocol
+    // Create a question for any name, rejecting names that can't be encoded
+    pub fn new_for(name: &str, record_type: u16, class: u16) -> Result<Self, DnsError> {
+        Ok(DnsQuestion {
+            name: Self::encode_checked_domain_name(name)?,
+            record_type,
+            class,
+        })
+    }
+
+    // Encode a domain name, checking label and total lengths (RFC 1035 2.3.4)
+    // and refusing empty labels such as in "a..b"
+    pub fn encode_checked_domain_name(domain: &str) -> Result<Vec<u8>, DnsError> {
+        let trimmed = domain.strip_suffix('.').unwrap_or(domain);
+
+        if !trimmed.is_empty() {
+            for label in trimmed.split('.') {
+                if label.is_empty() {
+                    return Err(DnsError::InvalidName(format!("empty label in {}", domain)));
+                }
+                if label.len() > 63 {
+                    return Err(DnsError::InvalidName(format!(
+                        "label longer than 63 bytes in {}",
+                        domain
+                    )));
+                }
+            }
+        }
+
+        let encoded = Self::encode_domain_name(trimmed);
+        if encoded.len() > MAX_NAME_LENGTH {
+            return Err(DnsError::InvalidName(format!(
+                "name longer than 255 bytes: {}",
+                domain
+            )));
+        }
+
+        Ok(encoded)
+    }
+
+    // Encode a domain name according to DNS protocol. The root ("" or ".") is
+    // the lone terminating zero byte.
     pub fn encode_domain_name(domain: &str) -> Vec<u8> {
+        if domain.is_empty() || domain == "." {
+            return vec![0];
+        }
+
         let mut encoded = Vec::new();
 
         for part in domain.split('.') {
@@ -35,10 +92,28 @@ impl DnsQuestion {
         encoded
     }
 
+    // Lowercase the ASCII letters in each label of an encoded name, leaving the
+    // length bytes alone. Stops at a compression pointer or a malformed label.
+    pub fn lowercase_domain_name(name: &[u8]) -> Vec<u8> {
+        let mut lowercased = name.to_vec();
+
+        let mut position = 0;
+        while let Some(&length) = lowercased.get(position) {
+            if length == 0 || length > 63 {
+                break;
+            }
+            let end = (position + 1 + length as usize).min(lowercased.len());
+            lowercased[position + 1..end].make_ascii_lowercase();
+            position = end;
+        }
+
+        lowercased
+    }
+
     // Parse a DNS question from bytes, returns the question and the number of bytes consumed
-    pub fn from_bytes(bytes: &[u8], start_pos: usize) -> Result<(Self, usize), &'static str> {
+    pub fn from_bytes(bytes: &[u8], start_pos: usize) -> Result<(Self, usize), DnsError> {
         if bytes.len() <= start_pos {
-            return Err("Buffer too small for question section");
+            return Err(DnsError::Parse("Buffer too small for question section"));
         }
 
         // Parse the domain name
@@ -47,7 +122,9 @@ impl DnsQuestion {
         // Make sure we have enough bytes for the record type and class (4 bytes)
         let next_pos = start_pos + bytes_consumed;
         if bytes.len() < next_pos + 4 {
-            return Err("Buffer too small for question record type and class");
+            return Err(DnsError::Parse(
+                "Buffer too small for question record type and class",
+            ));
         }
 
         // Parse record type and class
@@ -72,13 +149,15 @@ impl DnsQuestion {
     // When the two high bits of a length byte are set (11xxxxxx), it's a pointer to
     // another location in the packet where the rest of the name can be found.
     // The offset is encoded in the lower 14 bits of the two-byte pointer.
-    fn parse_domain_name(bytes: &[u8], start_pos: usize) -> Result<(Vec<u8>, usize), &'static str> {
+    fn parse_domain_name(bytes: &[u8], start_pos: usize) -> Result<(Vec<u8>, usize), DnsError> {
+        // Measure before copying, so pointer chains can't make us build oversized names
+        Self::name_expanded_len(bytes, start_pos)?;
+
         let mut position = start_pos;
         let mut name = Vec::new();
 
         // To detect compression loops
         let mut jumps = 0;
-        const MAX_JUMPS: usize = 10; // Prevent infinite loops due to malformed packets
 
         // For calculating bytes consumed
         #[allow(unused_assignments)]
@@ -89,7 +168,7 @@ impl DnsQuestion {
         loop {
             // Check for buffer overflow
             if position >= bytes.len() {
-                return Err("Unexpected end of domain name");
+                return Err(DnsError::Parse("Unexpected end of domain name"));
             }
 
             let length = bytes[position];
@@ -114,7 +193,7 @@ impl DnsQuestion {
             if (length & 0xC0) == 0xC0 {
                 // It's a pointer - calculate the offset
                 if position + 1 >= bytes.len() {
-                    return Err("Incomplete compression pointer");
+                    return Err(DnsError::Parse("Incomplete compression pointer"));
                 }
 
                 // If this is our first compression pointer, record the position
@@ -126,24 +205,36 @@ impl DnsQuestion {
                 // The offset is the lower 14 bits of the two bytes
                 let offset = (((length & 0x3F) as usize) << 8) | (bytes[position + 1] as usize);
 
+                // Pointers must refer to an earlier part of the message
+                if offset >= position {
+                    return Err(DnsError::InvalidCompressionPointer(offset));
+                }
+
                 // Jump to the new position
                 position = offset;
 
                 // Prevent infinite loops
                 jumps += 1;
                 if jumps > MAX_JUMPS {
-                    return Err("Too many compression pointers, possible loop");
+                    return Err(DnsError::Parse(
+                        "Too many compression pointers, possible loop",
+                    ));
                 }
 
                 continue;
             }
 
+            // 01xxxxxx and 10xxxxxx are reserved label types, not lengths
+            if (length & 0xC0) != 0 {
+                return Err(DnsError::InvalidLabel(length));
+            }
+
             // Regular label
             name.push(length);
 
             // Check for buffer overflow
             if position + 1 + length as usize > bytes.len() {
-                return Err("Domain name exceeds buffer size");
+                return Err(DnsError::Parse("Domain name exceeds buffer size"));
             }
 
             // Copy the label
@@ -156,13 +247,68 @@ impl DnsQuestion {
         Ok((name, bytes_consumed))
     }
 
+    // The length of the name at `start_pos` with its compression pointers
+    // expanded, counted without building it: labels, their length bytes and the
+    // final null. Fails as soon as the count passes 255 bytes.
+    pub fn name_expanded_len(bytes: &[u8], start_pos: usize) -> Result<usize, DnsError> {
+        let mut position = start_pos;
+        let mut length = 0;
+        let mut jumps = 0;
+
+        loop {
+            let label = *bytes
+                .get(position)
+                .ok_or(DnsError::Parse("Unexpected end of domain name"))?;
+
+            match label & 0xC0 {
+                0xC0 => {
+                    let low = *bytes
+                        .get(position + 1)
+                        .ok_or(DnsError::Parse("Incomplete compression pointer"))?;
+                    let offset = (((label & 0x3F) as usize) << 8) | low as usize;
+                    if offset >= position {
+                        return Err(DnsError::InvalidCompressionPointer(offset));
+                    }
+
+                    jumps += 1;
+                    if jumps > MAX_JUMPS {
+                        return Err(DnsError::Parse(
+                            "Too many compression pointers, possible loop",
+                        ));
+                    }
+                    position = offset;
+                }
+                0x00 => {
+                    length += 1 + label as usize;
+                    if length > MAX_NAME_LENGTH {
+                        return Err(DnsError::NameTooLong);
+                    }
+                    if label == 0 {
+                        return Ok(length);
+                    }
+                    if position + 1 + label as usize > bytes.len() {
+                        return Err(DnsError::Parse("Domain name exceeds buffer size"));
+                    }
+                    position += 1 + label as usize;
+                }
+                _ => return Err(DnsError::InvalidLabel(label)),
+            }
+        }
+    }
+
     // For debugging: decode the domain name to a human-readable form
-    pub fn decode_name(&self) -> Result<String, &'static str> {
+    pub fn decode_name(&self) -> Result<String, DnsError> {
+        Self::decode_domain_name(&self.name)
+    }
+
+    // Decode an encoded (uncompressed) domain name to its dotted form, without a
+    // trailing dot; the root decodes to an empty string
+    pub fn decode_domain_name(name: &[u8]) -> Result<String, DnsError> {
         let mut result = String::new();
         let mut i = 0;
 
-        while i < self.name.len() {
-            let length = self.name[i] as usize;
+        while i < name.len() {
+            let length = name[i] as usize;
             if length == 0 {
                 break; // End of domain name
             }
@@ -171,13 +317,13 @@ impl DnsQuestion {
                 result.push('.');
             }
 
-            if i + 1 + length > self.name.len() {
-                return Err("Invalid domain name encoding");
+            if i + 1 + length > name.len() {
+                return Err(DnsError::Parse("Invalid domain name encoding"));
             }
 
-            match str::from_utf8(&self.name[i + 1..i + 1 + length]) {
+            match str::from_utf8(&name[i + 1..i + 1 + length]) {
                 Ok(label) => result.push_str(label),
-                Err(_) => return Err("Non-UTF8 domain name label"),
+                Err(_) => return Err(DnsError::Parse("Non-UTF8 domain name label")),
             }
 
             i += 1 + length;
@@ -201,10 +347,183 @@ impl DnsQuestion {
     }
 
     // Static method to parse a domain name from a given position
-    pub fn parse_name_from(
-        bytes: &[u8],
-        start_pos: usize,
-    ) -> Result<(Vec<u8>, usize), &'static str> {
+    pub fn parse_name_from(bytes: &[u8], start_pos: usize) -> Result<(Vec<u8>, usize), DnsError> {
         Self::parse_domain_name(bytes, start_pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A question section: the given name bytes, then type A and class IN
+    fn question_bytes(name: &[u8]) -> Vec<u8> {
+        let mut bytes = name.to_vec();
+        bytes.extend_from_slice(&[0, 1, 0, 1]);
+        bytes
+    }
+
+    #[test]
+    fn reserved_label_types_are_rejected() {
+        for length in [0x40, 0x80, 0xBF] {
+            let mut name = vec![length];
+            name.extend(std::iter::repeat(b'a').take(200));
+            name.push(0);
+
+            let result = DnsQuestion::from_bytes(&question_bytes(&name), 0);
+            assert!(
+                matches!(result, Err(DnsError::InvalidLabel(label)) if label == length),
+                "{:#x} was accepted",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn longest_label_is_accepted() {
+        let domain = format!("{}.test", "a".repeat(63));
+        let bytes = question_bytes(&DnsQuestion::encode_domain_name(&domain));
+
+        let (question, consumed) = DnsQuestion::from_bytes(&bytes, 0).unwrap();
+        assert_eq!(consumed, bytes.len());
+        assert_eq!(question.decode_name().unwrap(), domain);
+    }
+
+    #[test]
+    fn pointers_must_point_backward() {
+        // A pointer to its own offset, and one to just past itself
+        for offset in [0x00, 0x02] {
+            let bytes = question_bytes(&[0xC0, offset]);
+            let result = DnsQuestion::from_bytes(&bytes, 0);
+            assert!(
+                matches!(result, Err(DnsError::InvalidCompressionPointer(o)) if o == offset as usize),
+                "pointer to {} was accepted",
+                offset
+            );
+        }
+    }
+
+    #[test]
+    fn backward_pointers_are_followed() {
+        // `example.test` at offset 0, then a name that is `www` plus a pointer to it
+        let mut bytes = DnsQuestion::encode_domain_name("example.test");
+        let start = bytes.len();
+        bytes.extend_from_slice(b"\x03www\xC0\x00");
+        bytes.extend_from_slice(&[0, 1, 0, 1]);
+
+        let (question, consumed) = DnsQuestion::from_bytes(&bytes, start).unwrap();
+        assert_eq!(question.decode_name().unwrap(), "www.example.test");
+        assert_eq!(consumed, 6 + 4);
+    }
+
+    // Three 63-byte labels at offset 0 (193 bytes expanded), then a name made of
+    // one label of `length` bytes and a pointer back to them
+    fn long_compressed_name(length: usize) -> (Vec<u8>, usize) {
+        let label = "a".repeat(63);
+        let mut bytes = DnsQuestion::encode_domain_name(&format!("{label}.{label}.{label}"));
+        let start = bytes.len();
+        bytes.push(length as u8);
+        bytes.extend(std::iter::repeat(b'b').take(length));
+        bytes.extend_from_slice(&[0xC0, 0x00]);
+        (question_bytes(&bytes), start)
+    }
+
+    #[test]
+    fn expanded_length_follows_pointers() {
+        let mut bytes = DnsQuestion::encode_domain_name("example.test");
+        let start = bytes.len();
+        bytes.extend_from_slice(b"\x03www\xC0\x00");
+
+        assert_eq!(DnsQuestion::name_expanded_len(&bytes, 0).unwrap(), 14);
+        assert_eq!(DnsQuestion::name_expanded_len(&bytes, start).unwrap(), 18);
+    }
+
+    #[test]
+    fn names_expanding_to_255_bytes_are_accepted() {
+        let (bytes, start) = long_compressed_name(61);
+        assert_eq!(DnsQuestion::name_expanded_len(&bytes, start).unwrap(), 255);
+
+        let (question, _) = DnsQuestion::from_bytes(&bytes, start).unwrap();
+        assert_eq!(question.name.len(), 255);
+    }
+
+    #[test]
+    fn names_expanding_past_255_bytes_are_rejected() {
+        let (bytes, start) = long_compressed_name(62);
+        assert!(matches!(
+            DnsQuestion::name_expanded_len(&bytes, start),
+            Err(DnsError::NameTooLong)
+        ));
+        assert!(matches!(
+            DnsQuestion::from_bytes(&bytes, start),
+            Err(DnsError::NameTooLong)
+        ));
+    }
+
+    #[test]
+    fn new_for_encodes_the_name() {
+        let question = DnsQuestion::new_for("example.com", 28, 1).unwrap();
+        assert_eq!(question.name, b"\x07example\x03com\x00");
+        assert_eq!((question.record_type, question.class), (28, 1));
+
+        // A trailing dot makes no difference, and the root is a lone null byte
+        assert_eq!(
+            DnsQuestion::new_for("example.com.", 28, 1).unwrap().name,
+            question.name
+        );
+        assert_eq!(DnsQuestion::new_for(".", 2, 1).unwrap().name, vec![0]);
+    }
+
+    #[test]
+    fn new_for_rejects_unencodable_names() {
+        let long_label = format!("{}.com", "a".repeat(64));
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        for name in ["a..b", ".a", long_label.as_str(), long_name.as_str()] {
+            assert!(
+                matches!(
+                    DnsQuestion::new_for(name, 1, 1),
+                    Err(DnsError::InvalidName(_))
+                ),
+                "{} was accepted",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn names_lowercase_label_by_label() {
+        let name = DnsQuestion::encode_domain_name("WwW.Example-1.TEST");
+        assert_eq!(
+            DnsQuestion::lowercase_domain_name(&name),
+            DnsQuestion::encode_domain_name("www.example-1.test")
+        );
+
+        // Lowercasing stops at a pointer rather than treating it as a label
+        let pointer = [3, b'W', b'W', b'W', 0xC0, b'X'];
+        assert_eq!(
+            DnsQuestion::lowercase_domain_name(&pointer),
+            [3, b'w', b'w', b'w', 0xC0, b'X']
+        );
+    }
+
+    #[test]
+    fn root_names_encode_to_a_single_zero() {
+        assert_eq!(DnsQuestion::encode_domain_name(""), [0]);
+        assert_eq!(DnsQuestion::encode_domain_name("."), [0]);
+        assert_eq!(DnsQuestion::encode_checked_domain_name(".").unwrap(), [0]);
+        assert_eq!(DnsQuestion::decode_domain_name(&[0]).unwrap(), "");
+    }
+
+    #[test]
+    fn root_questions_round_trip() {
+        let bytes = question_bytes(&[0]);
+        let (question, size) = DnsQuestion::from_bytes(&bytes, 0).unwrap();
+        assert_eq!(size, 5);
+        assert_eq!(question.name, [0]);
+        assert_eq!(question.decode_name().unwrap(), "");
+        assert_eq!(question.to_bytes()[..], bytes[..]);
+
+        let built = DnsQuestion::new_for(".", 2, 1).unwrap();
+        assert_eq!(built.to_bytes()[..], [0, 0, 2, 0, 1]);
+    }
+}
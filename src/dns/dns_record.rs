@@ -1,13 +1,30 @@
+use super::dns_error::DnsError;
+use super::dns_question::DnsQuestion;
 use bytes::{BufMut, BytesMut};
-use std::net::Ipv4Addr;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+// TTL given to zone records that don't specify one
+pub const DEFAULT_ZONE_TTL: u32 = 300;
+
+// Type code for ANAME zone records, from the private use range (RFC 6895): ANAME
+// was never assigned one, and these records are only ever resolved, not served
+pub const ANAME_TYPE: u16 = 65305;
+
+// Type code for NSEC records, the DNSSEC proof that a name or type doesn't exist
+pub const NSEC_TYPE: u16 = 47;
 
 // Structure for DNS Resource Records (answers)
+#[derive(Clone, Debug)]
 pub struct DnsRecord {
-    pub name: Vec<u8>,    // Domain name this record refers to
-    pub record_type: u16, // Type of record (1 = A, 28 = AAAA, etc.)
-    pub class: u16,       // Class of the record (1 = IN for Internet)
-    pub ttl: u32,         // Time to live in seconds
-    pub rdata: Vec<u8>,   // Record data (depends on type)
+    pub name: Vec<u8>,                // Domain name this record refers to
+    pub record_type: u16,             // Type of record (1 = A, 28 = AAAA, etc.)
+    pub class: u16,                   // Class of the record (1 = IN for Internet)
+    pub ttl: u32,                     // Time to live in seconds
+    pub rdata: Vec<u8>,               // Record data (depends on type)
+    pub received_at: Option<Instant>, // When the TTL started counting down (never serialized)
 }
 
 impl DnsRecord {
@@ -22,15 +39,192 @@ impl DnsRecord {
             class: 1,       // IN (Internet)
             ttl: 60,        // 60 seconds TTL
             rdata: ip_bytes,
+            received_at: None,
+        }
+    }
+
+    // Create a new AAAA record (IPv6 address) for a domain
+    pub fn new_aaaa(domain_name: Vec<u8>, ipv6: Ipv6Addr) -> Self {
+        DnsRecord {
+            name: domain_name,
+            record_type: 28, // AAAA record
+            class: 1,        // IN (Internet)
+            ttl: 60,         // 60 seconds TTL
+            rdata: ipv6.octets().to_vec(),
+            received_at: None,
+        }
+    }
+
+    // Create a CNAME record pointing at an encoded target name
+    pub fn new_cname(domain_name: Vec<u8>, target: Vec<u8>) -> Self {
+        DnsRecord {
+            name: domain_name,
+            record_type: 5, // CNAME record
+            class: 1,       // IN (Internet)
+            ttl: 60,        // 60 seconds TTL
+            rdata: target,
+            received_at: None,
+        }
+    }
+
+    // Create a PTR record naming the host behind a reverse-lookup name
+    pub fn new_ptr(domain_name: Vec<u8>, target: Vec<u8>) -> Self {
+        DnsRecord {
+            name: domain_name,
+            record_type: 12, // PTR record
+            class: 1,        // IN (Internet)
+            ttl: 60,         // 60 seconds TTL
+            rdata: target,
+            received_at: None,
+        }
+    }
+
+    // Create a DNAME record redirecting the whole subtree below the owner to an encoded target name
+    pub fn new_dname(domain_name: Vec<u8>, target: Vec<u8>) -> Self {
+        DnsRecord {
+            name: domain_name,
+            record_type: 39, // DNAME record
+            class: 1,        // IN (Internet)
+            ttl: 60,         // 60 seconds TTL
+            rdata: target,
+            received_at: None,
+        }
+    }
+
+    // Decode the target of a DNAME record
+    pub fn decode_dname(&self) -> Result<String, DnsError> {
+        if self.record_type != 39 {
+            return Err(DnsError::Parse("Not a DNAME record"));
+        }
+
+        DnsQuestion::decode_domain_name(&self.rdata)
+    }
+
+    // Create a TXT record; text longer than 255 bytes is split into several character-strings
+    pub fn new_txt(domain_name: Vec<u8>, class: u16, ttl: u32, text: &str) -> Self {
+        let mut rdata = Vec::new();
+
+        for chunk in text.as_bytes().chunks(255) {
+            rdata.push(chunk.len() as u8);
+            rdata.extend_from_slice(chunk);
+        }
+
+        // An empty TXT record still holds one (empty) character-string
+        if rdata.is_empty() {
+            rdata.push(0);
+        }
+
+        DnsRecord {
+            name: domain_name,
+            record_type: 16, // TXT record
+            class,
+            ttl,
+            rdata,
+            received_at: None,
+        }
+    }
+
+    // Create an SPF record (type 99); its data is laid out exactly like TXT
+    pub fn new_spf(domain_name: Vec<u8>, class: u16, ttl: u32, text: &str) -> Self {
+        DnsRecord {
+            record_type: 99, // SPF record
+            ..Self::new_txt(domain_name, class, ttl, text)
+        }
+    }
+
+    // Decode the policy text of an SPF record. Long policies are split over
+    // several character-strings, which are joined back together.
+    pub fn decode_spf(&self) -> Result<String, DnsError> {
+        if self.record_type != 99 {
+            return Err(DnsError::Parse("Not an SPF record"));
+        }
+
+        let mut text = Vec::new();
+        let mut i = 0;
+        while i < self.rdata.len() {
+            let length = self.rdata[i] as usize;
+            if i + 1 + length > self.rdata.len() {
+                return Err(DnsError::Parse("Character-string exceeds record data"));
+            }
+            text.extend_from_slice(&self.rdata[i + 1..i + 1 + length]);
+            i += 1 + length;
+        }
+
+        String::from_utf8(text).map_err(|_| DnsError::Parse("Non-UTF8 SPF text"))
+    }
+
+    // Create a TLSA record (type 52): certificate usage, selector, matching type,
+    // then the certificate association data (usually a hash)
+    pub fn new_tlsa(
+        domain_name: Vec<u8>,
+        class: u16,
+        ttl: u32,
+        usage: u8,
+        selector: u8,
+        matching_type: u8,
+        data: &[u8],
+    ) -> Self {
+        let mut rdata = vec![usage, selector, matching_type];
+        rdata.extend_from_slice(data);
+
+        DnsRecord {
+            name: domain_name,
+            record_type: 52, // TLSA record
+            class,
+            ttl,
+            rdata,
+            received_at: None,
+        }
+    }
+
+    // Create an SMIMEA record (type 53); its data is laid out exactly like TLSA
+    pub fn new_smimea(
+        domain_name: Vec<u8>,
+        class: u16,
+        ttl: u32,
+        usage: u8,
+        selector: u8,
+        matching_type: u8,
+        data: &[u8],
+    ) -> Self {
+        DnsRecord {
+            record_type: 53, // SMIMEA record
+            ..Self::new_tlsa(
+                domain_name,
+                class,
+                ttl,
+                usage,
+                selector,
+                matching_type,
+                data,
+            )
+        }
+    }
+
+    // Create an OPENPGPKEY record (type 61) holding a binary OpenPGP public key
+    pub fn new_openpgpkey(domain_name: Vec<u8>, class: u16, ttl: u32, key: &[u8]) -> Self {
+        DnsRecord {
+            name: domain_name,
+            record_type: 61, // OPENPGPKEY record
+            class,
+            ttl,
+            rdata: key.to_vec(),
+            received_at: None,
         }
     }
 
     // Serialize the record to bytes
     pub fn to_bytes(&self) -> BytesMut {
+        self.to_bytes_with_name(&self.name)
+    }
+
+    // Serialize the record using the given encoding of its owner name
+    // (e.g. a compression pointer instead of the full name)
+    pub fn to_bytes_with_name(&self, encoded_name: &[u8]) -> BytesMut {
         let mut bytes = BytesMut::new();
 
         // Put the domain name this record refers to
-        bytes.extend_from_slice(&self.name);
+        bytes.extend_from_slice(encoded_name);
 
         // Put record type, class, TTL
         bytes.put_u16(self.record_type);
@@ -46,15 +240,68 @@ impl DnsRecord {
         bytes
     }
 
+    // Where the names are in a type's record data: the fixed bytes before them
+    // and how many follow. None for types without names, whose data is copied
+    // as is, including TLSA, SMIMEA and OPENPGPKEY whose opaque data must never
+    // be decompressed.
+    fn rdata_names(record_type: u16) -> Option<(usize, usize)> {
+        match record_type {
+            2 | 5 | 12 | 39 => Some((0, 1)), // NS, CNAME, PTR, DNAME
+            15 => Some((2, 1)),              // MX: preference, exchange
+            33 => Some((6, 1)),              // SRV: priority, weight, port, target
+            6 => Some((0, 2)),               // SOA: mname, rname, then five numbers
+            _ => None,
+        }
+    }
+
+    // Whether the record data holds names, expanded when the record was parsed
+    pub fn rdata_has_names(&self) -> bool {
+        Self::rdata_names(self.record_type).is_some()
+    }
+
+    // Copy record data, expanding compressed names inside it for the types that
+    // carry names. A pointer only makes sense in the message it came from, and we
+    // re-serialize records at different offsets. Other types are copied verbatim.
+    fn expand_rdata(
+        bytes: &[u8],
+        record_type: u16,
+        start: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, DnsError> {
+        let end = start + length;
+
+        let Some((prefix, name_count)) = Self::rdata_names(record_type) else {
+            return Ok(bytes[start..end].to_vec());
+        };
+
+        if start + prefix > end {
+            return Err(DnsError::Parse("Record data too short for its type"));
+        }
+
+        let mut rdata = bytes[start..start + prefix].to_vec();
+        let mut position = start + prefix;
+
+        for _ in 0..name_count {
+            let (name, consumed) = DnsQuestion::parse_name_from(bytes, position)?;
+            position += consumed;
+            if position > end {
+                return Err(DnsError::Parse("Name in record data runs past RDLENGTH"));
+            }
+            rdata.extend_from_slice(&name);
+        }
+
+        rdata.extend_from_slice(&bytes[position..end]);
+        Ok(rdata)
+    }
+
     // Parse a DNS record from bytes
-    pub fn from_bytes(bytes: &[u8], start_pos: usize) -> Result<(Self, usize), &'static str> {
+    pub fn from_bytes(bytes: &[u8], start_pos: usize) -> Result<(Self, usize), DnsError> {
         if bytes.len() <= start_pos {
-            return Err("Buffer too small for record");
+            return Err(DnsError::Parse("Buffer too small for record"));
         }
 
         // Parse the domain name
-        let (name, name_bytes_consumed) =
-            super::dns_question::DnsQuestion::parse_name_from(bytes, start_pos)?;
+        let (name, name_bytes_consumed) = DnsQuestion::parse_name_from(bytes, start_pos)?;
 
         // Calculate position after the name
         let record_start = start_pos + name_bytes_consumed;
@@ -62,7 +309,7 @@ impl DnsRecord {
         // Make sure we have enough bytes for the fixed part of the record (10 bytes):
         // TYPE (2) + CLASS (2) + TTL (4) + RDLENGTH (2)
         if bytes.len() < record_start + 10 {
-            return Err("Buffer too small for record fields");
+            return Err(DnsError::Parse("Buffer too small for record fields"));
         }
 
         // Parse the record fields
@@ -78,12 +325,13 @@ impl DnsRecord {
 
         // Make sure we have enough bytes for the record data
         if bytes.len() < record_start + 10 + rdlength as usize {
-            return Err("Buffer too small for record data");
+            return Err(DnsError::Parse("Buffer too small for record data"));
         }
 
-        // Extract the record data
-        let mut rdata = Vec::with_capacity(rdlength as usize);
-        rdata.extend_from_slice(&bytes[record_start + 10..record_start + 10 + rdlength as usize]);
+        // Extract the record data. Whatever the type, exactly RDLENGTH bytes belong
+        // to it, so records after an unknown type still parse.
+        let rdata_start = record_start + 10;
+        let rdata = Self::expand_rdata(bytes, record_type, rdata_start, rdlength as usize)?;
 
         // Calculate total bytes consumed
         let total_consumed = name_bytes_consumed + 10 + rdlength as usize;
@@ -95,17 +343,705 @@ impl DnsRecord {
                 class,
                 ttl,
                 rdata,
+                received_at: None,
             },
             total_consumed,
         ))
     }
 
+    // Parse a zone-file line of the form `name [ttl] [class] TYPE rdata` using the default TTL
+    pub fn from_zone_line(name_origin: &str, line: &str) -> Result<Self, DnsError> {
+        Self::from_zone_line_with_ttl(name_origin, line, |_| DEFAULT_ZONE_TTL)
+    }
+
+    // Parse a zone-file line, giving records without an explicit TTL the one
+    // `default_ttl` picks for their type.
+    // `@` stands for the origin, names ending in '.' are absolute and anything else
+    // is relative to the origin.
+    pub fn from_zone_line_with_ttl(
+        name_origin: &str,
+        line: &str,
+        default_ttl: impl Fn(u16) -> u32,
+    ) -> Result<Self, DnsError> {
+        let tokens = Self::tokenize_zone_line(line)?;
+        if tokens.len() < 3 {
+            return Err(DnsError::Zone(format!(
+                "expected `name [ttl] [class] TYPE rdata`, got `{}`",
+                line.trim()
+            )));
+        }
+
+        let name = Self::resolve_zone_name(&tokens[0], name_origin);
+
+        // TTL and class are both optional and may come in either order
+        let mut ttl = None;
+        let mut class = 1; // IN (Internet)
+        let mut index = 1;
+        while index < tokens.len() - 1 {
+            let token = tokens[index].to_ascii_uppercase();
+            if let Ok(value) = token.parse::<u32>() {
+                ttl = Some(value);
+            } else if let Some(value) = Self::class_from_mnemonic(&token) {
+                class = value;
+            } else {
+                break;
+            }
+            index += 1;
+        }
+
+        let record_type = tokens[index].to_ascii_uppercase();
+        let rdata_tokens = &tokens[index + 1..];
+        let expect_fields = |count: usize| {
+            if rdata_tokens.len() == count {
+                Ok(())
+            } else {
+                Err(DnsError::Zone(format!(
+                    "{} record needs {} rdata field(s), got {}",
+                    record_type,
+                    count,
+                    rdata_tokens.len()
+                )))
+            }
+        };
+
+        let (record_type, rdata) = match record_type.as_str() {
+            "A" => {
+                expect_fields(1)?;
+                let ip = rdata_tokens[0].parse::<Ipv4Addr>().map_err(|_| {
+                    DnsError::Zone(format!("invalid IPv4 address {}", rdata_tokens[0]))
+                })?;
+                (1, ip.octets().to_vec())
+            }
+            "AAAA" => {
+                expect_fields(1)?;
+                let ip = rdata_tokens[0].parse::<Ipv6Addr>().map_err(|_| {
+                    DnsError::Zone(format!("invalid IPv6 address {}", rdata_tokens[0]))
+                })?;
+                (28, ip.octets().to_vec())
+            }
+            "NS" | "CNAME" | "PTR" | "DNAME" | "ANAME" => {
+                expect_fields(1)?;
+                let target = Self::resolve_zone_name(&rdata_tokens[0], name_origin);
+                let record_type = match record_type.as_str() {
+                    "NS" => 2,
+                    "CNAME" => 5,
+                    "PTR" => 12,
+                    "DNAME" => 39,
+                    _ => ANAME_TYPE,
+                };
+                (record_type, DnsQuestion::encode_domain_name(&target))
+            }
+            "MX" => {
+                expect_fields(2)?;
+                let preference = Self::parse_zone_number::<u16>(&rdata_tokens[0])?;
+                let exchange = Self::resolve_zone_name(&rdata_tokens[1], name_origin);
+
+                let mut rdata = preference.to_be_bytes().to_vec();
+                rdata.extend(DnsQuestion::encode_domain_name(&exchange));
+                (15, rdata)
+            }
+            "SRV" => {
+                expect_fields(4)?;
+                let mut rdata = Vec::new();
+                for field in &rdata_tokens[..3] {
+                    // Priority, weight and port
+                    rdata.extend(Self::parse_zone_number::<u16>(field)?.to_be_bytes());
+                }
+                let target = Self::resolve_zone_name(&rdata_tokens[3], name_origin);
+                rdata.extend(DnsQuestion::encode_domain_name(&target));
+                (33, rdata)
+            }
+            "SOA" => {
+                expect_fields(7)?;
+                let mname = Self::resolve_zone_name(&rdata_tokens[0], name_origin);
+                let rname = Self::resolve_zone_name(&rdata_tokens[1], name_origin);
+
+                let mut rdata = DnsQuestion::encode_domain_name(&mname);
+                rdata.extend(DnsQuestion::encode_domain_name(&rname));
+                for field in &rdata_tokens[2..] {
+                    // Serial, refresh, retry, expire and minimum
+                    rdata.extend(Self::parse_zone_number::<u32>(field)?.to_be_bytes());
+                }
+                (6, rdata)
+            }
+            "TXT" | "SPF" => {
+                if rdata_tokens.is_empty() {
+                    return Err(DnsError::Zone(format!("{} record needs text", record_type)));
+                }
+                // Each token becomes its own character-string
+                let mut rdata = Vec::new();
+                for token in rdata_tokens {
+                    rdata.extend(Self::new_txt(Vec::new(), class, 0, token).rdata);
+                }
+                (if record_type == "TXT" { 16 } else { 99 }, rdata)
+            }
+            "NSEC" => {
+                if rdata_tokens.is_empty() {
+                    return Err(DnsError::Zone(String::from(
+                        "NSEC record needs a next name",
+                    )));
+                }
+                let next = Self::resolve_zone_name(&rdata_tokens[0], name_origin);
+
+                let mut types = Vec::new();
+                for token in &rdata_tokens[1..] {
+                    let mnemonic = token.to_ascii_uppercase();
+                    types.push(Self::type_from_mnemonic(&mnemonic).ok_or_else(|| {
+                        DnsError::Zone(format!("unknown record type {} in NSEC", token))
+                    })?);
+                }
+
+                let mut rdata = DnsQuestion::encode_domain_name(&next);
+                rdata.extend(Self::encode_type_bitmap(&types));
+                (NSEC_TYPE, rdata)
+            }
+            other => {
+                return Err(DnsError::Zone(format!("unsupported record type {}", other)));
+            }
+        };
+
+        Ok(DnsRecord {
+            name: DnsQuestion::encode_domain_name(&name),
+            record_type,
+            class,
+            ttl: ttl.unwrap_or_else(|| default_ttl(record_type)),
+            rdata,
+            received_at: None,
+        })
+    }
+
+    // Turn a zone-file name into an absolute dotted name without the trailing dot
+    pub fn resolve_zone_name(name: &str, origin: &str) -> String {
+        let origin = origin.trim_end_matches('.');
+
+        if name == "@" {
+            origin.to_string()
+        } else if let Some(absolute) = name.strip_suffix('.') {
+            absolute.to_string()
+        } else if origin.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", name, origin)
+        }
+    }
+
+    // Map a class mnemonic to its numeric value
+    fn class_from_mnemonic(class: &str) -> Option<u16> {
+        match class {
+            "IN" => Some(1),
+            "CH" => Some(3),
+            "HS" => Some(4),
+            _ => None,
+        }
+    }
+
+    // Map a type mnemonic to its numeric value, for the types listed in NSEC
+    // records and per-type settings; `TYPEnnn` (RFC 3597) stands for any type
+    pub fn type_from_mnemonic(record_type: &str) -> Option<u16> {
+        match record_type {
+            "A" => Some(1),
+            "NS" => Some(2),
+            "CNAME" => Some(5),
+            "SOA" => Some(6),
+            "PTR" => Some(12),
+            "MX" => Some(15),
+            "TXT" => Some(16),
+            "AAAA" => Some(28),
+            "SRV" => Some(33),
+            "DNAME" => Some(39),
+            "DS" => Some(43),
+            "RRSIG" => Some(46),
+            "NSEC" => Some(NSEC_TYPE),
+            "DNSKEY" => Some(48),
+            "SPF" => Some(99),
+            _ => record_type.strip_prefix("TYPE")?.parse().ok(),
+        }
+    }
+
+    // The type bitmap of an NSEC record (RFC 4034 section 4.1.2): for each
+    // 256-type window in use, the window number, the bitmap length, and a bitmap
+    // with the most significant bit standing for the window's first type
+    fn encode_type_bitmap(types: &[u16]) -> Vec<u8> {
+        let mut types = types.to_vec();
+        types.sort_unstable();
+        types.dedup();
+
+        let mut bitmap = Vec::new();
+        for window in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+            let mut bits = [0u8; 32];
+            for record_type in window {
+                let low = (record_type & 0xff) as usize;
+                bits[low / 8] |= 0x80 >> (low % 8);
+            }
+            let length = bits.iter().rposition(|&byte| byte != 0).unwrap_or(0) + 1;
+
+            bitmap.push((window[0] >> 8) as u8);
+            bitmap.push(length as u8);
+            bitmap.extend_from_slice(&bits[..length]);
+        }
+        bitmap
+    }
+
+    fn parse_zone_number<T: std::str::FromStr>(token: &str) -> Result<T, DnsError> {
+        token
+            .parse()
+            .map_err(|_| DnsError::Zone(format!("invalid number {}", token)))
+    }
+
+    // Split a zone line on whitespace, keeping "quoted strings" together and
+    // dropping trailing ';' comments
+    fn tokenize_zone_line(line: &str) -> Result<Vec<String>, DnsError> {
+        let mut tokens = Vec::new();
+        let mut chars = line.chars().peekable();
+
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c == ';' {
+                break;
+            } else if c == '"' {
+                chars.next();
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => token.push(c),
+                        None => return Err(DnsError::Zone(String::from("unterminated quote"))),
+                    }
+                }
+                tokens.push(token);
+            } else {
+                let mut token = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == ';' {
+                        break;
+                    }
+                    token.push(c);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    // Seconds of TTL left at `now`, counting from when the record was received.
+    // Records without a receive time keep their full TTL.
+    pub fn remaining_ttl(&self, now: Instant) -> u32 {
+        match self.received_at {
+            Some(received_at) => {
+                let elapsed = now.saturating_duration_since(received_at).as_secs();
+                self.ttl.saturating_sub(elapsed.min(u32::MAX as u64) as u32)
+            }
+            None => self.ttl,
+        }
+    }
+
+    // Whether the record's TTL has run out at `now`
+    pub fn ttl_expired(&self, now: Instant) -> bool {
+        self.received_at.is_some() && self.remaining_ttl(now) == 0
+    }
+
+    // Decode the owner name to its dotted form
+    pub fn decode_name(&self) -> Result<String, DnsError> {
+        DnsQuestion::decode_domain_name(&self.name)
+    }
+
+    // Whether the record is owned by the given encoded name. Names compare ASCII
+    // case-insensitively; parsed names are already expanded, so compression in
+    // the original message doesn't matter.
+    pub fn name_matches(&self, query_name: &[u8]) -> bool {
+        self.name.eq_ignore_ascii_case(query_name)
+    }
+
+    // Interpret the record data as an IPv4 address (A records only)
+    pub fn rdata_as_ipv4(&self) -> Option<Ipv4Addr> {
+        if self.record_type != 1 || self.rdata.len() != 4 {
+            return None;
+        }
+
+        Some(Ipv4Addr::new(
+            self.rdata[0],
+            self.rdata[1],
+            self.rdata[2],
+            self.rdata[3],
+        ))
+    }
+
+    // Interpret the record data as an IPv6 address (AAAA records only)
+    pub fn rdata_as_ipv6(&self) -> Option<Ipv6Addr> {
+        if self.record_type != 28 || self.rdata.len() != 16 {
+            return None;
+        }
+
+        let mut octets = [0; 16];
+        octets.copy_from_slice(&self.rdata);
+        Some(Ipv6Addr::from(octets))
+    }
+
+    // Render the record data in a human-readable form (addresses, text, or hex for anything else)
+    pub fn rdata_to_string(&self) -> String {
+        if let Some(ipv4) = self.rdata_as_ipv4() {
+            return ipv4.to_string();
+        }
+
+        if let Some(ipv6) = self.rdata_as_ipv6() {
+            return ipv6.to_string();
+        }
+
+        match self.record_type {
+            // TXT and SPF records: one or more length-prefixed character-strings
+            16 | 99 => {
+                let mut parts = Vec::new();
+                let mut i = 0;
+                while i < self.rdata.len() {
+                    let length = self.rdata[i] as usize;
+                    let end = (i + 1 + length).min(self.rdata.len());
+                    parts.push(format!(
+                        "\"{}\"",
+                        String::from_utf8_lossy(&self.rdata[i + 1..end])
+                    ));
+                    i = end;
+                }
+                parts.join(" ")
+            }
+            _ => self
+                .rdata
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>(),
+        }
+    }
+
     // Helper to create a record for codecrafters.io pointing to 76.76.21.21
     pub fn default_codecrafters_record() -> Self {
         // Use the same domain name encoding as in the question
-        let domain = super::dns_question::DnsQuestion::encode_domain_name("codecrafters.io");
+        let domain = DnsQuestion::encode_domain_name("codecrafters.io");
         let ip = Ipv4Addr::new(76, 76, 21, 21); // 76.76.21.21 (Expected IP for codecrafters.io)
 
         Self::new(domain, ip)
     }
 }
+
+// Records are equal when they carry the same data: owner name (compared ASCII
+// case-insensitively), type, class and rdata. TTL and age don't count, so the
+// same record seen at different times is still one record (RFC 2181 section 5).
+impl PartialEq for DnsRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.name_matches(&other.name)
+            && self.record_type == other.record_type
+            && self.class == other.class
+            && self.rdata == other.rdata
+    }
+}
+
+impl Eq for DnsRecord {}
+
+impl Hash for DnsRecord {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.to_ascii_lowercase().hash(state);
+        self.record_type.hash(state);
+        self.class.hash(state);
+        self.rdata.hash(state);
+    }
+}
+
+// One-line presentation: owner, TTL, class, type and data
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let owner = self
+            .decode_name()
+            .unwrap_or_else(|_| String::from("<invalid>"));
+        write!(
+            f,
+            "{}.\t{}\tCLASS{}\tTYPE{}\t{}",
+            owner,
+            self.ttl,
+            self.class,
+            self.record_type,
+            self.rdata_to_string()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn name(domain: &str) -> Vec<u8> {
+        DnsQuestion::encode_domain_name(domain)
+    }
+
+    #[test]
+    fn a_records_read_as_ipv4() {
+        let record = DnsRecord::new(name("example.test"), Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(record.rdata_as_ipv4(), Some(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(record.rdata_as_ipv6(), None);
+    }
+
+    #[test]
+    fn aaaa_records_read_as_ipv6() {
+        let address: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let record = DnsRecord::new_aaaa(name("example.test"), address);
+        assert_eq!(record.rdata_as_ipv6(), Some(address));
+        assert_eq!(record.rdata_as_ipv4(), None);
+    }
+
+    #[test]
+    fn wrong_length_rdata_is_not_an_address() {
+        let mut a = DnsRecord::new(name("example.test"), Ipv4Addr::LOCALHOST);
+        a.rdata.push(0);
+        assert_eq!(a.rdata_as_ipv4(), None);
+
+        let mut aaaa = DnsRecord::new_aaaa(name("example.test"), Ipv6Addr::LOCALHOST);
+        aaaa.rdata.pop();
+        assert_eq!(aaaa.rdata_as_ipv6(), None);
+    }
+
+    #[test]
+    fn other_types_are_not_addresses() {
+        // Four bytes of TXT data are not an IPv4 address
+        let txt = DnsRecord::new_txt(name("example.test"), 1, 60, "abc");
+        assert_eq!(txt.rdata.len(), 4);
+        assert_eq!(txt.rdata_as_ipv4(), None);
+
+        let mut a_as_aaaa = DnsRecord::new_aaaa(name("example.test"), Ipv6Addr::LOCALHOST);
+        a_as_aaaa.record_type = 1;
+        assert_eq!(a_as_aaaa.rdata_as_ipv6(), None);
+    }
+
+    #[test]
+    fn zone_line_a_record() {
+        let record = DnsRecord::from_zone_line("example.test", "www 600 IN A 192.0.2.7").unwrap();
+        assert_eq!(record.name, name("www.example.test"));
+        assert_eq!(record.record_type, 1);
+        assert_eq!(record.class, 1);
+        assert_eq!(record.ttl, 600);
+        assert_eq!(record.rdata, vec![192, 0, 2, 7]);
+
+        // TTL and class default when left out
+        let record = DnsRecord::from_zone_line("example.test", "@ A 192.0.2.8").unwrap();
+        assert_eq!(record.name, name("example.test"));
+        assert_eq!(record.ttl, DEFAULT_ZONE_TTL);
+        assert_eq!(record.class, 1);
+    }
+
+    #[test]
+    fn zone_line_cname_with_relative_target() {
+        let record = DnsRecord::from_zone_line("example.test", "alias CNAME www").unwrap();
+        assert_eq!(record.record_type, 5);
+        assert_eq!(record.name, name("alias.example.test"));
+        assert_eq!(record.rdata, name("www.example.test"));
+
+        // A trailing dot makes the target absolute
+        let record = DnsRecord::from_zone_line("example.test", "alias CNAME other.test.").unwrap();
+        assert_eq!(record.rdata, name("other.test"));
+    }
+
+    #[test]
+    fn malformed_zone_lines_are_rejected() {
+        for line in [
+            "www A",
+            "www A 192.0.2",
+            "www A 192.0.2.1 192.0.2.2",
+            "www BOGUS data",
+            "www MX mail",
+        ] {
+            let result = DnsRecord::from_zone_line("example.test", line);
+            assert!(
+                matches!(result, Err(DnsError::Zone(_))),
+                "{} was accepted",
+                line
+            );
+        }
+    }
+
+    fn received(ttl: u32, at: Instant) -> DnsRecord {
+        let mut record = DnsRecord::new(name("example.test"), Ipv4Addr::LOCALHOST);
+        record.ttl = ttl;
+        record.received_at = Some(at);
+        record
+    }
+
+    #[test]
+    fn unexpired_records_count_down() {
+        let now = Instant::now();
+        let record = received(300, now);
+
+        assert!(!record.ttl_expired(now));
+        assert_eq!(record.remaining_ttl(now), 300);
+        assert_eq!(record.remaining_ttl(now + Duration::from_secs(100)), 200);
+        assert!(!record.ttl_expired(now + Duration::from_secs(299)));
+    }
+
+    #[test]
+    fn expired_records() {
+        let now = Instant::now();
+        let record = received(300, now);
+
+        assert!(record.ttl_expired(now + Duration::from_secs(300)));
+        assert_eq!(record.remaining_ttl(now + Duration::from_secs(1000)), 0);
+    }
+
+    #[test]
+    fn records_without_a_receive_time_never_expire() {
+        let now = Instant::now();
+        let record = DnsRecord::new(name("example.test"), Ipv4Addr::LOCALHOST);
+
+        assert!(!record.ttl_expired(now + Duration::from_secs(100_000)));
+        assert_eq!(record.remaining_ttl(now), record.ttl);
+    }
+
+    // Serialize a record on its own and parse it back
+    fn round_trip(record: &DnsRecord) -> DnsRecord {
+        let bytes = record.to_bytes();
+        let (parsed, consumed) = DnsRecord::from_bytes(&bytes, 0).unwrap();
+        assert_eq!(consumed, bytes.len());
+        parsed
+    }
+
+    #[test]
+    fn spf_records_round_trip() {
+        let policy = "v=spf1 ip4:192.0.2.0/24 -all";
+        let record = DnsRecord::new_spf(name("example.test"), 1, 3600, policy);
+        assert_eq!(record.record_type, 99);
+
+        let parsed = round_trip(&record);
+        assert_eq!(parsed.record_type, 99);
+        assert_eq!(parsed.ttl, 3600);
+        assert_eq!(parsed.rdata, record.rdata);
+        assert_eq!(parsed.decode_spf().unwrap(), policy);
+    }
+
+    #[test]
+    fn long_spf_policies_span_character_strings() {
+        let policy = format!("v=spf1 {} -all", "include:spf.example.test ".repeat(20));
+        let record = DnsRecord::new_spf(name("example.test"), 1, 3600, &policy);
+
+        assert!(policy.len() > 255);
+        assert_eq!(record.rdata[0], 255);
+        assert_eq!(round_trip(&record).decode_spf().unwrap(), policy);
+    }
+
+    #[test]
+    fn spf_data_is_the_same_as_txt() {
+        let txt = DnsRecord::new_txt(name("example.test"), 1, 60, "v=spf1 -all");
+        let spf = DnsRecord::new_spf(name("example.test"), 1, 60, "v=spf1 -all");
+        assert_eq!(txt.rdata, spf.rdata);
+        assert!(txt.decode_spf().is_err());
+    }
+
+    #[test]
+    fn tlsa_records_round_trip() {
+        // 3 1 1: a SHA-256 hash of the server's public key
+        let hash: Vec<u8> = (0..32).collect();
+        let record = DnsRecord::new_tlsa(name("_443._tcp.example.test"), 1, 300, 3, 1, 1, &hash);
+
+        let parsed = round_trip(&record);
+        assert_eq!(parsed.record_type, 52);
+        assert_eq!(&parsed.rdata[..3], &[3, 1, 1]);
+        assert_eq!(&parsed.rdata[3..], hash.as_slice());
+        assert_eq!(parsed.rdata, record.rdata);
+    }
+
+    #[test]
+    fn opaque_data_that_looks_like_a_pointer_is_kept() {
+        // Bytes that would read as a compression pointer in a name-bearing type
+        let data = [0xC0, 0x00, 0xC0, 0x0C];
+        let records = [
+            DnsRecord::new_tlsa(name("a.test"), 1, 60, 0xC0, 0x0C, 0, &data),
+            DnsRecord::new_smimea(name("a.test"), 1, 60, 0xC0, 0x0C, 0, &data),
+            DnsRecord::new_openpgpkey(name("a.test"), 1, 60, &data),
+        ];
+
+        for record in records {
+            let parsed = round_trip(&record);
+            assert_eq!(parsed.record_type, record.record_type);
+            assert_eq!(parsed.rdata, record.rdata);
+        }
+    }
+
+    #[test]
+    fn names_match_regardless_of_case() {
+        let record = DnsRecord::new(name("WWW.Example.TEST"), Ipv4Addr::new(192, 0, 2, 1));
+        assert!(record.name_matches(&name("www.example.test")));
+        assert!(record.name_matches(&name("WWW.EXAMPLE.TEST")));
+
+        assert!(!record.name_matches(&name("example.test")));
+        assert!(!record.name_matches(&name("www.example.test.other")));
+        assert!(!record.name_matches(&name("ww.example.test")));
+    }
+
+    fn hash_of(record: &DnsRecord) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        record.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn records_compare_by_data_not_ttl() {
+        let mut first = DnsRecord::new(name("www.example.test"), Ipv4Addr::new(192, 0, 2, 1));
+        first.ttl = 300;
+        let mut second = DnsRecord::new(name("WWW.Example.TEST"), Ipv4Addr::new(192, 0, 2, 1));
+        second.ttl = 60;
+
+        assert_eq!(first, second);
+        assert_eq!(hash_of(&first), hash_of(&second));
+
+        let other_address = DnsRecord::new(name("www.example.test"), Ipv4Addr::new(192, 0, 2, 2));
+        assert_ne!(first, other_address);
+        let mut other_class = first.clone();
+        other_class.class = 3;
+        assert_ne!(first, other_class);
+        let other_type = DnsRecord::new_txt(name("www.example.test"), 1, 300, "abc");
+        assert_ne!(first, other_type);
+    }
+
+    #[test]
+    fn nsec_type_bitmaps_cover_the_listed_types() {
+        let record = DnsRecord::from_zone_line("", "a.test. NSEC b.test. A MX RRSIG NSEC").unwrap();
+        assert_eq!(record.record_type, NSEC_TYPE);
+
+        let mut expected = name("b.test");
+        // Window 0, six bytes: A (1), MX (15), RRSIG (46) and NSEC (47)
+        expected.extend([0, 6, 0x40, 0x01, 0, 0, 0, 0x03]);
+        assert_eq!(record.rdata, expected);
+
+        assert!(DnsRecord::from_zone_line("", "a.test. NSEC").is_err());
+        assert!(DnsRecord::from_zone_line("", "a.test. NSEC b.test. BOGUS").is_err());
+    }
+
+    #[test]
+    fn zone_names_resolve_against_the_origin() {
+        assert_eq!(
+            DnsRecord::resolve_zone_name("@", "example.test."),
+            "example.test"
+        );
+        assert_eq!(
+            DnsRecord::resolve_zone_name("www", "example.test."),
+            "www.example.test"
+        );
+        assert_eq!(
+            DnsRecord::resolve_zone_name("www.other.test.", "example.test."),
+            "www.other.test"
+        );
+        // Without an origin, relative names are taken as they are
+        assert_eq!(
+            DnsRecord::resolve_zone_name("www.example.test", ""),
+            "www.example.test"
+        );
+    }
+
+    #[test]
+    fn rdata_names_are_relative_to_the_origin_too() {
+        let record = DnsRecord::from_zone_line("example.test.", "@ MX 10 mail").unwrap();
+        assert_eq!(record.name, name("example.test"));
+        assert_eq!(record.rdata[2..], name("mail.example.test"));
+
+        let record =
+            DnsRecord::from_zone_line("example.test.", "www CNAME cdn.other.test.").unwrap();
+        assert_eq!(record.rdata, name("cdn.other.test"));
+    }
+}
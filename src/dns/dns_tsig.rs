@@ -0,0 +1,484 @@
+use super::dns_error::DnsError;
+use super::dns_header::DnsHeader;
+use super::dns_question::DnsQuestion;
+use super::dns_record::DnsRecord;
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use bytes::{BufMut, BytesMut};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// TSIG (RFC 8945): transaction signatures with a shared secret. Only
+// HMAC-SHA256 is supported.
+pub const TSIG_TYPE: u16 = 250;
+const TSIG_CLASS: u16 = 255; // ANY
+const ALGORITHM: &str = "hmac-sha256";
+
+// Length of a full HMAC-SHA256 MAC, and of the shortest truncated one we
+// accept: the larger of 10 bytes and half the hash (RFC 8945 section 5.2.2.1)
+const MAC_SIZE: usize = 32;
+const MIN_MAC_SIZE: usize = 16;
+
+// Secrets are standard base64, with or without the trailing padding
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Allowed clock skew between signer and verifier, in seconds
+pub const DEFAULT_FUDGE: u16 = 300;
+
+// TSIG error codes, carried in the TSIG record of an error response
+pub const BADSIG: u16 = 16;
+pub const BADKEY: u16 = 17;
+pub const BADTIME: u16 = 18;
+
+// A named shared secret, configured as `name:base64secret`
+#[derive(Clone)]
+pub struct TsigKey {
+    pub name: Vec<u8>, // Encoded, lowercased key name
+    pub secret: Vec<u8>,
+}
+
+impl TsigKey {
+    pub fn from_arg(value: &str) -> Result<Self, String> {
+        let (name, secret) = value
+            .split_once(':')
+            .ok_or_else(|| format!("Expected name:secret for --tsig-key, got {}", value))?;
+        let secret = BASE64
+            .decode(secret)
+            .map_err(|_| format!("TSIG secret for {} is not valid base64", name))?;
+
+        Ok(TsigKey {
+            name: DnsQuestion::encode_domain_name(name).to_ascii_lowercase(),
+            secret,
+        })
+    }
+}
+
+// Why a request's signature was not accepted
+#[derive(Debug, PartialEq, Eq)]
+pub enum TsigError {
+    Unsigned,  // No TSIG record at all
+    Malformed, // A TSIG record we couldn't parse
+    BadKey,    // Unknown key name or algorithm
+    BadSig,    // The MAC doesn't match
+    BadTime,   // Signed outside the allowed clock skew
+}
+
+impl TsigError {
+    // The TSIG error code to report, if any
+    pub fn code(&self) -> u16 {
+        match self {
+            TsigError::BadKey => BADKEY,
+            TsigError::BadSig => BADSIG,
+            TsigError::BadTime => BADTIME,
+            TsigError::Unsigned | TsigError::Malformed => 0,
+        }
+    }
+}
+
+// The fields of a TSIG record's data
+struct Tsig {
+    key_name: Vec<u8>,
+    algorithm: Vec<u8>,
+    time_signed: u64, // 48-bit UNIX time
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl Tsig {
+    // A TSIG for the given message, with the MAC still to be filled in
+    fn new(message: &[u8], key: &TsigKey, time_signed: u64, error: u16) -> Result<Self, DnsError> {
+        let header = DnsHeader::from_bytes(message)?;
+
+        Ok(Tsig {
+            key_name: key.name.clone(),
+            algorithm: DnsQuestion::encode_domain_name(ALGORITHM),
+            time_signed,
+            fudge: DEFAULT_FUDGE,
+            mac: Vec::new(),
+            original_id: header.id,
+            error,
+            other: Vec::new(),
+        })
+    }
+
+    fn from_record(record: &DnsRecord) -> Result<Self, DnsError> {
+        let rdata = &record.rdata;
+        let (algorithm, mut position) = DnsQuestion::parse_name_from(rdata, 0)?;
+
+        let field = |position: usize, length: usize| {
+            rdata
+                .get(position..position + length)
+                .ok_or(DnsError::Truncated("TSIG record data"))
+        };
+        let read_u16 = |position: usize| -> Result<u16, DnsError> {
+            let bytes = field(position, 2)?;
+            Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+        };
+
+        let time = field(position, 6)?;
+        let time_signed = time
+            .iter()
+            .fold(0u64, |value, &byte| value << 8 | byte as u64);
+        let fudge = read_u16(position + 6)?;
+        let mac_size = read_u16(position + 8)? as usize;
+        let mac = field(position + 10, mac_size)?.to_vec();
+        position += 10 + mac_size;
+
+        let original_id = read_u16(position)?;
+        let error = read_u16(position + 2)?;
+        let other_size = read_u16(position + 4)? as usize;
+        let other = field(position + 6, other_size)?.to_vec();
+
+        Ok(Tsig {
+            key_name: record.name.clone(),
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+
+    fn to_record(&self) -> DnsRecord {
+        let mut rdata = BytesMut::new();
+        rdata.extend_from_slice(&self.algorithm);
+        rdata.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+        rdata.put_u16(self.fudge);
+        rdata.put_u16(self.mac.len() as u16);
+        rdata.extend_from_slice(&self.mac);
+        rdata.put_u16(self.original_id);
+        rdata.put_u16(self.error);
+        rdata.put_u16(self.other.len() as u16);
+        rdata.extend_from_slice(&self.other);
+
+        DnsRecord {
+            name: self.key_name.clone(),
+            record_type: TSIG_TYPE,
+            class: TSIG_CLASS,
+            ttl: 0,
+            rdata: rdata.to_vec(),
+            received_at: None,
+        }
+    }
+
+    // The TSIG variables covered by the MAC, names in canonical (lowercase) form
+    fn variables(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&self.key_name.to_ascii_lowercase());
+        bytes.put_u16(TSIG_CLASS);
+        bytes.put_u32(0); // TTL
+        bytes.extend_from_slice(&self.algorithm.to_ascii_lowercase());
+        bytes.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+        bytes.put_u16(self.fudge);
+        bytes.put_u16(self.error);
+        bytes.put_u16(self.other.len() as u16);
+        bytes.extend_from_slice(&self.other);
+        bytes
+    }
+}
+
+// An HMAC-SHA256 keyed with the shared secret, ready to be fed the signed data
+fn keyed_mac(key: &TsigKey) -> HmacSha256 {
+    HmacSha256::new_from_slice(&key.secret).expect("HMAC accepts keys of any length")
+}
+
+// MAC over an unsigned message, prefixed by the request MAC when signing a
+// response; finalized by the caller to produce or check a MAC
+fn message_mac(
+    key: &TsigKey,
+    request_mac: Option<&[u8]>,
+    message: &[u8],
+    tsig: &Tsig,
+) -> HmacSha256 {
+    let mut mac = keyed_mac(key);
+    if let Some(request_mac) = request_mac {
+        mac.update(&(request_mac.len() as u16).to_be_bytes());
+        mac.update(request_mac);
+    }
+    mac.update(message);
+    mac.update(&tsig.variables());
+    mac
+}
+
+fn compute_mac(key: &TsigKey, request_mac: Option<&[u8]>, message: &[u8], tsig: &Tsig) -> Vec<u8> {
+    message_mac(key, request_mac, message, tsig)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+// Sign a serialized message by appending a TSIG record. Responses pass the MAC
+// of the request they answer; `error` is the TSIG error code to report.
+pub fn sign(
+    message: &[u8],
+    key: &TsigKey,
+    request_mac: Option<&[u8]>,
+    time_signed: u64,
+    error: u16,
+) -> Result<Vec<u8>, DnsError> {
+    let mut tsig = Tsig::new(message, key, time_signed, error)?;
+    tsig.mac = compute_mac(key, request_mac, message, &tsig);
+    Ok(append(message, &tsig))
+}
+
+// Sign the messages of a multi-message response (a zone transfer). The first is
+// signed like any response; each later one covers the previous message's MAC,
+// itself and only the timers (RFC 8945 section 5.3.1).
+pub fn sign_stream(
+    messages: &[BytesMut],
+    key: &TsigKey,
+    request_mac: &[u8],
+    time_signed: u64,
+) -> Result<Vec<Vec<u8>>, DnsError> {
+    let mut signed = Vec::new();
+    let mut prior_mac = request_mac.to_vec();
+
+    for (index, message) in messages.iter().enumerate() {
+        let mut tsig = Tsig::new(message, key, time_signed, 0)?;
+        tsig.mac = if index == 0 {
+            compute_mac(key, Some(&prior_mac), message, &tsig)
+        } else {
+            let mut mac = keyed_mac(key);
+            mac.update(&(prior_mac.len() as u16).to_be_bytes());
+            mac.update(&prior_mac);
+            mac.update(message);
+            mac.update(&tsig.time_signed.to_be_bytes()[2..]);
+            mac.update(&tsig.fudge.to_be_bytes());
+            mac.finalize().into_bytes().to_vec()
+        };
+
+        signed.push(append(message, &tsig));
+        prior_mac = tsig.mac;
+    }
+
+    Ok(signed)
+}
+
+// Append an unsigned TSIG record reporting why a request's signature was
+// rejected (BADKEY, BADSIG): the client can't check a MAC from a key it may not share
+pub fn append_error(
+    message: &[u8],
+    key: &TsigKey,
+    time_signed: u64,
+    error: u16,
+) -> Result<Vec<u8>, DnsError> {
+    Ok(append(
+        message,
+        &Tsig::new(message, key, time_signed, error)?,
+    ))
+}
+
+fn append(message: &[u8], tsig: &Tsig) -> Vec<u8> {
+    let arcount = u16::from_be_bytes([message[10], message[11]]);
+
+    let mut signed = message.to_vec();
+    signed[10..12].copy_from_slice(&(arcount + 1).to_be_bytes());
+    signed.extend_from_slice(&tsig.to_record().to_bytes());
+    signed
+}
+
+// Verify the TSIG record closing a received message against our key and the
+// current UNIX time. On success, returns the request MAC needed to sign the reply.
+pub fn verify(packet: &[u8], key: &TsigKey, now: u64) -> Result<Vec<u8>, TsigError> {
+    let header = DnsHeader::from_bytes(packet).map_err(|_| TsigError::Malformed)?;
+    if header.arcount == 0 {
+        return Err(TsigError::Unsigned);
+    }
+
+    // Walk to the last record of the additional section, where TSIG must be
+    let mut position = 12;
+    for _ in 0..header.qdcount {
+        let (_, consumed) =
+            DnsQuestion::from_bytes(packet, position).map_err(|_| TsigError::Malformed)?;
+        position += consumed;
+    }
+    let records_before =
+        header.ancount as usize + header.nscount as usize + header.arcount as usize - 1;
+    for _ in 0..records_before {
+        let (_, consumed) =
+            DnsRecord::from_bytes(packet, position).map_err(|_| TsigError::Malformed)?;
+        position += consumed;
+    }
+
+    let tsig_start = position;
+    let (record, _) =
+        DnsRecord::from_bytes(packet, tsig_start).map_err(|_| TsigError::Malformed)?;
+    if record.record_type != TSIG_TYPE {
+        return Err(TsigError::Unsigned);
+    }
+    let tsig = Tsig::from_record(&record).map_err(|_| TsigError::Malformed)?;
+
+    if !tsig.key_name.eq_ignore_ascii_case(&key.name)
+        || !tsig
+            .algorithm
+            .eq_ignore_ascii_case(&DnsQuestion::encode_domain_name(ALGORITHM))
+    {
+        return Err(TsigError::BadKey);
+    }
+
+    // The MAC covers the message as it was before the TSIG record was added
+    let mut unsigned = packet[..tsig_start].to_vec();
+    unsigned[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+    unsigned[10..12].copy_from_slice(&(header.arcount - 1).to_be_bytes());
+
+    // A truncated MAC is checked against the same prefix of ours, but too short
+    // a one proves too little to be accepted at all
+    if !(MIN_MAC_SIZE..=MAC_SIZE).contains(&tsig.mac.len()) {
+        return Err(TsigError::Malformed);
+    }
+    message_mac(key, None, &unsigned, &tsig)
+        .verify_truncated_left(&tsig.mac)
+        .map_err(|_| TsigError::BadSig)?;
+
+    if now.abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+        return Err(TsigError::BadTime);
+    }
+
+    Ok(tsig.mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::dns_id::FixedIds;
+    use crate::dns::DnsMessage;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn key() -> TsigKey {
+        // The secret is "0123456789abcdef0123456789abcdef"
+        TsigKey::from_arg("tsig-key:MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap()
+    }
+
+    fn query() -> Vec<u8> {
+        DnsMessage::query(&FixedIds::new(vec![0x1234]), "example.com", 1)
+            .to_bytes()
+            .to_vec()
+    }
+
+    // Sign with the MAC cut down to its first `length` bytes
+    fn sign_truncated(message: &[u8], length: usize) -> Vec<u8> {
+        let mut tsig = Tsig::new(message, &key(), NOW, 0).unwrap();
+        tsig.mac = compute_mac(&key(), None, message, &tsig)[..length].to_vec();
+        append(message, &tsig)
+    }
+
+    #[test]
+    fn mac_matches_rfc_8945_digest() {
+        // HMAC-SHA256 over the message and TSIG variables, computed independently
+        let signed = sign(&query(), &key(), None, NOW, 0).unwrap();
+        let (record, _) = DnsRecord::from_bytes(&signed, query().len()).unwrap();
+        let tsig = Tsig::from_record(&record).unwrap();
+
+        let expected = "8a671d07b593e7f863abaf98dce40aabe6ecc6e8bcaaef60d5db9f4799b1b512";
+        let mac: String = tsig
+            .mac
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        assert_eq!(mac, expected);
+    }
+
+    #[test]
+    fn signed_message_verifies() {
+        let signed = sign(&query(), &key(), None, NOW, 0).unwrap();
+        let request_mac = verify(&signed, &key(), NOW).unwrap();
+        assert_eq!(request_mac.len(), MAC_SIZE);
+    }
+
+    #[test]
+    fn unsigned_message_is_reported_as_such() {
+        assert_eq!(verify(&query(), &key(), NOW), Err(TsigError::Unsigned));
+    }
+
+    #[test]
+    fn tampered_message_is_badsig() {
+        let mut signed = sign(&query(), &key(), None, NOW, 0).unwrap();
+        signed[13] ^= 0x20; // A letter of the question name changes case
+        assert_eq!(verify(&signed, &key(), NOW), Err(TsigError::BadSig));
+    }
+
+    #[test]
+    fn other_secret_is_badsig() {
+        let other = TsigKey::from_arg("tsig-key:c2VjcmV0").unwrap();
+        let signed = sign(&query(), &other, None, NOW, 0).unwrap();
+        assert_eq!(verify(&signed, &key(), NOW), Err(TsigError::BadSig));
+    }
+
+    #[test]
+    fn other_key_name_is_badkey() {
+        let mut other = key();
+        other.name = DnsQuestion::encode_domain_name("other-key");
+        let signed = sign(&query(), &other, None, NOW, 0).unwrap();
+        assert_eq!(verify(&signed, &key(), NOW), Err(TsigError::BadKey));
+    }
+
+    #[test]
+    fn clock_skew_beyond_fudge_is_badtime() {
+        let signed = sign(&query(), &key(), None, NOW, 0).unwrap();
+        let fudge = DEFAULT_FUDGE as u64;
+
+        assert!(verify(&signed, &key(), NOW + fudge).is_ok());
+        assert!(verify(&signed, &key(), NOW - fudge).is_ok());
+        assert_eq!(
+            verify(&signed, &key(), NOW + fudge + 1),
+            Err(TsigError::BadTime)
+        );
+        assert_eq!(
+            verify(&signed, &key(), NOW - fudge - 1),
+            Err(TsigError::BadTime)
+        );
+    }
+
+    #[test]
+    fn bad_signature_is_reported_before_bad_time() {
+        let mut signed = sign(&query(), &key(), None, NOW, 0).unwrap();
+        signed[13] ^= 0x20;
+        assert_eq!(verify(&signed, &key(), NOW + 3600), Err(TsigError::BadSig));
+    }
+
+    #[test]
+    fn truncated_mac_is_checked_against_our_prefix() {
+        let signed = sign_truncated(&query(), MIN_MAC_SIZE);
+        assert!(verify(&signed, &key(), NOW).is_ok());
+
+        let mut tampered = signed.clone();
+        tampered[13] ^= 0x20;
+        assert_eq!(verify(&tampered, &key(), NOW), Err(TsigError::BadSig));
+    }
+
+    #[test]
+    fn too_short_mac_is_malformed() {
+        let signed = sign_truncated(&query(), MIN_MAC_SIZE - 1);
+        assert_eq!(verify(&signed, &key(), NOW), Err(TsigError::Malformed));
+    }
+
+    #[test]
+    fn response_mac_covers_the_request_mac() {
+        let response = query();
+        let first = sign(&response, &key(), Some(&[1; MAC_SIZE]), NOW, 0).unwrap();
+        let second = sign(&response, &key(), Some(&[2; MAC_SIZE]), NOW, 0).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn secrets_decode_with_or_without_padding() {
+        let padded = TsigKey::from_arg("k:c2VjcmV0MQ==").unwrap();
+        let unpadded = TsigKey::from_arg("k:c2VjcmV0MQ").unwrap();
+        assert_eq!(padded.secret, b"secret1");
+        assert_eq!(unpadded.secret, b"secret1");
+        assert!(TsigKey::from_arg("k:not*base64").is_err());
+        assert!(TsigKey::from_arg("no-colon").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// The well-known NAT64 prefix (RFC 6052)
+pub const WELL_KNOWN_PREFIX: &str = "64:ff9b::/96";
+
+// A NAT64 prefix that IPv4 addresses get embedded into to form synthetic
+// IPv6 addresses (DNS64, RFC 6147)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dns64Prefix {
+    pub prefix: Ipv6Addr,
+    pub length: u8,
+}
+
+impl Dns64Prefix {
+    // Parse a prefix such as 64:ff9b::/96. RFC 6052 only allows lengths of
+    // 32, 40, 48, 56, 64 and 96.
+    pub fn from_cidr(cidr: &str) -> Result<Self, String> {
+        let (prefix, length) = match cidr.split_once('/') {
+            Some(parts) => parts,
+            None => return Err(format!("Missing prefix length in {}", cidr)),
+        };
+
+        let prefix: Ipv6Addr = prefix
+            .parse()
+            .map_err(|_| format!("Invalid IPv6 prefix in {}", cidr))?;
+        let length: u8 = length
+            .parse()
+            .map_err(|_| format!("Invalid prefix length in {}", cidr))?;
+
+        if ![32, 40, 48, 56, 64, 96].contains(&length) {
+            return Err(format!(
+                "NAT64 prefix length must be 32, 40, 48, 56, 64 or 96 in {}",
+                cidr
+            ));
+        }
+
+        Ok(Dns64Prefix { prefix, length })
+    }
+
+    // Embed an IPv4 address into the prefix. Bits 64-71 (the "u" octet) must stay
+    // zero, so for shorter prefixes the IPv4 bytes are split around it.
+    pub fn synthesize(&self, ipv4: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.octets();
+        let start = self.length as usize / 8;
+
+        let mut position = start;
+        for byte in ipv4.octets() {
+            if position == 8 {
+                octets[position] = 0;
+                position += 1;
+            }
+            octets[position] = byte;
+            position += 1;
+        }
+
+        // Everything after the embedded address is the zero suffix
+        for octet in octets.iter_mut().skip(position) {
+            *octet = 0;
+        }
+
+        Ipv6Addr::from(octets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn synthesize(prefix: &str, ipv4: &str) -> Ipv6Addr {
+        Dns64Prefix::from_cidr(prefix)
+            .unwrap()
+            .synthesize(ipv4.parse().unwrap())
+    }
+
+    #[test]
+    fn well_known_prefix_embeds_the_last_32_bits() {
+        assert_eq!(
+            synthesize(WELL_KNOWN_PREFIX, "192.0.2.33"),
+            "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn shorter_prefixes_skip_the_u_octet() {
+        // RFC 6052 section 2.4 examples
+        assert_eq!(
+            synthesize("2001:db8::/32", "192.0.2.33"),
+            "2001:db8:c000:221::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            synthesize("2001:db8:100::/40", "192.0.2.33"),
+            "2001:db8:1c0:2:21::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            synthesize("2001:db8:122::/48", "192.0.2.33"),
+            "2001:db8:122:c000:2:2100::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            synthesize("2001:db8:122:300::/56", "192.0.2.33"),
+            "2001:db8:122:3c0:0:221::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            synthesize("2001:db8:122:344::/64", "192.0.2.33"),
+            "2001:db8:122:344:c0:2:2100:0".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_prefixes_are_rejected() {
+        assert!(Dns64Prefix::from_cidr("64:ff9b::").is_err());
+        assert!(Dns64Prefix::from_cidr("64:ff9b::/80").is_err());
+        assert!(Dns64Prefix::from_cidr("192.0.2.0/96").is_err());
+    }
+}
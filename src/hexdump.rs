@@ -0,0 +1,56 @@
+// Format bytes like `hexdump -C`: offset, sixteen hex bytes split into two
+// groups of eight, then the printable ASCII characters ('.' for the rest)
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut output = String::new();
+
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        output.push_str(&format!("{:08x} ", line * 16));
+
+        for column in 0..16 {
+            if column == 8 {
+                output.push(' ');
+            }
+            match chunk.get(column) {
+                Some(byte) => output.push_str(&format!(" {:02x}", byte)),
+                None => output.push_str("   "),
+            }
+        }
+
+        output.push_str("  |");
+        for &byte in chunk {
+            output.push(if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            });
+        }
+        output.push_str("|\n");
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_and_partial_lines() {
+        let bytes: Vec<u8> = b"\x12\x34\x01\x00example!"
+            .iter()
+            .copied()
+            .chain(0..6)
+            .collect();
+
+        assert_eq!(
+            hexdump(&bytes),
+            "00000000  12 34 01 00 65 78 61 6d  70 6c 65 21 00 01 02 03  |.4..example!....|\n\
+             00000010  04 05                                             |..|\n"
+        );
+    }
+
+    #[test]
+    fn empty_input_prints_nothing() {
+        assert_eq!(hexdump(&[]), "");
+    }
+}
@@ -0,0 +1,73 @@
+use crate::dns::dns_error::DnsError;
+use crate::dns::dns_question::DnsQuestion;
+use crate::dns::dns_record::{DnsRecord, DEFAULT_ZONE_TTL};
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+
+// Static name-to-address mappings in /etc/hosts format
+#[derive(Default)]
+pub struct Hosts {
+    addresses: HashMap<Vec<u8>, Vec<IpAddr>>, // Keyed by lowercased encoded name
+}
+
+impl Hosts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: &str) -> Result<Self, DnsError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| DnsError::Zone(format!("cannot read {}: {}", path, e)))?;
+        Self::parse(&text)
+    }
+
+    // Parse lines of `address name [aliases...]`; '#' starts a comment
+    pub fn parse(text: &str) -> Result<Self, DnsError> {
+        let mut hosts = Hosts::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+
+            let address = match fields.next() {
+                Some(address) => address.parse::<IpAddr>().map_err(|_| {
+                    DnsError::Zone(format!("line {}: invalid address {}", index + 1, address))
+                })?,
+                None => continue,
+            };
+
+            for name in fields {
+                let name = DnsQuestion::encode_domain_name(&name.to_ascii_lowercase());
+                hosts.addresses.entry(name).or_default().push(address);
+            }
+        }
+
+        Ok(hosts)
+    }
+
+    // A/AAAA records for a name (IN class only)
+    pub fn lookup(&self, name: &[u8], record_type: u16, class: u16) -> Vec<DnsRecord> {
+        if class != 1 {
+            return Vec::new();
+        }
+
+        let addresses = match self.addresses.get(&name.to_ascii_lowercase()) {
+            Some(addresses) => addresses,
+            None => return Vec::new(),
+        };
+
+        addresses
+            .iter()
+            .filter_map(|address| match (address, record_type) {
+                (IpAddr::V4(ipv4), 1) => Some(DnsRecord::new(name.to_vec(), *ipv4)),
+                (IpAddr::V6(ipv6), 28) => Some(DnsRecord::new_aaaa(name.to_vec(), *ipv6)),
+                _ => None,
+            })
+            .map(|mut record| {
+                record.ttl = DEFAULT_ZONE_TTL;
+                record
+            })
+            .collect()
+    }
+}
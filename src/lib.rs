@@ -0,0 +1,27 @@
+pub mod blocklist;
+pub mod cache;
+pub mod config;
+pub mod config_file;
+#[cfg(unix)]
+pub mod control;
+pub mod cookie;
+pub mod dns;
+pub mod dns64;
+pub mod hexdump;
+pub mod hosts;
+pub mod metrics;
+#[cfg(unix)]
+pub mod privileges;
+pub mod regex;
+pub mod resolution;
+pub mod reverse;
+pub mod rules;
+pub mod semaphore;
+pub mod server;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+pub mod update;
+pub mod upstream;
+pub mod views;
+pub mod zone;
@@ -1,114 +1,418 @@
 use std::env;
-#[allow(unused_imports)]
-use std::net::UdpSocket;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{TcpListener, ToSocketAddrs, UdpSocket};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use dns::dns_header::DnsHeader;
-use dns::DnsMessage;
+#[cfg(unix)]
+use signal_hook::consts::{SIGINT, SIGTERM};
+#[cfg(unix)]
+use signal_hook::iterator::Signals;
 
-mod dns;
+use codecrafters_dns_server::blocklist::Blocklist;
+use codecrafters_dns_server::config::{Config, ResolverProto};
+#[cfg(unix)]
+use codecrafters_dns_server::control;
+use codecrafters_dns_server::dns::dns_error::DnsError;
+use codecrafters_dns_server::dns::dns_header::DnsHeader;
+use codecrafters_dns_server::dns::DnsMessage;
+use codecrafters_dns_server::hexdump::hexdump;
+use codecrafters_dns_server::hosts::Hosts;
+#[cfg(unix)]
+use codecrafters_dns_server::privileges;
+use codecrafters_dns_server::reverse::ReverseZone;
+use codecrafters_dns_server::server::Server;
+use codecrafters_dns_server::transport::{
+    DohTransport, DumpingTransport, TcpTransport, Transport, UdpTransport,
+};
+use codecrafters_dns_server::upstream::UpstreamSelector;
+use codecrafters_dns_server::views::View;
+use codecrafters_dns_server::zone::Zone;
+
+// Bind a query socket, explaining the usual causes when that fails
+fn bind_listener<A, T>(address: A, bind: fn(A) -> io::Result<T>) -> Result<T, String>
+where
+    A: ToSocketAddrs + fmt::Display + Copy,
+{
+    bind(address).map_err(|e| {
+        let hint = match e.kind() {
+            io::ErrorKind::AddrInUse => {
+                "another process is already using this port; stop it or pick a different port"
+            }
+            io::ErrorKind::PermissionDenied => {
+                "ports below 1024 need root (or CAP_NET_BIND_SERVICE); use a higher port or run with privileges"
+            }
+            _ => "check that the address is valid and available on this machine",
+        };
+        format!("Failed to bind to {}: {} ({})", address, e, hint)
+    })
+}
+
+// Build the upstream transport selected on the command line
+fn build_transport(config: &Config) -> Result<Box<dyn Transport>, DnsError> {
+    let mut upstreams: Vec<Box<dyn Transport>> = Vec::new();
+    for resolver in &config.resolvers {
+        upstreams.push(build_upstream(config, resolver)?);
+    }
+
+    // A single upstream needs no selection
+    let transport: Box<dyn Transport> = if upstreams.len() == 1 {
+        upstreams.remove(0)
+    } else {
+        Box::new(UpstreamSelector::new(upstreams, config.upstream_strategy))
+    };
+
+    if config.dump_packets {
+        return Ok(Box::new(DumpingTransport { inner: transport }));
+    }
+
+    Ok(transport)
+}
+
+fn build_upstream(config: &Config, resolver: &str) -> Result<Box<dyn Transport>, DnsError> {
+    let timeout = config.upstream_timeout;
+    match config.resolver_proto {
+        ResolverProto::Udp => Ok(Box::new(UdpTransport::new(resolver).with_timeout(timeout))),
+        ResolverProto::Tcp => Ok(Box::new(TcpTransport::new(resolver).with_timeout(timeout))),
+        ResolverProto::Doh => Ok(Box::new(DohTransport::new(resolver)?.with_timeout(timeout))),
+    }
+}
+
+// Decode a captured message from a file and print it instead of serving
+fn parse_file(path: &str) -> ! {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path, e);
+            process::exit(2);
+        }
+    };
+
+    match describe_message(&bytes) {
+        Ok(summary) => {
+            print!("{}", summary);
+            process::exit(0);
+        }
+        Err(report) => {
+            eprintln!("Failed to parse {}: {}", path, report);
+            process::exit(1);
+        }
+    }
+}
+
+// The decoded form of a captured message, or a report of what went wrong: the
+// first error, whatever did parse, every error found and a hex dump
+fn describe_message(bytes: &[u8]) -> Result<String, String> {
+    match DnsMessage::from_bytes(bytes) {
+        Ok(message) => Ok(message.to_string()),
+        Err(e) => {
+            let mut report = format!("{}\n", e);
+
+            let (partial, errors) = DnsMessage::from_bytes_lenient(bytes);
+            if DnsHeader::from_bytes(bytes).is_ok() {
+                report.push_str(&partial.to_string());
+            }
+            for error in errors {
+                report.push_str(&format!("Error: {}\n", error));
+            }
+            report.push_str(&hexdump(bytes));
+            Err(report)
+        }
+    }
+}
+
+// Re-download the zone every interval, keeping the last good copy when a fetch fails
+fn spawn_zone_refresh(server: Arc<Server>, url: String, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match Zone::fetch(
+            &url,
+            server.config.upstream_timeout,
+            &server.config.zone_ttls,
+        ) {
+            Ok(zone) => {
+                server.replace_zone(zone);
+                println!("Refreshed zone from {}", url);
+            }
+            Err(e) => eprintln!("Zone refresh failed, keeping previous zone: {}", e),
+        }
+    });
+}
+
+// Stop serving on SIGTERM or SIGINT, so that the cache is still saved on the way
+// out. A second signal exits right away.
+#[cfg(unix)]
+fn handle_signals(server: &Arc<Server>) {
+    let mut signals = match Signals::new([SIGTERM, SIGINT]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Failed to install signal handlers: {}", e);
+            return;
+        }
+    };
+
+    let server = Arc::clone(server);
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if server.is_shutting_down() {
+                process::exit(128 + signal);
+            }
+            println!("Received signal {}, shutting down", signal);
+            server.request_shutdown();
+        }
+    });
+}
 
 fn main() {
-    // Check if the program was run with a resolver address
+    // Report each span with its fields and elapsed time on stderr as it closes
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .init();
+
     let args: Vec<String> = env::args().collect();
-    let resolver_addr = if args.len() > 1 {
-        // Check if the argument is "--resolver"
-        if args[1] == "--resolver" {
-            // If there's another argument after "--resolver", use it
-            if args.len() > 2 {
-                args[2].clone()
-            } else {
-                // Default to Google's DNS server if no resolver is specified
-                String::from("8.8.8.8:53")
-            }
-        } else {
-            // Use the provided resolver
-            args[1].clone()
+    let config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+
+    if let Some(path) = &config.parse_file {
+        parse_file(path);
+    }
+
+    let transport = match build_transport(&config) {
+        Ok(transport) => transport,
+        Err(e) => {
+            eprintln!("Invalid resolver {}: {}", config.resolvers.join(","), e);
+            process::exit(2);
         }
-    } else {
-        // Default to Google's DNS server
-        String::from("8.8.8.8:53")
     };
 
-    println!("Using DNS resolver: {}", resolver_addr);
-
-    let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
-    let mut buf = [0; 512];
-
-    println!("DNS Server listening on 127.0.0.1:2053");
-
-    loop {
-        match udp_socket.recv_from(&mut buf) {
-            Ok((size, source)) => {
-                println!("Received {} bytes from {}", size, source);
-
-                // Try to parse the complete DNS message
-                match DnsMessage::from_bytes(&buf[..size]) {
-                    Ok(request) => {
-                        println!("Parsed DNS message:");
-                        println!("  ID: {}", request.header.id);
-                        println!("  Questions: {}", request.header.qdcount);
-
-                        // Log the domain names with additional details
-                        for (i, question) in request.questions.iter().enumerate() {
-                            if let Ok(domain) = question.decode_name() {
-                                println!("  Question {}: {} (Type: {}, Class: {}, Name length: {} bytes)",
-                                    i + 1, domain, question.record_type, question.class, question.name.len());
-                            } else {
-                                println!(
-                                    "  Question {}: <failed to decode> (Type: {}, Class: {})",
-                                    i + 1,
-                                    question.record_type,
-                                    question.class
-                                );
-                            }
-                        }
-
-                        // Forward the query to the external DNS server
-                        match DnsMessage::forward_query(&request, &resolver_addr) {
-                            Ok(forwarded_response) => {
-                                println!("Received response from external DNS server");
-                                println!("  Answers: {}", forwarded_response.header.ancount);
-
-                                // Send the forwarded response to the original client
-                                let response_bytes = forwarded_response.to_bytes();
-                                udp_socket
-                                    .send_to(&response_bytes, source)
-                                    .expect("Failed to send forwarded response");
-
-                                println!("Forwarded response to {}", source);
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to forward query: {}", e);
-
-                                // Fall back to our own response
-                                let response = DnsMessage::new_response_from_request(&request);
-                                let response_bytes = response.to_bytes();
-                                udp_socket
-                                    .send_to(&response_bytes, source)
-                                    .expect("Failed to send fallback response");
-
-                                println!("Sent fallback response to {}", source);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to parse DNS message: {}", e);
-
-                        // Fall back to header-only parsing if full message parsing fails
-                        if let Ok(header) = DnsHeader::from_bytes(&buf[..size]) {
-                            // Create a simple response based on just the header
-                            let response = DnsMessage::new_response_from_request_header(&header);
-                            udp_socket
-                                .send_to(&response.to_bytes(), source)
-                                .expect("Failed to send fallback response");
-                        }
-                    }
+    println!("Using DNS resolver: {}", transport.describe());
+
+    let zone = match &config.zone_path {
+        Some(path) => match Zone::load(path, &config.zone_ttls) {
+            Ok(zone) => zone,
+            Err(e) => {
+                eprintln!("Failed to load zone {}: {}", path, e);
+                process::exit(2);
+            }
+        },
+        None => match &config.zone_url {
+            Some(url) => match Zone::fetch(url, config.upstream_timeout, &config.zone_ttls) {
+                Ok(zone) => zone,
+                Err(e) => {
+                    eprintln!("Failed to load zone {}: {}", url, e);
+                    process::exit(2);
                 }
+            },
+            None => Zone::new(),
+        },
+    };
+
+    let hosts = match &config.hosts_path {
+        Some(path) => match Hosts::load(path) {
+            Ok(hosts) => hosts,
+            Err(e) => {
+                eprintln!("Failed to load hosts file {}: {}", path, e);
+                process::exit(2);
+            }
+        },
+        None => Hosts::new(),
+    };
+
+    let blocklist = match &config.blocklist_path {
+        Some(path) => match Blocklist::load(path) {
+            Ok(blocklist) => blocklist,
+            Err(e) => {
+                eprintln!("Failed to load blocklist {}: {}", path, e);
+                process::exit(2);
             }
+        },
+        None => Blocklist::new(),
+    };
+
+    let mut views = Vec::new();
+    for (network, path) in &config.views {
+        match Zone::load(path, &config.zone_ttls) {
+            Ok(zone) => views.push(View {
+                network: *network,
+                zone,
+            }),
             Err(e) => {
-                eprintln!("Error receiving data: {}", e);
-                break;
+                eprintln!("Failed to load view zone {}: {}", path, e);
+                process::exit(2);
             }
         }
     }
+
+    let mut reverse_zones = Vec::new();
+    for (network, path) in &config.reverse_zones {
+        match ReverseZone::load(*network, path) {
+            Ok(reverse_zone) => reverse_zones.push(reverse_zone),
+            Err(e) => {
+                eprintln!("Failed to load reverse zone {}: {}", path, e);
+                process::exit(2);
+            }
+        }
+    }
+
+    let server = Arc::new(
+        Server::new(config, transport, zone, hosts, blocklist)
+            .with_views(views)
+            .with_reverse_zones(reverse_zones),
+    );
+
+    #[cfg(unix)]
+    if let Some(path) = &server.config.control_path {
+        if let Err(e) = control::spawn(path, Arc::clone(&server)) {
+            eprintln!("Failed to open control socket {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+    if let (Some(url), Some(interval)) = (&server.config.zone_url, server.config.zone_refresh) {
+        spawn_zone_refresh(Arc::clone(&server), url.clone(), interval);
+    }
+
+    println!(
+        "Cache capacity: {} entries",
+        server.cache.lock().unwrap().capacity()
+    );
+    server.restore_cache();
+
+    let listen = server.config.listen;
+    let udp_socket = match bind_listener(listen, UdpSocket::bind) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    println!("DNS Server listening on {}", listen);
+
+    // IPv6 is a bonus: hosts without it still serve IPv4 clients
+    let udp_socket_v6 = server.config.listen_v6().and_then(|listen_v6| {
+        match bind_listener(listen_v6, UdpSocket::bind) {
+            Ok(socket) => {
+                println!("DNS Server listening on {}", listen_v6);
+                Some(socket)
+            }
+            Err(e) => {
+                eprintln!("Serving IPv4 only: {}", e);
+                None
+            }
+        }
+    });
+
+    // TCP serves large answers and zone transfers; UDP clients don't need it
+    let tcp_listener = match bind_listener(listen, TcpListener::bind) {
+        Ok(listener) => {
+            println!("DNS Server listening on {} (TCP)", listen);
+            Some(listener)
+        }
+        Err(e) => {
+            eprintln!("Serving UDP only: {}", e);
+            None
+        }
+    };
+
+    // Give up root now that the sockets are bound
+    #[cfg(unix)]
+    if server.config.user.is_some() || server.config.group.is_some() {
+        let user = server.config.user.as_deref();
+        let group = server.config.group.as_deref();
+        if let Err(e) = privileges::drop_privileges(user, group) {
+            eprintln!("Failed to drop privileges: {}", e);
+            process::exit(1);
+        }
+        println!("Dropped privileges");
+    }
+
+    #[cfg(unix)]
+    handle_signals(&server);
+
+    // Each socket gets its own loop so replies leave through the socket the query
+    // arrived on; maintenance only runs on the IPv4 one
+    let v6_thread = udp_socket_v6.map(|socket| {
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = server.serve(&socket, || {}) {
+                eprintln!("Error receiving IPv6 data: {}", e);
+            }
+        })
+    });
+
+    // Not joined: blocked in accept, it ends with the process
+    if let Some(listener) = tcp_listener {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.serve_tcp(&listener));
+    }
+
+    if let Err(e) = server.serve(&udp_socket, || server.run_maintenance()) {
+        eprintln!("Error receiving data: {}", e);
+    }
+
+    // Let the IPv6 loop notice the shutdown before saving state
+    if let Some(handle) = v6_thread {
+        server.request_shutdown();
+        let _ = handle.join();
+    }
+
+    server.persist_cache();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = format!("{}/fuzz/corpus/parse/{}", env!("CARGO_MANIFEST_DIR"), name);
+        fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn captured_messages_are_summarized() {
+        let summary = describe_message(&fixture("a_response_compressed.bin")).unwrap();
+
+        assert!(summary.starts_with(";; id 4660, opcode 0, rcode 0, flags: qr rd ra\n"));
+        assert!(summary.contains(";; QUESTION (1)\ncodecrafters.io.\tCLASS1\tTYPE1\n"));
+        assert!(summary.contains("76.76.21.21"), "{}", summary);
+    }
+
+    #[test]
+    fn parse_errors_report_the_partial_message_and_a_dump() {
+        // Cut off in the middle of the question name
+        let mut bytes = fixture("a_response_compressed.bin");
+        bytes.truncate(20);
+
+        let report = describe_message(&bytes).unwrap_err();
+        assert!(report.contains(";; id 4660"), "{}", report);
+        assert!(report.contains("Error: "), "{}", report);
+        assert!(report.contains("00000000  12 34 81 80"), "{}", report);
+    }
+
+    #[test]
+    fn binding_a_used_address_explains_the_failure() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = taken.local_addr().unwrap();
+
+        let error = bind_listener(address, UdpSocket::bind).unwrap_err();
+        assert!(error.starts_with(&format!("Failed to bind to {}: ", address)));
+        assert!(error.contains("already using this port"), "{}", error);
+    }
+
+    #[test]
+    fn binding_a_free_address_succeeds() {
+        let socket = bind_listener("127.0.0.1:0", UdpSocket::bind).unwrap();
+        assert!(socket.local_addr().unwrap().port() != 0);
+    }
 }
@@ -0,0 +1,42 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Counters shared between the query path and the control socket
+#[derive(Default)]
+pub struct Metrics {
+    pub queries: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    pub forwards: AtomicU64,
+    pub errors: AtomicU64,
+    pub dropped_overload: AtomicU64,
+    pub forwards_limited: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Bump a counter by one
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Render all counters as "name value" lines
+    pub fn render(&self) -> String {
+        let counters = [
+            ("queries", &self.queries),
+            ("cache_hits", &self.cache_hits),
+            ("cache_misses", &self.cache_misses),
+            ("forwards", &self.forwards),
+            ("errors", &self.errors),
+            ("dropped_overload", &self.dropped_overload),
+            ("forwards_limited", &self.forwards_limited),
+        ];
+
+        counters
+            .iter()
+            .map(|(name, counter)| format!("{} {}\n", name, counter.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
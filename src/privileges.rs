@@ -0,0 +1,124 @@
+use libc::{gid_t, uid_t};
+use std::fs;
+use std::io;
+
+// Look up a user in passwd(5)-formatted text, returning its uid and primary gid.
+// A plain number is taken as a uid without a primary group.
+pub fn resolve_user(user: &str, passwd: &str) -> Option<(uid_t, Option<gid_t>)> {
+    if let Ok(uid) = user.parse() {
+        return Some((uid, None));
+    }
+
+    // name:password:uid:gid:gecos:home:shell
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 4 || fields[0] != user {
+            return None;
+        }
+        Some((fields[2].parse().ok()?, fields[3].parse().ok()))
+    })
+}
+
+// Look up a group in group(5)-formatted text; a plain number is taken as a gid
+pub fn resolve_group(group: &str, groups: &str) -> Option<gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Some(gid);
+    }
+
+    // name:password:gid:members
+    groups.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 3 || fields[0] != group {
+            return None;
+        }
+        fields[2].parse().ok()
+    })
+}
+
+// Switch to the given user and/or group. Must run after privileged sockets are
+// bound. The group changes first, since after setuid we may no longer be allowed
+// to. Without an explicit group, the user's primary group is used.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), String> {
+    let (uid, primary_gid) = match user {
+        Some(user) => {
+            let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+            let (uid, gid) =
+                resolve_user(user, &passwd).ok_or_else(|| format!("Unknown user: {}", user))?;
+            (Some(uid), gid)
+        }
+        None => (None, None),
+    };
+
+    let gid = match group {
+        Some(group) => {
+            let groups = fs::read_to_string("/etc/group").unwrap_or_default();
+            Some(resolve_group(group, &groups).ok_or_else(|| format!("Unknown group: {}", group))?)
+        }
+        None => primary_gid,
+    };
+
+    if let Some(gid) = gid {
+        // Leave root's supplementary groups behind too
+        #[cfg(target_os = "linux")]
+        if unsafe { libc::getuid() } == 0 && unsafe { libc::setgroups(1, &gid) } != 0 {
+            return Err(format!("setgroups failed: {}", io::Error::last_os_error()));
+        }
+
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(format!(
+                "setgid({}) failed: {}",
+                gid,
+                io::Error::last_os_error()
+            ));
+        }
+    }
+
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(format!(
+                "setuid({}) failed: {}",
+                uid,
+                io::Error::last_os_error()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/bash\n\
+                          nobody:x:65534:65534:nobody:/nonexistent:/usr/sbin/nologin\n\
+                          dns:x:120:130::/var/lib/dns:/usr/sbin/nologin\n\
+                          broken:x:abc\n";
+
+    const GROUP: &str = "root:x:0:\n\
+                         nogroup:x:65534:\n\
+                         dns:x:130:dns\n";
+
+    #[test]
+    fn users_resolve_by_name_or_number() {
+        assert_eq!(resolve_user("dns", PASSWD), Some((120, Some(130))));
+        assert_eq!(resolve_user("nobody", PASSWD), Some((65534, Some(65534))));
+        assert_eq!(resolve_user("1000", PASSWD), Some((1000, None)));
+    }
+
+    #[test]
+    fn unknown_or_malformed_users_do_not_resolve() {
+        assert_eq!(resolve_user("ghost", PASSWD), None);
+        assert_eq!(resolve_user("broken", PASSWD), None);
+        // Names are matched whole
+        assert_eq!(resolve_user("dn", PASSWD), None);
+    }
+
+    #[test]
+    fn groups_resolve_by_name_or_number() {
+        assert_eq!(resolve_group("dns", GROUP), Some(130));
+        assert_eq!(resolve_group("nogroup", GROUP), Some(65534));
+        assert_eq!(resolve_group("42", GROUP), Some(42));
+        assert_eq!(resolve_group("ghost", GROUP), None);
+    }
+}
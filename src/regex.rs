@@ -0,0 +1,369 @@
+// A small regular expression engine, enough for --rule patterns:
+// literals, `.`, escapes (`\.`, `\d`, `\w`), classes like `[a-z0-9-]` or
+// `[^.]`, groups with alternation `(ads|track)`, and the quantifiers `*`, `+`
+// and `?`. A pattern must match the whole text; `^` and `$` are accepted and
+// implied. Matching ignores ASCII case, like DNS names.
+//
+// Patterns compile to an NFA that is simulated one byte at a time, so matching
+// takes time linear in the name whatever the pattern: names come from clients,
+// and a backtracking matcher would let them pick pathological inputs.
+#[derive(Clone, Debug)]
+pub struct Regex {
+    program: Vec<Instruction>,
+}
+
+// One NFA state
+#[derive(Clone, Debug)]
+enum Instruction {
+    Byte(u8),
+    Any,
+    Class {
+        negated: bool,
+        ranges: Vec<(u8, u8)>,
+    },
+    Split(usize, usize), // Continue at both
+    Jump(usize),
+    Match,
+}
+
+// Branches separated by `|`, each a sequence of pieces
+#[derive(Clone, Debug)]
+struct Alternation(Vec<Vec<Piece>>);
+
+// An atom and how many times it may repeat (max None: unbounded)
+#[derive(Clone, Debug)]
+struct Piece {
+    atom: Atom,
+    min: usize,
+    max: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+enum Atom {
+    Byte(u8), // Stored lowercased
+    Any,
+    Class {
+        negated: bool,
+        ranges: Vec<(u8, u8)>,
+    },
+    Group(Alternation),
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let mut body = pattern.strip_prefix('^').unwrap_or(pattern);
+        if body.ends_with('$') && !body.ends_with("\\$") {
+            body = &body[..body.len() - 1];
+        }
+
+        let mut parser = Parser {
+            bytes: body.as_bytes(),
+            position: 0,
+        };
+        let root = parser.alternation()?;
+        if parser.position < parser.bytes.len() {
+            return Err(format!("Unmatched ')' in pattern {}", pattern));
+        }
+
+        let mut program = Vec::new();
+        compile_alternation(&root, &mut program);
+        program.push(Instruction::Match);
+
+        Ok(Regex { program })
+    }
+
+    // Whether the pattern matches the whole text
+    pub fn is_match(&self, text: &str) -> bool {
+        // The step at which each state was last added, to add it once per step
+        let mut added = vec![usize::MAX; self.program.len()];
+        let mut states = Vec::new();
+        self.add_state(&mut states, &mut added, 0, 0);
+
+        for (step, byte) in text.bytes().enumerate() {
+            let byte = byte.to_ascii_lowercase();
+            let mut next = Vec::new();
+            for &state in &states {
+                let advances = match &self.program[state] {
+                    Instruction::Byte(expected) => *expected == byte,
+                    Instruction::Any => true,
+                    Instruction::Class { negated, ranges } => {
+                        let inside = ranges
+                            .iter()
+                            .any(|&(first, last)| (first..=last).contains(&byte));
+                        inside != *negated
+                    }
+                    _ => false,
+                };
+                if advances {
+                    self.add_state(&mut next, &mut added, state + 1, step + 1);
+                }
+            }
+
+            if next.is_empty() {
+                return false;
+            }
+            states = next;
+        }
+
+        states
+            .iter()
+            .any(|&state| matches!(self.program[state], Instruction::Match))
+    }
+
+    // Add a state, following jumps and splits to the states that consume input
+    fn add_state(&self, states: &mut Vec<usize>, added: &mut [usize], state: usize, step: usize) {
+        if added[state] == step {
+            return;
+        }
+        added[state] = step;
+
+        match self.program[state] {
+            Instruction::Split(first, second) => {
+                self.add_state(states, added, first, step);
+                self.add_state(states, added, second, step);
+            }
+            Instruction::Jump(target) => self.add_state(states, added, target, step),
+            _ => states.push(state),
+        }
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Result<u8, String> {
+        let byte = self
+            .peek()
+            .ok_or_else(|| String::from("Pattern ends unexpectedly"))?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn alternation(&mut self) -> Result<Alternation, String> {
+        let mut branches = vec![self.sequence()?];
+        while self.peek() == Some(b'|') {
+            self.position += 1;
+            branches.push(self.sequence()?);
+        }
+        Ok(Alternation(branches))
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Piece>, String> {
+        let mut pieces = Vec::new();
+        while let Some(byte) = self.peek() {
+            if byte == b'|' || byte == b')' {
+                break;
+            }
+            pieces.push(self.piece()?);
+        }
+        Ok(pieces)
+    }
+
+    fn piece(&mut self) -> Result<Piece, String> {
+        let atom = self.atom()?;
+        let (min, max) = match self.peek() {
+            Some(b'*') => (0, None),
+            Some(b'+') => (1, None),
+            Some(b'?') => (0, Some(1)),
+            _ => {
+                return Ok(Piece {
+                    atom,
+                    min: 1,
+                    max: Some(1),
+                })
+            }
+        };
+        self.position += 1;
+        Ok(Piece { atom, min, max })
+    }
+
+    fn atom(&mut self) -> Result<Atom, String> {
+        match self.next()? {
+            b'(' => {
+                let group = self.alternation()?;
+                if self.peek() != Some(b')') {
+                    return Err(String::from("Unclosed '(' in pattern"));
+                }
+                self.position += 1;
+                Ok(Atom::Group(group))
+            }
+            b'.' => Ok(Atom::Any),
+            b'[' => self.class(),
+            b'\\' => Ok(Self::escape(self.next()?)),
+            b'*' | b'+' | b'?' => Err(String::from("Quantifier with nothing to repeat")),
+            byte => Ok(Atom::Byte(byte.to_ascii_lowercase())),
+        }
+    }
+
+    fn escape(byte: u8) -> Atom {
+        match byte {
+            b'd' => Atom::Class {
+                negated: false,
+                ranges: vec![(b'0', b'9')],
+            },
+            b'w' => Atom::Class {
+                negated: false,
+                ranges: vec![(b'a', b'z'), (b'0', b'9'), (b'_', b'_')],
+            },
+            other => Atom::Byte(other.to_ascii_lowercase()),
+        }
+    }
+
+    // A bracketed class, after the opening '['
+    fn class(&mut self) -> Result<Atom, String> {
+        let negated = self.peek() == Some(b'^');
+        if negated {
+            self.position += 1;
+        }
+
+        let mut ranges = Vec::new();
+        loop {
+            let first = match self.next()? {
+                b']' => break,
+                b'\\' => self.next()?,
+                byte => byte,
+            };
+
+            // A '-' between two characters makes a range; elsewhere it's literal
+            let is_range = self.peek() == Some(b'-')
+                && self
+                    .bytes
+                    .get(self.position + 1)
+                    .is_some_and(|&b| b != b']');
+            if is_range {
+                self.position += 1;
+                let last = match self.next()? {
+                    b'\\' => self.next()?,
+                    byte => byte,
+                };
+                ranges.push((first.to_ascii_lowercase(), last.to_ascii_lowercase()));
+            } else {
+                ranges.push((first.to_ascii_lowercase(), first.to_ascii_lowercase()));
+            }
+        }
+
+        Ok(Atom::Class { negated, ranges })
+    }
+}
+
+// Compile each branch behind a split to the next, every branch but the last
+// jumping past the others when it's done
+fn compile_alternation(alternation: &Alternation, program: &mut Vec<Instruction>) {
+    let mut jumps = Vec::new();
+
+    for (index, branch) in alternation.0.iter().enumerate() {
+        if index + 1 == alternation.0.len() {
+            compile_sequence(branch, program);
+            break;
+        }
+
+        let split = program.len();
+        program.push(Instruction::Split(split + 1, 0));
+        compile_sequence(branch, program);
+        jumps.push(program.len());
+        program.push(Instruction::Jump(0));
+        program[split] = Instruction::Split(split + 1, program.len());
+    }
+
+    let end = program.len();
+    for jump in jumps {
+        program[jump] = Instruction::Jump(end);
+    }
+}
+
+fn compile_sequence(pieces: &[Piece], program: &mut Vec<Instruction>) {
+    for piece in pieces {
+        match (piece.min, piece.max) {
+            // x?
+            (0, Some(_)) => {
+                let split = program.len();
+                program.push(Instruction::Split(split + 1, 0));
+                compile_atom(&piece.atom, program);
+                program[split] = Instruction::Split(split + 1, program.len());
+            }
+            // x*
+            (0, None) => {
+                let split = program.len();
+                program.push(Instruction::Split(split + 1, 0));
+                compile_atom(&piece.atom, program);
+                program.push(Instruction::Jump(split));
+                program[split] = Instruction::Split(split + 1, program.len());
+            }
+            // x+
+            (_, None) => {
+                let start = program.len();
+                compile_atom(&piece.atom, program);
+                program.push(Instruction::Split(start, program.len() + 1));
+            }
+            // x
+            (_, Some(_)) => compile_atom(&piece.atom, program),
+        }
+    }
+}
+
+fn compile_atom(atom: &Atom, program: &mut Vec<Instruction>) {
+    match atom {
+        Atom::Byte(byte) => program.push(Instruction::Byte(*byte)),
+        Atom::Any => program.push(Instruction::Any),
+        Atom::Class { negated, ranges } => program.push(Instruction::Class {
+            negated: *negated,
+            ranges: ranges.clone(),
+        }),
+        Atom::Group(alternation) => compile_alternation(alternation, program),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Regex::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn patterns_match_the_whole_name() {
+        assert!(matches(r"ads\..*", "ads.example.test"));
+        assert!(!matches(r"ads\..*", "bads.example.test"));
+        assert!(!matches("example", "example.test"));
+        assert!(matches("^example\\.test$", "example.test"));
+    }
+
+    #[test]
+    fn matching_ignores_case() {
+        assert!(matches("Ads\\.Example\\.test", "ADS.example.TEST"));
+        assert!(matches("[a-z]+", "ABC"));
+    }
+
+    #[test]
+    fn classes_groups_and_quantifiers() {
+        assert!(matches(r"host\d+\.test", "host42.test"));
+        assert!(!matches(r"host\d+\.test", "host.test"));
+        assert!(matches(r"(ads|track)\.[^.]+\.test", "track.example.test"));
+        assert!(!matches(r"(ads|track)\.[^.]+\.test", "ads.a.b.test"));
+        assert!(matches(r"w?ww\.\w+\.test", "ww.my_site.test"));
+        assert!(matches("[-a]x", "-x"));
+    }
+
+    #[test]
+    fn malformed_patterns_are_rejected() {
+        for pattern in ["(ads", "ads)", "*ads", "[ads", "ads\\"] {
+            assert!(Regex::new(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn nested_quantifiers_stay_fast() {
+        // Exponential for a backtracking matcher
+        let started = std::time::Instant::now();
+        assert!(!matches("(a*)*b", &"a".repeat(200)));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
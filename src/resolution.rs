@@ -0,0 +1,47 @@
+// A place answers can come from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Zone,    // Locally served zone records
+    Hosts,   // Static hosts-file entries
+    Cache,   // Previously forwarded answers
+    Forward, // The upstream resolver
+}
+
+// The order in which sources are consulted for a query. The first source that
+// produces an answer wins; if none does, the query gets NXDOMAIN.
+pub struct ResolutionChain {
+    sources: Vec<Source>,
+}
+
+impl ResolutionChain {
+    // zone -> hosts -> cache -> forward, minus forwarding in local-only mode
+    pub fn new(local_only: bool) -> Self {
+        let mut sources = vec![Source::Zone, Source::Hosts, Source::Cache];
+        if !local_only {
+            sources.push(Source::Forward);
+        }
+
+        ResolutionChain { sources }
+    }
+
+    pub fn sources(&self) -> &[Source] {
+        &self.sources
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_are_tried_zone_hosts_cache_forward() {
+        assert_eq!(
+            ResolutionChain::new(false).sources(),
+            [Source::Zone, Source::Hosts, Source::Cache, Source::Forward]
+        );
+        assert_eq!(
+            ResolutionChain::new(true).sources(),
+            [Source::Zone, Source::Hosts, Source::Cache]
+        );
+    }
+}
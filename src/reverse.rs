@@ -0,0 +1,176 @@
+use crate::dns::dns_error::DnsError;
+use crate::dns::dns_question::DnsQuestion;
+use crate::views::ClientNetwork;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// The address a reverse-lookup name stands for: 1.2.3.4 for 4.3.2.1.in-addr.arpa,
+// and for ip6.arpa the address whose 32 nibbles are the labels in reverse order.
+// None for anything else, including names above a full address.
+pub fn address_from_reverse_name(name: &[u8]) -> Option<IpAddr> {
+    let name = DnsQuestion::decode_domain_name(name)
+        .ok()?
+        .to_ascii_lowercase();
+    let name = name.trim_end_matches('.');
+
+    if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = [0u8; 4];
+        let labels: Vec<&str> = labels.split('.').collect();
+        if labels.len() != 4 {
+            return None;
+        }
+        for (octet, label) in octets.iter_mut().rev().zip(labels) {
+            // No leading zeros, so each address has exactly one name
+            if label.len() > 1 && label.starts_with('0') {
+                return None;
+            }
+            *octet = label.parse().ok()?;
+        }
+        return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+    }
+
+    if let Some(labels) = name.strip_suffix(".ip6.arpa") {
+        let nibbles: Vec<&str> = labels.split('.').collect();
+        if nibbles.len() != 32 {
+            return None;
+        }
+        let mut value = 0u128;
+        for nibble in nibbles.iter().rev() {
+            if nibble.len() != 1 {
+                return None;
+            }
+            value = value << 4 | u8::from_str_radix(nibble, 16).ok()? as u128;
+        }
+        return Some(IpAddr::V6(Ipv6Addr::from(value)));
+    }
+
+    None
+}
+
+// Static PTR records for the addresses of one network, configured with
+// `--reverse-zone <cidr>:<file>`. The file is in /etc/hosts format, each
+// address mapped to its name.
+pub struct ReverseZone {
+    pub network: ClientNetwork,
+    names: HashMap<IpAddr, Vec<u8>>, // Encoded target names
+}
+
+impl ReverseZone {
+    pub fn load(network: ClientNetwork, path: &str) -> Result<Self, DnsError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| DnsError::Zone(format!("cannot read {}: {}", path, e)))?;
+        Self::parse(network, &text)
+    }
+
+    // Parse lines of `address name`; '#' starts a comment. Only the first name
+    // for an address is used, since PTR answers name a single host.
+    pub fn parse(network: ClientNetwork, text: &str) -> Result<Self, DnsError> {
+        let mut names = HashMap::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+
+            let (address, name) = match (fields.next(), fields.next()) {
+                (Some(address), Some(name)) => (address, name),
+                (None, _) => continue,
+                (Some(_), None) => {
+                    return Err(DnsError::Zone(format!("line {}: missing name", index + 1)))
+                }
+            };
+
+            let address = address.parse::<IpAddr>().map_err(|_| {
+                DnsError::Zone(format!("line {}: invalid address {}", index + 1, address))
+            })?;
+            if !network.contains(address) {
+                return Err(DnsError::Zone(format!(
+                    "line {}: {} is outside the reverse zone",
+                    index + 1,
+                    address
+                )));
+            }
+
+            names
+                .entry(address)
+                .or_insert_with(|| DnsQuestion::encode_domain_name(name));
+        }
+
+        Ok(ReverseZone { network, names })
+    }
+
+    // Whether the zone covers an address, whether or not it has a name for it
+    pub fn contains(&self, address: IpAddr) -> bool {
+        self.network.contains(address)
+    }
+
+    // The encoded name configured for an address
+    pub fn name_for(&self, address: IpAddr) -> Option<&[u8]> {
+        self.names.get(&address).map(|name| name.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address_of(name: &str) -> Option<IpAddr> {
+        address_from_reverse_name(&DnsQuestion::encode_domain_name(name))
+    }
+
+    fn network(cidr: &str) -> ClientNetwork {
+        ClientNetwork::from_cidr(cidr).unwrap()
+    }
+
+    #[test]
+    fn in_addr_names_are_reversed() {
+        assert_eq!(
+            address_of("4.3.2.192.in-addr.arpa"),
+            Some("192.2.3.4".parse().unwrap())
+        );
+        assert_eq!(
+            address_of("4.3.2.192.IN-ADDR.ARPA"),
+            Some("192.2.3.4".parse().unwrap())
+        );
+
+        // Partial names, leading zeros and out of range labels name no address
+        assert_eq!(address_of("3.2.192.in-addr.arpa"), None);
+        assert_eq!(address_of("04.3.2.192.in-addr.arpa"), None);
+        assert_eq!(address_of("256.3.2.192.in-addr.arpa"), None);
+        assert_eq!(address_of("4.3.2.192.example.test"), None);
+    }
+
+    #[test]
+    fn ip6_names_are_read_nibble_by_nibble() {
+        let name = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa";
+        assert_eq!(address_of(name), Some("2001:db8::1".parse().unwrap()));
+
+        assert_eq!(address_of("8.b.d.0.1.0.0.2.ip6.arpa"), None);
+        let wide = name.replacen("1.", "10.", 1);
+        assert_eq!(address_of(&wide), None);
+    }
+
+    #[test]
+    fn reverse_zones_map_addresses_to_names() {
+        let zone = ReverseZone::parse(
+            network("192.0.2.0/24"),
+            "# router\n192.0.2.1 gateway.example.test\n192.0.2.1 other.example.test\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            zone.name_for("192.0.2.1".parse().unwrap()),
+            Some(&DnsQuestion::encode_domain_name("gateway.example.test")[..])
+        );
+        assert!(zone.contains("192.0.2.9".parse().unwrap()));
+        assert_eq!(zone.name_for("192.0.2.9".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn reverse_zone_entries_must_be_inside_the_network() {
+        let network = network("192.0.2.0/24");
+        assert!(ReverseZone::parse(network, "198.51.100.1 stray.test\n").is_err());
+        assert!(ReverseZone::parse(network, "192.0.2.1\n").is_err());
+        assert!(ReverseZone::parse(network, "not-an-address host.test\n").is_err());
+    }
+}
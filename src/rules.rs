@@ -0,0 +1,114 @@
+use crate::regex::Regex;
+use std::net::IpAddr;
+
+// What to do with a query whose name matches a rule
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleAction {
+    Refuse,
+    NxDomain,
+    Forward,        // Resolve as usual: lets a name escape the rules after it
+    Answer(IpAddr), // Answer A (or AAAA) queries with this address
+}
+
+// A pattern over decoded query names and its action, from `--rule pattern:action`
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pattern: Regex,
+    action: RuleAction,
+}
+
+impl Rule {
+    // Parse `pattern:action`. The pattern ends at the first ':', so IPv6
+    // addresses can still be used as static answers.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (pattern, action) = value
+            .split_once(':')
+            .ok_or_else(|| format!("Expected pattern:action for --rule, got {}", value))?;
+
+        let action = match action {
+            "refuse" => RuleAction::Refuse,
+            "nxdomain" => RuleAction::NxDomain,
+            "forward" => RuleAction::Forward,
+            address => RuleAction::Answer(
+                address
+                    .parse()
+                    .map_err(|_| format!("Unknown rule action: {}", action))?,
+            ),
+        };
+        let pattern = Regex::new(pattern).map_err(|e| format!("Invalid rule {}: {}", value, e))?;
+
+        Ok(Rule { pattern, action })
+    }
+}
+
+// Rules checked in order against each query name; the first match decides
+#[derive(Clone, Debug, Default)]
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+}
+
+impl RuleEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // The action of the first rule matching the name (dotted, no trailing dot)
+    pub fn evaluate(&self, name: &str) -> Option<RuleAction> {
+        self.rules
+            .iter()
+            .find(|rule| rule.pattern.is_match(name))
+            .map(|rule| rule.action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(rules: &[&str]) -> RuleEngine {
+        let mut engine = RuleEngine::new();
+        for rule in rules {
+            engine.push(Rule::parse(rule).unwrap());
+        }
+        engine
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let engine = engine(&[
+            r"ads\.good\.test:forward",
+            r"ads\..*:refuse",
+            r".*\.test:nxdomain",
+        ]);
+
+        assert_eq!(engine.evaluate("ads.good.test"), Some(RuleAction::Forward));
+        assert_eq!(engine.evaluate("ads.bad.test"), Some(RuleAction::Refuse));
+        assert_eq!(engine.evaluate("www.bad.test"), Some(RuleAction::NxDomain));
+        assert_eq!(engine.evaluate("example.org"), None);
+    }
+
+    #[test]
+    fn actions_parse_including_addresses() {
+        let engine = engine(&[r".*\.internal:192.168.0.1", r".*\.v6:fd00::1"]);
+        assert_eq!(
+            engine.evaluate("db.internal"),
+            Some(RuleAction::Answer("192.168.0.1".parse().unwrap()))
+        );
+        assert_eq!(
+            engine.evaluate("db.v6"),
+            Some(RuleAction::Answer("fd00::1".parse().unwrap()))
+        );
+
+        assert!(Rule::parse("no-action").is_err());
+        assert!(Rule::parse("ads:explode").is_err());
+        assert!(Rule::parse("(ads:refuse").is_err());
+    }
+}
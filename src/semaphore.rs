@@ -0,0 +1,79 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// A counting semaphore: at most `permits` holders at a time
+pub struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+// A permit in use; dropping it hands the permit back
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    // Take a permit, waiting up to `wait` for one to be released. None when
+    // every permit is still held after that.
+    pub fn acquire_within(&self, wait: Duration) -> Option<Permit<'_>> {
+        let deadline = Instant::now() + wait;
+        let mut available = self.available.lock().unwrap();
+
+        while *available == 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            available = self.released.wait_timeout(available, remaining).unwrap().0;
+        }
+
+        *available -= 1;
+        Some(Permit { semaphore: self })
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn permits_run_out_and_come_back() {
+        let semaphore = Semaphore::new(2);
+        let first = semaphore.acquire_within(Duration::ZERO).unwrap();
+        let _second = semaphore.acquire_within(Duration::ZERO).unwrap();
+        assert!(semaphore
+            .acquire_within(Duration::from_millis(10))
+            .is_none());
+
+        drop(first);
+        assert!(semaphore.acquire_within(Duration::ZERO).is_some());
+    }
+
+    #[test]
+    fn waiters_get_released_permits() {
+        let semaphore = Semaphore::new(1);
+        let permit = semaphore.acquire_within(Duration::ZERO).unwrap();
+
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| semaphore.acquire_within(Duration::from_secs(5)).is_some());
+            thread::sleep(Duration::from_millis(50));
+            drop(permit);
+            assert!(waiter.join().unwrap());
+        });
+    }
+}
@@ -0,0 +1,3045 @@
+use crate::blocklist::Blocklist;
+use crate::cache::{CacheKey, DnsCache};
+use crate::config::{Config, DropPolicy, MultiQuestion};
+use crate::cookie::CookieJar;
+use crate::dns::dns_edns::{
+    self, ClientSubnet, Cookie, OptRecord, OPTION_CLIENT_SUBNET, OPTION_COOKIE,
+};
+use crate::dns::dns_error::DnsError;
+use crate::dns::dns_header::DnsHeader;
+use crate::dns::dns_id::{IdSource, RandomIds};
+use crate::dns::dns_question::DnsQuestion;
+use crate::dns::dns_record::{DnsRecord, ANAME_TYPE, DEFAULT_ZONE_TTL};
+use crate::dns::dns_tsig::{self, TsigError};
+use crate::dns::DnsMessage;
+use crate::dns64::Dns64Prefix;
+use crate::hexdump::hexdump;
+use crate::hosts::Hosts;
+use crate::metrics::Metrics;
+use crate::resolution::{ResolutionChain, Source};
+use crate::reverse::{self, ReverseZone};
+use crate::rules::RuleAction;
+use crate::semaphore::Semaphore;
+use crate::transport::{read_tcp_message, Transport};
+use crate::update::{self, OPCODE_UPDATE};
+use crate::views::View;
+use crate::zone::Zone;
+use bytes::BytesMut;
+use std::io::{self, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How often the serve loop wakes up to do maintenance, even without traffic
+pub const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long a client's TCP connection may sit idle between queries
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Smallest UDP receive buffer: room for EDNS queries carrying cookies, client
+// subnets or TSIG signatures, which don't fit the classic 512 bytes
+const MIN_UDP_RECEIVE_SIZE: usize = 4096;
+
+// QTYPE of a full zone transfer (RFC 5936)
+const QTYPE_AXFR: u16 = 252;
+
+// Zone transfers are split into messages of about this many bytes
+const TRANSFER_MESSAGE_SIZE: usize = 16 * 1024;
+
+// Version string disclosed through version.bind / version.server CH queries
+const VERSION_TEXT: &str = concat!("codecrafters-dns-server ", env!("CARGO_PKG_VERSION"));
+
+// Everything needed to turn an incoming packet into a response
+pub struct Server {
+    pub config: Config,
+    pub transport: Box<dyn Transport>,
+    pub ids: Box<dyn IdSource>,
+    pub cache: Mutex<DnsCache>,
+    pub metrics: Metrics,
+    pub zone: RwLock<Zone>,
+    pub views: Vec<View>,
+    pub reverse_zones: Vec<ReverseZone>,
+    pub hosts: Hosts,
+    pub blocklist: Blocklist,
+    pub chain: ResolutionChain,
+    // Bounds in-flight forwards with --max-concurrent-forwards
+    pub forward_slots: Option<Semaphore>,
+    pub cookies: CookieJar,
+    pub shutdown: AtomicBool,
+}
+
+impl Server {
+    pub fn new(
+        config: Config,
+        transport: Box<dyn Transport>,
+        zone: Zone,
+        hosts: Hosts,
+        blocklist: Blocklist,
+    ) -> Self {
+        let mut cache = DnsCache::new(config.cache_size);
+        if config.serve_stale {
+            cache = cache.with_max_stale(config.max_stale);
+        }
+        let cache = Mutex::new(cache);
+        let chain = ResolutionChain::new(config.local_only);
+        let forward_slots = config.max_concurrent_forwards.map(Semaphore::new);
+
+        Server {
+            config,
+            transport,
+            ids: Box::new(RandomIds),
+            cache,
+            metrics: Metrics::new(),
+            zone: RwLock::new(zone),
+            views: Vec::new(),
+            reverse_zones: Vec::new(),
+            hosts,
+            blocklist,
+            chain,
+            forward_slots,
+            cookies: CookieJar::new(),
+            shutdown: AtomicBool::new(false),
+        }
+    }
+
+    // Take upstream query IDs from a different source (e.g. a fixed sequence in tests)
+    pub fn with_id_source(mut self, ids: Box<dyn IdSource>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    // Serve these zones instead of the default one to clients in their networks
+    pub fn with_views(mut self, views: Vec<View>) -> Self {
+        self.views = views;
+        self
+    }
+
+    // Answer PTR queries for these networks' addresses from static maps
+    pub fn with_reverse_zones(mut self, reverse_zones: Vec<ReverseZone>) -> Self {
+        self.reverse_zones = reverse_zones;
+        self
+    }
+
+    // Swap in a freshly loaded zone; queries in flight finish against the old one
+    pub fn replace_zone(&self, zone: Zone) {
+        *self.zone.write().unwrap() = zone;
+    }
+
+    // Ask the serve loop to stop after its current iteration
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    // Answer queries on the socket until shutdown is requested. The socket read
+    // times out periodically so that `maintenance` runs (and shutdown is noticed)
+    // even when no traffic arrives.
+    //
+    // With `--workers`, queries are handed to a pool of worker threads through a
+    // bounded queue. When the queue is full the query is dropped (or refused) on
+    // the spot, so a flood can't make the queue grow without limit.
+    pub fn serve(&self, socket: &UdpSocket, mut maintenance: impl FnMut()) -> io::Result<()> {
+        socket.set_read_timeout(Some(MAINTENANCE_INTERVAL))?;
+
+        if self.config.workers == 0 {
+            return self.receive_loop(socket, &mut maintenance, |packet, source| {
+                self.respond(socket, packet, source)
+            });
+        }
+
+        let (sender, receiver) =
+            mpsc::sync_channel::<(Vec<u8>, SocketAddr)>(self.config.queue_size);
+        let receiver = Mutex::new(receiver);
+
+        thread::scope(|scope| {
+            for _ in 0..self.config.workers {
+                scope.spawn(|| loop {
+                    // The sender going away means the receive loop has stopped
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok((packet, source)) => self.respond(socket, &packet, source),
+                        Err(_) => break,
+                    }
+                });
+            }
+
+            let result = self.receive_loop(socket, &mut maintenance, |packet, source| match sender
+                .try_send((packet.to_vec(), source))
+            {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => self.shed_query(socket, packet, source),
+                Err(TrySendError::Disconnected(_)) => {}
+            });
+            drop(sender);
+            result
+        })
+    }
+
+    // Receive packets until shutdown, passing each to `handle`
+    fn receive_loop(
+        &self,
+        socket: &UdpSocket,
+        maintenance: &mut impl FnMut(),
+        mut handle: impl FnMut(&[u8], SocketAddr),
+    ) -> io::Result<()> {
+        // Clients may send anything up to the payload size we advertise
+        let size = MIN_UDP_RECEIVE_SIZE.max(self.config.edns_udp_size as usize);
+        let mut buf = vec![0; size];
+        let mut last_maintenance = Instant::now();
+
+        while !self.is_shutting_down() {
+            match socket.recv_from(&mut buf) {
+                Ok((size, source)) => {
+                    println!("Received {} bytes from {}", size, source);
+                    if self.config.dump_packets {
+                        print!("{}", hexdump(&buf[..size]));
+                    }
+
+                    handle(&buf[..size], source);
+                }
+                // Read timed out or was interrupted by a signal: nothing arrived,
+                // fall through to maintenance (and the shutdown check)
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+
+            // Busy servers still get periodic maintenance
+            if last_maintenance.elapsed() >= MAINTENANCE_INTERVAL {
+                maintenance();
+                last_maintenance = Instant::now();
+            }
+        }
+
+        Ok(())
+    }
+
+    // Answer queries over TCP until shutdown is requested, one thread per
+    // connection. Every message is preceded by its length as two bytes. This is
+    // the only path that serves zone transfers.
+    pub fn serve_tcp(&self, listener: &TcpListener) {
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                if self.is_shutting_down() {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        scope.spawn(move || self.serve_connection(stream));
+                    }
+                    Err(e) => eprintln!("Failed to accept TCP connection: {}", e),
+                }
+            }
+        });
+    }
+
+    // Answer the queries arriving on one connection until the client closes it
+    // or leaves it idle
+    fn serve_connection(&self, mut stream: TcpStream) {
+        let Ok(peer) = stream.peer_addr() else {
+            return;
+        };
+        if stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT)).is_err() {
+            return;
+        }
+
+        while let Ok(packet) = read_tcp_message(&mut stream) {
+            println!("Received {} bytes over TCP from {}", packet.len(), peer);
+            if self.config.dump_packets {
+                print!("{}", hexdump(&packet));
+            }
+
+            let responses = self.handle_tcp_query(&packet, peer.ip());
+            self.apply_debug_delay();
+
+            for response in responses {
+                let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+                framed.extend_from_slice(&response);
+                if let Err(e) = stream.write_all(&framed) {
+                    eprintln!("Failed to send response to {}: {}", peer, e);
+                    return;
+                }
+            }
+            println!("Sent response to {} over TCP", peer);
+        }
+    }
+
+    // With --debug-delay, hold the response back so clients' timeout handling
+    // can be tested. Debugging only: it stalls whichever thread is sending.
+    fn apply_debug_delay(&self) {
+        if let Some(delay) = self.config.debug_delay {
+            thread::sleep(delay);
+        }
+    }
+
+    // Answer one query and send the response back to where it came from
+    fn respond(&self, socket: &UdpSocket, packet: &[u8], source: SocketAddr) {
+        if let Some(response_bytes) = self.handle_query(packet, source.ip()) {
+            self.send_response(socket, &response_bytes, source);
+        }
+    }
+
+    // The worker queue is full: drop the query, refusing it first if configured to
+    fn shed_query(&self, socket: &UdpSocket, packet: &[u8], source: SocketAddr) {
+        Metrics::increment(&self.metrics.dropped_overload);
+        println!("Worker queue full, dropping query from {}", source);
+
+        if self.config.overload_refuse {
+            if let Ok(request) = DnsMessage::from_bytes(packet) {
+                let response = DnsMessage::new_refused_from_request(&request);
+                self.send_response(socket, &response.to_bytes(), source);
+            }
+        }
+    }
+
+    fn send_response(&self, socket: &UdpSocket, response_bytes: &[u8], source: SocketAddr) {
+        self.apply_debug_delay();
+        if self.config.dump_packets {
+            println!("Sending {} bytes to {}", response_bytes.len(), source);
+            print!("{}", hexdump(response_bytes));
+        }
+        match socket.send_to(response_bytes, source) {
+            Ok(_) => println!("Sent response to {}", source),
+            Err(e) => eprintln!("Failed to send response to {}: {}", source, e),
+        }
+    }
+
+    // Periodic housekeeping run from the serve loop
+    pub fn run_maintenance(&self) {
+        let purged = self.cache.lock().unwrap().purge_expired(Instant::now());
+        if purged > 0 {
+            println!("Purged {} expired cache entries", purged);
+        }
+    }
+
+    // Restore the cache saved by a previous run, starting fresh if the file is
+    // missing, corrupt or from an incompatible version
+    pub fn restore_cache(&self) {
+        if let Some(path) = &self.config.cache_file {
+            match self.cache.lock().unwrap().load(path, Instant::now()) {
+                Ok(restored) => println!("Restored {} cache entries from {}", restored, path),
+                Err(e) => println!("Starting with an empty cache ({}: {})", path, e),
+            }
+        }
+    }
+
+    // Save the cache so the next run doesn't start cold
+    pub fn persist_cache(&self) {
+        if let Some(path) = &self.config.cache_file {
+            match self.cache.lock().unwrap().save(path, Instant::now()) {
+                Ok(saved) => println!("Saved {} cache entries to {}", saved, path),
+                Err(e) => eprintln!("Failed to save cache to {}: {}", path, e),
+            }
+        }
+    }
+
+    // Handle a raw query packet from a client, returning the bytes to send back (if any)
+    pub fn handle_query(&self, packet: &[u8], client: IpAddr) -> Option<BytesMut> {
+        self.handle_message(packet, client, false)
+            .into_iter()
+            .next()
+    }
+
+    // Process a query received over TCP: responses aren't truncated, and zone
+    // transfers may span several messages
+    pub fn handle_tcp_query(&self, packet: &[u8], client: IpAddr) -> Vec<BytesMut> {
+        self.handle_message(packet, client, true)
+    }
+
+    fn handle_message(&self, packet: &[u8], client: IpAddr, over_tcp: bool) -> Vec<BytesMut> {
+        Metrics::increment(&self.metrics.queries);
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "request",
+            qname = tracing::field::Empty,
+            rcode = tracing::field::Empty
+        )
+        .entered();
+
+        // Try to parse the complete DNS message
+        match DnsMessage::from_bytes(packet) {
+            Ok(mut request) => {
+                Self::log_request(&request);
+                #[cfg(feature = "tracing")]
+                span.record("qname", tracing::field::display(Self::query_name(&request)));
+
+                let request_mac = match self.check_tsig(packet, &mut request) {
+                    Ok(request_mac) => request_mac,
+                    Err(response) => return vec![response],
+                };
+
+                if !over_tcp && self.forces_tcp(client) {
+                    println!("Sending TC=1 to make {} retry over TCP", client);
+                    let mut response = DnsMessage::new_answer_from_request(&request, Vec::new());
+                    response.header.set_truncated(true);
+                    response.header.set_recursion_available(true);
+                    return vec![self.finish_response(
+                        &request,
+                        response,
+                        client,
+                        over_tcp,
+                        request_mac,
+                    )];
+                }
+
+                if Self::is_transfer(&request) {
+                    return self.answer_transfer(&request, client, over_tcp, request_mac);
+                }
+
+                self.answer(&request, client)
+                    .map(|response| {
+                        #[cfg(feature = "tracing")]
+                        span.record("rcode", response.header.rcode());
+                        self.finish_response(&request, response, client, over_tcp, request_mac)
+                    })
+                    .into_iter()
+                    .collect()
+            }
+            Err(e) => {
+                eprintln!("Failed to parse DNS message: {}", e);
+                Metrics::increment(&self.metrics.errors);
+
+                // Fall back to header-only parsing if full message parsing fails
+                let Ok(header) = DnsHeader::from_bytes(packet) else {
+                    return Vec::new();
+                };
+
+                // The client sent something malformed: say so
+                vec![DnsMessage::new_formerr(&header).to_bytes()]
+            }
+        }
+    }
+
+    // Whether --force-tcp-for covers the client: its UDP queries only get an
+    // empty truncated response, so it must ask again over TCP
+    fn forces_tcp(&self, client: IpAddr) -> bool {
+        self.config
+            .force_tcp_for
+            .iter()
+            .any(|network| network.contains(client))
+    }
+
+    // Size the response for the client (down to one answer with --single-answer),
+    // then serialize it, signed when the request was
+    fn finish_response(
+        &self,
+        request: &DnsMessage,
+        mut response: DnsMessage,
+        client: IpAddr,
+        over_tcp: bool,
+        request_mac: Option<Vec<u8>>,
+    ) -> BytesMut {
+        if self.config.single_answer {
+            response.keep_single_answer();
+        }
+        self.fit_to_client(request, &mut response, client, over_tcp);
+
+        // Catch our own bugs during development
+        #[cfg(debug_assertions)]
+        if let Err(e) = response.validate() {
+            eprintln!("Warning: sending an inconsistent response: {}", e);
+        }
+
+        match (&self.config.tsig_key, request_mac) {
+            (Some(key), Some(request_mac)) => {
+                Self::sign_response(response.to_bytes(), |bytes, now| {
+                    dns_tsig::sign(bytes, key, Some(&request_mac), now, 0)
+                })
+            }
+            _ => response.to_bytes(),
+        }
+    }
+
+    // With --tsig-key, check the request's TSIG signature. Signed requests get
+    // their TSIG record removed (it must not be forwarded or applied) and its MAC
+    // returned so the response can be signed. Unsigned queries are still
+    // answered, unsigned updates are refused, and bad signatures get NOTAUTH.
+    fn check_tsig(
+        &self,
+        packet: &[u8],
+        request: &mut DnsMessage,
+    ) -> Result<Option<Vec<u8>>, BytesMut> {
+        let Some(key) = &self.config.tsig_key else {
+            return Ok(None);
+        };
+
+        match dns_tsig::verify(packet, key, Self::unix_now()) {
+            Ok(request_mac) => {
+                request.additionals.pop();
+                request.sync_counts();
+                Ok(Some(request_mac))
+            }
+            Err(TsigError::Unsigned) if request.header.opcode() != OPCODE_UPDATE => Ok(None),
+            Err(TsigError::Unsigned) => {
+                println!("Unsigned dynamic update refused (--tsig-key is set)");
+                Err(DnsMessage::new_refused_from_request(request).to_bytes())
+            }
+            // A TSIG record we can't interpret makes the whole message suspect
+            // (RFC 8945 section 5.2)
+            Err(TsigError::Malformed) => {
+                println!("Malformed TSIG record, answering FORMERR");
+                Metrics::increment(&self.metrics.errors);
+                Err(DnsMessage::new_error_response(request, 1).to_bytes())
+            }
+            Err(e) => {
+                println!("TSIG verification failed: {:?}", e);
+                Metrics::increment(&self.metrics.errors);
+
+                // NOTAUTH, with the TSIG error code saying why
+                let response = DnsMessage::new_error_response(request, 9);
+                Err(Self::sign_response(response.to_bytes(), |bytes, now| {
+                    dns_tsig::append_error(bytes, key, now, e.code())
+                }))
+            }
+        }
+    }
+
+    // Add a TSIG record to a serialized response, sending it unsigned if that fails
+    fn sign_response(
+        bytes: BytesMut,
+        sign: impl FnOnce(&[u8], u64) -> Result<Vec<u8>, DnsError>,
+    ) -> BytesMut {
+        match sign(&bytes, Self::unix_now()) {
+            Ok(signed) => BytesMut::from(&signed[..]),
+            Err(e) => {
+                eprintln!("Failed to sign response: {}", e);
+                bytes
+            }
+        }
+    }
+
+    fn unix_now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    // Give EDNS clients our OPT record advertising our UDP payload size (and a
+    // server cookie when they sent a client cookie), then truncate the response
+    // to what the client can receive: 512 bytes without EDNS, otherwise the
+    // smaller of its advertised size and ours. Over TCP only the two-byte length
+    // prefix limits the size.
+    fn fit_to_client(
+        &self,
+        request: &DnsMessage,
+        response: &mut DnsMessage,
+        client: IpAddr,
+        over_tcp: bool,
+    ) {
+        let our_size = self.config.edns_udp_size;
+
+        let limit = match request.opt_record() {
+            Some(client_opt) => {
+                if response.opt_record().is_none() {
+                    let opt = OptRecord::new(our_size).dnssec_ok(request.dnssec_ok());
+                    response.additionals.push(opt.build());
+                    response.sync_counts();
+                }
+
+                let cookie = dns_edns::find_option(&client_opt.rdata, OPTION_COOKIE)
+                    .and_then(|data| Cookie::from_option_data(&data))
+                    .map(|cookie| {
+                        self.cookies
+                            .server_cookie(cookie.client, client, Self::unix_now() as u32)
+                            .to_option_data()
+                    });
+                if let Some(opt) = response.opt_record_mut() {
+                    opt.class = our_size;
+                    dns_edns::set_option(&mut opt.rdata, OPTION_COOKIE, cookie);
+                }
+
+                client_opt.class.clamp(512, our_size)
+            }
+            None => 512,
+        };
+
+        let limit = if over_tcp { u16::MAX } else { limit };
+        response.truncate_to(limit as usize);
+    }
+
+    // Build the response for a successfully parsed request, or None to stay silent
+    fn answer(&self, request: &DnsMessage, client: IpAddr) -> Option<DnsMessage> {
+        if request.header.opcode() == OPCODE_UPDATE {
+            return Some(self.answer_update(request));
+        }
+
+        if request.questions.len() > 1 && self.config.multi_question == MultiQuestion::Reject {
+            println!(
+                "Rejecting request with {} questions",
+                request.questions.len()
+            );
+            return Some(DnsMessage::new_error_response(request, 1));
+        }
+
+        if Self::has_malformed_cookie(request) {
+            println!("Malformed DNS cookie, answering FORMERR");
+            return Some(DnsMessage::new_error_response(request, 1));
+        }
+
+        if let Some(response) = self.answer_version_query(request) {
+            return Some(response);
+        }
+
+        // Queries for blocked record types get NODATA without being forwarded
+        if self.is_blocked_type(request) {
+            println!("Record type blocked, answering with no data");
+            let mut response = DnsMessage::new_nodata_from_request(request, None);
+            response.header.set_recursion_available(true);
+            return Some(response);
+        }
+
+        if self.is_blocked_domain(request) {
+            return self.answer_blocked_domain(request);
+        }
+
+        if let Some(response) = self.answer_from_rules(request) {
+            return Some(response);
+        }
+
+        // Try each source in precedence order
+        for source in self.chain.sources() {
+            let response = match source {
+                Source::Zone => self
+                    .answer_from_reverse_zone(request)
+                    .or_else(|| self.answer_from_zone(request, client)),
+                Source::Hosts => self.answer_from_hosts(request),
+                Source::Cache => self.answer_from_cache(request),
+                Source::Forward if !request.header.recursion_desired() => {
+                    Some(self.answer_without_recursion(request, client))
+                }
+                Source::Forward => Some(self.answer_by_forwarding(request)),
+            };
+
+            if response.is_some() {
+                return response;
+            }
+        }
+
+        println!("No source could answer, returning NXDOMAIN");
+        Some(DnsMessage::new_nxdomain_from_request(request))
+    }
+
+    // Whether the request carries a cookie option of a length RFC 7873 forbids
+    fn has_malformed_cookie(request: &DnsMessage) -> bool {
+        request
+            .opt_record()
+            .and_then(|opt| dns_edns::find_option(&opt.rdata, OPTION_COOKIE))
+            .is_some_and(|data| Cookie::from_option_data(&data).is_none())
+    }
+
+    // Name of the first question, for spans
+    #[cfg(feature = "tracing")]
+    fn query_name(request: &DnsMessage) -> String {
+        request
+            .questions
+            .first()
+            .and_then(|question| question.decode_name().ok())
+            .unwrap_or_default()
+    }
+
+    // Only single-question requests are cached
+    fn cache_key(request: &DnsMessage) -> Option<CacheKey> {
+        match request.questions.as_slice() {
+            [question] => Some(CacheKey::new(
+                &question.name,
+                question.record_type,
+                question.class,
+            )),
+            _ => None,
+        }
+    }
+
+    // Answer from previously cached upstream answers
+    fn answer_from_cache(&self, request: &DnsMessage) -> Option<DnsMessage> {
+        let key = Self::cache_key(request)?;
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "cache_lookup",
+            qname = %Self::query_name(request),
+            hit = tracing::field::Empty
+        )
+        .entered();
+        let answers = self.cache.lock().unwrap().get(&key, Instant::now());
+        #[cfg(feature = "tracing")]
+        {
+            span.record("hit", answers.is_some());
+            span.exit();
+        }
+
+        let answers = match answers {
+            Some(answers) => answers,
+            None => {
+                Metrics::increment(&self.metrics.cache_misses);
+                return None;
+            }
+        };
+
+        println!("Answering from cache ({} answers)", answers.len());
+        Metrics::increment(&self.metrics.cache_hits);
+        let mut response = DnsMessage::new_answer_from_request(request, answers);
+        response.header.set_recursion_available(true);
+        Some(response)
+    }
+
+    // Answer single-question A/AAAA requests from the hosts file
+    fn answer_from_hosts(&self, request: &DnsMessage) -> Option<DnsMessage> {
+        let [question] = request.questions.as_slice() else {
+            return None;
+        };
+
+        let answers = self
+            .hosts
+            .lookup(&question.name, question.record_type, question.class);
+        if answers.is_empty() {
+            return None;
+        }
+
+        println!("Answering from hosts file ({} answers)", answers.len());
+        let mut response = DnsMessage::new_answer_from_request(request, answers);
+        response.header.set_recursion_available(true);
+        Some(response)
+    }
+
+    // With --serve-stale, answer from a recently expired cache entry when the
+    // upstream can't be reached
+    fn answer_stale(&self, request: &DnsMessage) -> Option<DnsMessage> {
+        if !self.config.serve_stale {
+            return None;
+        }
+
+        let key = Self::cache_key(request)?;
+        let answers = self.cache.lock().unwrap().get_stale(&key, Instant::now())?;
+
+        println!(
+            "Upstream unavailable, serving stale answers ({})",
+            answers.len()
+        );
+        let mut response = DnsMessage::new_answer_from_request(request, answers);
+        response.header.set_recursion_available(true);
+        Some(response)
+    }
+
+    // Forward the query upstream, caching what comes back
+    fn answer_by_forwarding(&self, request: &DnsMessage) -> DnsMessage {
+        let deadline = self.request_deadline();
+
+        match self.resolve_by_forwarding(request, deadline) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Failed to forward query: {}", e);
+                Metrics::increment(&self.metrics.errors);
+
+                if let Some(response) = self.answer_stale(request) {
+                    return response;
+                }
+
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    println!("Request timeout exceeded, answering SERVFAIL");
+                } else {
+                    println!("Upstream unreachable, answering SERVFAIL");
+                }
+                DnsMessage::new_error_response(request, 2)
+            }
+        }
+    }
+
+    // All upstream attempts for a request share one overall budget
+    fn request_deadline(&self) -> Option<Instant> {
+        self.config
+            .request_timeout
+            .map(|timeout| Instant::now() + timeout)
+    }
+
+    // Forward a request upstream and post-process (and cache) the response. An
+    // upstream that can't be reached is an error, left to the caller to answer.
+    fn resolve_by_forwarding(
+        &self,
+        request: &DnsMessage,
+        deadline: Option<Instant>,
+    ) -> Result<DnsMessage, DnsError> {
+        let cache_key = Self::cache_key(request);
+
+        // Forward the query to the external DNS server, with our own client
+        // subnet if configured and our own cookie in place of the client's
+        let upstream_request = self.upstream_request(request);
+
+        // Held until the response (and any DNS64 follow-up) is in
+        let _permit = match &self.forward_slots {
+            Some(slots) => match slots.acquire_within(self.config.forward_wait) {
+                Some(permit) => Some(permit),
+                None => {
+                    println!("Too many forwards in flight, answering SERVFAIL");
+                    Metrics::increment(&self.metrics.forwards_limited);
+                    return Ok(DnsMessage::new_servfail_from_request(request));
+                }
+            },
+            None => None,
+        };
+
+        Metrics::increment(&self.metrics.forwards);
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "forward",
+            qname = %Self::query_name(request),
+            rcode = tracing::field::Empty
+        )
+        .entered();
+        let forwarded = DnsMessage::forward_query(
+            &upstream_request,
+            self.transport.as_ref(),
+            self.config.max_answers,
+            self.ids.as_ref(),
+            deadline,
+            self.config.multi_question == MultiQuestion::Split,
+        );
+        #[cfg(feature = "tracing")]
+        {
+            match &forwarded {
+                Ok(response) => {
+                    span.record("rcode", response.header.rcode());
+                    tracing::debug!("upstream answered");
+                }
+                Err(e) => tracing::debug!(error = %e, "forward failed"),
+            }
+            span.exit();
+        }
+
+        let mut forwarded_response = forwarded?;
+        println!("Received response from external DNS server");
+        println!("  Answers: {}", forwarded_response.header.ancount);
+
+        if !self.config.relay_upstream_errors {
+            if let Some(reason) = Self::upstream_rejected(request, &forwarded_response) {
+                // The upstream choked on the query we built, so the failure is ours
+                eprintln!("ERROR: upstream rejected our forwarded query: {}", reason);
+                Metrics::increment(&self.metrics.errors);
+
+                return Ok(DnsMessage::new_servfail_from_request(request));
+            }
+        }
+
+        // Don't pass on reserved bits the upstream shouldn't have set
+        forwarded_response.header.clear_reserved_bits();
+        self.take_upstream_cookie(&mut forwarded_response);
+        Self::relay_client_subnet(request, &mut forwarded_response);
+
+        if let Some(prefix) = &self.config.dns64 {
+            if let Some(synthesized) =
+                self.synthesize_dns64(&upstream_request, &forwarded_response, prefix, deadline)
+            {
+                forwarded_response = synthesized;
+            }
+        }
+
+        if self.config.minimal_responses {
+            forwarded_response.strip_to_minimal();
+        }
+
+        // Lowercased before caching, so cached answers come out the same
+        if self.config.canonicalize_names {
+            forwarded_response.canonicalize_owner_names();
+        }
+
+        // Duplicate records are redundant (RFC 2181 section 5.5)
+        forwarded_response.dedup_answers();
+        forwarded_response.order_answers();
+
+        // Protect clients and the cache from pathological upstream TTLs
+        forwarded_response.clamp_ttls(self.config.min_ttl, self.config.max_ttl);
+
+        // Remember successful answers for next time
+        if let Some(key) = cache_key {
+            // Partial (truncated) answers would be served as if complete,
+            // and answers fetched with CD set may not have been validated
+            if forwarded_response.header.rcode() == 0
+                && !forwarded_response.header.truncated()
+                && !request.header.checking_disabled()
+            {
+                let mut cache = self.cache.lock().unwrap();
+                cache.insert(
+                    key,
+                    forwarded_response.answers.clone(),
+                    forwarded_response.min_ttl().unwrap_or(0),
+                    Instant::now(),
+                );
+                println!("Cache holds {}/{} entries", cache.len(), cache.capacity());
+            }
+        }
+
+        Ok(forwarded_response)
+    }
+
+    // DNS64: when an AAAA query came back without AAAA records, look up the A
+    // records instead and answer with them embedded in the NAT64 prefix
+    fn synthesize_dns64(
+        &self,
+        request: &DnsMessage,
+        response: &DnsMessage,
+        prefix: &Dns64Prefix,
+        deadline: Option<Instant>,
+    ) -> Option<DnsMessage> {
+        let [question] = request.questions.as_slice() else {
+            return None;
+        };
+        if question.record_type != 28 || question.class != 1 || response.header.rcode() != 0 {
+            return None;
+        }
+        if response
+            .answers
+            .iter()
+            .any(|record| record.record_type == 28)
+        {
+            return None;
+        }
+
+        let mut a_request = request.clone();
+        a_request.questions[0].record_type = 1;
+        Metrics::increment(&self.metrics.forwards);
+        let a_response = DnsMessage::forward_query(
+            &a_request,
+            self.transport.as_ref(),
+            self.config.max_answers,
+            self.ids.as_ref(),
+            deadline,
+            self.config.multi_question == MultiQuestion::Split,
+        )
+        .ok()?;
+
+        // Keep the CNAME chain, turn A records into synthetic AAAA records
+        let answers: Vec<DnsRecord> = a_response
+            .answers
+            .into_iter()
+            .filter_map(|record| match record.record_type {
+                1 => {
+                    let ipv4 = record.rdata_as_ipv4()?;
+                    Some(DnsRecord {
+                        record_type: 28,
+                        rdata: prefix.synthesize(ipv4).octets().to_vec(),
+                        ..record
+                    })
+                }
+                5 => Some(record),
+                _ => None,
+            })
+            .collect();
+
+        if !answers.iter().any(|record| record.record_type == 28) {
+            return None;
+        }
+
+        println!("Synthesized {} DNS64 answers", answers.len());
+        let mut synthesized = response.clone();
+        synthesized.answers = answers;
+        synthesized.authorities.clear();
+        synthesized.sync_counts();
+        // Made-up records can't have been validated
+        synthesized.header.set_authentic_data(false);
+        Some(synthesized)
+    }
+
+    // Why an upstream response indicates it couldn't make sense of our query, if it does
+    fn upstream_rejected(request: &DnsMessage, response: &DnsMessage) -> Option<&'static str> {
+        if response.header.opcode() != request.header.opcode() {
+            return Some("response opcode does not match the query");
+        }
+
+        match response.header.rcode() {
+            1 => Some("FORMERR"),
+            4 => Some("NOTIMP"),
+            _ => None,
+        }
+    }
+
+    // The request as sent upstream. Only EDNS requests carry a cookie, so
+    // upstreams without EDNS never see an OPT record they might reject.
+    fn upstream_request(&self, request: &DnsMessage) -> DnsMessage {
+        let mut upstream_request = match &self.config.ecs {
+            Some(subnet) => Self::with_client_subnet(request, subnet),
+            None => request.clone(),
+        };
+
+        if let Some(opt) = upstream_request.opt_record_mut() {
+            let cookie = self.cookies.upstream_request_cookie();
+            dns_edns::set_option(&mut opt.rdata, OPTION_COOKIE, Some(cookie.to_option_data()));
+        }
+
+        upstream_request
+    }
+
+    // Learn the upstream's server cookie and drop it from the response: the
+    // client gets a cookie of ours instead
+    fn take_upstream_cookie(&self, response: &mut DnsMessage) {
+        let Some(opt) = response.opt_record_mut() else {
+            return;
+        };
+
+        if let Some(cookie) = dns_edns::find_option(&opt.rdata, OPTION_COOKIE)
+            .and_then(|data| Cookie::from_option_data(&data))
+        {
+            self.cookies.learn_upstream_cookie(&cookie);
+        }
+        dns_edns::set_option(&mut opt.rdata, OPTION_COOKIE, None);
+    }
+
+    // Copy of the request carrying the given EDNS Client Subnet option, adding an
+    // OPT record if the client didn't send one
+    fn with_client_subnet(request: &DnsMessage, subnet: &ClientSubnet) -> DnsMessage {
+        let mut upstream_request = request.clone();
+
+        if upstream_request.opt_record().is_none() {
+            // Advertise only the classic UDP payload size the client can take
+            upstream_request
+                .additionals
+                .push(OptRecord::new(512).build());
+            upstream_request.sync_counts();
+        }
+
+        if let Some(opt) = upstream_request.opt_record_mut() {
+            dns_edns::set_option(
+                &mut opt.rdata,
+                OPTION_CLIENT_SUBNET,
+                Some(subnet.to_option_data()),
+            );
+        }
+
+        upstream_request
+    }
+
+    // Make the relayed EDNS data match what the client sent: no OPT for clients
+    // without EDNS, and the client's own subnet echoed with the upstream's scope
+    fn relay_client_subnet(request: &DnsMessage, response: &mut DnsMessage) {
+        let client_opt = match request.opt_record() {
+            Some(opt) => opt,
+            None => {
+                response
+                    .additionals
+                    .retain(|record| record.record_type != 41);
+                response.sync_counts();
+                return;
+            }
+        };
+
+        let client_subnet = dns_edns::find_option(&client_opt.rdata, OPTION_CLIENT_SUBNET)
+            .and_then(|data| ClientSubnet::from_option_data(&data));
+        let upstream_scope = response
+            .opt_record()
+            .and_then(|opt| dns_edns::find_option(&opt.rdata, OPTION_CLIENT_SUBNET))
+            .and_then(|data| ClientSubnet::from_option_data(&data))
+            .map(|subnet| subnet.scope_prefix);
+
+        if let Some(opt) = response.opt_record_mut() {
+            let echoed = client_subnet.map(|mut subnet| {
+                subnet.scope_prefix = upstream_scope.unwrap_or(0);
+                subnet.to_option_data()
+            });
+            dns_edns::set_option(&mut opt.rdata, OPTION_CLIENT_SUBNET, echoed);
+        }
+    }
+
+    // An iterative query (RD=0) we couldn't answer locally isn't forwarded: names
+    // under a delegation in our zone get a referral to its name servers, anything
+    // else is refused since we aren't authoritative for it
+    fn answer_without_recursion(&self, request: &DnsMessage, client: IpAddr) -> DnsMessage {
+        let delegation = match request.questions.as_slice() {
+            [question] => self.with_zone_for(client, |zone| {
+                zone.find_delegation(&question.name, question.class)
+            }),
+            _ => None,
+        };
+
+        let Some((ns_records, glue)) = delegation else {
+            println!("Recursion not desired and no local answer, refusing");
+            return DnsMessage::new_refused_from_request(request);
+        };
+
+        println!(
+            "Referring to {} name servers ({} glue records)",
+            ns_records.len(),
+            glue.len()
+        );
+        let mut response = DnsMessage::new_answer_from_request(request, Vec::new());
+        response.authorities = ns_records;
+        response.additionals = glue;
+        response.sync_counts();
+        response
+    }
+
+    // Apply a dynamic update to the local zone, if updates are allowed at all
+    fn answer_update(&self, request: &DnsMessage) -> DnsMessage {
+        if !self.config.allow_update {
+            println!("Dynamic update refused (--allow-update not set)");
+            return DnsMessage::new_notimp_from_request(request);
+        }
+
+        let rcode = match update::apply(&mut self.zone.write().unwrap(), request) {
+            Ok(added) => {
+                println!("Dynamic update added {} records to the zone", added);
+                0
+            }
+            Err(rcode) => {
+                println!("Dynamic update rejected with RCODE {}", rcode);
+                rcode
+            }
+        };
+
+        let mut response = DnsMessage::new_answer_from_request(request, Vec::new());
+        response.header.set_rcode(rcode as u16);
+        response
+    }
+
+    fn is_transfer(request: &DnsMessage) -> bool {
+        matches!(request.questions.as_slice(), [question] if question.record_type == QTYPE_AXFR)
+    }
+
+    // Stream the zone named by an AXFR query, if the client is allowed to
+    // transfer it (--allow-transfer). Transfers need TCP, and we only transfer
+    // zones we hold the SOA for.
+    fn answer_transfer(
+        &self,
+        request: &DnsMessage,
+        client: IpAddr,
+        over_tcp: bool,
+        request_mac: Option<Vec<u8>>,
+    ) -> Vec<BytesMut> {
+        let question = &request.questions[0];
+        let refuse = |rcode| {
+            let response = DnsMessage::new_error_response(request, rcode);
+            vec![self.finish_response(request, response, client, over_tcp, request_mac.clone())]
+        };
+
+        if !over_tcp {
+            println!("Refusing zone transfer over UDP");
+            return refuse(5);
+        }
+        if !self
+            .config
+            .allow_transfer
+            .iter()
+            .any(|network| network.contains(client))
+        {
+            println!("Zone transfer refused for {}", client);
+            return refuse(5);
+        }
+
+        let records =
+            self.with_zone_for(client, |zone| zone.transfer(&question.name, question.class));
+        let Some(records) = records else {
+            println!("Not authoritative for the requested zone transfer");
+            return refuse(9);
+        };
+        println!("Transferring {} records to {}", records.len(), client);
+
+        // Pack the records into as few messages as the size budget allows
+        let mut messages = Vec::new();
+        let mut answers = Vec::new();
+        let mut size = 0;
+        for record in records {
+            let record_size = record.to_bytes().len();
+            if !answers.is_empty() && size + record_size > TRANSFER_MESSAGE_SIZE {
+                messages.push(Self::transfer_message(
+                    request,
+                    std::mem::take(&mut answers),
+                ));
+                size = 0;
+            }
+            size += record_size;
+            answers.push(record);
+        }
+        messages.push(Self::transfer_message(request, answers));
+
+        let (Some(key), Some(request_mac)) = (&self.config.tsig_key, request_mac) else {
+            return messages;
+        };
+        match dns_tsig::sign_stream(&messages, key, &request_mac, Self::unix_now()) {
+            Ok(signed) => signed
+                .iter()
+                .map(|bytes| BytesMut::from(&bytes[..]))
+                .collect(),
+            Err(e) => {
+                eprintln!("Failed to sign zone transfer: {}", e);
+                messages
+            }
+        }
+    }
+
+    fn transfer_message(request: &DnsMessage, answers: Vec<DnsRecord>) -> BytesMut {
+        let mut message = DnsMessage::new_answer_from_request(request, answers);
+        message.header.set_authoritative(true);
+        message.to_bytes()
+    }
+
+    // Run `f` against the zone this client sees: its view's zone if it is in a
+    // view's network, the default zone otherwise
+    fn with_zone_for<T>(&self, client: IpAddr, f: impl FnOnce(&Zone) -> T) -> T {
+        match self.views.iter().find(|view| view.network.contains(client)) {
+            Some(view) => f(&view.zone),
+            None => f(&self.zone.read().unwrap()),
+        }
+    }
+
+    // Answer single-question requests authoritatively from the local zone
+    fn answer_from_zone(&self, request: &DnsMessage, client: IpAddr) -> Option<DnsMessage> {
+        let [question] = request.questions.as_slice() else {
+            return None;
+        };
+
+        if let Some(response) = self.answer_from_aname(request, client) {
+            return Some(response);
+        }
+
+        let Some((answers, additionals)) = self.with_zone_for(client, |zone| {
+            let mut answers =
+                zone.lookup_following_cnames(&question.name, question.record_type, question.class);
+
+            // Names below a DNAME are answered with the DNAME and a synthesized CNAME
+            if answers.is_empty() {
+                answers = zone.synthesize_dname(&question.name, question.class)?;
+            }
+
+            // Save the client a lookup by including the addresses of NS/MX/SRV targets
+            let additionals = if self.config.minimal_responses {
+                Vec::new()
+            } else {
+                zone.target_addresses(&answers, question.class)
+            };
+
+            Some((answers, additionals))
+        }) else {
+            return self.deny_from_signed_zone(request, client);
+        };
+
+        println!(
+            "Answering from local zone ({} answers, {} additional)",
+            answers.len(),
+            additionals.len()
+        );
+        let mut response = DnsMessage::new_answer_from_request(request, answers);
+        response.additionals = additionals;
+        response.sync_counts();
+        response.header.set_authoritative(true);
+        Some(response)
+    }
+
+    // Authoritative NXDOMAIN/NODATA for a name in a signed zone, with the SOA
+    // and, for clients that set the DO bit, the NSEC records proving it. Names in
+    // unsigned zones fall through to the other sources as before.
+    fn deny_from_signed_zone(&self, request: &DnsMessage, client: IpAddr) -> Option<DnsMessage> {
+        let question = &request.questions[0];
+        let denial =
+            self.with_zone_for(client, |zone| zone.deny(&question.name, question.class))?;
+
+        println!(
+            "Denying from signed zone ({} NSEC records)",
+            denial.nsec.len()
+        );
+        let mut response = if denial.name_exists {
+            DnsMessage::new_nodata_from_request(request, Some(denial.soa))
+        } else {
+            let mut response = DnsMessage::new_nxdomain_from_request(request);
+            response.authorities.push(denial.soa);
+            response
+        };
+        if request.dnssec_ok() {
+            response.authorities.extend(denial.nsec);
+        }
+        response.sync_counts();
+        response.header.set_authoritative(true);
+        Some(response)
+    }
+
+    // Answer for addresses inside a --reverse-zone network: the configured PTR
+    // name, NODATA for other types, NXDOMAIN for addresses without a name
+    fn answer_from_reverse_zone(&self, request: &DnsMessage) -> Option<DnsMessage> {
+        let [question] = request.questions.as_slice() else {
+            return None;
+        };
+        if question.class != 1 {
+            return None;
+        }
+
+        let address = reverse::address_from_reverse_name(&question.name)?;
+        let reverse_zone = self
+            .reverse_zones
+            .iter()
+            .find(|reverse_zone| reverse_zone.contains(address))?;
+
+        let mut response = match (reverse_zone.name_for(address), question.record_type) {
+            (Some(name), 12) => {
+                println!("Answering PTR for {} from reverse zone", address);
+                let mut record = DnsRecord::new_ptr(question.name.clone(), name.to_vec());
+                record.ttl = DEFAULT_ZONE_TTL;
+                DnsMessage::new_answer_from_request(request, vec![record])
+            }
+            (Some(_), _) => DnsMessage::new_nodata_from_request(request, None),
+            (None, _) => DnsMessage::new_nxdomain_from_request(request),
+        };
+        response.header.set_authoritative(true);
+        Some(response)
+    }
+
+    // A/AAAA queries for a name with an ANAME (and no records of that type) get
+    // the ANAME target's addresses under the queried name. The target is resolved
+    // from the cache or upstream like any forwarded query, so it stays cached for
+    // the target's TTL.
+    fn answer_from_aname(&self, request: &DnsMessage, client: IpAddr) -> Option<DnsMessage> {
+        let question = &request.questions[0];
+        if question.record_type != 1 && question.record_type != 28 {
+            return None;
+        }
+
+        let (target, soa) = self.with_zone_for(client, |zone| {
+            if !zone
+                .lookup(&question.name, question.record_type, question.class)
+                .is_empty()
+            {
+                return None;
+            }
+            let aname = zone
+                .lookup(&question.name, ANAME_TYPE, question.class)
+                .into_iter()
+                .next()?;
+            Some((aname.rdata, zone.soa(&question.name, question.class)))
+        })?;
+
+        let target = DnsQuestion::decode_domain_name(&target).ok()?;
+        println!("Resolving ANAME target {}", target);
+        let target_request = DnsMessage::query(self.ids.as_ref(), &target, question.record_type);
+        // A target that can't be resolved makes the apex SERVFAIL rather than
+        // answer authoritatively with nothing
+        let resolved = match self.answer_from_cache(&target_request) {
+            Some(response) => Some(response),
+            None if self.chain.sources().contains(&Source::Forward) => {
+                match self.resolve_by_forwarding(&target_request, self.request_deadline()) {
+                    Ok(response) => Some(response),
+                    Err(e) => {
+                        eprintln!("Failed to resolve ANAME target: {}", e);
+                        Metrics::increment(&self.metrics.errors);
+                        Some(DnsMessage::new_servfail_from_request(&target_request))
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let answers: Vec<DnsRecord> = resolved
+            .iter()
+            .flat_map(|response| &response.answers)
+            .filter(|record| record.record_type == question.record_type)
+            .map(|record| DnsRecord {
+                name: question.name.clone(),
+                ..record.clone()
+            })
+            .collect();
+
+        let mut response = match resolved {
+            Some(resolved) if answers.is_empty() && resolved.header.rcode() == 2 => {
+                DnsMessage::new_servfail_from_request(request)
+            }
+            _ if answers.is_empty() => DnsMessage::new_nodata_from_request(request, soa),
+            _ => DnsMessage::new_answer_from_request(request, answers),
+        };
+        response.header.set_authoritative(true);
+        Some(response)
+    }
+
+    // Check whether every question asks for a record type we refuse to resolve
+    fn is_blocked_type(&self, request: &DnsMessage) -> bool {
+        !request.questions.is_empty()
+            && request
+                .questions
+                .iter()
+                .all(|question| self.config.blocked_types.contains(&question.record_type))
+    }
+
+    // Whether any question asks about a blocklisted domain
+    fn is_blocked_domain(&self, request: &DnsMessage) -> bool {
+        request
+            .questions
+            .iter()
+            .any(|question| self.blocklist.contains(&question.name))
+    }
+
+    // Answer a blocklisted query with the sinkhole address of the matching family
+    // (NODATA for other types), or decline it if no sinkhole is configured
+    fn answer_blocked_domain(&self, request: &DnsMessage) -> Option<DnsMessage> {
+        if self.config.sinkhole.is_empty() {
+            println!("Domain blocked, declining");
+            return self.decline(request);
+        }
+
+        let answers = request
+            .questions
+            .iter()
+            .flat_map(|question| {
+                self.config.sinkhole.iter().filter_map(move |address| {
+                    match (address, question.record_type) {
+                        (IpAddr::V4(ipv4), 1) => Some(DnsRecord::new(question.name.clone(), *ipv4)),
+                        (IpAddr::V6(ipv6), 28) => {
+                            Some(DnsRecord::new_aaaa(question.name.clone(), *ipv6))
+                        }
+                        _ => None,
+                    }
+                })
+            })
+            .collect();
+
+        println!("Domain blocked, answering with the sinkhole");
+        let mut response = DnsMessage::new_answer_from_request(request, answers);
+        response.header.set_recursion_available(true);
+        Some(response)
+    }
+
+    // Apply the first --rule matching the query name. None when no rule matches
+    // or the rule says to resolve the name as usual.
+    fn answer_from_rules(&self, request: &DnsMessage) -> Option<DnsMessage> {
+        if self.config.rules.is_empty() {
+            return None;
+        }
+        let [question] = request.questions.as_slice() else {
+            return None;
+        };
+        let name = question.decode_name().ok()?;
+
+        let action = self.config.rules.evaluate(&name)?;
+        println!("Rule matched {}: {:?}", name, action);
+
+        let mut response = match action {
+            RuleAction::Refuse => DnsMessage::new_refused_from_request(request),
+            RuleAction::NxDomain => DnsMessage::new_nxdomain_from_request(request),
+            RuleAction::Forward => return None,
+            RuleAction::Answer(address) => {
+                let answer = match (address, question.record_type) {
+                    (IpAddr::V4(ipv4), 1) => Some(DnsRecord::new(question.name.clone(), ipv4)),
+                    (IpAddr::V6(ipv6), 28) => {
+                        Some(DnsRecord::new_aaaa(question.name.clone(), ipv6))
+                    }
+                    _ => None,
+                };
+                DnsMessage::new_answer_from_request(request, answer.into_iter().collect())
+            }
+        };
+        response.header.set_recursion_available(true);
+        Some(response)
+    }
+
+    // Decline to answer a query according to the configured drop policy
+    fn decline(&self, request: &DnsMessage) -> Option<DnsMessage> {
+        match self.config.drop_policy {
+            DropPolicy::Refuse => Some(DnsMessage::new_refused_from_request(request)),
+            DropPolicy::Silent => {
+                println!("Silently dropping query {}", request.header.id);
+                None
+            }
+        }
+    }
+
+    // Answer CH-class version.bind / version.server queries locally; None when
+    // this isn't a version query at all
+    fn answer_version_query(&self, request: &DnsMessage) -> Option<DnsMessage> {
+        let question = request.questions.first()?;
+
+        // Only CH (Chaos) class queries are version queries
+        if question.class != 3 {
+            return None;
+        }
+
+        let name = question.decode_name().ok()?.to_ascii_lowercase();
+        if name != "version.bind" && name != "version.server" {
+            return None;
+        }
+
+        // Operators who don't want to be fingerprinted refuse these outright
+        if self.config.hide_version {
+            println!("Refusing version query for {}", name);
+            return Some(DnsMessage::new_refused_from_request(request));
+        }
+
+        // Answer TXT and ANY queries, anything else gets an empty answer
+        let answers = if question.record_type == 16 || question.record_type == 255 {
+            vec![DnsRecord::new_txt(
+                question.name.clone(),
+                3,
+                0,
+                VERSION_TEXT,
+            )]
+        } else {
+            Vec::new()
+        };
+
+        Some(DnsMessage::new_answer_from_request(request, answers))
+    }
+
+    // Log the parsed request with details about each question
+    fn log_request(request: &DnsMessage) {
+        println!("Parsed DNS message:");
+        println!("  ID: {}", request.header.id);
+        println!("  Questions: {}", request.header.qdcount);
+
+        // Log the domain names with additional details
+        for (i, question) in request.questions.iter().enumerate() {
+            if let Ok(domain) = question.decode_name() {
+                println!(
+                    "  Question {}: {} (Type: {}, Class: {}, Name length: {} bytes)",
+                    i + 1,
+                    domain,
+                    question.record_type,
+                    question.class,
+                    question.name.len()
+                );
+            } else {
+                println!(
+                    "  Question {}: <failed to decode> (Type: {}, Class: {})",
+                    i + 1,
+                    question.record_type,
+                    question.class
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::STALE_ANSWER_TTL;
+    use crate::dns::dns_id::FixedIds;
+    use crate::transport::UdpTransport;
+    use crate::upstream::{UpstreamSelector, UpstreamStrategy};
+    use crate::views::ClientNetwork;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::Arc;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    type Reply = dyn Fn(&DnsMessage) -> Option<DnsMessage> + Send + Sync;
+
+    // A stand-in upstream: answers each query with `reply` (None means it can't be
+    // reached) and keeps every query it was sent
+    #[derive(Clone)]
+    struct MockUpstream {
+        queries: Arc<Mutex<Vec<DnsMessage>>>,
+        reply: Arc<Reply>,
+    }
+
+    impl MockUpstream {
+        fn new(reply: impl Fn(&DnsMessage) -> Option<DnsMessage> + Send + Sync + 'static) -> Self {
+            MockUpstream {
+                queries: Arc::new(Mutex::new(Vec::new())),
+                reply: Arc::new(reply),
+            }
+        }
+
+        // Answers every query with one A record for the queried name
+        fn answering(address: Ipv4Addr) -> Self {
+            Self::new(move |query| {
+                let answer = DnsRecord::new(query.questions[0].name.clone(), address);
+                Some(upstream_answer(query, vec![answer]))
+            })
+        }
+
+        fn unreachable() -> Self {
+            Self::new(|_| None)
+        }
+
+        fn queries(&self) -> Vec<DnsMessage> {
+            self.queries.lock().unwrap().clone()
+        }
+    }
+
+    impl Transport for MockUpstream {
+        fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, DnsError> {
+            let query = DnsMessage::from_bytes(query)?;
+            self.queries.lock().unwrap().push(query.clone());
+            match (self.reply)(&query) {
+                Some(response) => Ok(response.to_bytes().to_vec()),
+                None => Err(io::Error::new(ErrorKind::TimedOut, "mock upstream down").into()),
+            }
+        }
+
+        fn describe(&self) -> String {
+            String::from("mock")
+        }
+    }
+
+    // An upstream's answer to a query, with RA set as a recursive resolver would
+    fn upstream_answer(query: &DnsMessage, answers: Vec<DnsRecord>) -> DnsMessage {
+        let mut response = DnsMessage::new_answer_from_request(query, answers);
+        response.header.set_recursion_available(true);
+        response
+    }
+
+    fn server(args: &[&str], upstream: &MockUpstream) -> Server {
+        server_with_zone(args, upstream, "")
+    }
+
+    fn server_with_zone(args: &[&str], upstream: &MockUpstream, zone: &str) -> Server {
+        let args: Vec<String> = std::iter::once("server")
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect();
+        let config = Config::from_args(&args).unwrap();
+        let zone = Zone::parse(zone, &config.zone_ttls).unwrap();
+
+        Server::new(
+            config,
+            Box::new(upstream.clone()),
+            zone,
+            Hosts::new(),
+            Blocklist::new(),
+        )
+    }
+
+    fn query(name: &str, record_type: u16) -> DnsMessage {
+        DnsMessage::query(&FixedIds::new(vec![0x1234]), name, record_type)
+    }
+
+    // Send a request as a UDP client would, returning the parsed response if any
+    fn ask(server: &Server, request: &DnsMessage) -> Option<DnsMessage> {
+        let response = server.handle_query(&request.to_bytes(), CLIENT)?;
+        Some(DnsMessage::from_bytes(&response).unwrap())
+    }
+
+    fn version_query(name: &str) -> DnsMessage {
+        let mut request = query(name, 16);
+        request.questions[0].class = 3;
+        request
+    }
+
+    #[test]
+    fn version_queries_are_answered_in_chaos_class() {
+        let upstream = MockUpstream::unreachable();
+        let server = server(&[], &upstream);
+
+        for name in ["version.bind", "VERSION.SERVER"] {
+            let response = ask(&server, &version_query(name)).unwrap();
+            assert_eq!(response.header.rcode(), 0);
+            assert_eq!(response.answers.len(), 1);
+            assert_eq!(response.answers[0].class, 3);
+            assert!(response.answers[0].rdata_to_string().contains(VERSION_TEXT));
+        }
+        assert!(upstream.queries().is_empty());
+    }
+
+    #[test]
+    fn hidden_version_queries_are_refused() {
+        let upstream = MockUpstream::unreachable();
+
+        for policy in ["refuse", "silent"] {
+            let server = server(&["--hide-version", "--drop-policy", policy], &upstream);
+            let response = ask(&server, &version_query("version.bind")).unwrap();
+            assert_eq!(response.header.rcode(), 5);
+            assert!(response.answers.is_empty());
+            assert_eq!(response.questions.len(), 1);
+        }
+    }
+
+    #[test]
+    fn version_names_in_class_in_are_not_special() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&["--hide-version"], &upstream);
+
+        let response = ask(&server, &query("version.bind", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 0);
+        assert_eq!(upstream.queries().len(), 1);
+    }
+
+    fn blocking(mut server: Server, blocklist: &str) -> Server {
+        server.blocklist = Blocklist::parse(blocklist);
+        server
+    }
+
+    #[test]
+    fn refuse_policy_answers_declined_queries_with_refused() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = blocking(server(&["--drop-policy", "refuse"], &upstream), "ads.test");
+
+        let response = ask(&server, &query("ads.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 5);
+        assert_eq!(response.header.id, 0x1234);
+        assert_eq!(response.questions.len(), 1);
+        assert!(upstream.queries().is_empty());
+    }
+
+    #[test]
+    fn silent_policy_drops_declined_queries() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = blocking(server(&["--drop-policy", "silent"], &upstream), "ads.test");
+
+        assert!(ask(&server, &query("ads.test", 1)).is_none());
+        assert!(upstream.queries().is_empty());
+
+        // Queries that aren't declined are still answered
+        assert!(ask(&server, &query("example.test", 1)).is_some());
+    }
+
+    #[test]
+    fn blocked_types_get_nodata_without_forwarding() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&["--block-type", "28"], &upstream);
+
+        let response = ask(&server, &query("example.test", 28)).unwrap();
+        assert_eq!(response.header.rcode(), 0);
+        assert_eq!(response.header.ancount, 0);
+        assert!(response.answers.is_empty());
+        assert!(upstream.queries().is_empty());
+
+        // Other types are still forwarded
+        let response = ask(&server, &query("example.test", 1)).unwrap();
+        assert_eq!(response.header.ancount, 1);
+        assert_eq!(upstream.queries().len(), 1);
+    }
+
+    #[test]
+    fn maintenance_runs_without_traffic() {
+        let upstream = MockUpstream::unreachable();
+        let server = server(&[], &upstream);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let started = Instant::now();
+        let mut runs = 0;
+        server
+            .serve(&socket, || {
+                runs += 1;
+                server.request_shutdown();
+            })
+            .unwrap();
+
+        assert_eq!(runs, 1);
+        assert!(started.elapsed() >= MAINTENANCE_INTERVAL);
+    }
+
+    #[test]
+    fn cached_any_answers_are_kept_apart_from_specific_types() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&[], &upstream);
+
+        ask(&server, &query("example.test", 255)).unwrap();
+        ask(&server, &query("example.test", 1)).unwrap();
+        assert_eq!(upstream.queries().len(), 2);
+
+        // Each is now answered from its own cache entry
+        ask(&server, &query("example.test", 255)).unwrap();
+        ask(&server, &query("example.test", 1)).unwrap();
+        assert_eq!(upstream.queries().len(), 2);
+    }
+
+    fn client_subnet_of(message: &DnsMessage) -> Option<ClientSubnet> {
+        let opt = message.opt_record()?;
+        dns_edns::find_option(&opt.rdata, OPTION_CLIENT_SUBNET)
+            .and_then(|data| ClientSubnet::from_option_data(&data))
+    }
+
+    #[test]
+    fn configured_client_subnet_is_sent_upstream() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&["--ecs", "192.0.2.0/24"], &upstream);
+
+        let response = ask(&server, &query("example.test", 1)).unwrap();
+
+        let sent = &upstream.queries()[0];
+        let opt = sent.opt_record().unwrap();
+        assert_eq!(
+            dns_edns::find_option(&opt.rdata, OPTION_CLIENT_SUBNET),
+            Some(vec![0, 1, 24, 0, 192, 0, 2])
+        );
+        // A client without EDNS gets no OPT record back
+        assert!(response.opt_record().is_none());
+    }
+
+    #[test]
+    fn upstream_scope_is_relayed_with_the_clients_subnet() {
+        let upstream = MockUpstream::new(|query| {
+            let mut response = upstream_answer(query, Vec::new());
+            let mut subnet = ClientSubnet::from_cidr("198.51.100.0/24").unwrap();
+            subnet.scope_prefix = 16;
+            response
+                .additionals
+                .push(OptRecord::new(1232).client_subnet(&subnet).build());
+            response.sync_counts();
+            Some(response)
+        });
+        let server = server(&[], &upstream);
+
+        let mut request = query("example.test", 1);
+        let subnet = ClientSubnet::from_cidr("198.51.100.0/24").unwrap();
+        request
+            .additionals
+            .push(OptRecord::new(1232).client_subnet(&subnet).build());
+        request.sync_counts();
+
+        let response = ask(&server, &request).unwrap();
+        let echoed = client_subnet_of(&response).unwrap();
+        assert_eq!(echoed.address, subnet.address);
+        assert_eq!(echoed.source_prefix, 24);
+        assert_eq!(echoed.scope_prefix, 16);
+    }
+
+    // The address of the first answer, or None for an empty answer
+    fn first_address(response: &DnsMessage) -> Option<Ipv4Addr> {
+        response.answers.first().and_then(DnsRecord::rdata_as_ipv4)
+    }
+
+    // A server with www.example.test in whichever of the zone, hosts file and
+    // cache are asked for, each with its own address
+    fn overlapping(args: &[&str], upstream: &MockUpstream, sources: &[Source]) -> Server {
+        let zone = if sources.contains(&Source::Zone) {
+            "www.example.test A 192.0.2.10\n"
+        } else {
+            ""
+        };
+        let mut server = server_with_zone(args, upstream, zone);
+
+        if sources.contains(&Source::Hosts) {
+            server.hosts = Hosts::parse("192.0.2.20 www.example.test\n").unwrap();
+        }
+        if sources.contains(&Source::Cache) {
+            let name = DnsQuestion::encode_domain_name("www.example.test");
+            let answer = DnsRecord::new(name.clone(), Ipv4Addr::new(192, 0, 2, 30));
+            server.cache.lock().unwrap().insert(
+                CacheKey::new(&name, 1, 1),
+                vec![answer],
+                300,
+                Instant::now(),
+            );
+        }
+        server
+    }
+
+    #[test]
+    fn sources_are_consulted_in_precedence_order() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 40));
+        let cases = [
+            (
+                &[Source::Zone, Source::Hosts, Source::Cache][..],
+                Ipv4Addr::new(192, 0, 2, 10),
+            ),
+            (
+                &[Source::Hosts, Source::Cache],
+                Ipv4Addr::new(192, 0, 2, 20),
+            ),
+            (&[Source::Cache], Ipv4Addr::new(192, 0, 2, 30)),
+            (&[], Ipv4Addr::new(192, 0, 2, 40)),
+        ];
+
+        for (sources, expected) in cases {
+            let server = overlapping(&[], &upstream, sources);
+            let response = ask(&server, &query("www.example.test", 1)).unwrap();
+            assert_eq!(first_address(&response), Some(expected), "{:?}", sources);
+        }
+        assert_eq!(upstream.queries().len(), 1);
+    }
+
+    #[test]
+    fn local_only_still_answers_from_the_cache() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 40));
+
+        let server = overlapping(&["--local-only"], &upstream, &[Source::Cache]);
+        let response = ask(&server, &query("www.example.test", 1)).unwrap();
+        assert_eq!(first_address(&response), Some(Ipv4Addr::new(192, 0, 2, 30)));
+
+        let server = overlapping(&["--local-only"], &upstream, &[]);
+        let response = ask(&server, &query("www.example.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 3);
+        assert!(upstream.queries().is_empty());
+    }
+
+    // An upstream whose answers come with an NS record in authority, its glue in
+    // additional and an OPT record
+    fn upstream_with_extras() -> MockUpstream {
+        MockUpstream::new(|query| {
+            let name = query.questions[0].name.clone();
+            let answer = DnsRecord::new(name, Ipv4Addr::new(192, 0, 2, 1));
+            let mut response = upstream_answer(query, vec![answer]);
+            response
+                .authorities
+                .push(DnsRecord::from_zone_line("", "example.test. NS ns1.example.test.").unwrap());
+            response.additionals.extend([
+                DnsRecord::from_zone_line("", "ns1.example.test. A 192.0.2.53").unwrap(),
+                OptRecord::new(1232).build(),
+            ]);
+            response.sync_counts();
+            Some(response)
+        })
+    }
+
+    fn edns_query(name: &str, record_type: u16) -> DnsMessage {
+        let mut request = query(name, record_type);
+        request.additionals.push(OptRecord::new(1232).build());
+        request.sync_counts();
+        request
+    }
+
+    #[test]
+    fn minimal_responses_keep_only_answers_and_opt() {
+        let upstream = upstream_with_extras();
+        let server = server(&["--minimal-responses"], &upstream);
+
+        let response = ask(&server, &edns_query("www.example.test", 1)).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert!(response.authorities.is_empty());
+        assert_eq!(response.additionals.len(), 1);
+        assert!(response.opt_record().is_some());
+        assert_eq!((response.header.nscount, response.header.arcount), (0, 1));
+    }
+
+    #[test]
+    fn full_responses_are_relayed_by_default() {
+        let upstream = upstream_with_extras();
+        let server = server(&[], &upstream);
+
+        let response = ask(&server, &edns_query("www.example.test", 1)).unwrap();
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.additionals.len(), 2);
+    }
+
+    #[test]
+    fn relayed_responses_have_z_cleared() {
+        let upstream = MockUpstream::new(|query| {
+            let answer = DnsRecord::new(query.questions[0].name.clone(), Ipv4Addr::LOCALHOST);
+            let mut response = upstream_answer(query, vec![answer]);
+            response.header.flags |= 0x0040;
+            Some(response)
+        });
+        let server = server(&[], &upstream);
+
+        let response = ask(&server, &query("example.test", 1)).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.header.flags & 0x0040, 0);
+    }
+
+    fn upstream_failing_with(rcode: u8) -> MockUpstream {
+        MockUpstream::new(move |query| Some(DnsMessage::new_error_response(query, rcode)))
+    }
+
+    #[test]
+    fn upstream_formerr_and_notimp_become_servfail() {
+        for rcode in [1, 4] {
+            let upstream = upstream_failing_with(rcode);
+            let server = server(&[], &upstream);
+
+            let response = ask(&server, &query("example.test", 1)).unwrap();
+            assert_eq!(response.header.rcode(), 2, "upstream rcode {}", rcode);
+            assert_eq!(response.header.id, 0x1234);
+        }
+    }
+
+    #[test]
+    fn upstream_errors_can_be_relayed_as_is() {
+        let upstream = upstream_failing_with(1);
+        let server = server(&["--relay-upstream-errors"], &upstream);
+
+        let response = ask(&server, &query("example.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 1);
+    }
+
+    #[test]
+    fn other_upstream_errors_are_relayed() {
+        let upstream = upstream_failing_with(5);
+        let server = server(&[], &upstream);
+
+        let response = ask(&server, &query("example.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 5);
+    }
+
+    #[test]
+    fn sinkholed_domains_get_the_sinkhole_address() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let args = ["--sinkhole", "0.0.0.0", "--sinkhole", "::"];
+        let server = blocking(server(&args, &upstream), "*.doubleclick.test");
+
+        let response = ask(&server, &query("ad.doubleclick.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 0);
+        assert_eq!(first_address(&response), Some(Ipv4Addr::UNSPECIFIED));
+
+        let response = ask(&server, &query("ad.doubleclick.test", 28)).unwrap();
+        assert_eq!(
+            response.answers[0].rdata_as_ipv6(),
+            Some(Ipv6Addr::UNSPECIFIED)
+        );
+
+        // Other types get no data
+        let response = ask(&server, &query("ad.doubleclick.test", 16)).unwrap();
+        assert_eq!(response.header.rcode(), 0);
+        assert!(response.answers.is_empty());
+
+        assert!(upstream.queries().is_empty());
+    }
+
+    // An upstream with A records for every name and AAAA records only for
+    // dual.test; other AAAA queries get NODATA
+    fn ipv4_only_upstream() -> MockUpstream {
+        MockUpstream::new(|query| {
+            let question = &query.questions[0];
+            let answers = match question.record_type {
+                1 => vec![DnsRecord::new(
+                    question.name.clone(),
+                    Ipv4Addr::new(192, 0, 2, 33),
+                )],
+                28 if question.name == DnsQuestion::encode_domain_name("dual.test") => {
+                    vec![DnsRecord::new_aaaa(
+                        question.name.clone(),
+                        "2001:db8::1".parse().unwrap(),
+                    )]
+                }
+                _ => Vec::new(),
+            };
+            Some(upstream_answer(query, answers))
+        })
+    }
+
+    #[test]
+    fn dns64_synthesizes_aaaa_from_a() {
+        let upstream = ipv4_only_upstream();
+        let server = server(&["--dns64", "64:ff9b::/96"], &upstream);
+
+        let response = ask(&server, &query("v4only.test", 28)).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].record_type, 28);
+        assert_eq!(
+            response.answers[0].rdata_as_ipv6(),
+            Some("64:ff9b::c000:221".parse().unwrap())
+        );
+        assert_eq!(upstream.queries().len(), 2);
+    }
+
+    #[test]
+    fn dns64_keeps_real_aaaa_records() {
+        let upstream = ipv4_only_upstream();
+        let server = server(&["--dns64", "64:ff9b::/96"], &upstream);
+
+        let response = ask(&server, &query("dual.test", 28)).unwrap();
+        assert_eq!(
+            response.answers[0].rdata_as_ipv6(),
+            Some("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(upstream.queries().len(), 1);
+    }
+
+    #[test]
+    fn upstream_ad_bit_is_relayed() {
+        let upstream = MockUpstream::new(|query| {
+            let answers = vec![DnsRecord::new(
+                query.questions[0].name.clone(),
+                Ipv4Addr::new(192, 0, 2, 5),
+            )];
+            let mut response = upstream_answer(query, answers);
+            response.header.set_authentic_data(true);
+            Some(response)
+        });
+        let server = server(&[], &upstream);
+
+        let response = ask(&server, &query("signed.test", 1)).unwrap();
+        assert!(response.header.authentic_data());
+    }
+
+    #[test]
+    fn dns64_answers_are_never_authentic() {
+        let upstream = MockUpstream::new(|query| {
+            let question = &query.questions[0];
+            let answers = match question.record_type {
+                1 => vec![DnsRecord::new(
+                    question.name.clone(),
+                    Ipv4Addr::new(192, 0, 2, 33),
+                )],
+                _ => Vec::new(),
+            };
+            let mut response = upstream_answer(query, answers);
+            response.header.set_authentic_data(true);
+            Some(response)
+        });
+        let server = server(&["--dns64", "64:ff9b::/96"], &upstream);
+
+        let response = ask(&server, &query("v4only.test", 28)).unwrap();
+        assert_eq!(response.answers[0].record_type, 28);
+        assert!(!response.header.authentic_data());
+    }
+
+    // An upstream answering with `count` A records, enough to overflow small payloads
+    fn upstream_with_many_answers(count: u8) -> MockUpstream {
+        MockUpstream::new(move |query| {
+            let answers = (0..count)
+                .map(|host| {
+                    DnsRecord::new(
+                        query.questions[0].name.clone(),
+                        Ipv4Addr::new(192, 0, 2, host),
+                    )
+                })
+                .collect();
+            Some(upstream_answer(query, answers))
+        })
+    }
+
+    #[test]
+    fn our_opt_advertises_the_configured_size() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+
+        let response = ask(&server(&[], &upstream), &edns_query("a.test", 1)).unwrap();
+        assert_eq!(response.opt_record().unwrap().class, 1232);
+
+        let server = server(&["--edns-udp-size", "1400"], &upstream);
+        let response = ask(&server, &edns_query("a.test", 1)).unwrap();
+        assert_eq!(response.opt_record().unwrap().class, 1400);
+    }
+
+    #[test]
+    fn our_size_caps_what_the_client_advertises() {
+        let upstream = upstream_with_many_answers(60);
+        let server = server(&["--edns-udp-size", "512"], &upstream);
+
+        let mut request = query("a.test", 1);
+        request.additionals.push(OptRecord::new(4096).build());
+        request.sync_counts();
+
+        let bytes = server.handle_query(&request.to_bytes(), CLIENT).unwrap();
+        assert!(bytes.len() <= 512);
+        assert!(DnsMessage::from_bytes(&bytes).unwrap().header.truncated());
+    }
+
+    fn upstream_with_ttl(ttl: u32) -> MockUpstream {
+        MockUpstream::new(move |query| {
+            let mut answer = DnsRecord::new(query.questions[0].name.clone(), Ipv4Addr::LOCALHOST);
+            answer.ttl = ttl;
+            Some(upstream_answer(query, vec![answer]))
+        })
+    }
+
+    #[test]
+    fn ttl_zero_answers_are_relayed_but_not_cached() {
+        let upstream = upstream_with_ttl(0);
+        let server = server(&[], &upstream);
+
+        for _ in 0..2 {
+            let response = ask(&server, &query("a.test", 1)).unwrap();
+            assert_eq!(response.answers[0].ttl, 0);
+        }
+        assert_eq!(upstream.queries().len(), 2);
+        assert!(server.cache.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ttl_zero_is_raised_only_by_an_explicit_min_ttl() {
+        let upstream = upstream_with_ttl(0);
+        let server = server(&["--min-ttl", "30"], &upstream);
+
+        let response = ask(&server, &query("a.test", 1)).unwrap();
+        assert_eq!(response.answers[0].ttl, 30);
+    }
+
+    // An UPDATE for example.test adding `new.example.test A 192.0.2.5`
+    fn add_record_update() -> DnsMessage {
+        let mut request = query("example.test", 6);
+        request.header = DnsHeader::new_query(0x1234, false, 1);
+        request.header.flags |= OPCODE_UPDATE << 11;
+        request.authorities.push(DnsRecord::new(
+            DnsQuestion::encode_domain_name("new.example.test"),
+            Ipv4Addr::new(192, 0, 2, 5),
+        ));
+        request.sync_counts();
+        request
+    }
+
+    #[test]
+    fn allowed_updates_add_to_the_zone() {
+        let upstream = MockUpstream::unreachable();
+        let server = server(&["--allow-update"], &upstream);
+
+        let response = ask(&server, &add_record_update()).unwrap();
+        assert_eq!(response.header.rcode(), 0);
+        assert_eq!(response.header.opcode(), OPCODE_UPDATE);
+
+        let response = ask(&server, &query("new.example.test", 1)).unwrap();
+        assert_eq!(first_address(&response), Some(Ipv4Addr::new(192, 0, 2, 5)));
+        assert!(upstream.queries().is_empty());
+    }
+
+    #[test]
+    fn updates_need_allow_update() {
+        let upstream = MockUpstream::unreachable();
+        let server = server(&[], &upstream);
+
+        let response = ask(&server, &add_record_update()).unwrap();
+        assert_eq!(response.header.rcode(), 4);
+        assert!(server.zone.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn forwarded_queries_use_the_servers_id_source() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server =
+            server(&[], &upstream).with_id_source(Box::new(FixedIds::new(vec![0x0101, 0x0202])));
+
+        let first = ask(&server, &query("a.test", 1)).unwrap();
+        let second = ask(&server, &query("b.test", 1)).unwrap();
+
+        let sent: Vec<u16> = upstream.queries().iter().map(|q| q.header.id).collect();
+        assert_eq!(sent, [0x0101, 0x0202]);
+        // Clients get their own ID back
+        assert_eq!((first.header.id, second.header.id), (0x1234, 0x1234));
+    }
+
+    const DELEGATING_ZONE: &str = "\
+        example.test SOA ns.example.test. hostmaster.example.test. 1 3600 600 86400 300\n\
+        sub.example.test NS ns1.sub.example.test.\n\
+        ns1.sub.example.test A 192.0.2.53\n";
+
+    fn iterative_query(name: &str, record_type: u16) -> DnsMessage {
+        let mut request = query(name, record_type);
+        request.header = DnsHeader::new_query(0x1234, false, 1);
+        request
+    }
+
+    #[test]
+    fn iterative_queries_under_a_delegation_get_a_referral() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server_with_zone(&[], &upstream, DELEGATING_ZONE);
+
+        let response = ask(&server, &iterative_query("www.sub.example.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 0);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.authorities[0].record_type, 2);
+        assert_eq!(
+            first_address(&DnsMessage {
+                answers: response.additionals.clone(),
+                ..response.clone()
+            }),
+            Some(Ipv4Addr::new(192, 0, 2, 53))
+        );
+        assert!(upstream.queries().is_empty());
+    }
+
+    #[test]
+    fn iterative_queries_elsewhere_are_refused() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server_with_zone(&[], &upstream, DELEGATING_ZONE);
+
+        let response = ask(&server, &iterative_query("www.other.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 5);
+        assert!(upstream.queries().is_empty());
+    }
+
+    fn with_expired_entry(server: Server) -> Server {
+        let name = DnsQuestion::encode_domain_name("www.example.test");
+        let answer = DnsRecord::new(name.clone(), Ipv4Addr::new(192, 0, 2, 30));
+        let inserted = Instant::now().checked_sub(Duration::from_secs(20)).unwrap();
+        server
+            .cache
+            .lock()
+            .unwrap()
+            .insert(CacheKey::new(&name, 1, 1), vec![answer], 10, inserted);
+        server
+    }
+
+    #[test]
+    fn stale_answers_are_served_when_upstream_is_down() {
+        let upstream = MockUpstream::unreachable();
+        let server = with_expired_entry(server(&["--serve-stale"], &upstream));
+
+        let response = ask(&server, &query("www.example.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 0);
+        assert_eq!(first_address(&response), Some(Ipv4Addr::new(192, 0, 2, 30)));
+        assert_eq!(response.answers[0].ttl, STALE_ANSWER_TTL);
+    }
+
+    #[test]
+    fn stale_answers_respect_max_stale() {
+        let upstream = MockUpstream::unreachable();
+        let server = with_expired_entry(server(&["--serve-stale", "--max-stale", "5"], &upstream));
+
+        let response = ask(&server, &query("www.example.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 2);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn stale_answers_are_not_served_by_default() {
+        let upstream = MockUpstream::unreachable();
+        let server = with_expired_entry(server(&[], &upstream));
+
+        let response = ask(&server, &query("www.example.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 2);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn failed_forwards_answer_servfail() {
+        let upstream = MockUpstream::unreachable();
+        let server = server(&["--request-timeout", "5000"], &upstream);
+
+        // No made-up address, even with time left before the deadline
+        let response = ask(&server, &query("codecrafters.io", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 2);
+        assert!(response.answers.is_empty());
+        assert!(response.header.recursion_available());
+        assert_eq!(upstream.queries().len(), 1);
+    }
+
+    // Send a query from a fresh client socket, returning the answer and where it came from
+    fn exchange_udp(server_address: SocketAddr, client_address: &str) -> (DnsMessage, SocketAddr) {
+        let client = UdpSocket::bind(client_address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .send_to(&query("www.example.test", 1).to_bytes(), server_address)
+            .unwrap();
+
+        let mut buf = [0; 512];
+        let (size, source) = client.recv_from(&mut buf).unwrap();
+        (DnsMessage::from_bytes(&buf[..size]).unwrap(), source)
+    }
+
+    #[test]
+    fn ipv4_and_ipv6_clients_are_answered_from_their_own_socket() {
+        // Hosts without IPv6 have nothing to test
+        let Ok(socket_v6) = UdpSocket::bind("[::1]:0") else {
+            return;
+        };
+        let socket_v4 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&[], &upstream);
+
+        thread::scope(|scope| {
+            scope.spawn(|| server.serve(&socket_v4, || {}).unwrap());
+            scope.spawn(|| server.serve(&socket_v6, || {}).unwrap());
+
+            for (socket, client_address) in [(&socket_v4, "127.0.0.1:0"), (&socket_v6, "[::1]:0")] {
+                let server_address = socket.local_addr().unwrap();
+                let (response, source) = exchange_udp(server_address, client_address);
+                assert_eq!(source, server_address);
+                assert_eq!(response.header.id, 0x1234);
+                assert_eq!(first_address(&response), Some(Ipv4Addr::new(192, 0, 2, 1)));
+            }
+
+            server.request_shutdown();
+        });
+    }
+
+    #[test]
+    fn malformed_queries_get_formerr() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&[], &upstream);
+
+        // A valid header announcing a question that isn't there
+        let mut packet = query("www.example.test", 1).to_bytes().to_vec();
+        packet.truncate(16);
+
+        let response = server.handle_query(&packet, CLIENT).unwrap();
+        let header = DnsHeader::from_bytes(&response).unwrap();
+        assert_eq!(header.id, 0x1234);
+        assert_eq!(header.rcode(), 1);
+        assert_eq!(response.len(), 12);
+        assert!(upstream.queries().is_empty());
+    }
+
+    #[test]
+    fn packets_shorter_than_a_header_are_ignored() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&[], &upstream);
+
+        assert!(server.handle_query(&[0x12, 0x34, 0x01], CLIENT).is_none());
+    }
+
+    fn ask_from(server: &Server, request: &DnsMessage, client: IpAddr) -> DnsMessage {
+        let response = server.handle_query(&request.to_bytes(), client).unwrap();
+        DnsMessage::from_bytes(&response).unwrap()
+    }
+
+    #[test]
+    fn views_answer_by_client_network() {
+        let upstream = MockUpstream::unreachable();
+        let internal = View {
+            network: ClientNetwork::from_cidr("10.0.0.0/8").unwrap(),
+            zone: Zone::parse("www.example.test A 10.0.0.80\n", &[]).unwrap(),
+        };
+        let server = server_with_zone(&[], &upstream, "www.example.test A 192.0.2.80\n")
+            .with_views(vec![internal]);
+        let request = query("www.example.test", 1);
+
+        let inside = ask_from(&server, &request, "10.1.2.3".parse().unwrap());
+        assert_eq!(first_address(&inside), Some(Ipv4Addr::new(10, 0, 0, 80)));
+
+        let outside = ask_from(&server, &request, "198.51.100.7".parse().unwrap());
+        assert_eq!(first_address(&outside), Some(Ipv4Addr::new(192, 0, 2, 80)));
+    }
+
+    #[test]
+    fn full_worker_queue_sheds_queries() {
+        // Keeps the only worker busy long enough for the queue to fill
+        let upstream = MockUpstream::new(|query| {
+            thread::sleep(Duration::from_millis(300));
+            let answer = DnsRecord::new(query.questions[0].name.clone(), Ipv4Addr::LOCALHOST);
+            Some(upstream_answer(query, vec![answer]))
+        });
+        let server = server(
+            &["--workers", "1", "--queue-size", "1", "--overload-refuse"],
+            &upstream,
+        );
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = socket.local_addr().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let rcodes = thread::scope(|scope| {
+            scope.spawn(|| server.serve(&socket, || {}).unwrap());
+
+            let sent = 5;
+            for _ in 0..sent {
+                client
+                    .send_to(&query("www.example.test", 1).to_bytes(), server_address)
+                    .unwrap();
+            }
+
+            // One query is being answered and at most one more waits in the
+            // queue; the rest are refused straight away
+            let mut rcodes = Vec::new();
+            let mut buf = [0; 512];
+            while rcodes.len() < sent {
+                let Ok(size) = client.recv(&mut buf) else {
+                    break;
+                };
+                rcodes.push(DnsHeader::from_bytes(&buf[..size]).unwrap().rcode());
+            }
+
+            server.request_shutdown();
+            rcodes
+        });
+
+        let refused = rcodes.iter().filter(|&&rcode| rcode == 5).count();
+        assert_eq!(rcodes.len(), 5);
+        assert!(refused >= 3, "rcodes {:?}", rcodes);
+        assert_eq!(
+            server.metrics.dropped_overload.load(Ordering::Relaxed),
+            refused as u64
+        );
+    }
+
+    #[test]
+    fn local_mx_answers_carry_the_exchange_address() {
+        let upstream = MockUpstream::unreachable();
+        let server = server_with_zone(
+            &[],
+            &upstream,
+            "example.test MX 10 mail.example.test.\nmail.example.test A 192.0.2.25\n",
+        );
+
+        let response = ask(&server, &query("example.test", 15)).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].record_type, 15);
+        assert_eq!(response.additionals.len(), 1);
+        assert_eq!(response.additionals[0].rdata, [192, 0, 2, 25]);
+        assert_eq!(response.header.arcount, 1);
+    }
+
+    #[test]
+    fn request_timeout_bounds_failover_across_slow_upstreams() {
+        // Upstreams that take the query and never answer
+        let silent = [
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+        ];
+        let upstreams: Vec<Box<dyn Transport>> = silent
+            .iter()
+            .map(|socket| {
+                let address = socket.local_addr().unwrap().to_string();
+                Box::new(UdpTransport::new(&address).with_timeout(Duration::from_secs(2)))
+                    as Box<dyn Transport>
+            })
+            .collect();
+
+        let args = ["server", "--request-timeout", "300"].map(String::from);
+        let server = Server::new(
+            Config::from_args(&args).unwrap(),
+            Box::new(UpstreamSelector::new(upstreams, UpstreamStrategy::First)),
+            Zone::new(),
+            Hosts::new(),
+            Blocklist::new(),
+        );
+
+        let started = Instant::now();
+        let response = ask(&server, &query("www.example.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 2);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    fn two_question_query() -> DnsMessage {
+        let mut request = query("a.example.test", 1);
+        request
+            .questions
+            .push(DnsQuestion::new_for("b.example.test", 1, 1).unwrap());
+        request.header.qdcount = 2;
+        request
+    }
+
+    fn question_counts(upstream: &MockUpstream) -> Vec<usize> {
+        upstream
+            .queries()
+            .iter()
+            .map(|query| query.questions.len())
+            .collect()
+    }
+
+    #[test]
+    fn multi_question_reject_answers_formerr() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&["--multi-question", "reject"], &upstream);
+
+        let response = ask(&server, &two_question_query()).unwrap();
+        assert_eq!(response.header.rcode(), 1);
+        assert!(upstream.queries().is_empty());
+    }
+
+    #[test]
+    fn multi_question_split_forwards_each_question() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&["--multi-question", "split"], &upstream);
+
+        let response = ask(&server, &two_question_query()).unwrap();
+        assert_eq!(response.header.rcode(), 0);
+        assert_eq!(response.questions.len(), 2);
+        assert_eq!(response.answers.len(), 2);
+        assert_eq!(question_counts(&upstream), [1, 1]);
+    }
+
+    #[test]
+    fn multi_question_passthrough_forwards_the_request_whole() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&["--multi-question", "passthrough"], &upstream);
+
+        let response = ask(&server, &two_question_query()).unwrap();
+        assert_eq!(response.questions.len(), 2);
+        assert_eq!(question_counts(&upstream), [2]);
+    }
+
+    const TRANSFER_ZONE: &str = "\
+        example.test SOA ns.example.test. hostmaster.example.test. 1 3600 600 86400 300\n\
+        example.test NS ns.example.test.\n\
+        ns.example.test A 192.0.2.53\n\
+        www.example.test A 192.0.2.80\n\
+        www.other.test A 198.51.100.80\n";
+
+    fn transfer(server: &Server, client: IpAddr, over_tcp: bool) -> Vec<DnsMessage> {
+        let request = query("example.test", QTYPE_AXFR).to_bytes();
+        let responses = if over_tcp {
+            server.handle_tcp_query(&request, client)
+        } else {
+            server.handle_query(&request, client).into_iter().collect()
+        };
+        responses
+            .iter()
+            .map(|bytes| DnsMessage::from_bytes(bytes).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn zone_transfers_are_bracketed_by_the_soa() {
+        let upstream = MockUpstream::unreachable();
+        let server = server_with_zone(
+            &["--allow-transfer", "127.0.0.0/8"],
+            &upstream,
+            TRANSFER_ZONE,
+        );
+
+        let messages = transfer(&server, CLIENT, true);
+        assert_eq!(messages.len(), 1);
+        assert_ne!(messages[0].header.flags & (1 << 10), 0);
+        let types: Vec<u16> = messages[0]
+            .answers
+            .iter()
+            .map(|record| record.record_type)
+            .collect();
+        // SOA, then the zone's records without the one outside it, then SOA again
+        assert_eq!(types, [6, 2, 1, 1, 6]);
+        assert!(upstream.queries().is_empty());
+    }
+
+    #[test]
+    fn large_zone_transfers_span_several_messages() {
+        let mut zone = String::from(TRANSFER_ZONE);
+        for i in 0..1000 {
+            zone.push_str(&format!("host{}.example.test A 192.0.2.{}\n", i, i % 256));
+        }
+        let upstream = MockUpstream::unreachable();
+        let server = server_with_zone(&["--allow-transfer", "127.0.0.0/8"], &upstream, &zone);
+
+        let messages = transfer(&server, CLIENT, true);
+        assert!(messages.len() > 1);
+        let answers: Vec<&DnsRecord> = messages
+            .iter()
+            .flat_map(|message| &message.answers)
+            .collect();
+        assert_eq!(answers.len(), 1000 + 5);
+        assert_eq!(answers.first().unwrap().record_type, 6);
+        assert_eq!(answers.last().unwrap().record_type, 6);
+        assert!(messages.iter().all(|message| message.header.id == 0x1234));
+    }
+
+    #[test]
+    fn zone_transfers_are_refused_unless_allowed_over_tcp() {
+        let upstream = MockUpstream::unreachable();
+        let server = server_with_zone(
+            &["--allow-transfer", "10.0.0.0/8"],
+            &upstream,
+            TRANSFER_ZONE,
+        );
+
+        let outsider = transfer(&server, CLIENT, true);
+        assert_eq!(outsider.len(), 1);
+        assert_eq!(outsider[0].header.rcode(), 5);
+
+        let over_udp = transfer(&server, "10.0.0.1".parse().unwrap(), false);
+        assert_eq!(over_udp[0].header.rcode(), 5);
+        assert!(over_udp[0].answers.is_empty());
+    }
+
+    #[test]
+    fn transfers_of_unknown_zones_are_notauth() {
+        let upstream = MockUpstream::unreachable();
+        let server = server_with_zone(&["--allow-transfer", "127.0.0.0/8"], &upstream, "");
+
+        let messages = transfer(&server, CLIENT, true);
+        assert_eq!(messages[0].header.rcode(), 9);
+    }
+
+    #[test]
+    fn apex_ns_and_soa_are_answered_authoritatively() {
+        let upstream = MockUpstream::unreachable();
+        let server = server_with_zone(&[], &upstream, TRANSFER_ZONE);
+
+        let ns = ask(&server, &query("example.test", 2)).unwrap();
+        assert_ne!(ns.header.flags & (1 << 10), 0);
+        assert_eq!(ns.answers.len(), 1);
+        assert_eq!(ns.answers[0].record_type, 2);
+        // The name server's address comes along
+        assert_eq!(ns.additionals.len(), 1);
+        assert_eq!(ns.additionals[0].rdata, [192, 0, 2, 53]);
+
+        let soa = ask(&server, &query("EXAMPLE.test", 6)).unwrap();
+        assert_ne!(soa.header.flags & (1 << 10), 0);
+        assert_eq!(soa.answers.len(), 1);
+        assert_eq!(soa.answers[0].record_type, 6);
+        assert!(upstream.queries().is_empty());
+    }
+
+    fn slow_upstream(delay: Duration) -> MockUpstream {
+        MockUpstream::new(move |query| {
+            thread::sleep(delay);
+            let answer = DnsRecord::new(query.questions[0].name.clone(), Ipv4Addr::LOCALHOST);
+            Some(upstream_answer(query, vec![answer]))
+        })
+    }
+
+    // Ask twice at once, the second query arriving while the first is being forwarded
+    fn ask_overlapping(server: &Server) -> (DnsMessage, DnsMessage) {
+        thread::scope(|scope| {
+            let first = scope.spawn(|| ask(server, &query("a.example.test", 1)).unwrap());
+            thread::sleep(Duration::from_millis(50));
+            let second = ask(server, &query("b.example.test", 1)).unwrap();
+            (first.join().unwrap(), second)
+        })
+    }
+
+    #[test]
+    fn forwards_beyond_the_limit_get_servfail() {
+        let upstream = slow_upstream(Duration::from_millis(300));
+        let server = server(
+            &["--max-concurrent-forwards", "1", "--forward-wait", "0"],
+            &upstream,
+        );
+
+        let (first, second) = ask_overlapping(&server);
+        assert_eq!(first.header.rcode(), 0);
+        assert_eq!(second.header.rcode(), 2);
+        assert_eq!(server.metrics.forwards_limited.load(Ordering::Relaxed), 1);
+        assert_eq!(upstream.queries().len(), 1);
+    }
+
+    #[test]
+    fn forwards_wait_for_a_free_slot() {
+        let upstream = slow_upstream(Duration::from_millis(100));
+        let server = server(
+            &["--max-concurrent-forwards", "1", "--forward-wait", "2000"],
+            &upstream,
+        );
+
+        let (first, second) = ask_overlapping(&server);
+        assert_eq!(first.header.rcode(), 0);
+        assert_eq!(second.header.rcode(), 0);
+        assert_eq!(upstream.queries().len(), 2);
+    }
+
+    #[test]
+    fn debug_delay_holds_back_responses() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&["--debug-delay", "150"], &upstream);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        thread::scope(|scope| {
+            scope.spawn(|| server.serve(&socket, || {}).unwrap());
+
+            let started = Instant::now();
+            let (response, _) = exchange_udp(socket.local_addr().unwrap(), "127.0.0.1:0");
+            assert!(started.elapsed() >= Duration::from_millis(150));
+            assert_eq!(first_address(&response), Some(Ipv4Addr::new(192, 0, 2, 1)));
+
+            server.request_shutdown();
+        });
+    }
+
+    #[test]
+    fn rules_are_applied_before_forwarding() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(
+            &[
+                "--rule",
+                r".*\.internal:192.168.0.1",
+                "--rule",
+                r".*:refuse",
+            ],
+            &upstream,
+        );
+
+        let internal = ask(&server, &query("db.internal", 1)).unwrap();
+        assert_eq!(
+            first_address(&internal),
+            Some(Ipv4Addr::new(192, 168, 0, 1))
+        );
+
+        // A static IPv4 answer has nothing to say about AAAA
+        let aaaa = ask(&server, &query("db.internal", 28)).unwrap();
+        assert_eq!(aaaa.header.rcode(), 0);
+        assert!(aaaa.answers.is_empty());
+
+        let other = ask(&server, &query("www.example.test", 1)).unwrap();
+        assert_eq!(other.header.rcode(), 5);
+        assert!(upstream.queries().is_empty());
+    }
+
+    fn cookie_query(name: &str, cookie: &[u8]) -> DnsMessage {
+        let mut request = edns_query(name, 1);
+        let opt = request.opt_record_mut().unwrap();
+        dns_edns::set_option(&mut opt.rdata, OPTION_COOKIE, Some(cookie.to_vec()));
+        request
+    }
+
+    fn cookie_of(message: &DnsMessage) -> Option<Cookie> {
+        let data = dns_edns::find_option(&message.opt_record()?.rdata, OPTION_COOKIE)?;
+        Cookie::from_option_data(&data)
+    }
+
+    // Answers with a server cookie for whatever client cookie it was sent
+    fn upstream_with_cookies() -> MockUpstream {
+        MockUpstream::new(|query| {
+            let answer = DnsRecord::new(query.questions[0].name.clone(), Ipv4Addr::LOCALHOST);
+            let mut response = upstream_answer(query, vec![answer]);
+            let mut opt = OptRecord::new(1232).build();
+            if let Some(cookie) = cookie_of(query) {
+                let data = [&cookie.client[..], b"upstream"].concat();
+                dns_edns::set_option(&mut opt.rdata, OPTION_COOKIE, Some(data));
+            }
+            response.additionals.push(opt);
+            response.sync_counts();
+            Some(response)
+        })
+    }
+
+    #[test]
+    fn client_cookies_get_a_server_cookie() {
+        let upstream = upstream_with_cookies();
+        let server = server(&[], &upstream);
+
+        let response = ask(&server, &cookie_query("www.example.test", b"client01")).unwrap();
+        let cookie = cookie_of(&response).unwrap();
+        assert_eq!(&cookie.client, b"client01");
+        assert_eq!(cookie.server.len(), 16);
+        // Never the upstream's cookie, which was meant for us
+        assert_ne!(&cookie.server[..], b"upstream");
+    }
+
+    #[test]
+    fn upstreams_get_our_cookie_not_the_clients() {
+        let upstream = upstream_with_cookies();
+        let server = server(&[], &upstream);
+
+        // Different names, so the second is forwarded too rather than cached
+        ask(&server, &cookie_query("a.example.test", b"client01")).unwrap();
+        ask(&server, &cookie_query("b.example.test", b"client01")).unwrap();
+
+        let sent: Vec<Cookie> = upstream.queries().iter().filter_map(cookie_of).collect();
+        assert_eq!(sent.len(), 2);
+        assert_ne!(&sent[0].client, b"client01");
+        assert!(sent[0].server.is_empty());
+        // The second query presents the server cookie learned from the first
+        assert_eq!(sent[1].client, sent[0].client);
+        assert_eq!(sent[1].server, b"upstream");
+    }
+
+    #[test]
+    fn malformed_cookies_get_formerr() {
+        let upstream = upstream_with_cookies();
+        let server = server(&[], &upstream);
+
+        let response = ask(&server, &cookie_query("www.example.test", b"short")).unwrap();
+        assert_eq!(response.header.rcode(), 1);
+        assert!(upstream.queries().is_empty());
+    }
+
+    fn reverse_server(upstream: &MockUpstream) -> Server {
+        let network = ClientNetwork::from_cidr("192.0.2.0/24").unwrap();
+        let reverse_zone = ReverseZone::parse(network, "192.0.2.1 gateway.example.test\n").unwrap();
+        server(&[], upstream).with_reverse_zones(vec![reverse_zone])
+    }
+
+    #[test]
+    fn reverse_zones_answer_ptr_queries() {
+        let upstream = MockUpstream::unreachable();
+        let server = reverse_server(&upstream);
+
+        let response = ask(&server, &query("1.2.0.192.in-addr.arpa", 12)).unwrap();
+        assert_eq!(response.header.rcode(), 0);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].record_type, 12);
+        assert_eq!(
+            response.answers[0].rdata,
+            DnsQuestion::encode_domain_name("gateway.example.test")
+        );
+        assert_ne!(response.header.flags & (1 << 10), 0);
+    }
+
+    #[test]
+    fn reverse_zones_deny_what_they_do_not_hold() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = reverse_server(&upstream);
+
+        let unnamed = ask(&server, &query("9.2.0.192.in-addr.arpa", 12)).unwrap();
+        assert_eq!(unnamed.header.rcode(), 3);
+
+        let other_type = ask(&server, &query("1.2.0.192.in-addr.arpa", 16)).unwrap();
+        assert_eq!(other_type.header.rcode(), 0);
+        assert!(other_type.answers.is_empty());
+        assert!(upstream.queries().is_empty());
+
+        // Addresses outside every reverse zone are forwarded as usual
+        ask(&server, &query("1.100.51.198.in-addr.arpa", 12)).unwrap();
+        assert_eq!(upstream.queries().len(), 1);
+    }
+
+    #[test]
+    fn canonicalized_responses_keep_the_question_case() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&["--canonicalize-names"], &upstream);
+
+        let response = ask(&server, &query("WwW.Example.TEST", 1)).unwrap();
+        assert_eq!(
+            response.questions[0].name,
+            DnsQuestion::encode_domain_name("WwW.Example.TEST")
+        );
+        assert_eq!(
+            response.answers[0].name,
+            DnsQuestion::encode_domain_name("www.example.test")
+        );
+    }
+
+    #[test]
+    fn owner_names_are_relayed_as_is_by_default() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&[], &upstream);
+
+        let response = ask(&server, &query("WwW.Example.TEST", 1)).unwrap();
+        assert_eq!(
+            response.answers[0].name,
+            DnsQuestion::encode_domain_name("WwW.Example.TEST")
+        );
+    }
+
+    #[test]
+    fn single_answer_trims_relayed_responses() {
+        let upstream = upstream_with_many_answers(5);
+        let server = server(&["--single-answer"], &upstream);
+
+        let response = ask(&server, &query("pool.example.test", 1)).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.header.ancount, 1);
+        assert_eq!(first_address(&response), Some(Ipv4Addr::new(192, 0, 2, 0)));
+    }
+
+    #[test]
+    fn checking_disabled_and_dnssec_ok_are_forwarded() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&[], &upstream);
+
+        let mut request = query("a.example.test", 1);
+        request.header.set_checking_disabled(true);
+        request
+            .additionals
+            .push(OptRecord::new(1232).dnssec_ok(true).build());
+        request.sync_counts();
+        ask(&server, &request).unwrap();
+        ask(&server, &query("b.example.test", 1)).unwrap();
+
+        let queries = upstream.queries();
+        assert!(queries[0].header.checking_disabled());
+        assert!(queries[0].dnssec_ok());
+        assert!(!queries[1].header.checking_disabled());
+        assert!(!queries[1].dnssec_ok());
+    }
+
+    const ANAME_ZONE: &str = "\
+        example.test SOA ns.example.test. hostmaster.example.test. 1 3600 600 86400 300\n\
+        example.test ANAME cdn.provider.test.\n\
+        pinned.example.test ANAME cdn.provider.test.\n\
+        pinned.example.test A 192.0.2.99\n";
+
+    #[test]
+    fn aname_answers_with_the_target_addresses() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server_with_zone(&[], &upstream, ANAME_ZONE);
+
+        let response = ask(&server, &query("example.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 0);
+        assert_ne!(response.header.flags & (1 << 10), 0);
+        assert_eq!(first_address(&response), Some(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(
+            response.answers[0].name,
+            DnsQuestion::encode_domain_name("example.test")
+        );
+
+        let queries = upstream.queries();
+        assert_eq!(queries.len(), 1);
+        assert_eq!(
+            queries[0].questions[0].name,
+            DnsQuestion::encode_domain_name("cdn.provider.test")
+        );
+
+        // The target's answer is cached like any other
+        ask(&server, &query("example.test", 1)).unwrap();
+        assert_eq!(upstream.queries().len(), 1);
+    }
+
+    #[test]
+    fn aname_yields_to_records_of_the_queried_type() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server_with_zone(&[], &upstream, ANAME_ZONE);
+
+        let response = ask(&server, &query("pinned.example.test", 1)).unwrap();
+        assert_eq!(first_address(&response), Some(Ipv4Addr::new(192, 0, 2, 99)));
+        assert!(upstream.queries().is_empty());
+    }
+
+    #[test]
+    fn aname_targets_without_addresses_give_nodata() {
+        let upstream = ipv4_only_upstream();
+        let server = server_with_zone(&[], &upstream, ANAME_ZONE);
+
+        let response = ask(&server, &query("example.test", 28)).unwrap();
+        assert_eq!(response.header.rcode(), 0);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities[0].record_type, 6);
+    }
+
+    #[test]
+    fn unreachable_aname_targets_give_servfail() {
+        let upstream = MockUpstream::unreachable();
+        let server = server_with_zone(&[], &upstream, ANAME_ZONE);
+
+        let response = ask(&server, &query("example.test", 1)).unwrap();
+        assert_eq!(response.header.rcode(), 2);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn forced_clients_are_sent_to_tcp() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 1));
+        let server = server(&["--force-tcp-for", "127.0.0.0/8"], &upstream);
+        let request = query("www.example.test", 1);
+
+        let over_udp = ask(&server, &request).unwrap();
+        assert!(over_udp.header.truncated());
+        assert!(over_udp.answers.is_empty());
+        assert!(upstream.queries().is_empty());
+
+        let over_tcp = server.handle_tcp_query(&request.to_bytes(), CLIENT);
+        let over_tcp = DnsMessage::from_bytes(&over_tcp[0]).unwrap();
+        assert!(!over_tcp.header.truncated());
+        assert_eq!(first_address(&over_tcp), Some(Ipv4Addr::new(192, 0, 2, 1)));
+
+        // Clients outside the network keep using UDP
+        let other = ask_from(&server, &request, "198.51.100.7".parse().unwrap());
+        assert!(!other.header.truncated());
+        assert_eq!(first_address(&other), Some(Ipv4Addr::new(192, 0, 2, 1)));
+    }
+
+    #[test]
+    fn signed_zone_denials_carry_nsec_for_dnssec_clients() {
+        let upstream = MockUpstream::unreachable();
+        let server = server_with_zone(
+            &[],
+            &upstream,
+            "example.test SOA ns.example.test. host.example.test. 1 3600 600 86400 300\n\
+             example.test NSEC www.example.test. SOA NSEC\n\
+             www.example.test A 192.0.2.80\n\
+             www.example.test NSEC example.test. A NSEC\n",
+        );
+        let authority_types = |response: &DnsMessage| -> Vec<u16> {
+            response
+                .authorities
+                .iter()
+                .map(|record| record.record_type)
+                .collect()
+        };
+
+        let mut request = query("nope.example.test", 1);
+        request
+            .additionals
+            .push(OptRecord::new(1232).dnssec_ok(true).build());
+        request.sync_counts();
+        let response = ask(&server, &request).unwrap();
+        assert_eq!(response.header.rcode(), 3);
+        assert_eq!(authority_types(&response), [6, 47]);
+
+        let nodata = ask(&server, &query("www.example.test", 28)).unwrap();
+        assert_eq!(nodata.header.rcode(), 0);
+        assert!(nodata.answers.is_empty());
+        // Without the DO bit there is no proof, only the SOA
+        assert_eq!(authority_types(&nodata), [6]);
+        assert!(upstream.queries().is_empty());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tracing_test::traced_test]
+    #[test]
+    fn forwarded_queries_are_traced() {
+        let upstream = MockUpstream::answering(Ipv4Addr::new(192, 0, 2, 7));
+        let server = server(&[], &upstream);
+
+        ask(&server, &query("traced.test", 1)).unwrap();
+        assert!(logs_contain("forward{qname=traced.test rcode=0}"));
+
+        // Answered from the cache the second time, so not forwarded again
+        ask(&server, &query("traced.test", 1)).unwrap();
+        logs_assert(|lines: &[&str]| {
+            match lines
+                .iter()
+                .filter(|line| line.contains("forward{"))
+                .count()
+            {
+                1 => Ok(()),
+                count => Err(format!("{} forward spans", count)),
+            }
+        });
+    }
+}
@@ -0,0 +1,165 @@
+use crate::dns::dns_error::DnsError;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, OnceLock};
+
+// TLS client settings trusting the usual web roots, built once and shared
+pub fn default_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            config_with_roots(roots)
+        })
+        .clone()
+}
+
+// TLS client settings trusting only the given roots (e.g. a private CA)
+pub fn config_with_roots(roots: RootCertStore) -> Arc<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+// Send a request over TLS on a connected stream and read the response until the
+// server closes the connection
+pub fn round_trip(
+    stream: TcpStream,
+    host: &str,
+    config: Arc<ClientConfig>,
+    head: &[u8],
+    body: &[u8],
+) -> Result<Vec<u8>, DnsError> {
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|_| DnsError::Http(format!("Invalid TLS server name: {}", host)))?;
+    let connection = ClientConnection::new(config, server_name)
+        .map_err(|e| DnsError::Http(format!("TLS setup failed: {}", e)))?;
+    let mut stream = StreamOwned::new(connection, stream);
+
+    stream.write_all(head)?;
+    stream.write_all(body)?;
+
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response) {
+        Ok(_) => {}
+        // Plenty of servers close without a TLS close_notify; the HTTP framing
+        // still tells whether the response is complete
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+    use rustls::{ServerConfig, ServerConnection};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    // A one-shot HTTPS server for `localhost` with a freshly made self-signed
+    // certificate. Returns the port, the certificate and the raw request received.
+    fn mock_https_server(
+        response: &'static [u8],
+    ) -> (u16, CertificateDer<'static>, JoinHandle<Vec<u8>>) {
+        let certified =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let certificate = certified.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.clone()], key.into())
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut stream = StreamOwned::new(connection, stream);
+
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => return request,
+                    Ok(read) => request.extend_from_slice(&buf[..read]),
+                }
+            }
+
+            stream.write_all(response).unwrap();
+            stream.conn.send_close_notify();
+            stream.flush().unwrap();
+            request
+        });
+
+        (port, certificate, handle)
+    }
+
+    fn connect(port: u16) -> TcpStream {
+        TcpStream::connect(("127.0.0.1", port)).unwrap()
+    }
+
+    #[test]
+    fn round_trip_over_tls() {
+        let (port, certificate, server) =
+            mock_https_server(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate).unwrap();
+
+        let head = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let response = round_trip(
+            connect(port),
+            "localhost",
+            config_with_roots(roots),
+            head,
+            &[],
+        )
+        .unwrap();
+
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(b"\r\n\r\nok"));
+        assert_eq!(server.join().unwrap(), head);
+    }
+
+    #[test]
+    fn untrusted_certificates_are_rejected() {
+        let (port, _, server) = mock_https_server(b"HTTP/1.1 200 OK\r\n\r\n");
+
+        let head = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert!(round_trip(connect(port), "localhost", default_config(), head, &[]).is_err());
+        assert!(server.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn certificates_must_match_the_host() {
+        let (port, certificate, server) = mock_https_server(b"HTTP/1.1 200 OK\r\n\r\n");
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate).unwrap();
+
+        let head = b"GET / HTTP/1.1\r\nHost: dns.example\r\n\r\n";
+        let result = round_trip(
+            connect(port),
+            "dns.example",
+            config_with_roots(roots),
+            head,
+            &[],
+        );
+        assert!(result.is_err());
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,762 @@
+use crate::dns::dns_error::DnsError;
+use crate::hexdump::hexdump;
+#[cfg(feature = "tls")]
+use crate::tls;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long we wait for an upstream server before giving up, unless configured
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A way of sending a wire-format query upstream and getting the wire-format answer back
+pub trait Transport: Send + Sync {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, DnsError>;
+
+    // Like `exchange`, but don't wait past `deadline`. Transports that can shorten
+    // their own timeout override this; the default only checks before starting.
+    fn exchange_until(&self, query: &[u8], deadline: Instant) -> Result<Vec<u8>, DnsError> {
+        remaining_until(deadline)?;
+        self.exchange(query)
+    }
+
+    // Human-readable description of the upstream, used for logging
+    fn describe(&self) -> String;
+}
+
+// Time left before a deadline, or a timeout error once it has passed
+pub fn remaining_until(deadline: Instant) -> Result<Duration, DnsError> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "request deadline exceeded").into());
+    }
+    Ok(remaining)
+}
+
+// Plain DNS over UDP
+pub struct UdpTransport {
+    pub server: String,
+    pub timeout: Duration,
+}
+
+impl UdpTransport {
+    pub fn new(server: &str) -> Self {
+        UdpTransport {
+            server: server.to_string(),
+            timeout: UPSTREAM_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl UdpTransport {
+    fn exchange_within(&self, query: &[u8], timeout: Duration) -> Result<Vec<u8>, DnsError> {
+        // Use a fresh ephemeral socket for every exchange
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_read_timeout(Some(timeout))?;
+
+        socket.send_to(query, self.server.as_str())?;
+
+        // EDNS answers may exceed the classic 512-byte limit
+        let mut buf = [0; 4096];
+        let (size, _) = socket.recv_from(&mut buf)?;
+
+        Ok(buf[..size].to_vec())
+    }
+}
+
+impl Transport for UdpTransport {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, DnsError> {
+        self.exchange_within(query, self.timeout)
+    }
+
+    fn exchange_until(&self, query: &[u8], deadline: Instant) -> Result<Vec<u8>, DnsError> {
+        let timeout = self.timeout.min(remaining_until(deadline)?);
+        self.exchange_within(query, timeout)
+    }
+
+    fn describe(&self) -> String {
+        format!("udp://{}", self.server)
+    }
+}
+
+// Idle connections kept per TCP upstream, and how long one may sit unused
+pub const TCP_POOL_MAX_IDLE: usize = 4;
+pub const TCP_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// DNS over TCP (RFC 7766): each message is prefixed with its two-byte length.
+// Connections are kept open and reused for later queries to the same upstream;
+// one that turns out to be broken is discarded and the query retried on a fresh
+// connection.
+pub struct TcpTransport {
+    pub server: String,
+    pub timeout: Duration,
+    pool: Mutex<Vec<IdleConnection>>,
+}
+
+struct IdleConnection {
+    stream: TcpStream,
+    idle_since: Instant,
+}
+
+impl TcpTransport {
+    pub fn new(server: &str) -> Self {
+        TcpTransport {
+            server: server.to_string(),
+            timeout: UPSTREAM_TIMEOUT,
+            pool: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Number of idle connections currently pooled
+    pub fn idle_connections(&self) -> usize {
+        self.pool.lock().unwrap().len()
+    }
+
+    fn exchange_within(&self, query: &[u8], timeout: Duration) -> Result<Vec<u8>, DnsError> {
+        // A pooled connection may have been closed by the server in the meantime
+        if let Some(mut stream) = self.take_idle() {
+            match Self::round_trip(&mut stream, query, timeout) {
+                Ok(response) => {
+                    self.put_idle(stream);
+                    return Ok(response);
+                }
+                Err(e) => println!("Discarding broken connection to {}: {}", self.server, e),
+            }
+        }
+
+        let address = self
+            .server
+            .to_socket_addrs()?
+            .next()
+            .ok_or(DnsError::Parse("Cannot resolve TCP upstream address"))?;
+        let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+        let response = Self::round_trip(&mut stream, query, timeout)?;
+        self.put_idle(stream);
+        Ok(response)
+    }
+
+    // Send one length-prefixed query and read the length-prefixed response
+    fn round_trip(
+        stream: &mut TcpStream,
+        query: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, DnsError> {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let length =
+            u16::try_from(query.len()).map_err(|_| DnsError::Parse("Query too large for TCP"))?;
+        let mut message = length.to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+        stream.write_all(&message)?;
+
+        read_tcp_message(stream)
+    }
+
+    // Take the most recently used idle connection, dropping any that idled too long
+    fn take_idle(&self) -> Option<TcpStream> {
+        let mut pool = self.pool.lock().unwrap();
+        pool.retain(|idle| idle.idle_since.elapsed() < TCP_POOL_IDLE_TIMEOUT);
+        pool.pop().map(|idle| idle.stream)
+    }
+
+    fn put_idle(&self, stream: TcpStream) {
+        let mut pool = self.pool.lock().unwrap();
+        if pool.len() < TCP_POOL_MAX_IDLE {
+            pool.push(IdleConnection {
+                stream,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, DnsError> {
+        self.exchange_within(query, self.timeout)
+    }
+
+    fn exchange_until(&self, query: &[u8], deadline: Instant) -> Result<Vec<u8>, DnsError> {
+        let timeout = self.timeout.min(remaining_until(deadline)?);
+        self.exchange_within(query, timeout)
+    }
+
+    fn describe(&self) -> String {
+        format!("tcp://{}", self.server)
+    }
+}
+
+// The parts of an http:// or https:// URL
+struct HttpUrl {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: Option<String>, // None when the URL has no path
+}
+
+impl HttpUrl {
+    fn parse(url: &str) -> Result<Self, DnsError> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = url.strip_prefix("https://") {
+            if !cfg!(feature = "tls") {
+                return Err(DnsError::Http(String::from(
+                    "https:// endpoints need TLS, which this build does not include; rebuild with `--features tls` or use an http:// endpoint",
+                )));
+            }
+            (true, rest)
+        } else {
+            return Err(DnsError::Http(format!("Unsupported URL: {}", url)));
+        };
+
+        // Split authority from path
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], Some(rest[index..].to_string())),
+            None => (rest, None),
+        };
+
+        // Split host from optional port
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => match port.parse::<u16>() {
+                Ok(port) => (host, port),
+                Err(_) => return Err(DnsError::Http(format!("Invalid port in URL: {}", url))),
+            },
+            None => (authority, if tls { 443 } else { 80 }),
+        };
+
+        if host.is_empty() {
+            return Err(DnsError::Http(format!("Missing host in URL: {}", url)));
+        }
+
+        Ok(HttpUrl {
+            tls,
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+}
+
+// Send one HTTP/1.1 request (over TLS for https://) and read the raw response
+// until the server closes
+fn http_round_trip(
+    url: &HttpUrl,
+    timeout: Duration,
+    head: &[u8],
+    body: &[u8],
+) -> Result<Vec<u8>, DnsError> {
+    let address = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| DnsError::Http(format!("Cannot resolve {}", url.host)))?;
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    #[cfg(feature = "tls")]
+    if url.tls {
+        return tls::round_trip(stream, &url.host, tls::default_config(), head, body);
+    }
+
+    stream.write_all(head)?;
+    stream.write_all(body)?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
+
+// Read one DNS message framed for TCP: a two-byte length, then exactly that many
+// bytes, however the stream splits them across reads. The result is the bare
+// message, ready for `DnsMessage::from_bytes`.
+pub fn read_tcp_message(stream: &mut impl Read) -> Result<Vec<u8>, DnsError> {
+    let mut length = [0; 2];
+    stream.read_exact(&mut length)?;
+
+    let mut message = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+// Fetch a resource with a plain HTTP GET, failing on any status other than 200
+pub fn http_get(url: &str, timeout: Duration) -> Result<Vec<u8>, DnsError> {
+    let parsed = HttpUrl::parse(url)?;
+    let path = parsed.path.as_deref().unwrap_or("/");
+
+    let request_head = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Connection: close\r\n\r\n",
+        path, parsed.host
+    );
+    let response = http_round_trip(&parsed, timeout, request_head.as_bytes(), &[])?;
+
+    let (status, body) = DohTransport::parse_response(&response)?;
+    if status != 200 {
+        return Err(DnsError::Http(format!(
+            "{} returned status {}",
+            url, status
+        )));
+    }
+
+    Ok(body)
+}
+
+// DNS-over-HTTPS (RFC 8484) using POST with an application/dns-message body
+//
+// https:// endpoints need the "tls" feature; without it only plain http://
+// endpoints (e.g. behind a TLS-terminating proxy) can be used.
+pub struct DohTransport {
+    endpoint: HttpUrl,
+    pub timeout: Duration,
+}
+
+impl DohTransport {
+    // Build a transport from a URL like https://dns.example/dns-query
+    pub fn new(url: &str) -> Result<Self, DnsError> {
+        let mut endpoint = HttpUrl::parse(url)?;
+        endpoint
+            .path
+            .get_or_insert_with(|| String::from("/dns-query"));
+
+        Ok(DohTransport {
+            endpoint,
+            timeout: UPSTREAM_TIMEOUT,
+        })
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Split a raw HTTP response into its status code and body
+    fn parse_response(response: &[u8]) -> Result<(u16, Vec<u8>), DnsError> {
+        let header_end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(index) => index,
+            None => return Err(DnsError::Http(String::from("Incomplete HTTP response"))),
+        };
+
+        let head = String::from_utf8_lossy(&response[..header_end]);
+        let mut lines = head.split("\r\n");
+
+        // Status line looks like "HTTP/1.1 200 OK"
+        let status = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| DnsError::Http(String::from("Malformed HTTP status line")))?;
+
+        let mut content_length = None;
+        let mut chunked = false;
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                let name = name.trim().to_ascii_lowercase();
+                let value = value.trim();
+                if name == "content-length" {
+                    content_length = value.parse::<usize>().ok();
+                } else if name == "transfer-encoding" && value.eq_ignore_ascii_case("chunked") {
+                    chunked = true;
+                }
+            }
+        }
+
+        let raw_body = &response[header_end + 4..];
+        let body = if chunked {
+            Self::decode_chunked(raw_body)?
+        } else {
+            match content_length {
+                Some(length) if length <= raw_body.len() => raw_body[..length].to_vec(),
+                Some(_) => {
+                    return Err(DnsError::Http(String::from(
+                        "HTTP body shorter than Content-Length",
+                    )))
+                }
+                None => raw_body.to_vec(),
+            }
+        };
+
+        Ok((status, body))
+    }
+
+    // Decode a "Transfer-Encoding: chunked" body
+    fn decode_chunked(mut raw: &[u8]) -> Result<Vec<u8>, DnsError> {
+        let mut body = Vec::new();
+
+        loop {
+            let line_end = match raw.windows(2).position(|w| w == b"\r\n") {
+                Some(index) => index,
+                None => return Err(DnsError::Http(String::from("Malformed chunked body"))),
+            };
+
+            // Chunk size is hex, optionally followed by extensions
+            let size_line = String::from_utf8_lossy(&raw[..line_end]);
+            let size_hex = size_line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size_hex, 16)
+                .map_err(|_| DnsError::Http(String::from("Invalid chunk size")))?;
+
+            raw = &raw[line_end + 2..];
+            if size == 0 {
+                break;
+            }
+
+            if raw.len() < size + 2 {
+                return Err(DnsError::Http(String::from("Truncated chunked body")));
+            }
+
+            body.extend_from_slice(&raw[..size]);
+            raw = &raw[size + 2..];
+        }
+
+        Ok(body)
+    }
+}
+
+impl DohTransport {
+    fn exchange_within(&self, query: &[u8], timeout: Duration) -> Result<Vec<u8>, DnsError> {
+        // Send the query as the POST body
+        let request_head = format!(
+            "POST {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Content-Type: application/dns-message\r\n\
+             Accept: application/dns-message\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.endpoint.path.as_deref().unwrap_or_default(),
+            self.endpoint.host,
+            query.len()
+        );
+
+        let response = http_round_trip(&self.endpoint, timeout, request_head.as_bytes(), query)?;
+
+        let (status, body) = Self::parse_response(&response)?;
+        if status != 200 {
+            return Err(DnsError::Http(format!(
+                "DoH server returned status {}",
+                status
+            )));
+        }
+
+        Ok(body)
+    }
+}
+
+impl Transport for DohTransport {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, DnsError> {
+        self.exchange_within(query, self.timeout)
+    }
+
+    fn exchange_until(&self, query: &[u8], deadline: Instant) -> Result<Vec<u8>, DnsError> {
+        let timeout = self.timeout.min(remaining_until(deadline)?);
+        self.exchange_within(query, timeout)
+    }
+
+    fn describe(&self) -> String {
+        let endpoint = &self.endpoint;
+        format!(
+            "{}://{}:{}{}",
+            if endpoint.tls { "https" } else { "http" },
+            endpoint.host,
+            endpoint.port,
+            endpoint.path.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+// Wraps another transport and prints a hex dump of every query and answer
+pub struct DumpingTransport {
+    pub inner: Box<dyn Transport>,
+}
+
+impl DumpingTransport {
+    fn dump_around(
+        &self,
+        query: &[u8],
+        send: impl FnOnce(&[u8]) -> Result<Vec<u8>, DnsError>,
+    ) -> Result<Vec<u8>, DnsError> {
+        println!("Sending {} bytes to {}", query.len(), self.inner.describe());
+        print!("{}", hexdump(query));
+
+        let response = send(query)?;
+
+        println!(
+            "Received {} bytes from {}",
+            response.len(),
+            self.inner.describe()
+        );
+        print!("{}", hexdump(&response));
+        Ok(response)
+    }
+}
+
+impl Transport for DumpingTransport {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, DnsError> {
+        self.dump_around(query, |query| self.inner.exchange(query))
+    }
+
+    fn exchange_until(&self, query: &[u8], deadline: Instant) -> Result<Vec<u8>, DnsError> {
+        self.dump_around(query, |query| self.inner.exchange_until(query, deadline))
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    // A one-shot HTTP server: answers the first request with `response` and hands
+    // back the raw request it received
+    fn mock_http_server(response: &'static [u8]) -> (u16, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request(&mut stream);
+            stream.write_all(response).unwrap();
+            request
+        });
+
+        (port, handle)
+    }
+
+    // Read an HTTP request head and its Content-Length body
+    fn read_request(stream: &mut TcpStream) -> Vec<u8> {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let read = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..read]);
+
+            let Some(head_end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                continue;
+            };
+            let head = String::from_utf8_lossy(&request[..head_end]).to_ascii_lowercase();
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map_or(0, |value| value.trim().parse().unwrap());
+            if request.len() >= head_end + 4 + length || read == 0 {
+                return request;
+            }
+        }
+    }
+
+    const QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x01";
+
+    #[test]
+    fn doh_posts_the_query_and_returns_the_body() {
+        let (port, server) = mock_http_server(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: 5\r\n\r\nhello",
+        );
+        let url = format!("http://127.0.0.1:{}/resolve", port);
+        let transport = DohTransport::new(&url).unwrap();
+
+        assert_eq!(transport.exchange(QUERY).unwrap(), b"hello");
+
+        let request = server.join().unwrap();
+        let text = String::from_utf8_lossy(&request);
+        assert!(text.starts_with("POST /resolve HTTP/1.1\r\n"), "{}", text);
+        assert!(text.contains("Content-Type: application/dns-message\r\n"));
+        assert!(text.contains(&format!("Content-Length: {}\r\n", QUERY.len())));
+        assert!(request.ends_with(QUERY));
+    }
+
+    #[test]
+    fn doh_decodes_chunked_bodies() {
+        let (port, server) = mock_http_server(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+        );
+        let transport = DohTransport::new(&format!("http://127.0.0.1:{}", port)).unwrap();
+
+        assert_eq!(transport.exchange(QUERY).unwrap(), b"abcde");
+        let request = server.join().unwrap();
+        assert!(request.starts_with(b"POST /dns-query HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn doh_rejects_error_statuses() {
+        let (port, server) =
+            mock_http_server(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+        let transport = DohTransport::new(&format!("http://127.0.0.1:{}", port)).unwrap();
+
+        let error = transport.exchange(QUERY).unwrap_err();
+        assert!(error.to_string().contains("503"), "{}", error);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn http_get_fetches_the_body() {
+        let (port, server) = mock_http_server(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nzone");
+        let url = format!("http://127.0.0.1:{}/example.zone", port);
+
+        assert_eq!(http_get(&url, UPSTREAM_TIMEOUT).unwrap(), b"zone");
+        let request = server.join().unwrap();
+        assert!(request.starts_with(b"GET /example.zone HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn urls_are_split_into_their_parts() {
+        let url = HttpUrl::parse("http://dns.example:8053/dns-query").unwrap();
+        assert!(!url.tls);
+        assert_eq!(url.host, "dns.example");
+        assert_eq!(url.port, 8053);
+        assert_eq!(url.path.as_deref(), Some("/dns-query"));
+
+        let url = HttpUrl::parse("http://dns.example").unwrap();
+        assert_eq!(url.port, 80);
+        assert_eq!(url.path, None);
+
+        assert!(HttpUrl::parse("http://:80/").is_err());
+        assert!(HttpUrl::parse("http://dns.example:http/").is_err());
+        assert!(HttpUrl::parse("ftp://dns.example/").is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn https_urls_default_to_port_443() {
+        let url = HttpUrl::parse("https://dns.example/dns-query").unwrap();
+        assert!(url.tls);
+        assert_eq!(url.port, 443);
+
+        let transport = DohTransport::new("https://dns.example").unwrap();
+        assert_eq!(transport.describe(), "https://dns.example:443/dns-query");
+    }
+
+    #[cfg(not(feature = "tls"))]
+    #[test]
+    fn https_urls_need_the_tls_feature() {
+        let error = HttpUrl::parse("https://dns.example/dns-query")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("--features tls"), "{}", error);
+    }
+
+    // A TCP DNS server echoing every query back, counting the connections it
+    // accepts. With `one_per_connection` it hangs up after each answer.
+    fn mock_tcp_server(one_per_connection: bool) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&accepted);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    while let Ok(query) = read_tcp_message(&mut stream) {
+                        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+                        message.extend_from_slice(&query);
+                        stream.write_all(&message).unwrap();
+                        if one_per_connection {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        (address, accepted)
+    }
+
+    #[test]
+    fn tcp_connections_are_reused() {
+        let (address, accepted) = mock_tcp_server(false);
+        let transport = TcpTransport::new(&address);
+
+        assert_eq!(transport.exchange(QUERY).unwrap(), QUERY);
+        assert_eq!(transport.exchange(QUERY).unwrap(), QUERY);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(transport.idle_connections(), 1);
+    }
+
+    #[test]
+    fn broken_tcp_connections_are_replaced() {
+        let (address, accepted) = mock_tcp_server(true);
+        let transport = TcpTransport::new(&address);
+
+        assert_eq!(transport.exchange(QUERY).unwrap(), QUERY);
+        // The pooled connection has been closed by the server by now
+        assert_eq!(transport.exchange(QUERY).unwrap(), QUERY);
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert_eq!(transport.idle_connections(), 1);
+    }
+
+    // Hands out its bytes a few at a time, like a slow TCP peer
+    struct ChunkedReader {
+        data: Vec<u8>,
+        position: usize,
+        chunk: usize,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let end = (self.position + self.chunk)
+                .min(self.data.len())
+                .min(self.position + buf.len());
+            let read = end - self.position;
+            buf[..read].copy_from_slice(&self.data[self.position..end]);
+            self.position = end;
+            Ok(read)
+        }
+    }
+
+    fn framed(message: &[u8]) -> Vec<u8> {
+        let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(message);
+        framed
+    }
+
+    #[test]
+    fn tcp_messages_are_reassembled_from_short_reads() {
+        let mut data = framed(QUERY);
+        data.extend(framed(b"second"));
+
+        for chunk in [1, 3, 7] {
+            let mut reader = ChunkedReader {
+                data: data.clone(),
+                position: 0,
+                chunk,
+            };
+            assert_eq!(
+                read_tcp_message(&mut reader).unwrap(),
+                QUERY,
+                "chunk {}",
+                chunk
+            );
+            assert_eq!(read_tcp_message(&mut reader).unwrap(), b"second");
+            assert!(read_tcp_message(&mut reader).is_err());
+        }
+    }
+
+    #[test]
+    fn truncated_tcp_messages_are_errors() {
+        let mut data = framed(QUERY);
+        data.truncate(data.len() - 1);
+        let mut reader = ChunkedReader {
+            data,
+            position: 0,
+            chunk: 4,
+        };
+        assert!(read_tcp_message(&mut reader).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::dns::DnsMessage;
+use crate::zone::{is_within, Zone};
+
+// OPCODE of a dynamic update message (RFC 2136)
+pub const OPCODE_UPDATE: u16 = 5;
+
+// RCODEs an update can fail with
+const RCODE_FORMERR: u8 = 1;
+const RCODE_NOTIMP: u8 = 4;
+const RCODE_NOTZONE: u8 = 10;
+
+// Classes that turn an update record into a deletion
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+
+// Apply a dynamic update to the zone, returning how many records were added or
+// the RCODE to reject it with. Only prerequisite-free additions are supported:
+// the zone section names the zone, the prerequisite section must be empty and
+// every update record is added with the zone's class. The whole update is
+// checked before anything is applied, so a rejected update changes nothing.
+pub fn apply(zone: &mut Zone, request: &DnsMessage) -> Result<usize, u8> {
+    // The zone section holds exactly one SOA-typed entry naming the zone
+    let [zone_entry] = request.questions.as_slice() else {
+        return Err(RCODE_FORMERR);
+    };
+    if zone_entry.record_type != 6 {
+        return Err(RCODE_FORMERR);
+    }
+
+    // Prerequisites (carried in the answer section) are not supported yet
+    if !request.answers.is_empty() {
+        return Err(RCODE_NOTIMP);
+    }
+
+    // Every update record must have parsed, or we'd apply a partial update
+    if request.authorities.len() != request.header.nscount as usize {
+        return Err(RCODE_FORMERR);
+    }
+
+    for record in &request.authorities {
+        if !is_within(&record.name, &zone_entry.name) {
+            return Err(RCODE_NOTZONE);
+        }
+
+        match record.class {
+            class if class == zone_entry.class => {}
+            // Deletions are not supported yet
+            CLASS_NONE | CLASS_ANY => return Err(RCODE_NOTIMP),
+            _ => return Err(RCODE_FORMERR),
+        }
+
+        // Meta types (OPT, TKEY, TSIG, IXFR, AXFR, MAILB, MAILA, ANY) can't be stored
+        if record.record_type == 41 || (249..=255).contains(&record.record_type) {
+            return Err(RCODE_FORMERR);
+        }
+    }
+
+    // Adding a record that is already present is a no-op
+    let mut added = 0;
+    for record in &request.authorities {
+        if !zone.contains(record) {
+            zone.insert(record.clone(), 1);
+            added += 1;
+        }
+    }
+
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::dns_header::DnsHeader;
+    use crate::dns::dns_question::DnsQuestion;
+    use crate::dns::dns_record::DnsRecord;
+    use std::net::Ipv4Addr;
+
+    fn name(domain: &str) -> Vec<u8> {
+        DnsQuestion::encode_domain_name(domain)
+    }
+
+    // An UPDATE for `zone` adding the given records
+    fn update(zone: &str, records: Vec<DnsRecord>) -> DnsMessage {
+        let mut header = DnsHeader::new_query(7, false, 1);
+        header.flags |= OPCODE_UPDATE << 11;
+        let mut message = DnsMessage {
+            header,
+            questions: vec![DnsQuestion {
+                name: name(zone),
+                record_type: 6,
+                class: 1,
+            }],
+            answers: Vec::new(),
+            authorities: records,
+            additionals: Vec::new(),
+        };
+        message.sync_counts();
+        message
+    }
+
+    fn a_record(domain: &str) -> DnsRecord {
+        DnsRecord::new(name(domain), Ipv4Addr::new(192, 0, 2, 5))
+    }
+
+    #[test]
+    fn additions_are_applied() {
+        let mut zone = Zone::new();
+        let request = update("example.test", vec![a_record("new.example.test")]);
+
+        assert_eq!(apply(&mut zone, &request), Ok(1));
+        let answers = zone.lookup(&name("new.example.test"), 1, 1);
+        assert_eq!(
+            answers[0].rdata_as_ipv4(),
+            Some(Ipv4Addr::new(192, 0, 2, 5))
+        );
+
+        // Adding it again changes nothing
+        assert_eq!(apply(&mut zone, &request), Ok(0));
+        assert_eq!(zone.lookup(&name("new.example.test"), 1, 1).len(), 1);
+    }
+
+    #[test]
+    fn records_outside_the_zone_reject_the_whole_update() {
+        let mut zone = Zone::new();
+        let request = update(
+            "example.test",
+            vec![a_record("new.example.test"), a_record("other.test")],
+        );
+
+        assert_eq!(apply(&mut zone, &request), Err(RCODE_NOTZONE));
+        assert!(zone.is_empty());
+    }
+
+    #[test]
+    fn unsupported_updates_are_rejected() {
+        let mut zone = Zone::new();
+
+        let mut deletion = a_record("old.example.test");
+        deletion.class = CLASS_ANY;
+        let request = update("example.test", vec![deletion]);
+        assert_eq!(apply(&mut zone, &request), Err(RCODE_NOTIMP));
+
+        let mut request = update("example.test", Vec::new());
+        request.answers.push(a_record("example.test"));
+        request.sync_counts();
+        assert_eq!(apply(&mut zone, &request), Err(RCODE_NOTIMP));
+
+        let mut request = update("example.test", vec![a_record("a.example.test")]);
+        request.questions[0].record_type = 1;
+        assert_eq!(apply(&mut zone, &request), Err(RCODE_FORMERR));
+
+        assert!(zone.is_empty());
+    }
+}
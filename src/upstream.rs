@@ -0,0 +1,383 @@
+use crate::dns::dns_error::DnsError;
+use crate::dns::dns_id::random_u64;
+use crate::transport::{remaining_until, Transport};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Weight given to the newest latency sample in the moving average
+const EWMA_ALPHA: f64 = 0.3;
+
+// Consecutive failures after which an upstream is considered dead
+pub const FAILURE_THRESHOLD: u32 = 3;
+
+// How long a dead upstream is skipped before it gets another try
+pub const DEAD_COOLDOWN: Duration = Duration::from_secs(30);
+
+// How to pick which upstream gets a query
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UpstreamStrategy {
+    First,      // Always prefer the first upstream, the rest are fallbacks
+    RoundRobin, // Rotate through the upstreams
+    Random,     // Start from a random upstream
+    Fastest,    // Prefer the upstream with the lowest average latency
+}
+
+// What we know about one upstream: its latency (an exponentially weighted
+// moving average) and whether it has been failing
+#[derive(Clone, Copy, Default)]
+struct UpstreamState {
+    average: Option<Duration>,
+    consecutive_failures: u32,
+    dead_until: Option<Instant>,
+}
+
+impl UpstreamState {
+    fn record(&mut self, sample: Duration) {
+        self.average = Some(match self.average {
+            Some(average) => average.mul_f64(1.0 - EWMA_ALPHA) + sample.mul_f64(EWMA_ALPHA),
+            None => sample,
+        });
+    }
+
+    // Dead upstreams are skipped until their cooldown ends, after which the next
+    // query re-probes them
+    fn is_dead(&self, now: Instant) -> bool {
+        matches!(self.dead_until, Some(until) if now < until)
+    }
+}
+
+// Spreads queries over several upstream transports according to a strategy.
+// If the chosen upstream fails, the others are tried in turn. Upstreams that keep
+// failing are marked dead and only tried once every live one has failed.
+pub struct UpstreamSelector {
+    upstreams: Vec<Box<dyn Transport>>,
+    strategy: UpstreamStrategy,
+    next: AtomicUsize,
+    states: Mutex<Vec<UpstreamState>>,
+}
+
+impl UpstreamSelector {
+    pub fn new(upstreams: Vec<Box<dyn Transport>>, strategy: UpstreamStrategy) -> Self {
+        let states = Mutex::new(vec![UpstreamState::default(); upstreams.len()]);
+
+        UpstreamSelector {
+            upstreams,
+            strategy,
+            next: AtomicUsize::new(0),
+            states,
+        }
+    }
+
+    // Indices of the upstreams in the order they should be tried at `now`
+    pub fn order(&self, now: Instant) -> Vec<usize> {
+        let count = self.upstreams.len();
+        if count == 0 {
+            return Vec::new();
+        }
+
+        let states = self.states.lock().unwrap();
+        let preferred: Vec<usize> = match self.strategy {
+            UpstreamStrategy::Fastest => {
+                // Unmeasured upstreams sort first so that every one gets sampled
+                let mut order: Vec<usize> = (0..count).collect();
+                order.sort_by_key(|&index| states[index].average.unwrap_or_default());
+                order
+            }
+            strategy => {
+                let start = match strategy {
+                    UpstreamStrategy::RoundRobin => {
+                        self.next.fetch_add(1, Ordering::Relaxed) % count
+                    }
+                    UpstreamStrategy::Random => (random_u64() % count as u64) as usize,
+                    _ => 0,
+                };
+                (0..count).map(|offset| (start + offset) % count).collect()
+            }
+        };
+
+        // Live upstreams first; dead ones remain as a last resort
+        let (live, dead): (Vec<usize>, Vec<usize>) = preferred
+            .into_iter()
+            .partition(|&index| !states[index].is_dead(now));
+        live.into_iter().chain(dead).collect()
+    }
+
+    // Note a successful exchange and its latency
+    pub fn record_success(&self, index: usize, latency: Duration) {
+        if let Some(state) = self.states.lock().unwrap().get_mut(index) {
+            state.record(latency);
+            state.consecutive_failures = 0;
+            state.dead_until = None;
+        }
+    }
+
+    // Note a failed exchange, marking the upstream dead once it keeps failing.
+    // Returns true when this failure is the one that marked it dead.
+    pub fn record_failure(&self, index: usize, elapsed: Duration, now: Instant) -> bool {
+        let mut states = self.states.lock().unwrap();
+        let state = match states.get_mut(index) {
+            Some(state) => state,
+            None => return false,
+        };
+
+        // A failure counts as a timeout-length sample so that "fastest" avoids it
+        state.record(elapsed.max(Duration::from_secs(5)));
+        state.consecutive_failures += 1;
+
+        // A re-probe that fails again starts a new cooldown straight away
+        let was_dead = state.dead_until.is_some();
+        if state.consecutive_failures >= FAILURE_THRESHOLD {
+            state.dead_until = Some(now + DEAD_COOLDOWN);
+            return !was_dead;
+        }
+
+        false
+    }
+
+    // Whether an upstream is currently being skipped
+    pub fn is_dead(&self, index: usize, now: Instant) -> bool {
+        match self.states.lock().unwrap().get(index) {
+            Some(state) => state.is_dead(now),
+            None => false,
+        }
+    }
+
+    // Current average latency of an upstream, if it has answered yet
+    pub fn average_latency(&self, index: usize) -> Option<Duration> {
+        self.states.lock().unwrap().get(index)?.average
+    }
+}
+
+impl UpstreamSelector {
+    // Try the upstreams in order until one answers, giving up on failover once the
+    // deadline (if any) has passed
+    fn exchange_with(&self, query: &[u8], deadline: Option<Instant>) -> Result<Vec<u8>, DnsError> {
+        let mut last_error = DnsError::Parse("No upstream resolvers configured");
+
+        for index in self.order(Instant::now()) {
+            if let Some(deadline) = deadline {
+                remaining_until(deadline)?;
+            }
+
+            let started = Instant::now();
+            let result = match deadline {
+                Some(deadline) => self.upstreams[index].exchange_until(query, deadline),
+                None => self.upstreams[index].exchange(query),
+            };
+            match result {
+                Ok(response) => {
+                    self.record_success(index, started.elapsed());
+                    return Ok(response);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Upstream {} failed: {}",
+                        self.upstreams[index].describe(),
+                        e
+                    );
+                    if self.record_failure(index, started.elapsed(), Instant::now()) {
+                        eprintln!(
+                            "Upstream {} marked dead for {:?}",
+                            self.upstreams[index].describe(),
+                            DEAD_COOLDOWN
+                        );
+                    }
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+impl Transport for UpstreamSelector {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, DnsError> {
+        self.exchange_with(query, None)
+    }
+
+    fn exchange_until(&self, query: &[u8], deadline: Instant) -> Result<Vec<u8>, DnsError> {
+        self.exchange_with(query, Some(deadline))
+    }
+
+    fn describe(&self) -> String {
+        let upstreams: Vec<String> = self.upstreams.iter().map(|u| u.describe()).collect();
+        upstreams.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Arc;
+
+    // An upstream that answers with its own name (or fails), counting its queries
+    struct NamedUpstream {
+        name: &'static str,
+        up: bool,
+        queries: Arc<AtomicUsize>,
+    }
+
+    impl Transport for NamedUpstream {
+        fn exchange(&self, _query: &[u8]) -> Result<Vec<u8>, DnsError> {
+            self.queries.fetch_add(1, Ordering::Relaxed);
+            if self.up {
+                Ok(self.name.as_bytes().to_vec())
+            } else {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "down").into())
+            }
+        }
+
+        fn describe(&self) -> String {
+            self.name.to_string()
+        }
+    }
+
+    // A selector over upstreams named by `names`, with those in `down` failing.
+    // Also returns each upstream's query counter.
+    fn selector(
+        names: &[&'static str],
+        down: &[&str],
+        strategy: UpstreamStrategy,
+    ) -> (UpstreamSelector, Vec<Arc<AtomicUsize>>) {
+        let counters: Vec<Arc<AtomicUsize>> = names
+            .iter()
+            .map(|_| Arc::new(AtomicUsize::new(0)))
+            .collect();
+        let upstreams = names
+            .iter()
+            .zip(&counters)
+            .map(|(&name, queries)| {
+                Box::new(NamedUpstream {
+                    name,
+                    up: !down.contains(&name),
+                    queries: queries.clone(),
+                }) as Box<dyn Transport>
+            })
+            .collect();
+        (UpstreamSelector::new(upstreams, strategy), counters)
+    }
+
+    fn answerer(selector: &UpstreamSelector) -> String {
+        String::from_utf8(selector.exchange(b"query").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_robin_cycles_through_upstreams() {
+        let (selector, _) = selector(&["a", "b", "c"], &[], UpstreamStrategy::RoundRobin);
+
+        let answerers: Vec<String> = (0..6).map(|_| answerer(&selector)).collect();
+        assert_eq!(answerers, ["a", "b", "c", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn first_always_prefers_the_first_upstream() {
+        let (selector, counters) = selector(&["a", "b"], &[], UpstreamStrategy::First);
+
+        for _ in 0..3 {
+            assert_eq!(answerer(&selector), "a");
+        }
+        assert_eq!(counters[1].load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn fastest_prefers_the_lowest_average_latency() {
+        let (selector, _) = selector(&["slow", "fast"], &[], UpstreamStrategy::Fastest);
+
+        for _ in 0..5 {
+            selector.record_success(0, Duration::from_millis(80));
+            selector.record_success(1, Duration::from_millis(10));
+        }
+        assert_eq!(selector.order(Instant::now()), [1, 0]);
+        assert_eq!(answerer(&selector), "fast");
+
+        // The average follows the fast upstream slowing down
+        for _ in 0..10 {
+            selector.record_success(1, Duration::from_millis(200));
+        }
+        assert_eq!(selector.order(Instant::now()), [0, 1]);
+    }
+
+    #[test]
+    fn fastest_samples_unmeasured_upstreams_first() {
+        let (selector, _) = selector(&["a", "b"], &[], UpstreamStrategy::Fastest);
+        selector.record_success(0, Duration::from_millis(5));
+
+        assert_eq!(selector.order(Instant::now()), [1, 0]);
+    }
+
+    #[test]
+    fn failed_upstreams_fail_over_to_the_next() {
+        let (selector, counters) = selector(&["a", "b"], &["a"], UpstreamStrategy::First);
+
+        assert_eq!(answerer(&selector), "b");
+        assert_eq!(counters[0].load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn failing_upstreams_are_skipped_until_their_cooldown_ends() {
+        let (selector, counters) = selector(&["a", "b"], &["a"], UpstreamStrategy::First);
+
+        for _ in 0..FAILURE_THRESHOLD {
+            assert_eq!(answerer(&selector), "b");
+        }
+        let now = Instant::now();
+        assert!(selector.is_dead(0, now));
+        assert_eq!(selector.order(now), [1, 0]);
+
+        // While dead it isn't tried at all
+        assert_eq!(answerer(&selector), "b");
+        assert_eq!(
+            counters[0].load(Ordering::Relaxed),
+            FAILURE_THRESHOLD as usize
+        );
+
+        // After the cooldown it is preferred again, to be re-probed
+        let later = now + DEAD_COOLDOWN;
+        assert!(!selector.is_dead(0, later));
+        assert_eq!(selector.order(later), [0, 1]);
+    }
+
+    #[test]
+    fn a_failed_re_probe_restarts_the_cooldown() {
+        let (selector, _) = selector(&["a"], &[], UpstreamStrategy::First);
+        let now = Instant::now();
+
+        let marked: Vec<bool> = (0..FAILURE_THRESHOLD)
+            .map(|_| selector.record_failure(0, Duration::ZERO, now))
+            .collect();
+        assert_eq!(marked.last(), Some(&true));
+        assert_eq!(marked.iter().filter(|&&dead| dead).count(), 1);
+
+        let later = now + DEAD_COOLDOWN;
+        assert!(!selector.is_dead(0, later));
+        assert!(!selector.record_failure(0, Duration::ZERO, later));
+        assert!(selector.is_dead(0, later));
+    }
+
+    #[test]
+    fn a_success_revives_an_upstream() {
+        let (selector, _) = selector(&["a"], &[], UpstreamStrategy::First);
+        let now = Instant::now();
+        for _ in 0..FAILURE_THRESHOLD {
+            selector.record_failure(0, Duration::ZERO, now);
+        }
+
+        selector.record_success(0, Duration::from_millis(5));
+        assert!(!selector.is_dead(0, now));
+    }
+
+    #[test]
+    fn dead_upstreams_are_the_last_resort() {
+        let (selector, counters) = selector(&["a"], &[], UpstreamStrategy::First);
+        let now = Instant::now();
+        for _ in 0..FAILURE_THRESHOLD {
+            selector.record_failure(0, Duration::ZERO, now);
+        }
+
+        assert_eq!(answerer(&selector), "a");
+        assert_eq!(counters[0].load(Ordering::Relaxed), 1);
+    }
+}
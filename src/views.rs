@@ -0,0 +1,135 @@
+use crate::zone::Zone;
+use std::net::IpAddr;
+
+// A block of client addresses, e.g. 10.0.0.0/8
+#[derive(Clone, Copy, Debug)]
+pub struct ClientNetwork {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl ClientNetwork {
+    // Parse CIDR notation like 192.168.0.0/16 or fd00::/8
+    pub fn from_cidr(cidr: &str) -> Result<Self, String> {
+        let (address, prefix) = match cidr.split_once('/') {
+            Some(parts) => parts,
+            None => return Err(format!("Missing prefix length in {}", cidr)),
+        };
+
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("Invalid address in {}", cidr))?;
+        let prefix_length: u8 = prefix
+            .parse()
+            .map_err(|_| format!("Invalid prefix length in {}", cidr))?;
+
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        if prefix_length > max_prefix {
+            return Err(format!("Prefix length too long in {}", cidr));
+        }
+
+        Ok(ClientNetwork {
+            address,
+            prefix_length,
+        })
+    }
+
+    // Whether a client address falls inside the network. IPv4 clients reaching
+    // us over IPv6 (::ffff:a.b.c.d) count as IPv4.
+    pub fn contains(&self, client: IpAddr) -> bool {
+        match (self.address, client.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(client)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_length as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(client) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(client)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_length as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(client) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Parse a `--view` (or `--reverse-zone`) value, `<cidr>:<zonefile>`. IPv6 CIDRs
+// contain colons too, so the separator is the first colon after the prefix
+// length's slash.
+pub fn parse_view_arg(value: &str, flag: &str) -> Result<(ClientNetwork, String), String> {
+    let separator = value
+        .find('/')
+        .and_then(|slash| value[slash..].find(':').map(|offset| slash + offset));
+
+    match separator {
+        Some(index) if index + 1 < value.len() => Ok((
+            ClientNetwork::from_cidr(&value[..index])?,
+            value[index + 1..].to_string(),
+        )),
+        _ => Err(format!(
+            "Expected <cidr>:<zonefile> for {}, got {}",
+            flag, value
+        )),
+    }
+}
+
+// A zone served instead of the default one to clients from a network
+// (split-horizon DNS). The first view whose network contains the client wins.
+pub struct View {
+    pub network: ClientNetwork,
+    pub zone: Zone,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(cidr: &str) -> ClientNetwork {
+        ClientNetwork::from_cidr(cidr).unwrap()
+    }
+
+    #[test]
+    fn networks_contain_their_clients() {
+        let internal = network("10.0.0.0/8");
+        assert!(internal.contains("10.255.0.1".parse().unwrap()));
+        assert!(!internal.contains("11.0.0.1".parse().unwrap()));
+        // IPv4-mapped IPv6 clients count as IPv4
+        assert!(internal.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!internal.contains("fd00::1".parse().unwrap()));
+
+        let everyone = network("0.0.0.0/0");
+        assert!(everyone.contains("198.51.100.7".parse().unwrap()));
+
+        let ula = network("fd00::/8");
+        assert!(ula.contains("fd12::1".parse().unwrap()));
+        assert!(!ula.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_cidrs_are_rejected() {
+        for cidr in [
+            "10.0.0.0",
+            "10.0.0.0/33",
+            "fd00::/129",
+            "nope/8",
+            "10.0.0.0/x",
+        ] {
+            assert!(ClientNetwork::from_cidr(cidr).is_err(), "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn view_args_split_after_the_prefix_length() {
+        let (network, path) = parse_view_arg("fd00::/8:internal.zone", "--view").unwrap();
+        assert!(network.contains("fd00::1".parse().unwrap()));
+        assert_eq!(path, "internal.zone");
+
+        let (_, path) = parse_view_arg("10.0.0.0/8:/etc/zones/internal", "--view").unwrap();
+        assert_eq!(path, "/etc/zones/internal");
+
+        assert!(parse_view_arg("10.0.0.0/8:", "--view").is_err());
+        assert!(parse_view_arg("internal.zone", "--view").is_err());
+    }
+}
@@ -0,0 +1,1020 @@
+use crate::dns::dns_error::DnsError;
+use crate::dns::dns_id::random_u64;
+use crate::dns::dns_record::{DnsRecord, DEFAULT_ZONE_TTL, NSEC_TYPE};
+use crate::transport::http_get;
+use std::collections::HashMap;
+use std::fs;
+use std::time::Duration;
+
+// How many CNAMEs we follow within the zone before giving up on a chain
+const MAX_CNAME_DEPTH: usize = 8;
+
+// A record served from the local zone, with its weight for answer ordering
+pub struct ZoneEntry {
+    pub record: DnsRecord,
+    pub weight: u32,
+}
+
+// A negative answer from a signed zone: the SOA for the authority section and
+// the NSEC records proving the denial (RFC 4035 section 3.1.3)
+pub struct Denial {
+    pub name_exists: bool, // NODATA rather than NXDOMAIN
+    pub soa: DnsRecord,
+    pub nsec: Vec<DnsRecord>,
+}
+
+// A zone-file directive, setting how the records after it are read
+enum Directive {
+    Ttl(u32),       // Default TTL
+    Origin(String), // Name relative names are appended to
+}
+
+// Locally served records, keyed by lowercased encoded owner name
+#[derive(Default)]
+pub struct Zone {
+    records: HashMap<Vec<u8>, Vec<ZoneEntry>>,
+}
+
+impl Zone {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Load a zone file from disk
+    pub fn load(path: &str, type_ttls: &[(u16, u32)]) -> Result<Self, DnsError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| DnsError::Zone(format!("cannot read {}: {}", path, e)))?;
+        Self::parse(&text, type_ttls)
+    }
+
+    // Download a zone file over plain HTTP
+    pub fn fetch(url: &str, timeout: Duration, type_ttls: &[(u16, u32)]) -> Result<Self, DnsError> {
+        let body = http_get(url, timeout)
+            .map_err(|e| DnsError::Zone(format!("cannot fetch {}: {}", url, e)))?;
+        let text = String::from_utf8(body)
+            .map_err(|_| DnsError::Zone(format!("{} is not valid UTF-8", url)))?;
+        Self::parse(&text, type_ttls)
+    }
+
+    // Parse zone text with one record per line: `name [ttl] [class] TYPE rdata [weight]`.
+    // The optional trailing weight only applies to A/AAAA records.
+    // Blank lines and lines starting with ';' or '#' are ignored.
+    //
+    // Names ending in '.' are absolute; others are relative to the `$ORIGIN`
+    // from the lines above, and `@` stands for the origin itself. Without an
+    // origin every name is taken as absolute.
+    //
+    // Records without a TTL get the one configured for their type in `type_ttls`
+    // (--zone-ttl), else the zone's `$TTL` from the lines above, else
+    // DEFAULT_ZONE_TTL.
+    pub fn parse(text: &str, type_ttls: &[(u16, u32)]) -> Result<Self, DnsError> {
+        let mut zone = Zone::new();
+        let mut zone_ttl = DEFAULT_ZONE_TTL;
+        let mut origin = String::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with(';') || trimmed.starts_with('#') {
+                continue;
+            }
+
+            if trimmed.starts_with('$') {
+                match Self::parse_directive(trimmed, &origin) {
+                    Ok(Directive::Ttl(ttl)) => zone_ttl = ttl,
+                    Ok(Directive::Origin(name)) => origin = name,
+                    Err(message) => {
+                        return Err(DnsError::Zone(format!("line {}: {}", index + 1, message)))
+                    }
+                }
+                continue;
+            }
+
+            let default_ttl = |record_type: u16| {
+                type_ttls
+                    .iter()
+                    .find(|(configured, _)| *configured == record_type)
+                    .map_or(zone_ttl, |(_, ttl)| *ttl)
+            };
+
+            let (line, weight) = Self::split_weight(trimmed);
+            let record = DnsRecord::from_zone_line_with_ttl(&origin, line, default_ttl).map_err(
+                |e| match e {
+                    DnsError::Zone(message) => {
+                        DnsError::Zone(format!("line {}: {}", index + 1, message))
+                    }
+                    other => other,
+                },
+            )?;
+
+            zone.insert(record, weight);
+        }
+
+        Ok(zone)
+    }
+
+    // A `$TTL <seconds>` or `$ORIGIN <name>` line; a relative origin is taken
+    // relative to the current one
+    fn parse_directive(line: &str, origin: &str) -> Result<Directive, String> {
+        let line = line.split(';').next().unwrap_or("");
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let directive = fields[0].to_ascii_uppercase();
+
+        match (directive.as_str(), fields.as_slice()) {
+            ("$TTL", [_, ttl]) => ttl
+                .parse()
+                .map(Directive::Ttl)
+                .map_err(|_| format!("invalid $TTL {}", ttl)),
+            ("$TTL", _) => Err(String::from("expected `$TTL <seconds>`")),
+            ("$ORIGIN", [_, name]) => Ok(Directive::Origin(DnsRecord::resolve_zone_name(
+                name, origin,
+            ))),
+            ("$ORIGIN", _) => Err(String::from("expected `$ORIGIN <name>`")),
+            _ => Err(format!("unsupported directive {}", fields[0])),
+        }
+    }
+
+    // Split the optional trailing weight off an A/AAAA line
+    fn split_weight(line: &str) -> (&str, u32) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let is_address = fields.iter().rev().nth(2).is_some_and(|field| {
+            field.eq_ignore_ascii_case("A") || field.eq_ignore_ascii_case("AAAA")
+        });
+
+        match (
+            is_address,
+            fields.last().and_then(|last| last.parse::<u32>().ok()),
+        ) {
+            (true, Some(weight)) => {
+                let end = line.rfind(char::is_whitespace).unwrap_or(line.len());
+                (line[..end].trim_end(), weight)
+            }
+            _ => (line, 1),
+        }
+    }
+
+    // Add a record to the zone
+    pub fn insert(&mut self, record: DnsRecord, weight: u32) {
+        self.records
+            .entry(record.name.to_ascii_lowercase())
+            .or_default()
+            .push(ZoneEntry { record, weight });
+    }
+
+    // Whether an identical record (owner, type, class and data) is already served
+    pub fn contains(&self, record: &DnsRecord) -> bool {
+        self.records
+            .get(&record.name.to_ascii_lowercase())
+            .is_some_and(|entries| entries.iter().any(|entry| entry.record == *record))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    // Find the records for a name/type/class, ordered by weighted random selection
+    // so that higher-weight records come first more often
+    pub fn lookup(&self, name: &[u8], record_type: u16, class: u16) -> Vec<DnsRecord> {
+        let mut candidates: Vec<&ZoneEntry> = match self.records.get(&name.to_ascii_lowercase()) {
+            Some(entries) => entries
+                .iter()
+                .filter(|entry| {
+                    entry.record.record_type == record_type && entry.record.class == class
+                })
+                .collect(),
+            None => return Vec::new(),
+        };
+
+        let mut ordered = Vec::with_capacity(candidates.len());
+        while !candidates.is_empty() {
+            let index = Self::pick_weighted(&candidates);
+            ordered.push(candidates.remove(index).record.clone());
+        }
+
+        ordered
+    }
+
+    // Like `lookup`, but when the name only has a CNAME, follow the chain through
+    // the zone: the answer is the CNAMEs in order followed by the target's records.
+    // A chain leaving the zone (or too long) yields just the CNAMEs found so far.
+    pub fn lookup_following_cnames(
+        &self,
+        name: &[u8],
+        record_type: u16,
+        class: u16,
+    ) -> Vec<DnsRecord> {
+        let mut answers = Vec::new();
+        let mut current = name.to_vec();
+
+        for _ in 0..=MAX_CNAME_DEPTH {
+            let records = self.lookup(&current, record_type, class);
+            if !records.is_empty() || record_type == 5 {
+                answers.extend(records);
+                return answers;
+            }
+
+            let cname = match self.lookup(&current, 5, class).into_iter().next() {
+                Some(cname) => cname,
+                None => return answers,
+            };
+
+            current = cname.rdata.clone();
+            answers.push(cname);
+
+            // A chain that comes back to a name already visited is a loop
+            if answers.iter().any(|record| record.name_matches(&current)) {
+                println!("CNAME loop detected, stopping");
+                return answers;
+            }
+        }
+
+        println!(
+            "CNAME chain longer than {} links, stopping",
+            MAX_CNAME_DEPTH
+        );
+        answers
+    }
+
+    // If an ancestor of the name carries a DNAME, return that DNAME plus the CNAME it
+    // implies for the name: the owner's prefix is rewritten onto the DNAME target
+    // (e.g. `a.old.test` under `old.test DNAME new.test` becomes `a.new.test`).
+    pub fn synthesize_dname(&self, name: &[u8], class: u16) -> Option<Vec<DnsRecord>> {
+        let lowercase_name = name.to_ascii_lowercase();
+
+        // Walk the label boundaries; each suffix is the encoded ancestor name
+        let mut position = 0;
+        while position < name.len() && name[position] != 0 {
+            position += 1 + name[position] as usize;
+            if position >= name.len() {
+                break;
+            }
+
+            let ancestor = &lowercase_name[position..];
+            let dname = self.records.get(ancestor).and_then(|entries| {
+                entries
+                    .iter()
+                    .find(|entry| entry.record.record_type == 39 && entry.record.class == class)
+            });
+
+            if let Some(entry) = dname {
+                let mut target = name[..position].to_vec();
+                target.extend_from_slice(&entry.record.rdata);
+
+                // The rewritten name must still fit the 255-byte limit
+                if target.len() > 255 {
+                    return None;
+                }
+
+                let mut cname = DnsRecord::new_cname(name.to_vec(), target);
+                cname.class = class;
+                cname.ttl = entry.record.ttl;
+
+                return Some(vec![entry.record.clone(), cname]);
+            }
+        }
+
+        None
+    }
+
+    // The SOA of the zone whose apex is `name`, if we are authoritative for one there
+    pub fn soa(&self, name: &[u8], class: u16) -> Option<DnsRecord> {
+        self.lookup(name, 6, class).into_iter().next()
+    }
+
+    // Find the closest delegation covering a name: the NS records at the name or its
+    // nearest ancestor that has any, plus whatever A/AAAA glue the zone holds for
+    // the name servers. NS records at an apex (next to an SOA) name our own
+    // servers, not a delegation, so the search stops there.
+    pub fn find_delegation(
+        &self,
+        name: &[u8],
+        class: u16,
+    ) -> Option<(Vec<DnsRecord>, Vec<DnsRecord>)> {
+        // Walk the label boundaries from the name itself up to (not including) the root
+        let mut position = 0;
+        while position < name.len() && name[position] != 0 {
+            if self.soa(&name[position..], class).is_some() {
+                return None;
+            }
+
+            let ns_records = self.lookup(&name[position..], 2, class);
+            if !ns_records.is_empty() {
+                let glue = ns_records
+                    .iter()
+                    .flat_map(|ns| self.addresses(&ns.rdata, class))
+                    .collect();
+
+                return Some((ns_records, glue));
+            }
+
+            position += 1 + name[position] as usize;
+        }
+
+        None
+    }
+
+    // Every record of the zone rooted at `origin`, for a zone transfer: the SOA,
+    // then the other records at or below the origin in canonical name order, then
+    // the SOA again. None when we hold no SOA for the origin, i.e. aren't
+    // authoritative for it.
+    pub fn transfer(&self, origin: &[u8], class: u16) -> Option<Vec<DnsRecord>> {
+        let soa = self.soa(origin, class)?;
+
+        let mut records = vec![soa.clone()];
+        records.extend(
+            self.iter()
+                .filter(|(name, record)| {
+                    is_within(name, origin) && record.class == class && record.record_type != 6
+                })
+                .map(|(_, record)| record.clone()),
+        );
+        records.push(soa);
+
+        Some(records)
+    }
+
+    // Every record held, with its lowercased owner name, in canonical name order
+    // and by type within a name, so dumps and transfers come out the same every time
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &DnsRecord)> {
+        let mut names: Vec<&Vec<u8>> = self.records.keys().collect();
+        names.sort_by_cached_key(|name| canonical_labels(name));
+
+        names.into_iter().flat_map(move |name| {
+            let mut records: Vec<&DnsRecord> = self.records[name]
+                .iter()
+                .map(|entry| &entry.record)
+                .collect();
+            records.sort_by_key(|record| record.record_type);
+            records
+                .into_iter()
+                .map(move |record| (name.as_slice(), record))
+        })
+    }
+
+    // The negative answer for a name without records of the queried type, in a
+    // zone we're authoritative for that is signed (has NSEC records). A name that
+    // exists gets its own NSEC, showing which types it has; an empty non-terminal
+    // the NSEC covering it; and a name that doesn't exist the NSECs covering it
+    // and the wildcard at its closest encloser. None outside our zones, below a
+    // delegation, or in an unsigned zone, where there is nothing to prove.
+    pub fn deny(&self, name: &[u8], class: u16) -> Option<Denial> {
+        let name = name.to_ascii_lowercase();
+
+        let mut apex = None;
+        let mut position = 0;
+        while position < name.len() {
+            let ancestor = &name[position..];
+            if let Some(soa) = self.soa(ancestor, class) {
+                apex = Some((ancestor, soa));
+                break;
+            }
+            if !self.lookup(ancestor, 2, class).is_empty() || name[position] == 0 {
+                return None;
+            }
+            position += 1 + name[position] as usize;
+        }
+        let (apex, soa) = apex?;
+
+        let nsec: Vec<&DnsRecord> = self
+            .records
+            .iter()
+            .filter(|(owner, _)| is_within(owner, apex))
+            .flat_map(|(_, entries)| entries.iter().map(|entry| &entry.record))
+            .filter(|record| record.record_type == NSEC_TYPE && record.class == class)
+            .collect();
+        if nsec.is_empty() {
+            return None;
+        }
+
+        // Whether a name owns records, or has descendants that do
+        let exists =
+            |candidate: &[u8]| self.records.keys().any(|owner| is_within(owner, candidate));
+
+        let (name_exists, proofs) = if self.records.contains_key(&name) {
+            let own = nsec
+                .iter()
+                .copied()
+                .filter(|record| record.name.eq_ignore_ascii_case(&name));
+            (true, own.collect())
+        } else if exists(&name) {
+            (true, Vec::from_iter(Self::covering(&nsec, &name)))
+        } else {
+            // The closest encloser: the nearest ancestor that exists, at worst the apex
+            let mut position = 1 + name[0] as usize;
+            while !exists(&name[position..]) {
+                position += 1 + name[position] as usize;
+            }
+            let mut wildcard = vec![1, b'*'];
+            wildcard.extend_from_slice(&name[position..]);
+
+            let mut proofs = Vec::from_iter(Self::covering(&nsec, &name));
+            if let Some(record) = Self::covering(&nsec, &wildcard) {
+                if !proofs.contains(&record) {
+                    proofs.push(record);
+                }
+            }
+            (false, proofs)
+        };
+
+        Some(Denial {
+            name_exists,
+            soa,
+            nsec: proofs.into_iter().cloned().collect(),
+        })
+    }
+
+    // The NSEC record whose owner sorts before a name and whose next name sorts
+    // after it, in canonical order; the last NSEC's next name wraps to the apex
+    fn covering<'a>(nsec: &[&'a DnsRecord], name: &[u8]) -> Option<&'a DnsRecord> {
+        let name = canonical_key(name);
+        nsec.iter().copied().find(|record| {
+            let owner = canonical_key(&record.name);
+            let next = canonical_key(next_name(&record.rdata));
+            owner < name && (name < next || next <= owner)
+        })
+    }
+
+    // Additional-section processing: the A/AAAA records the zone holds for the
+    // name server of each NS record (so apex NS answers come with their
+    // addresses), the exchange of each MX record and the target of each SRV record
+    pub fn target_addresses(&self, answers: &[DnsRecord], class: u16) -> Vec<DnsRecord> {
+        let mut additionals: Vec<DnsRecord> = Vec::new();
+
+        for answer in answers {
+            let target = match answer.record_type {
+                2 => Some(&answer.rdata[..]), // NS: name server
+                15 => answer.rdata.get(2..),  // MX: preference, exchange
+                33 => answer.rdata.get(6..),  // SRV: priority, weight, port, target
+                _ => None,
+            };
+
+            for address in target
+                .map(|target| self.addresses(target, class))
+                .unwrap_or_default()
+            {
+                // Several records may share a target
+                if !additionals.contains(&address) {
+                    additionals.push(address);
+                }
+            }
+        }
+
+        additionals
+    }
+
+    // The A and AAAA records for a name
+    fn addresses(&self, name: &[u8], class: u16) -> Vec<DnsRecord> {
+        let mut addresses = self.lookup(name, 1, class);
+        addresses.extend(self.lookup(name, 28, class));
+        addresses
+    }
+
+    // Pick an index with probability proportional to its weight (weight 0 entries go last)
+    fn pick_weighted(candidates: &[&ZoneEntry]) -> usize {
+        let total: u64 = candidates.iter().map(|entry| entry.weight as u64).sum();
+        if total == 0 {
+            return 0;
+        }
+
+        let mut target = random_u64() % total;
+        for (index, entry) in candidates.iter().enumerate() {
+            if target < entry.weight as u64 {
+                return index;
+            }
+            target -= entry.weight as u64;
+        }
+
+        0
+    }
+}
+
+// Whether an encoded name equals the encoded zone name or lies below it
+pub fn is_within(name: &[u8], zone: &[u8]) -> bool {
+    let name = name.to_ascii_lowercase();
+    let zone = zone.to_ascii_lowercase();
+
+    // Walk the label boundaries; each suffix is an encoded ancestor name
+    let mut position = 0;
+    while position < name.len() {
+        if name[position..] == zone[..] {
+            return true;
+        }
+        if name[position] == 0 {
+            break;
+        }
+        position += 1 + name[position] as usize;
+    }
+
+    false
+}
+
+// The labels of an encoded name from the root down, so that sorting by them
+// gives DNSSEC canonical order (RFC 4034): parents before their children
+fn canonical_labels(name: &[u8]) -> Vec<&[u8]> {
+    let mut labels = Vec::new();
+    let mut position = 0;
+    while position < name.len() && name[position] != 0 {
+        let end = (position + 1 + name[position] as usize).min(name.len());
+        labels.push(&name[position + 1..end]);
+        position = end;
+    }
+    labels.reverse();
+    labels
+}
+
+// A name's lowercased labels root first, which compare in canonical DNS name
+// order (RFC 4034 section 6.1)
+fn canonical_key(name: &[u8]) -> Vec<Vec<u8>> {
+    canonical_labels(&name.to_ascii_lowercase())
+        .into_iter()
+        .map(<[u8]>::to_vec)
+        .collect()
+}
+
+// The next owner name leading an NSEC record's data
+fn next_name(rdata: &[u8]) -> &[u8] {
+    let mut position = 0;
+    while position < rdata.len() && rdata[position] != 0 {
+        position += 1 + rdata[position] as usize;
+    }
+    &rdata[..(position + 1).min(rdata.len())]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::dns_question::DnsQuestion;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+
+    fn name(domain: &str) -> Vec<u8> {
+        DnsQuestion::encode_domain_name(domain)
+    }
+
+    fn zone(text: &str) -> Zone {
+        Zone::parse(text, &[]).unwrap()
+    }
+
+    // How often each address comes first over `rounds` lookups
+    fn first_counts(zone: &Zone, domain: &str, rounds: usize) -> HashMap<Ipv4Addr, usize> {
+        let mut counts = HashMap::new();
+        for _ in 0..rounds {
+            let answers = zone.lookup(&name(domain), 1, 1);
+            *counts
+                .entry(answers[0].rdata_as_ipv4().unwrap())
+                .or_default() += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn heavier_records_come_first_more_often() {
+        let zone = zone(
+            "www.example.test A 192.0.2.1 3\n\
+             www.example.test A 192.0.2.2 1\n",
+        );
+
+        let rounds = 4000;
+        let counts = first_counts(&zone, "www.example.test", rounds);
+        let heavy = counts[&Ipv4Addr::new(192, 0, 2, 1)] as f64 / rounds as f64;
+
+        // Expected 0.75; the bounds are many standard deviations wide
+        assert!(
+            (0.70..0.80).contains(&heavy),
+            "heavy record first {}",
+            heavy
+        );
+        assert_eq!(zone.lookup(&name("www.example.test"), 1, 1).len(), 2);
+    }
+
+    #[test]
+    fn unweighted_records_share_first_place() {
+        let zone = zone(
+            "www.example.test A 192.0.2.1\n\
+             www.example.test A 192.0.2.2\n",
+        );
+
+        let rounds = 4000;
+        let counts = first_counts(&zone, "www.example.test", rounds);
+        let first = counts[&Ipv4Addr::new(192, 0, 2, 1)] as f64 / rounds as f64;
+        assert!(
+            (0.45..0.55).contains(&first),
+            "first record first {}",
+            first
+        );
+    }
+
+    #[test]
+    fn zero_weight_records_go_last() {
+        let zone = zone(
+            "www.example.test A 192.0.2.1 0\n\
+             www.example.test A 192.0.2.2 5\n",
+        );
+
+        let counts = first_counts(&zone, "www.example.test", 200);
+        assert_eq!(counts.get(&Ipv4Addr::new(192, 0, 2, 1)), None);
+    }
+
+    #[test]
+    fn names_under_a_dname_get_a_synthesized_cname() {
+        let zone = zone("old.test 300 DNAME new.test\n");
+
+        let answers = zone.synthesize_dname(&name("www.a.OLD.test"), 1).unwrap();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].decode_dname().unwrap(), "new.test");
+
+        let cname = &answers[1];
+        assert_eq!(cname.record_type, 5);
+        assert_eq!(cname.name, name("www.a.OLD.test"));
+        assert_eq!(cname.rdata, name("www.a.new.test"));
+        assert_eq!(cname.ttl, 300);
+    }
+
+    #[test]
+    fn a_dname_does_not_rewrite_its_own_owner() {
+        let zone = zone("old.test DNAME new.test\n");
+        assert!(zone.synthesize_dname(&name("old.test"), 1).is_none());
+        assert!(zone.synthesize_dname(&name("other.test"), 1).is_none());
+        assert!(zone.synthesize_dname(&name("a.old.test"), 3).is_none());
+    }
+
+    #[test]
+    fn rewrites_past_the_name_limit_are_not_synthesized() {
+        let long_target = format!("{}.test", vec!["a".repeat(63); 3].join("."));
+        let zone = zone(&format!("old.test DNAME {}\n", long_target));
+
+        let owner = format!("{}.old.test", "b".repeat(63));
+        assert!(zone.synthesize_dname(&name(&owner), 1).is_none());
+        assert!(zone.synthesize_dname(&name("short.old.test"), 1).is_some());
+    }
+
+    fn owners_and_types(records: &[DnsRecord]) -> Vec<(String, u16)> {
+        records
+            .iter()
+            .map(|record| (record.decode_name().unwrap(), record.record_type))
+            .collect()
+    }
+
+    #[test]
+    fn cname_chains_are_followed_to_the_address() {
+        let zone = zone(
+            "www.example.test CNAME web.example.test.\n\
+             web.example.test CNAME example.test.\n\
+             example.test A 192.0.2.4\n",
+        );
+
+        let answers = zone.lookup_following_cnames(&name("www.example.test"), 1, 1);
+        assert_eq!(
+            owners_and_types(&answers),
+            [
+                (String::from("www.example.test"), 5),
+                (String::from("web.example.test"), 5),
+                (String::from("example.test"), 1),
+            ]
+        );
+        assert_eq!(
+            answers[2].rdata_as_ipv4(),
+            Some(Ipv4Addr::new(192, 0, 2, 4))
+        );
+
+        // Asking for the CNAME itself doesn't follow it
+        let answers = zone.lookup_following_cnames(&name("www.example.test"), 5, 1);
+        assert_eq!(answers.len(), 1);
+    }
+
+    #[test]
+    fn cname_loops_stop() {
+        let zone = zone(
+            "a.test CNAME b.test.\n\
+             b.test CNAME a.test.\n",
+        );
+
+        let answers = zone.lookup_following_cnames(&name("a.test"), 1, 1);
+        assert_eq!(answers.len(), 2);
+    }
+
+    #[test]
+    fn long_cname_chains_stop_at_the_depth_limit() {
+        let text: String = (0..20)
+            .map(|hop| format!("h{}.test CNAME h{}.test.\n", hop, hop + 1))
+            .collect();
+        let zone = zone(&text);
+
+        let answers = zone.lookup_following_cnames(&name("h0.test"), 1, 1);
+        assert_eq!(answers.len(), MAX_CNAME_DEPTH + 1);
+        assert!(answers.iter().all(|record| record.record_type == 5));
+    }
+
+    #[test]
+    fn chains_leaving_the_zone_return_the_cnames() {
+        let zone = zone("www.example.test CNAME cdn.elsewhere.test.\n");
+
+        let answers = zone.lookup_following_cnames(&name("www.example.test"), 1, 1);
+        assert_eq!(
+            owners_and_types(&answers),
+            [(String::from("www.example.test"), 5)]
+        );
+    }
+
+    // A one-shot HTTP server answering any request with `response`
+    fn serve_once(response: String) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 512];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(read) => request.extend_from_slice(&buf[..read]),
+                }
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+
+        port
+    }
+
+    fn http_ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn zones_are_fetched_over_http() {
+        let port = serve_once(http_ok(
+            "$ORIGIN example.test.\nwww A 192.0.2.80\nmail 120 TXT hello\n",
+        ));
+        let url = format!("http://127.0.0.1:{}/example.zone", port);
+
+        let zone = Zone::fetch(&url, TIMEOUT, &[(16, 30)]).unwrap();
+        let www = zone.lookup(&name("www.example.test"), 1, 1);
+        assert_eq!(www[0].rdata_as_ipv4(), Some(Ipv4Addr::new(192, 0, 2, 80)));
+        assert_eq!(zone.lookup(&name("mail.example.test"), 16, 1)[0].ttl, 120);
+    }
+
+    #[test]
+    fn failed_downloads_are_errors() {
+        let port = serve_once(String::from(
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        ));
+        let url = format!("http://127.0.0.1:{}/missing.zone", port);
+        assert!(matches!(
+            Zone::fetch(&url, TIMEOUT, &[]),
+            Err(DnsError::Zone(_))
+        ));
+
+        let port = serve_once(http_ok("www A not-an-address\n"));
+        let url = format!("http://127.0.0.1:{}/bad.zone", port);
+        assert!(Zone::fetch(&url, TIMEOUT, &[]).is_err());
+    }
+
+    #[test]
+    fn delegations_are_found_with_their_glue() {
+        let zone = zone(
+            "example.test SOA ns.example.test. host.example.test. 1 3600 600 86400 300\n\
+             example.test NS ns.example.test.\n\
+             sub.example.test NS ns1.sub.example.test.\n\
+             sub.example.test NS ns.elsewhere.test.\n\
+             ns1.sub.example.test A 192.0.2.53\n",
+        );
+
+        let (ns_records, glue) = zone
+            .find_delegation(&name("a.b.sub.example.test"), 1)
+            .unwrap();
+        assert_eq!(ns_records.len(), 2);
+        assert_eq!(glue.len(), 1);
+        assert_eq!(glue[0].name, name("ns1.sub.example.test"));
+
+        // The apex NS records are ours, not a delegation
+        assert!(zone.find_delegation(&name("www.example.test"), 1).is_none());
+        assert!(zone.find_delegation(&name("other.test"), 1).is_none());
+    }
+
+    #[test]
+    fn mx_and_srv_targets_are_added_once() {
+        let local = zone(
+            "example.test MX 10 mail.example.test.\n\
+             example.test MX 20 mail.example.test.\n\
+             _sip._udp.example.test SRV 0 5 5060 sip.example.test.\n\
+             mail.example.test A 192.0.2.25\n\
+             mail.example.test AAAA 2001:db8::25\n\
+             sip.example.test A 192.0.2.60\n",
+        );
+
+        let mx = local.lookup(&name("example.test"), 15, 1);
+        let additionals = local.target_addresses(&mx, 1);
+        let types: Vec<u16> = additionals
+            .iter()
+            .map(|record| record.record_type)
+            .collect();
+        assert_eq!(types, [1, 28]);
+        assert!(additionals
+            .iter()
+            .all(|record| record.name == name("mail.example.test")));
+
+        let srv = local.lookup(&name("_sip._udp.example.test"), 33, 1);
+        let additionals = local.target_addresses(&srv, 1);
+        assert_eq!(additionals.len(), 1);
+        assert_eq!(additionals[0].rdata, [192, 0, 2, 60]);
+
+        // Targets outside the zone have nothing to add
+        let external = zone("example.test MX 10 mx.elsewhere.test.\n");
+        let mx = external.lookup(&name("example.test"), 15, 1);
+        assert!(external.target_addresses(&mx, 1).is_empty());
+    }
+
+    const SIGNED_ZONE: &str = "\
+        example.test SOA ns.example.test. host.example.test. 1 3600 600 86400 300\n\
+        example.test NSEC a.b.example.test. SOA NSEC\n\
+        a.b.example.test A 192.0.2.1\n\
+        a.b.example.test NSEC www.example.test. A NSEC\n\
+        www.example.test A 192.0.2.80\n\
+        www.example.test NSEC example.test. A NSEC\n";
+
+    fn nsec_owners(denial: &Denial) -> Vec<Vec<u8>> {
+        denial
+            .nsec
+            .iter()
+            .map(|record| record.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn existing_names_are_proven_by_their_own_nsec() {
+        let signed = zone(SIGNED_ZONE);
+        let denial = signed.deny(&name("WWW.example.test"), 1).unwrap();
+        assert!(denial.name_exists);
+        assert_eq!(denial.soa.record_type, 6);
+        assert_eq!(nsec_owners(&denial), [name("www.example.test")]);
+    }
+
+    #[test]
+    fn empty_non_terminals_are_proven_by_the_covering_nsec() {
+        let signed = zone(SIGNED_ZONE);
+        let denial = signed.deny(&name("b.example.test"), 1).unwrap();
+        assert!(denial.name_exists);
+        assert_eq!(nsec_owners(&denial), [name("example.test")]);
+    }
+
+    #[test]
+    fn missing_names_are_proven_with_the_wildcard() {
+        let signed = zone(SIGNED_ZONE);
+
+        // nope.example.test sits between a.b and www; *.example.test between the
+        // apex and a.b
+        let denial = signed.deny(&name("nope.example.test"), 1).unwrap();
+        assert!(!denial.name_exists);
+        assert_eq!(
+            nsec_owners(&denial),
+            [name("a.b.example.test"), name("example.test")]
+        );
+
+        // Past the last name, the covering NSEC wraps to the apex
+        let denial = signed.deny(&name("zzz.example.test"), 1).unwrap();
+        assert_eq!(
+            nsec_owners(&denial),
+            [name("www.example.test"), name("example.test")]
+        );
+    }
+
+    #[test]
+    fn unsigned_zones_and_other_names_have_no_denial() {
+        let unsigned =
+            zone("example.test SOA ns.example.test. host.example.test. 1 3600 600 86400 300\n");
+        assert!(unsigned.deny(&name("nope.example.test"), 1).is_none());
+
+        let signed = zone(SIGNED_ZONE);
+        assert!(signed.deny(&name("nope.other.test"), 1).is_none());
+    }
+
+    fn ttl_of(zone: &Zone, domain: &str, record_type: u16) -> u32 {
+        zone.lookup(&name(domain), record_type, 1)[0].ttl
+    }
+
+    #[test]
+    fn ttl_directives_set_the_default_for_later_records() {
+        let parsed = zone(
+            "before.test A 192.0.2.1\n\
+             $TTL 600 ; ten minutes\n\
+             after.test A 192.0.2.2\n\
+             explicit.test 30 A 192.0.2.3\n\
+             $ttl 60\n\
+             last.test IN A 192.0.2.4\n",
+        );
+
+        assert_eq!(ttl_of(&parsed, "before.test", 1), DEFAULT_ZONE_TTL);
+        assert_eq!(ttl_of(&parsed, "after.test", 1), 600);
+        assert_eq!(ttl_of(&parsed, "explicit.test", 1), 30);
+        assert_eq!(ttl_of(&parsed, "last.test", 1), 60);
+    }
+
+    #[test]
+    fn type_ttls_override_the_zone_default() {
+        let parsed = Zone::parse(
+            "$TTL 600\n\
+             example.test NS ns.example.test.\n\
+             example.test 30 NS ns2.example.test.\n\
+             ns.example.test A 192.0.2.53\n",
+            &[(2, 86400)],
+        )
+        .unwrap();
+
+        let mut ns_ttls: Vec<u32> = parsed
+            .lookup(&name("example.test"), 2, 1)
+            .iter()
+            .map(|record| record.ttl)
+            .collect();
+        // Lookups shuffle records of equal weight
+        ns_ttls.sort_unstable();
+        assert_eq!(ns_ttls, [30, 86400]);
+        assert_eq!(ttl_of(&parsed, "ns.example.test", 1), 600);
+    }
+
+    #[test]
+    fn bad_directives_name_their_line() {
+        for text in [
+            "$TTL\n",
+            "$TTL soon\n",
+            "a.test A 192.0.2.1\n$INCLUDE other.zone\n",
+        ] {
+            let error = Zone::parse(text, &[]).err().unwrap().to_string();
+            let line = text.lines().count();
+            assert!(error.contains(&format!("line {}", line)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn origin_directives_apply_to_later_names() {
+        let parsed = zone(
+            "$ORIGIN example.test.\n\
+             @ A 192.0.2.1\n\
+             www A 192.0.2.80\n\
+             $ORIGIN sub\n\
+             host A 192.0.2.90\n\
+             $ORIGIN other.test.\n\
+             alias CNAME www.example.test.\n",
+        );
+
+        let records: Vec<DnsRecord> = parsed.iter().map(|(_, record)| record.clone()).collect();
+        assert_eq!(
+            owners_and_types(&records),
+            [
+                ("example.test".to_string(), 1),
+                ("host.sub.example.test".to_string(), 1),
+                ("www.example.test".to_string(), 1),
+                ("alias.other.test".to_string(), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn origin_directives_need_a_name() {
+        assert!(Zone::parse("$ORIGIN\n", &[]).is_err());
+        assert!(Zone::parse("$ORIGIN a.test. b.test.\n", &[]).is_err());
+    }
+
+    #[test]
+    fn iteration_is_canonical_whatever_the_insertion_order() {
+        let lines = [
+            "WWW.example.test A 192.0.2.80",
+            "example.test MX 10 mail.example.test.",
+            "b.example.test A 192.0.2.2",
+            "example.test A 192.0.2.1",
+            "z.a.example.test A 192.0.2.3",
+            "example.other A 198.51.100.1",
+        ];
+        let expected = [
+            ("example.other".to_string(), 1),
+            ("example.test".to_string(), 1),
+            ("example.test".to_string(), 15),
+            ("z.a.example.test".to_string(), 1),
+            ("b.example.test".to_string(), 1),
+            ("www.example.test".to_string(), 1),
+        ];
+
+        for reversed in [false, true] {
+            let mut text: Vec<&str> = lines.to_vec();
+            if reversed {
+                text.reverse();
+            }
+            let parsed = zone(&text.join("\n"));
+
+            let owners: Vec<(String, u16)> = parsed
+                .iter()
+                .map(|(owner, record)| {
+                    let owner = DnsQuestion::decode_domain_name(owner).unwrap();
+                    (owner, record.record_type)
+                })
+                .collect();
+            assert_eq!(owners, expected);
+        }
+    }
+}
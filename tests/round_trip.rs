@@ -0,0 +1,157 @@
+// Relaying a message means parsing it and encoding it again. These captures,
+// held inline, check that every name survives that trip whatever pointers
+// the original used, and that OPT, TSIG and truncated messages come back intact.
+
+use codecrafters_dns_server::dns::dns_edns;
+use codecrafters_dns_server::dns::dns_question::DnsQuestion;
+use codecrafters_dns_server::dns::dns_tsig::{self, TsigKey};
+use codecrafters_dns_server::dns::DnsMessage;
+use std::net::Ipv4Addr;
+
+// www.example.com A, answered by a CNAME to web.example.com and its address.
+// The CNAME owner points at the question name, its target is `web` plus a
+// pointer to `example.com` inside the question, and the A owner points into
+// the CNAME's own data.
+const CNAME_CHAIN: &[u8] = &[
+    0xBE, 0xEF, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, // header
+    0x03, b'w', b'w', b'w', // 12: www
+    0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', // 16: example
+    0x03, b'c', b'o', b'm', 0x00, // 24: com
+    0x00, 0x01, 0x00, 0x01, // A IN
+    0xC0, 0x0C, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x0E, 0x10, 0x00, 0x06, // CNAME
+    0x03, b'w', b'e', b'b', 0xC0, 0x10, // 45: web -> example.com
+    0xC0, 0x2D, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0E, 0x10, 0x00, 0x04, // A
+    0x5D, 0xB8, 0xD8, 0x22, // 93.184.216.34
+];
+
+// example.com A with an EDNS OPT record: 1232-byte payload, DO set and an
+// 8-byte client cookie
+const WITH_OPT: &[u8] = &[
+    0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, // header
+    0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, // 12
+    0x00, 0x01, 0x00, 0x01, // A IN
+    0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2C, 0x00, 0x04, // A
+    0xC0, 0x00, 0x02, 0x01, // 192.0.2.1
+    0x00, 0x00, 0x29, 0x04, 0xD0, 0x00, 0x00, 0x80, 0x00, 0x00, 0x0C, // OPT
+    0x00, 0x0A, 0x00, 0x08, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, // cookie
+];
+
+// A UDP answer cut short by the server: TC set and only the question kept
+const TRUNCATED: &[u8] = &[
+    0x56, 0x78, 0x83, 0x80, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // header
+    0x03, b'b', b'i', b'g', 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm',
+    0x00, // big.example.com
+    0x00, 0x10, 0x00, 0x01, // TXT IN
+];
+
+// Parse a message, encode it again and parse the result
+fn relay(bytes: &[u8]) -> (DnsMessage, DnsMessage) {
+    let parsed = DnsMessage::from_bytes(bytes).unwrap();
+    let relayed = DnsMessage::from_buf(parsed.to_bytes()).unwrap();
+    (parsed, relayed)
+}
+
+// Every name in a message, in wire order: questions, owners, then the names
+// inside CNAME data
+fn names(message: &DnsMessage) -> Vec<String> {
+    let mut names: Vec<String> = message
+        .questions
+        .iter()
+        .map(|question| question.decode_name().unwrap())
+        .collect();
+    for record in message.answers.iter().chain(&message.additionals) {
+        names.push(record.decode_name().unwrap());
+        if record.record_type == 5 {
+            names.push(DnsQuestion::decode_domain_name(&record.rdata).unwrap());
+        }
+    }
+    names
+}
+
+#[test]
+fn cname_chains_decompress_and_survive_relaying() {
+    let (parsed, relayed) = relay(CNAME_CHAIN);
+
+    let expected = [
+        "www.example.com",
+        "www.example.com",
+        "web.example.com",
+        "web.example.com",
+    ];
+    assert_eq!(names(&parsed), expected);
+    assert_eq!(names(&relayed), expected);
+    assert_eq!(
+        relayed.answers[1].rdata_as_ipv4(),
+        Some(Ipv4Addr::new(93, 184, 216, 34))
+    );
+    assert!(relayed.semantically_eq(&parsed));
+
+    // Re-encoding compresses as well as the original did
+    assert!(parsed.to_bytes().len() <= CNAME_CHAIN.len());
+}
+
+#[test]
+fn opt_records_survive_relaying() {
+    let (parsed, relayed) = relay(WITH_OPT);
+
+    for message in [&parsed, &relayed] {
+        let opt = message.opt_record().unwrap();
+        assert_eq!(opt.name, [0]);
+        assert_eq!(opt.class, 1232);
+        assert!(message.dnssec_ok());
+        assert_eq!(
+            dns_edns::find_option(&opt.rdata, 10),
+            Some(vec![1, 2, 3, 4, 5, 6, 7, 8])
+        );
+    }
+    assert_eq!(names(&relayed), ["example.com", "example.com", ""]);
+    assert!(relayed.semantically_eq(&parsed));
+}
+
+#[test]
+fn tsig_signed_messages_still_verify_after_relaying() {
+    let key = TsigKey::from_arg("transfer.example.com:c2VjcmV0LXNlY3JldC1zZWNyZXQ=").unwrap();
+    let now = 1_700_000_000;
+    let signed = dns_tsig::sign(CNAME_CHAIN, &key, None, now, 0).unwrap();
+
+    let (parsed, relayed) = relay(&signed);
+    let tsig = relayed.additionals.last().unwrap();
+    assert_eq!(tsig.record_type, dns_tsig::TSIG_TYPE);
+    assert_eq!(tsig.decode_name().unwrap(), "transfer.example.com");
+    assert!(relayed.semantically_eq(&parsed));
+
+    // The MAC covers the bytes before the TSIG record, so they must come back
+    // exactly as signed
+    assert!(dns_tsig::verify(&parsed.to_bytes(), &key, now).is_ok());
+}
+
+#[test]
+fn truncated_messages_keep_tc_and_their_question() {
+    let (parsed, relayed) = relay(TRUNCATED);
+
+    for message in [&parsed, &relayed] {
+        assert!(message.header.truncated());
+        assert_eq!(message.header.id, 0x5678);
+        assert!(message.answers.is_empty());
+    }
+    assert_eq!(names(&relayed), ["big.example.com"]);
+    assert_eq!(relayed.questions[0].record_type, 16);
+    assert_eq!(&parsed.to_bytes()[..], TRUNCATED);
+}
+
+#[test]
+fn answers_cut_off_mid_record_are_dropped() {
+    // The CNAME chain with the A record's address missing
+    let cut = &CNAME_CHAIN[..CNAME_CHAIN.len() - 4];
+    let parsed = DnsMessage::from_bytes(cut).unwrap();
+    assert_eq!(parsed.answers.len(), 1);
+
+    let mut relayable = parsed.clone();
+    relayable.sync_counts();
+    let relayed = DnsMessage::from_buf(relayable.to_bytes()).unwrap();
+    assert_eq!(
+        names(&relayed),
+        ["www.example.com", "www.example.com", "web.example.com"]
+    );
+    assert_eq!(relayed.header.ancount, 1);
+}